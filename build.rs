@@ -0,0 +1,39 @@
+//! Records what `--version` and `--build-info` report about the build
+
+use std::{path::Path, process::Command};
+
+fn main() {
+    // Rebuilt when a commit is made or checked out
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    if let Ok(head) = std::fs::read_to_string(".git/HEAD")
+        && let Some(branch) = head.trim().strip_prefix("ref: ")
+        && Path::new(".git").join(branch).exists()
+    {
+        println!("cargo:rerun-if-changed=.git/{}", branch);
+    }
+
+    // Builds from a source archive have no commit
+    if let Ok(output) = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        && output.status.success()
+    {
+        let commit = String::from_utf8_lossy(&output.stdout);
+        println!("cargo:rustc-env=CUPRUM_GIT_COMMIT={}", commit.trim());
+    }
+
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(name, _)| Some(name.strip_prefix("CARGO_FEATURE_")?.to_lowercase()))
+        .map(|feature| feature.replace('_', "-"))
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=CUPRUM_FEATURES={}", features.join(","));
+    println!(
+        "cargo:rustc-env=CUPRUM_PROFILE={}",
+        std::env::var("PROFILE").unwrap_or_default()
+    );
+    println!(
+        "cargo:rustc-env=CUPRUM_TARGET={}",
+        std::env::var("TARGET").unwrap_or_default()
+    );
+}
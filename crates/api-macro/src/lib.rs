@@ -1,16 +1,62 @@
 use heck::ToUpperCamelCase;
-use proc_macro::{Span, TokenStream};
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
 use quote::quote;
 use syn::{
-    Attribute, Ident, Token, Type, parenthesized,
+    Attribute, Expr, ExprLit, Ident, Lit, LitStr, Token, Type, parenthesized,
     parse::{Parse, ParseStream},
-    parse_macro_input, parse_quote,
+    parse_quote,
 };
 
 struct Method {
     name: Ident,
     args: Vec<(Ident, Type)>,
     ret: Option<Type>,
+    /// Former names from `#[alias = "..."]`, still accepted on the wire
+    aliases: Vec<LitStr>,
+    /// `///` comments, copied to the generated method and enum variants
+    docs: Vec<Attribute>,
+    /// `#[deprecated]`, copied to the generated method only so the editor can still
+    /// handle the request without warnings
+    deprecated: Option<Attribute>,
+    /// `Capability` variant from `#[capability(...)]` that plugins need to call the method
+    capability: Option<Ident>,
+}
+
+/// Attributes accepted on a method
+#[derive(Default)]
+struct MethodAttrs {
+    aliases: Vec<LitStr>,
+    docs: Vec<Attribute>,
+    deprecated: Option<Attribute>,
+    capability: Option<Ident>,
+}
+
+fn parse_method_attrs(attrs: Vec<Attribute>) -> syn::Result<MethodAttrs> {
+    let mut method_attrs = MethodAttrs::default();
+    for attr in attrs {
+        if attr.path().is_ident("doc") {
+            method_attrs.docs.push(attr);
+        } else if attr.path().is_ident("deprecated") {
+            method_attrs.deprecated = Some(attr);
+        } else if attr.path().is_ident("capability") {
+            method_attrs.capability = Some(attr.parse_args()?);
+        } else if attr.path().is_ident("alias") {
+            match &attr.meta.require_name_value()?.value {
+                Expr::Lit(ExprLit {
+                    lit: Lit::Str(alias),
+                    ..
+                }) => method_attrs.aliases.push(alias.clone()),
+                value => return Err(syn::Error::new_spanned(value, "expected a string")),
+            }
+        } else {
+            return Err(syn::Error::new_spanned(
+                attr,
+                "expected a doc comment, `#[deprecated]`, `#[alias = \"...\"]` or `#[capability(...)]`",
+            ));
+        }
+    }
+    Ok(method_attrs)
 }
 
 struct ApiDef {
@@ -22,6 +68,7 @@ impl Parse for ApiDef {
         let mut methods = Vec::new();
 
         while !input.is_empty() {
+            let attrs = parse_method_attrs(input.call(Attribute::parse_outer)?)?;
             input.parse::<Token![fn]>()?;
             let name: Ident = input.parse()?;
 
@@ -51,7 +98,15 @@ impl Parse for ApiDef {
                 None
             };
 
-            methods.push(Method { name, args, ret });
+            methods.push(Method {
+                name,
+                args,
+                ret,
+                aliases: attrs.aliases,
+                docs: attrs.docs,
+                deprecated: attrs.deprecated,
+                capability: attrs.capability,
+            });
         }
 
         Ok(ApiDef { methods })
@@ -60,7 +115,14 @@ impl Parse for ApiDef {
 
 #[proc_macro]
 pub fn define_api(input: TokenStream) -> TokenStream {
-    let ApiDef { methods } = parse_macro_input!(input as ApiDef);
+    match syn::parse(input) {
+        Ok(api) => expand(api).into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand(api: ApiDef) -> TokenStream2 {
+    let ApiDef { methods } = api;
 
     let methods_impl = methods.iter().map(|method| {
         let method_name = &method.name;
@@ -85,27 +147,33 @@ pub fn define_api(input: TokenStream) -> TokenStream {
         let method_args = method_args.map(|arg| arg.1);
 
         let method_camel_name = method_name.to_string().to_upper_camel_case();
-        let method_camel_name = Ident::new(&method_camel_name, Span::call_site().into());
+        let method_camel_name = Ident::new(&method_camel_name, Span::call_site());
+        let docs = &method.docs;
+        let deprecated = &method.deprecated;
 
         if let Some(method_ret) = method_ret {
             quote! {
-                pub async fn #method_name(&mut self, #( #method_args_with_type ),* ) -> anyhow::Result<#method_ret> {
+                #( #docs )*
+                #deprecated
+                pub async fn #method_name(&mut self, #( #method_args_with_type ),* ) -> Result<#method_ret, CuprumApiError> {
                     if let Some(CuprumApiResponseKind::#method_camel_name(result)) = self
                         .provider
-                        .send_message(CuprumApiRequestKind::#method_camel_name( #( #method_args ),* ))
+                        .send_message(CuprumApiRequestKind::#method_camel_name { #( #method_args ),* })
                         .await?
                     {
                         Ok(result)
                     } else {
-                        Err(anyhow::anyhow!("mismatched types"))
+                        Err(CuprumApiError::MismatchedResponse)
                     }
                 }
             }
         } else {
             quote! {
-                pub async fn #method_name(&mut self, #( #method_args_with_type ),* ) -> anyhow::Result<()> {
+                #( #docs )*
+                #deprecated
+                pub async fn #method_name(&mut self, #( #method_args_with_type ),* ) -> Result<(), CuprumApiError> {
                     self.provider
-                        .send_message(CuprumApiRequestKind::#method_camel_name( #( #method_args ),*))
+                        .send_message(CuprumApiRequestKind::#method_camel_name { #( #method_args ),* })
                         .await?;
                     Ok(())
                 }
@@ -113,29 +181,95 @@ pub fn define_api(input: TokenStream) -> TokenStream {
         }
     });
 
+    // Variants are tagged with the method name and requests carry their arguments by name,
+    // so renaming a variant or reordering arguments does not change the wire format
     let methods_enums = methods.iter().map(|method| {
         let method_camel_name = method.name.to_string().to_upper_camel_case();
-        let method_camel_name = Ident::new(&method_camel_name, Span::call_site().into());
+        let method_camel_name = Ident::new(&method_camel_name, Span::call_site());
+        let tag = method.name.to_string();
+        let aliases = &method.aliases;
+        let docs = &method.docs;
+        let serde_attr = quote! {
+            #( #docs )*
+            #[serde(rename = #tag #(, alias = #aliases )*)]
+        };
 
         let method_args = method.args.iter().map(|arg| {
+            let arg_name = &arg.0;
             let arg_type = &arg.1;
             quote! {
-                #arg_type
+                #arg_name: #arg_type
             }
         });
 
         (
             quote! {
-                #method_camel_name( #( #method_args ),* )
+                #serde_attr
+                #method_camel_name { #( #method_args ),* }
             },
             (method.ret.clone()).map(|method_ret| {
                 quote! {
+                    #serde_attr
                     #method_camel_name( #method_ret )
                 }
             }),
         )
     });
 
+    // Each method can also be added to a batch
+    let batch_methods = methods.iter().map(|method| {
+        let method_name = &method.name;
+        let method_camel_name = method_name.to_string().to_upper_camel_case();
+        let method_camel_name = Ident::new(&method_camel_name, Span::call_site());
+        let docs = &method.docs;
+        let deprecated = &method.deprecated;
+        let arg_names = method.args.iter().map(|arg| &arg.0);
+        let args_with_type = method.args.iter().map(|(arg_name, arg_type)| {
+            quote! {
+                #arg_name: #arg_type
+            }
+        });
+
+        quote! {
+            #( #docs )*
+            #deprecated
+            pub fn #method_name(mut self, #( #args_with_type ),* ) -> Self {
+                self.requests
+                    .push(CuprumApiRequestKind::#method_camel_name { #( #arg_names ),* });
+                self
+            }
+        }
+    });
+
+    let method_infos = methods.iter().map(|method| {
+        let name = method.name.to_string();
+        let returns = method.ret.is_some();
+        let args = method.args.iter().map(|arg| arg.0.to_string());
+        quote! {
+            ApiMethodInfo {
+                name: #name,
+                returns: #returns,
+                args: &[ #( #args ),* ],
+            }
+        }
+    });
+
+    let request_infos = methods.iter().map(|method| {
+        let method_camel_name = method.name.to_string().to_upper_camel_case();
+        let method_camel_name = Ident::new(&method_camel_name, Span::call_site());
+        let tag = method.name.to_string();
+        let capability = match &method.capability {
+            Some(capability) => quote! { Some(Capability::#capability) },
+            None => quote! { None },
+        };
+        (
+            quote! { Self::#method_camel_name { .. } => #tag },
+            quote! { Self::#method_camel_name { .. } => #capability },
+        )
+    });
+    let request_names = request_infos.clone().map(|info| info.0);
+    let request_capabilities = request_infos.map(|info| info.1);
+
     let request = methods_enums.clone().map(|method_enums| method_enums.0);
     let response = methods_enums.filter_map(|method_enums| method_enums.1);
 
@@ -147,24 +281,52 @@ pub fn define_api(input: TokenStream) -> TokenStream {
     let expanded = quote! {
         #enum_derive_attr
         pub enum CuprumApiRequestKind {
-            #( #request ),*
+            #( #request, )*
+            /// Several requests answered at once, in order
+            #[serde(rename = "batch")]
+            Batch { requests: Vec<CuprumApiRequestKind> },
+        }
+
+        impl CuprumApiRequestKind {
+            /// The method name, as the request is tagged on the wire
+            pub fn method_name(&self) -> &'static str {
+                match self {
+                    #( #request_names, )*
+                    Self::Batch { .. } => "batch",
+                }
+            }
+
+            /// What a plugin needs to be allowed to make this request.
+            /// A batch needs what each of its requests needs.
+            pub fn capability(&self) -> Option<Capability> {
+                match self {
+                    #( #request_capabilities, )*
+                    Self::Batch { .. } => None,
+                }
+            }
         }
 
         #struct_derive_attr
         pub struct CuprumApiRequest {
+            pub version: u32,
             pub id: RequestId,
             pub kind: CuprumApiRequestKind,
         }
 
         #enum_derive_attr
         pub enum CuprumApiResponseKind {
-            #( #response ),*
+            #( #response, )*
+            /// The result of each request of a batch. One failing does not stop the others.
+            #[serde(rename = "batch")]
+            Batch(Vec<Result<Option<CuprumApiResponseKind>, CuprumApiError>>),
         }
 
         #struct_derive_attr
         pub struct CuprumApiResponse {
+            pub version: u32,
             pub id: RequestId,
             pub kind: Option<CuprumApiResponseKind>,
+            pub error: Option<CuprumApiError>,
         }
 
         #api_struct_derive_attr
@@ -177,9 +339,115 @@ pub fn define_api(input: TokenStream) -> TokenStream {
                 Self { provider }
             }
 
+            /// Start a batch of requests, sent together with `CuprumApiBatch::send`
+            pub fn batch(&mut self) -> CuprumApiBatch<'_, T> {
+                CuprumApiBatch {
+                    api: self,
+                    requests: Vec::new(),
+                }
+            }
+
             #( #methods_impl )*
         }
+
+        /// Requests collected to be sent in one message
+        pub struct CuprumApiBatch<'a, T: CuprumApiProvider> {
+            api: &'a mut CuprumApi<T>,
+            requests: Vec<CuprumApiRequestKind>,
+        }
+
+        impl<T: CuprumApiProvider> CuprumApiBatch<'_, T> {
+            /// Send the requests, getting the result of each in the same order
+            pub async fn send(
+                self,
+            ) -> Result<Vec<Result<Option<CuprumApiResponseKind>, CuprumApiError>>, CuprumApiError> {
+                let requests = self.requests;
+                if let Some(CuprumApiResponseKind::Batch(results)) = self
+                    .api
+                    .provider
+                    .send_message(CuprumApiRequestKind::Batch { requests })
+                    .await?
+                {
+                    Ok(results)
+                } else {
+                    Err(CuprumApiError::MismatchedResponse)
+                }
+            }
+
+            #( #batch_methods )*
+        }
+
+        /// Every method of the API, in declaration order
+        pub const API_METHODS: &[ApiMethodInfo] = &[
+            #( #method_infos, )*
+            ApiMethodInfo {
+                name: "batch",
+                returns: true,
+                args: &["requests"],
+            },
+        ];
     };
 
-    expanded.into()
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand_str(input: TokenStream2) -> String {
+        expand(syn::parse2(input).unwrap()).to_string()
+    }
+
+    #[test]
+    fn test_docs_and_deprecated() {
+        let expanded = expand_str(quote! {
+            /// Get the mode
+            #[deprecated]
+            fn get_mode() -> Mode
+        });
+
+        // On the method, the batch method, the request variant and the response variant
+        assert_eq!(expanded.matches("# [doc = r\" Get the mode\"]").count(), 4);
+        assert_eq!(expanded.matches("# [deprecated]").count(), 2);
+        assert!(expanded.contains(
+            "# [deprecated] pub async fn get_mode (& mut self ,) -> Result < Mode , CuprumApiError >"
+        ));
+    }
+
+    #[test]
+    fn test_method_index() {
+        let expanded = expand_str(quote! {
+            fn get_line(buf: Option<BufferId>, y: usize) -> String
+            fn close_popup(id: PopupId)
+        });
+        assert!(expanded.contains(
+            "pub const API_METHODS : & [ApiMethodInfo] = & [\
+             ApiMethodInfo { name : \"get_line\" , returns : true , args : & [\"buf\" , \"y\"] , } , \
+             ApiMethodInfo { name : \"close_popup\" , returns : false , args : & [\"id\"] , } , \
+             ApiMethodInfo { name : \"batch\" , returns : true , args : & [\"requests\"] , } ,]"
+        ));
+    }
+
+    #[test]
+    fn test_capability() {
+        let expanded = expand_str(quote! {
+            #[capability(WriteBuffers)]
+            fn insert_line(buf: Option<BufferId>, y: usize, line: String)
+            fn get_mode() -> Mode
+        });
+        assert!(expanded.contains(
+            "Self :: InsertLine { .. } => Some (Capability :: WriteBuffers) , \
+             Self :: GetMode { .. } => None ,"
+        ));
+    }
+
+    #[test]
+    fn test_unknown_attribute() {
+        let input = quote! {
+            #[inline]
+            fn get_mode() -> Mode
+        };
+        assert!(syn::parse2::<ApiDef>(input).is_err());
+    }
 }
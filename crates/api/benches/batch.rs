@@ -0,0 +1,110 @@
+//! Compare reading lines with one request each and with a single batch.
+//!
+//! Run with `cargo bench -p api`. It talks over a Unix domain socket like plugins do, so
+//! it only runs on Unix.
+
+#[cfg(unix)]
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use api::{
+    CuprumApi, CuprumApiRequest, CuprumApiRequestKind, CuprumApiResponse, CuprumApiResponseKind,
+    CuprumApiResult, DefaultCuprumApiProvider, Hello, PROTOCOL_VERSION,
+};
+#[cfg(unix)]
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixListener,
+};
+
+#[cfg(unix)]
+const LINES: usize = 1000;
+
+#[cfg(unix)]
+fn answer(request: CuprumApiRequestKind) -> CuprumApiResult {
+    match request {
+        CuprumApiRequestKind::GetLine { y, .. } => {
+            Ok(Some(CuprumApiResponseKind::GetLine(format!("line {}", y))))
+        }
+        CuprumApiRequestKind::Batch { requests } => Ok(Some(CuprumApiResponseKind::Batch(
+            requests.into_iter().map(answer).collect(),
+        ))),
+        request => panic!("unexpected request {:?}", request),
+    }
+}
+
+/// Accept one plugin and answer its requests like the editor would, without the editor state
+#[cfg(unix)]
+async fn fake_editor(listener: UnixListener) {
+    let (stream, _) = listener.accept().await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    let hello = serde_json::to_string(&Hello {
+        protocol_version: PROTOCOL_VERSION,
+        editor_version: env!("CARGO_PKG_VERSION").to_string(),
+    })
+    .unwrap();
+    writer
+        .write_all(format!("{}\n", hello).as_bytes())
+        .await
+        .unwrap();
+
+    let mut line = String::new();
+    reader.read_line(&mut line).await.unwrap();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await.unwrap() == 0 {
+            return;
+        }
+        let request: CuprumApiRequest = serde_json::from_str(&line).unwrap();
+        let response = CuprumApiResponse::new(request.id, answer(request.kind));
+        let response = serde_json::to_string(&response).unwrap();
+        writer
+            .write_all(format!("{}\n", response).as_bytes())
+            .await
+            .unwrap();
+    }
+}
+
+#[cfg(unix)]
+fn report(name: &str, elapsed: Duration) {
+    println!(
+        "{:<24} {:>10.2?} ({:.2?} per line)",
+        name,
+        elapsed,
+        elapsed / LINES as u32
+    );
+}
+
+#[cfg(unix)]
+#[tokio::main]
+async fn main() {
+    let path = std::env::temp_dir().join(format!("cuprum-bench-{}.sock", std::process::id()));
+    std::fs::remove_file(&path).ok();
+    let listener = UnixListener::bind(&path).unwrap();
+    tokio::spawn(fake_editor(listener));
+    let mut api = CuprumApi::new(DefaultCuprumApiProvider::with_socket(path.clone(), None));
+
+    let start = Instant::now();
+    for y in 0..LINES {
+        api.get_line(None, y).await.unwrap();
+    }
+    report("1000 get_line requests", start.elapsed());
+
+    let start = Instant::now();
+    let mut batch = api.batch();
+    for y in 0..LINES {
+        batch = batch.get_line(None, y);
+    }
+    let results = batch.send().await.unwrap();
+    assert_eq!(results.len(), LINES);
+    report("1 batch of 1000", start.elapsed());
+
+    std::fs::remove_file(path).ok();
+}
+
+#[cfg(not(unix))]
+fn main() {
+    println!("Skipped: the benchmark needs Unix domain sockets");
+}
@@ -1,15 +1,35 @@
+//! The API between the editor and its plugins.
+//!
+//! Every message is one line of JSON. After the `Hello` / `HelloReply` handshake:
+//!
+//! - the plugin sends `CuprumApiRequest`s, which the editor answers with `CuprumApiResponse`s
+//!   carrying the same `RequestId`;
+//! - the editor sends `CuprumNotification`s, which are not answered;
+//! - the editor sends `PluginRequest`s, which the plugin answers with `PluginResponse`s
+//!   carrying the same `PluginRequestId`. These ids are counted separately from `RequestId`s.
+//!
+//! Messages are told apart by their shape, see `EditorMessage` and `PluginMessage`.
+
 use std::{
     collections::HashMap,
     fmt::{self, Debug, Display},
-    sync::Arc,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
 };
 
-use anyhow::anyhow;
 use api_macro::define_api;
 use serde::{Deserialize, Serialize};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, stdin, stdout},
-    sync::{Mutex, Notify},
+    io::{
+        self, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader,
+        stdin, stdout,
+    },
+    sync::{Mutex, Notify, oneshot},
+    time::timeout,
 };
 use utils::vec2::{IVec2, UVec2};
 
@@ -19,6 +39,121 @@ pub struct BufferId(pub usize);
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct WindowId(pub usize);
 
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PopupId(pub usize);
+
+/// Replace the characters `start..end` of the content, with lines joined by `\n`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TextEdit {
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+/// Characters `start..end` of the content, with `x` counted in characters like LSP positions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TextRange {
+    pub start: UVec2,
+    pub end: UVec2,
+}
+
+/// Replace a range of the content, the line-based form of `TextEdit`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RangeEdit {
+    pub range: TextRange,
+    pub text: String,
+}
+
+/// Ordered from the most severe
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum DiagnosticSeverity {
+    Error,
+    Warning,
+    Info,
+    Hint,
+}
+
+impl Display for DiagnosticSeverity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                DiagnosticSeverity::Error => "error",
+                DiagnosticSeverity::Warning => "warning",
+                DiagnosticSeverity::Info => "info",
+                DiagnosticSeverity::Hint => "hint",
+            }
+        )
+    }
+}
+
+/// A problem reported for a range of a buffer, such as a compiler error
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub range: TextRange,
+    pub severity: DiagnosticSeverity,
+    pub message: String,
+}
+
+/// What a plugin may do, granted in its manifest. Requests that need none are always allowed.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    ReadBuffers,
+    WriteBuffers,
+    ChangeMode,
+    /// Add mappings, which can run any command
+    RunKeymaps,
+    /// Open and write files
+    Filesystem,
+    /// Change options with `set_option`
+    ChangeOptions,
+}
+
+impl Capability {
+    pub const ALL: &[Capability] = &[
+        Capability::ReadBuffers,
+        Capability::WriteBuffers,
+        Capability::ChangeMode,
+        Capability::RunKeymaps,
+        Capability::Filesystem,
+        Capability::ChangeOptions,
+    ];
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Capability::ReadBuffers => "read_buffers",
+                Capability::WriteBuffers => "write_buffers",
+                Capability::ChangeMode => "change_mode",
+                Capability::RunKeymaps => "run_keymaps",
+                Capability::Filesystem => "filesystem",
+                Capability::ChangeOptions => "change_options",
+            }
+        )
+    }
+}
+
+/// A bordered box drawn on top of the text area
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Popup {
+    pub lines: Vec<String>,
+    /// Top-left corner on the screen
+    pub position: UVec2,
+    /// Outer size, including the border
+    pub size: UVec2,
+    pub border: bool,
+    pub title: Option<String>,
+    /// Closed by Esc. Popups that stay open, like a clock, leave Esc to the mode.
+    #[serde(default)]
+    pub dismissable: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Position {
     Number(usize),
@@ -26,7 +161,35 @@ pub enum Position {
     End,
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SearchDirection {
+    Forward,
+    Backward,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MessageLevel {
+    Info,
+    Error,
+}
+
+/// Which end of the status line a plugin segment is placed at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StatusAlign {
+    Left,
+    Right,
+}
+
+/// Where `open_window` places the new window relative to the active one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SplitDirection {
+    /// Above/below
+    Horizontal,
+    /// Side by side
+    Vertical,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Mode {
     #[default]
     Normal,
@@ -51,56 +214,502 @@ impl Display for Mode {
     }
 }
 
+/// What `get_buffer_info` reports about a buffer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BufferInfo {
+    pub id: BufferId,
+    pub path: Option<String>,
+    /// File name, or `[No Name]` for buffers without a file
+    pub name: String,
+    /// Whether there are unsaved changes
+    pub dirty: bool,
+    pub line_count: usize,
+    pub read_only: bool,
+    /// Such as `rust`, if it is known
+    pub filetype: Option<String>,
+}
+
+/// What `get_editor_info` reports about the running editor
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EditorInfo {
+    /// Semver version, with the commit as build metadata when it is known
+    pub version: String,
+    /// Short hash of the commit the editor was built from
+    pub commit: Option<String>,
+    /// The `PROTOCOL_VERSION` of the editor
+    pub protocol_version: u32,
+    /// Cargo features the editor was built with
+    pub features: Vec<String>,
+    /// Where plugins are installed
+    pub plugin_dir: String,
+}
+
+/// An entry of the editor's message history
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryMessage {
+    /// Local time in RFC 3339 format
+    pub time: String,
+    pub is_error: bool,
+    pub text: String,
+}
+
+/// Version of the messages exchanged with plugins, bumped on every wire-format change,
+/// including any change to the request, response and notification enums
+pub const PROTOCOL_VERSION: u32 = 17;
+
+/// How long to wait for the answer to a request by default
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// First message from the editor to a plugin
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub editor_version: String,
+}
+
+/// The plugin's answer to `Hello`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HelloReply {
+    pub protocol_version: u32,
+    /// The value of `PLUGIN_ID_ENV`, which tells the editor which plugin connected to its socket
+    #[serde(default)]
+    pub plugin_id: Option<u64>,
+}
+
+/// Environment variable with the path of the editor's socket, set for the plugins it starts.
+/// Without it, plugins talk to the editor over stdin and stdout.
+pub const SOCKET_ENV: &str = "CUPRUM_SOCKET";
+/// Environment variable with the id a plugin sends back in `HelloReply`
+pub const PLUGIN_ID_ENV: &str = "CUPRUM_PLUGIN_ID";
+
+type Reader = BufReader<Box<dyn AsyncRead + Unpin + Send>>;
+type Writer = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// Why a request failed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CuprumApiError {
+    /// The named buffer, window, popup or option does not exist
+    NotFound(String),
+    /// A line or position is outside the buffer
+    OutOfRange,
+    /// Reading or writing a file failed
+    Io(String),
+    /// The buffer cannot be modified
+    ReadOnly,
+    /// The buffer has no file to be saved to
+    NoFileName,
+    InvalidArgument(String),
+    /// The request is not allowed from this client
+    NotAllowed(String),
+    /// The plugin's manifest does not grant the capability the request needs
+    PermissionDenied(Capability),
+    /// The editor and the plugin speak different protocol versions
+    VersionMismatch {
+        expected: u32,
+        found: u32,
+    },
+    /// The request could not be sent or the response could not be read
+    Transport(String),
+    /// The response does not belong to the request
+    MismatchedResponse,
+    /// No response arrived before the deadline
+    Timeout,
+}
+
+impl Display for CuprumApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CuprumApiError::NotFound(what) => write!(f, "{} not found", what),
+            CuprumApiError::OutOfRange => write!(f, "position out of range"),
+            CuprumApiError::Io(err) => write!(f, "I/O error: {}", err),
+            CuprumApiError::ReadOnly => write!(f, "buffer is read-only"),
+            CuprumApiError::NoFileName => write!(f, "buffer has no file name"),
+            CuprumApiError::InvalidArgument(err) => write!(f, "invalid argument: {}", err),
+            CuprumApiError::NotAllowed(err) => write!(f, "not allowed: {}", err),
+            CuprumApiError::PermissionDenied(capability) => {
+                write!(f, "permission denied: needs the {} capability", capability)
+            }
+            CuprumApiError::VersionMismatch { expected, found } => write!(
+                f,
+                "protocol version mismatch: expected {}, found {}",
+                expected, found
+            ),
+            CuprumApiError::Transport(err) => write!(f, "transport error: {}", err),
+            CuprumApiError::MismatchedResponse => write!(f, "mismatched response"),
+            CuprumApiError::Timeout => write!(f, "request timed out"),
+        }
+    }
+}
+
+impl std::error::Error for CuprumApiError {}
+
+/// What the editor answers to a request: a value (if the method returns one) or an error
+pub type CuprumApiResult = Result<Option<CuprumApiResponseKind>, CuprumApiError>;
+
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RequestId(pub usize);
 
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PluginRequestId(pub usize);
+
+/// A request from the editor to a plugin
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PluginRequest {
+    pub request_id: PluginRequestId,
+    pub request: PluginRequestKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PluginRequestKind {
+    /// Check that the plugin still reads its messages. `DefaultCuprumApiProvider` answers it.
+    Ping,
+    /// Get candidates from a source added with `register_completion_source`.
+    /// Answered with `PluginResponseKind::Completions`.
+    Complete {
+        source: String,
+        buf: BufferId,
+        cursor: UVec2,
+        /// The word characters before the cursor, possibly empty after a trigger character
+        prefix: String,
+    },
+    /// Sent to plugins subscribed to `EventKind::BufWritePre` before a buffer is written,
+    /// which waits for the answer. Answered with `PluginResponseKind::Edits` of `content`,
+    /// applied like `apply_text_edits` before the buffer is written.
+    BufWritePre { buf: BufferId, content: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PluginResponseKind {
+    Pong,
+    Completions(Vec<CompletionItem>),
+    Edits(Vec<RangeEdit>),
+}
+
+/// A candidate offered by a completion source
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompletionItem {
+    /// Shown in the popup
+    pub label: String,
+    /// Replaces the prefix when the candidate is selected
+    pub insert_text: String,
+    #[serde(default)]
+    pub detail: Option<String>,
+}
+
+/// A plugin's answer to a `PluginRequest`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PluginResponse {
+    pub request_id: PluginRequestId,
+    pub result: Result<PluginResponseKind, CuprumApiError>,
+}
+
+/// A line sent from the editor to a plugin
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EditorMessage {
+    Response(CuprumApiResponse),
+    Request(PluginRequest),
+    Notification(CuprumNotification),
+}
+
+/// A line sent from a plugin to the editor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PluginMessage {
+    Request(CuprumApiRequest),
+    Response(PluginResponse),
+}
+
+/// Kinds of events a plugin can subscribe to
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EventKind {
+    /// Sent as `PluginRequestKind::BufWritePre` instead of an event
+    BufWritePre,
+    BufWritePost,
+    BufEnter,
+    InsertChar,
+    ModeChanged,
+    CursorMoved,
+    BufferChanged,
+    OptionChanged,
+}
+
+impl EventKind {
+    /// What a plugin needs to subscribe to these events, as they carry the text of buffers
+    pub fn capability(&self) -> Option<Capability> {
+        match self {
+            EventKind::BufWritePre | EventKind::InsertChar | EventKind::BufferChanged => {
+                Some(Capability::ReadBuffers)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// An event sent to subscribed plugins. The editor does not wait for plugins to handle it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EditorEvent {
+    /// A buffer was written
+    BufWritePost {
+        buf: BufferId,
+    },
+    /// Another buffer became the active one
+    BufEnter {
+        buf: BufferId,
+    },
+    /// A character was typed in insert mode at `pos`
+    InsertChar {
+        buf: BufferId,
+        pos: UVec2,
+        ch: char,
+    },
+    ModeChanged {
+        mode: Mode,
+    },
+    /// The cursor moved. Sent once it has stayed put for 50ms, with where it stopped.
+    CursorMoved {
+        win: WindowId,
+        pos: UVec2,
+    },
+    /// The characters `start..old_end` of the content were replaced with `new_text`.
+    /// Offsets are character indices into the content with lines joined by `\n`, as it
+    /// was before this change. `revision` increases by one with every change of a
+    /// buffer, so a gap means changes were missed and the content should be fetched
+    /// again with `get_content`. Typing in insert mode is coalesced into one change.
+    BufferChanged {
+        buf: BufferId,
+        revision: u64,
+        start: usize,
+        old_end: usize,
+        new_text: String,
+    },
+    /// An option was changed with `:set` or `set_option`. `name` is the full name and
+    /// `value` is formatted like `get_option` returns it.
+    OptionChanged {
+        name: String,
+        value: String,
+    },
+}
+
+impl EditorEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            EditorEvent::BufWritePost { .. } => EventKind::BufWritePost,
+            EditorEvent::BufEnter { .. } => EventKind::BufEnter,
+            EditorEvent::InsertChar { .. } => EventKind::InsertChar,
+            EditorEvent::ModeChanged { .. } => EventKind::ModeChanged,
+            EditorEvent::CursorMoved { .. } => EventKind::CursorMoved,
+            EditorEvent::BufferChanged { .. } => EventKind::BufferChanged,
+            EditorEvent::OptionChanged { .. } => EventKind::OptionChanged,
+        }
+    }
+}
+
+/// A message sent from the editor to a plugin without expecting a response
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CuprumNotification {
+    /// The user ran a command registered with `register_command`
+    RunCommand {
+        name: String,
+        bang: bool,
+        /// First and last line (0-based) if a range was given
+        range: Option<(usize, usize)>,
+        args: String,
+    },
+    /// Something happened that the plugin subscribed to
+    Event(EditorEvent),
+    /// The user typed a mapping registered with `register_keymap`
+    Keymap { name: String },
+}
+
 define_api!(
+    /// Switch the editor to another mode
+    #[capability(ChangeMode)]
     fn change_mode(mode: Mode)
+    fn get_mode() -> Mode
+    /// Not implemented yet
+    #[capability(Filesystem)]
     fn open_file(path: Option<String>) -> BufferId
+    /// Write a buffer to its file. `path` is not used yet.
+    #[capability(Filesystem)]
     fn save_buffer(buf: Option<BufferId>, path: Option<String>)
+    #[capability(ReadBuffers)]
     fn get_line_count(buf: Option<BufferId>) -> usize
+    /// Length of line `y` in characters
+    #[capability(ReadBuffers)]
     fn get_line_length(buf: Option<BufferId>, y: usize) -> usize
+    #[capability(ReadBuffers)]
     fn get_char(buf: Option<BufferId>, pos: UVec2) -> char
+    #[capability(ReadBuffers)]
     fn get_line(buf: Option<BufferId>, y: usize) -> String
+    #[capability(ReadBuffers)]
     fn get_all_lines(buf: Option<BufferId>) -> Vec<String>
+    /// Lines `start_line..start_line + count`, fewer if the buffer ends first
+    #[capability(ReadBuffers)]
+    fn get_lines(buf: Option<BufferId>, start_line: usize, count: usize) -> Vec<String>
+    /// Characters `start..end` of the content, counting line breaks
+    #[capability(ReadBuffers)]
+    fn get_text(buf: Option<BufferId>, start: usize, end: usize) -> String
+    /// The lines joined by `\n`. This copies the whole buffer, so prefer `get_lines` or
+    /// `get_text` when only part of it is needed.
+    #[capability(ReadBuffers)]
     fn get_content(buf: Option<BufferId>) -> String
+    #[capability(ReadBuffers)]
+    fn get_buffer_info(buf: Option<BufferId>) -> BufferInfo
+    /// Character offset of the next match of `pattern` from `from`, wrapping around
+    #[capability(ReadBuffers)]
+    fn search(buf: Option<BufferId>, pattern: String, from: usize, direction: SearchDirection) -> Option<usize>
+    /// Character ranges of every match of `pattern` in the content
+    #[capability(ReadBuffers)]
+    fn find_all(buf: Option<BufferId>, pattern: String) -> Vec<(usize, usize)>
+    /// Version and build of the editor, for plugins to log what they talk to
+    fn get_editor_info() -> EditorInfo
+    fn list_buffers() -> Vec<BufferId>
+    /// Each window with the buffer it shows
+    fn list_windows() -> Vec<(WindowId, BufferId)>
+    fn get_active_window() -> WindowId
+    /// Split the active window to show a buffer
+    fn open_window(buf: Option<BufferId>, split: SplitDirection) -> WindowId
+    fn close_window(win: Option<WindowId>)
+    #[capability(WriteBuffers)]
     fn insert_char(buf: Option<BufferId>, pos: UVec2, ch: char)
+    /// Insert a line before line `y`
+    #[capability(WriteBuffers)]
     fn insert_line(buf: Option<BufferId>, y: usize, line: String)
+    /// Replace a character, returning the old one
+    #[capability(WriteBuffers)]
     fn replace_char(buf: Option<BufferId>, pos: UVec2, ch: char) -> char
+    /// Replace a line, returning the old one
+    #[capability(WriteBuffers)]
     fn replace_line(buf: Option<BufferId>, y: usize, line: String) -> String
+    /// Replace every line, returning the old ones
+    #[capability(WriteBuffers)]
     fn replace_all_lines(buf: Option<BufferId>, lines: Vec<String>) -> Vec<String>
+    /// Replace the whole content, returning the old one
+    #[capability(WriteBuffers)]
     fn replace_content(buf: Option<BufferId>, content: String) -> String
+    /// Apply non-overlapping edits as one change, undone in one step
+    #[capability(WriteBuffers)]
+    fn apply_edits(buf: Option<BufferId>, edits: Vec<TextEdit>)
+    /// `apply_edits` with line/character ranges
+    #[capability(WriteBuffers)]
+    fn apply_text_edits(buf: Option<BufferId>, edits: Vec<RangeEdit>)
+    /// Remove a character, returning it. Line breaks are left to `join_lines`.
+    #[capability(WriteBuffers)]
     fn remove_char(buf: Option<BufferId>, pos: UVec2) -> char
+    /// Remove a line, returning it
+    #[capability(WriteBuffers)]
     fn remove_line(buf: Option<BufferId>, y: usize) -> String
+    /// Break a line in two at `pos`
+    #[capability(WriteBuffers)]
     fn split_line(buf: Option<BufferId>, pos: UVec2)
+    /// Join line `y` with the line after it
+    #[capability(WriteBuffers)]
     fn join_lines(buf: Option<BufferId>, y: usize)
     fn get_cursor(win: Option<WindowId>) -> UVec2
+    /// Where the visual selection started
     fn get_visual_start(win: Option<WindowId>) -> UVec2
+    /// The visual selection in order, ending after its last character
+    fn get_selection(win: Option<WindowId>) -> TextRange
     fn move_by(win: Option<WindowId>, offset: IVec2)
     fn move_to_x(win: Option<WindowId>, pos: Position)
     fn move_to_y(win: Option<WindowId>, pos: Position)
+    /// Add a command. Running it sends `CuprumNotification::RunCommand`.
+    fn register_command(name: String)
+    /// Receive `CuprumNotification::Event` for these kinds of events. Events that carry text
+    /// need the capability `EventKind::capability` names.
+    fn subscribe(events: Vec<EventKind>)
+    /// Offer completion candidates, answering `PluginRequestKind::Complete`.
+    /// Typing one of `trigger_chars` in insert mode starts completion from this source.
+    fn register_completion_source(name: String, trigger_chars: Vec<char>)
+    /// The value of an option as `:set` shows it
+    fn get_option(name: String) -> String
+    #[capability(ChangeOptions)]
+    fn set_option(name: String, value: String)
+    fn open_popup(popup: Popup) -> PopupId
+    fn update_popup(id: PopupId, popup: Popup)
+    fn close_popup(id: PopupId)
+    /// Replace the diagnostics of a buffer, underlined in the text and listed by `:llist`
+    fn publish_diagnostics(buf: Option<BufferId>, diagnostics: Vec<Diagnostic>)
+    /// Highlight characters `start..end` of line `y` in the active window until
+    /// `clear_highlights`. `:noh` only hides search matches, and keeps these.
+    fn add_highlight(y: usize, start: usize, end: usize)
+    /// Remove every highlight added by `add_highlight`
+    fn clear_highlights()
+    /// Show text in a popup below the cursor, closed by the next key
+    fn show_hover(win: Option<WindowId>, text: String) -> PopupId
+    /// Show a message on the command line and add it to the history
+    fn show_message(level: MessageLevel, text: String)
+    /// Ask the user for a line of text, or `None` if they cancel. Never times out.
+    fn prompt_input(prompt: String) -> Option<String>
+    /// Add or update text on the status line
+    fn set_statusline_segment(id: String, text: String, align: StatusAlign)
+    fn remove_statusline_segment(id: String)
+    /// Map keys like `:map`. `mode` is a `:map` prefix such as `n` or `i`.
+    #[capability(RunKeymaps)]
+    fn set_keymap(mode: String, lhs: String, rhs: String, noremap: bool)
+    #[capability(RunKeymaps)]
+    fn del_keymap(mode: String, lhs: String)
+    /// Map keys to send `CuprumNotification::Keymap` with `name`
+    #[capability(RunKeymaps)]
+    fn register_keymap(mode: Mode, keys: String, name: String)
+    /// The message history, oldest first
+    fn get_messages() -> Vec<HistoryMessage>
+    #[capability(ReadBuffers)]
+    fn get_register(name: char) -> String
+    #[capability(WriteBuffers)]
+    fn set_register(name: char, text: String, linewise: bool)
 );
 
+/// A method of the API, for listing what the editor supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApiMethodInfo {
+    pub name: &'static str,
+    pub returns: bool,
+    pub args: &'static [&'static str],
+}
+
 pub trait CuprumApiProvider {
     #[allow(async_fn_in_trait)]
-    async fn send_message(
-        &mut self,
-        kind: CuprumApiRequestKind,
-    ) -> anyhow::Result<Option<CuprumApiResponseKind>>;
+    async fn send_message(&mut self, kind: CuprumApiRequestKind) -> CuprumApiResult;
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 pub struct DefaultCuprumApiProvider {
-    requests: Arc<Mutex<Vec<CuprumApiRequest>>>,
+    /// Requests and responses to write to the editor
+    requests: Arc<Mutex<Vec<PluginMessage>>>,
     request_notify: Arc<Notify>,
-    responses: Arc<Mutex<HashMap<RequestId, Option<CuprumApiResponseKind>>>>,
-    response_notify: Arc<Notify>,
-    next_index: Arc<Mutex<usize>>,
+    /// Requests waiting for their response
+    pending: Arc<Mutex<HashMap<RequestId, oneshot::Sender<CuprumApiResult>>>>,
+    notifications: Arc<Mutex<Vec<CuprumNotification>>>,
+    notification_notify: Arc<Notify>,
+    /// Requests from the editor that the provider does not answer itself
+    editor_requests: Arc<Mutex<Vec<PluginRequest>>>,
+    editor_request_notify: Arc<Notify>,
+    next_index: Arc<AtomicUsize>,
+    timeout: Duration,
+}
+
+impl Default for DefaultCuprumApiProvider {
+    fn default() -> Self {
+        Self {
+            requests: Default::default(),
+            request_notify: Default::default(),
+            pending: Default::default(),
+            notifications: Default::default(),
+            notification_notify: Default::default(),
+            editor_requests: Default::default(),
+            editor_request_notify: Default::default(),
+            next_index: Default::default(),
+            timeout: DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
 }
 
 impl DefaultCuprumApiProvider {
     async fn process_request(
-        requests: &Arc<Mutex<Vec<CuprumApiRequest>>>,
+        writer: &mut Writer,
+        requests: &Arc<Mutex<Vec<PluginMessage>>>,
         request_notify: &Arc<Notify>,
     ) -> anyhow::Result<()> {
         request_notify.notified().await;
@@ -114,55 +723,169 @@ impl DefaultCuprumApiProvider {
         for request in requests {
             let request = serde_json::to_string(&request)?;
 
-            let mut stdout = stdout();
-            stdout.write_all(request.as_bytes()).await?;
-            stdout.write_all(b"\n").await?;
-            stdout.flush().await?;
+            writer.write_all(request.as_bytes()).await?;
+            writer.write_all(b"\n").await?;
+            writer.flush().await?;
         }
 
         Ok(())
     }
 
-    async fn process_response(
-        responses: &Arc<Mutex<HashMap<RequestId, Option<CuprumApiResponseKind>>>>,
-        response_notify: &Arc<Notify>,
-    ) -> anyhow::Result<()> {
-        let mut reader = BufReader::new(stdin());
-        let mut response = String::new();
-        reader.read_line(&mut response).await?;
+    async fn process_response(&self, reader: &mut Reader) -> anyhow::Result<()> {
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
 
-        let response: CuprumApiResponse = serde_json::from_str(&response)?;
-
-        let mut responses = responses.lock().await;
-        responses.insert(response.id, response.kind);
-        response_notify.notify_one();
+        match serde_json::from_str(&line)? {
+            EditorMessage::Response(response) => {
+                let id = response.id;
+                let result = if response.version != PROTOCOL_VERSION {
+                    Err(CuprumApiError::VersionMismatch {
+                        expected: PROTOCOL_VERSION,
+                        found: response.version,
+                    })
+                } else {
+                    response.into_result()
+                };
+                match self.pending.lock().await.remove(&id) {
+                    Some(answer) => {
+                        answer.send(result).ok();
+                    }
+                    // The request timed out
+                    None => eprintln!("unexpected response to {:?}", id),
+                }
+            }
+            EditorMessage::Request(PluginRequest {
+                request_id,
+                request: PluginRequestKind::Ping,
+            }) => {
+                self.respond(request_id, Ok(PluginResponseKind::Pong)).await;
+            }
+            EditorMessage::Request(request) => {
+                self.editor_requests.lock().await.push(request);
+                self.editor_request_notify.notify_one();
+            }
+            EditorMessage::Notification(notification) => {
+                let mut notifications = self.notifications.lock().await;
+                notifications.push(notification);
+                self.notification_notify.notify_one();
+            }
+        }
 
         Ok(())
     }
 
+    /// Answer a request from the editor
+    pub async fn respond(
+        &self,
+        request_id: PluginRequestId,
+        result: Result<PluginResponseKind, CuprumApiError>,
+    ) {
+        let mut requests = self.requests.lock().await;
+        requests.push(PluginMessage::Response(PluginResponse {
+            request_id,
+            result,
+        }));
+        self.request_notify.notify_one();
+    }
+
+    /// Wait for the next notification from the editor
+    pub async fn next_notification(&self) -> CuprumNotification {
+        loop {
+            {
+                let mut notifications = self.notifications.lock().await;
+                if !notifications.is_empty() {
+                    return notifications.remove(0);
+                }
+            }
+            self.notification_notify.notified().await;
+        }
+    }
+
+    /// Wait for the next request from the editor, to be answered with `respond`
+    pub async fn next_request(&self) -> PluginRequest {
+        loop {
+            {
+                let mut requests = self.editor_requests.lock().await;
+                if !requests.is_empty() {
+                    return requests.remove(0);
+                }
+            }
+            self.editor_request_notify.notified().await;
+        }
+    }
+
+    /// Connect to the editor over its socket if it gave one, otherwise over stdin and stdout
     pub fn new() -> Self {
+        #[cfg(unix)]
+        if let Ok(path) = std::env::var(SOCKET_ENV) {
+            let plugin_id = std::env::var(PLUGIN_ID_ENV)
+                .ok()
+                .and_then(|id| id.parse().ok());
+            return Self::with_socket(path.into(), plugin_id);
+        }
+        Self::connect(
+            async {
+                Ok((
+                    BufReader::new(Box::new(stdin()) as _),
+                    Box::new(stdout()) as _,
+                ))
+            },
+            None,
+        )
+    }
+
+    /// Connect to the editor listening on `path`. Only Unix has the sockets the editor
+    /// listens on.
+    #[cfg(unix)]
+    pub fn with_socket(path: std::path::PathBuf, plugin_id: Option<u64>) -> Self {
+        Self::connect(
+            async move {
+                let (reader, writer) = tokio::net::UnixStream::connect(path).await?.into_split();
+                Ok((BufReader::new(Box::new(reader) as _), Box::new(writer) as _))
+            },
+            plugin_id,
+        )
+    }
+
+    fn connect(
+        transport: impl Future<Output = io::Result<(Reader, Writer)>> + Send + 'static,
+        plugin_id: Option<u64>,
+    ) -> Self {
         let provider = Self::default();
 
         let requests = provider.requests.clone();
         let request_notify = provider.request_notify.clone();
-        let responses = provider.responses.clone();
-        let response_notify = provider.response_notify.clone();
+        let receiver = provider.clone();
 
         tokio::spawn(async move {
-            loop {
-                match Self::process_request(&requests, &request_notify).await {
-                    Ok(_) => {}
-                    Err(err) => {
-                        eprintln!("{}", err);
-                        break;
-                    }
+            let (mut reader, mut writer) = match transport.await {
+                Ok(transport) => transport,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return;
                 }
+            };
+
+            // Nothing else may be written before the handshake is done
+            if let Err(err) = answer_hello(&mut reader, &mut writer, plugin_id).await {
+                eprintln!("{}", err);
+                return;
             }
-        });
 
-        tokio::spawn(async move {
+            tokio::spawn(async move {
+                loop {
+                    match Self::process_request(&mut writer, &requests, &request_notify).await {
+                        Ok(_) => {}
+                        Err(err) => {
+                            eprintln!("{}", err);
+                            break;
+                        }
+                    }
+                }
+            });
+
             loop {
-                match Self::process_response(&responses, &response_notify).await {
+                match receiver.process_response(&mut reader).await {
                     Ok(_) => {}
                     Err(err) => {
                         eprintln!("{}", err);
@@ -174,32 +897,391 @@ impl DefaultCuprumApiProvider {
 
         provider
     }
+
+    /// Set how long to wait for the editor to answer a request
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+/// Plugin side of the handshake: read the editor's `Hello` and answer with our protocol version
+pub async fn answer_hello<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    plugin_id: Option<u64>,
+) -> Result<Hello, CuprumApiError>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let transport = |err: &dyn Display| CuprumApiError::Transport(err.to_string());
+
+    let mut line = String::new();
+    reader
+        .read_line(&mut line)
+        .await
+        .map_err(|err| transport(&err))?;
+    let hello: Hello = serde_json::from_str(&line).map_err(|err| transport(&err))?;
+
+    let reply = serde_json::to_string(&HelloReply {
+        protocol_version: PROTOCOL_VERSION,
+        plugin_id,
+    })
+    .map_err(|err| transport(&err))?;
+    writer
+        .write_all(format!("{}\n", reply).as_bytes())
+        .await
+        .map_err(|err| transport(&err))?;
+    writer.flush().await.map_err(|err| transport(&err))?;
+
+    if hello.protocol_version != PROTOCOL_VERSION {
+        return Err(CuprumApiError::VersionMismatch {
+            expected: PROTOCOL_VERSION,
+            found: hello.protocol_version,
+        });
+    }
+    Ok(hello)
+}
+
+/// Parse an option value as `get_option` and `EditorEvent::OptionChanged` give it,
+/// such as `true` for a flag or `8` for a number
+pub fn parse_option<V: FromStr>(name: &str, value: &str) -> Result<V, CuprumApiError> {
+    value
+        .parse()
+        .map_err(|_| CuprumApiError::InvalidArgument(format!("{}={}", name, value)))
+}
+
+impl<T: CuprumApiProvider> CuprumApi<T> {
+    /// `get_option` parsed into a `bool`, a number or another `FromStr` type
+    pub async fn get_option_as<V: FromStr>(&mut self, name: &str) -> Result<V, CuprumApiError> {
+        let value = self.get_option(name.to_string()).await?;
+        parse_option(name, &value)
+    }
 }
 
 impl CuprumApiProvider for DefaultCuprumApiProvider {
-    async fn send_message(
-        &mut self,
-        kind: CuprumApiRequestKind,
-    ) -> anyhow::Result<Option<CuprumApiResponseKind>> {
-        let id = {
-            let mut next_index = self.next_index.lock().await;
-
-            let id = RequestId(*next_index);
-            let mut requests = self.requests.lock().await;
-            requests.push(CuprumApiRequest { id, kind });
-
-            *next_index += 1;
-            id
+    async fn send_message(&mut self, kind: CuprumApiRequestKind) -> CuprumApiResult {
+        let name = format!("{:?}", kind);
+        // The user may take as long as they like to answer a prompt
+        let deadline = match kind {
+            CuprumApiRequestKind::PromptInput { .. } => Duration::MAX,
+            _ => self.timeout,
         };
-
+        let id = RequestId(self.next_index.fetch_add(1, Ordering::Relaxed));
+        let (answer, response) = oneshot::channel();
+        self.pending.lock().await.insert(id, answer);
+        self.requests
+            .lock()
+            .await
+            .push(PluginMessage::Request(CuprumApiRequest::new(id, kind)));
         self.request_notify.notify_one();
-        self.response_notify.notified().await;
 
-        let responses = self.responses.lock().await;
-        let response = responses
-            .get(&id)
-            .ok_or(anyhow!("Failed to get response"))?;
+        match timeout(deadline, response).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(CuprumApiError::Transport("no response".to_string())),
+            Err(_) => {
+                self.pending.lock().await.remove(&id);
+                eprintln!("request {:?} timed out: {}", id, name);
+                Err(CuprumApiError::Timeout)
+            }
+        }
+    }
+}
+
+impl CuprumApiRequest {
+    pub fn new(id: RequestId, kind: CuprumApiRequestKind) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            id,
+            kind,
+        }
+    }
+}
+
+impl CuprumApiResponse {
+    pub fn new(id: RequestId, result: CuprumApiResult) -> Self {
+        let (kind, error) = match result {
+            Ok(kind) => (kind, None),
+            Err(err) => (None, Some(err)),
+        };
+        Self {
+            version: PROTOCOL_VERSION,
+            id,
+            kind,
+            error,
+        }
+    }
+
+    pub fn into_result(self) -> CuprumApiResult {
+        match self.error {
+            Some(err) => Err(err),
+            None => Ok(self.kind),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages_are_told_apart_by_shape() {
+        let editor_messages = [
+            EditorMessage::Response(CuprumApiResponse::new(
+                RequestId(1),
+                Ok(Some(CuprumApiResponseKind::GetMode(Mode::Normal))),
+            )),
+            EditorMessage::Request(PluginRequest {
+                request_id: PluginRequestId(1),
+                request: PluginRequestKind::Ping,
+            }),
+            EditorMessage::Notification(CuprumNotification::Keymap {
+                name: "date".to_string(),
+            }),
+        ];
+        for message in editor_messages {
+            let json = serde_json::to_string(&message).unwrap();
+            let parsed: EditorMessage = serde_json::from_str(&json).unwrap();
+            assert_eq!(
+                std::mem::discriminant(&parsed),
+                std::mem::discriminant(&message),
+                "{}",
+                json
+            );
+        }
+
+        let plugin_messages = [
+            PluginMessage::Request(CuprumApiRequest::new(
+                RequestId(1),
+                CuprumApiRequestKind::GetMode {},
+            )),
+            PluginMessage::Response(PluginResponse {
+                request_id: PluginRequestId(1),
+                result: Ok(PluginResponseKind::Pong),
+            }),
+        ];
+        for message in plugin_messages {
+            let json = serde_json::to_string(&message).unwrap();
+            let parsed: PluginMessage = serde_json::from_str(&json).unwrap();
+            assert_eq!(
+                std::mem::discriminant(&parsed),
+                std::mem::discriminant(&message),
+                "{}",
+                json
+            );
+        }
+    }
+
+    /// The wire format of a few requests and responses. If these change, plugins built
+    /// against an older version break, so `PROTOCOL_VERSION` must be bumped too.
+    #[test]
+    fn test_wire_format() {
+        let requests = [
+            (CuprumApiRequestKind::GetMode {}, r#"{"get_mode":{}}"#),
+            (
+                CuprumApiRequestKind::InsertChar {
+                    buf: Some(BufferId(2)),
+                    pos: UVec2::new(3, 4),
+                    ch: 'x',
+                },
+                r#"{"insert_char":{"buf":2,"pos":{"x":3,"y":4},"ch":"x"}}"#,
+            ),
+            (
+                CuprumApiRequestKind::SetRegister {
+                    name: 'a',
+                    text: "hi".to_string(),
+                    linewise: true,
+                },
+                r#"{"set_register":{"name":"a","text":"hi","linewise":true}}"#,
+            ),
+            (
+                CuprumApiRequestKind::Batch {
+                    requests: vec![CuprumApiRequestKind::GetMode {}],
+                },
+                r#"{"batch":{"requests":[{"get_mode":{}}]}}"#,
+            ),
+        ];
+        for (request, json) in requests {
+            assert_eq!(serde_json::to_string(&request).unwrap(), json);
+            let parsed: CuprumApiRequestKind = serde_json::from_str(json).unwrap();
+            assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+        }
+
+        // Arguments are read by name, in any order
+        let request: CuprumApiRequestKind =
+            serde_json::from_str(r#"{"get_line":{"y":1,"buf":null}}"#).unwrap();
+        assert!(matches!(
+            request,
+            CuprumApiRequestKind::GetLine { buf: None, y: 1 }
+        ));
+
+        let response = CuprumApiResponse::new(
+            RequestId(1),
+            Ok(Some(CuprumApiResponseKind::GetMode(Mode::Visual))),
+        );
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            format!(
+                r#"{{"version":{},"id":1,"kind":{{"get_mode":"Visual"}},"error":null}}"#,
+                PROTOCOL_VERSION
+            )
+        );
+        assert_eq!(
+            serde_json::to_string(&CuprumApiResponseKind::GetLine("abc".to_string())).unwrap(),
+            r#"{"get_line":"abc"}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&CuprumApiResponseKind::Batch(vec![
+                Ok(None),
+                Err(CuprumApiError::OutOfRange)
+            ]))
+            .unwrap(),
+            r#"{"batch":[{"Ok":null},{"Err":"OutOfRange"}]}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests() {
+        let (plugin, editor) = io::duplex(4096);
+        let (plugin_reader, plugin_writer) = io::split(plugin);
+        let provider = DefaultCuprumApiProvider::connect(
+            async move {
+                Ok((
+                    BufReader::new(Box::new(plugin_reader) as _),
+                    Box::new(plugin_writer) as _,
+                ))
+            },
+            None,
+        );
+
+        // Answers `get_line` with the line number, once every request arrived, last first
+        let (editor_reader, mut editor_writer) = io::split(editor);
+        tokio::spawn(async move {
+            let mut reader = BufReader::new(editor_reader);
+            let hello = serde_json::to_string(&Hello {
+                protocol_version: PROTOCOL_VERSION,
+                editor_version: String::new(),
+            })
+            .unwrap();
+            editor_writer
+                .write_all(format!("{}\n", hello).as_bytes())
+                .await
+                .unwrap();
+
+            let mut requests = Vec::new();
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            while requests.len() < 100 {
+                line.clear();
+                reader.read_line(&mut line).await.unwrap();
+                let request: CuprumApiRequest = serde_json::from_str(&line).unwrap();
+                requests.push(request);
+            }
+            for request in requests.into_iter().rev() {
+                let CuprumApiRequestKind::GetLine { y, .. } = request.kind else {
+                    panic!("unexpected request {:?}", request.kind);
+                };
+                let response = CuprumApiResponse::new(
+                    request.id,
+                    Ok(Some(CuprumApiResponseKind::GetLine(y.to_string()))),
+                );
+                let response = serde_json::to_string(&response).unwrap();
+                editor_writer
+                    .write_all(format!("{}\n", response).as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let tasks: Vec<_> = (0..100)
+            .map(|y| {
+                let mut api = CuprumApi::new(provider.clone());
+                tokio::spawn(async move { api.get_line(None, y).await })
+            })
+            .collect();
+        for (y, task) in tasks.into_iter().enumerate() {
+            assert_eq!(task.await.unwrap(), Ok(y.to_string()));
+        }
+        assert!(provider.pending.lock().await.is_empty());
+    }
+
+    #[test]
+    fn test_api_methods() {
+        let get_line = API_METHODS
+            .iter()
+            .find(|method| method.name == "get_line")
+            .unwrap();
+        assert_eq!(get_line.args, ["buf", "y"]);
+        assert!(get_line.returns);
+        assert!(
+            API_METHODS
+                .iter()
+                .any(|method| method.name == "close_popup" && !method.returns)
+        );
+    }
+
+    /// An API whose only method was renamed twice
+    #[allow(dead_code)] // Only the generated enums are used
+    mod renamed {
+        use serde::{Deserialize, Serialize};
+
+        use crate::{ApiMethodInfo, Capability, CuprumApiError, RequestId};
+
+        pub trait CuprumApiProvider {
+            #[allow(async_fn_in_trait)]
+            async fn send_message(
+                &mut self,
+                kind: CuprumApiRequestKind,
+            ) -> Result<Option<CuprumApiResponseKind>, CuprumApiError>;
+        }
+
+        api_macro::define_api!(
+            #[alias = "get_name"]
+            #[alias = "get_label"]
+            fn get_title(buf: usize) -> String
+        );
+    }
+
+    #[test]
+    fn test_renamed_method_alias() {
+        use renamed::{CuprumApiRequestKind, CuprumApiResponseKind};
+
+        for json in [
+            r#"{"get_title":{"buf":1}}"#,
+            r#"{"get_name":{"buf":1}}"#,
+            r#"{"get_label":{"buf":1}}"#,
+        ] {
+            let request: CuprumApiRequestKind = serde_json::from_str(json).unwrap();
+            assert!(matches!(request, CuprumApiRequestKind::GetTitle { buf: 1 }));
+        }
+        let response: CuprumApiResponseKind = serde_json::from_str(r#"{"get_name":"a"}"#).unwrap();
+        assert!(matches!(response, CuprumApiResponseKind::GetTitle(title) if title == "a"));
+        // New messages are written with the new name
+        assert_eq!(
+            serde_json::to_string(&CuprumApiRequestKind::GetTitle { buf: 1 }).unwrap(),
+            r#"{"get_title":{"buf":1}}"#
+        );
+    }
+
+    #[test]
+    fn test_response_round_trip() {
+        let response = CuprumApiResponse::new(RequestId(3), Err(CuprumApiError::OutOfRange));
+        let json = serde_json::to_string(&response).unwrap();
+        let response: CuprumApiResponse = serde_json::from_str(&json).unwrap();
+        assert_eq!(response.version, PROTOCOL_VERSION);
+        assert_eq!(
+            response.into_result().unwrap_err(),
+            CuprumApiError::OutOfRange
+        );
 
-        Ok(response.clone())
+        let response = CuprumApiResponse::new(
+            RequestId(4),
+            Ok(Some(CuprumApiResponseKind::GetLineCount(2))),
+        );
+        assert!(matches!(
+            response.into_result(),
+            Ok(Some(CuprumApiResponseKind::GetLineCount(2)))
+        ));
     }
 }
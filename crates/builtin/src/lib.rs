@@ -1,22 +1,41 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use api::{
-    CuprumApi, CuprumApiProvider, CuprumApiRequestKind, CuprumApiResponseKind, Mode, Position,
+    CuprumApi, CuprumApiError, CuprumApiProvider, CuprumApiRequestKind, CuprumApiResult,
+    DEFAULT_REQUEST_TIMEOUT, Mode, Position, RangeEdit, RequestId, TextRange,
+};
+use tokio::{
+    sync::{Mutex, Notify},
+    time::timeout,
+};
+use utils::{
+    grapheme,
+    vec2::{IVec2, UVec2},
 };
-use tokio::sync::{Mutex, Notify};
-use utils::vec2::{IVec2, UVec2};
 
 pub type Messages = Vec<(
     Arc<Notify>,
-    Arc<Mutex<Option<CuprumApiResponseKind>>>,
+    Arc<Mutex<CuprumApiResult>>,
     CuprumApiRequestKind,
 )>;
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct BuiltinApiProvider {
     next_index: usize,
     notify: Arc<Notify>,
     pub messages: Arc<Mutex<Messages>>,
+    timeout: Duration,
+}
+
+impl Default for BuiltinApiProvider {
+    fn default() -> Self {
+        Self {
+            next_index: 0,
+            notify: Default::default(),
+            messages: Default::default(),
+            timeout: DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
 }
 
 impl BuiltinApiProvider {
@@ -31,24 +50,31 @@ impl BuiltinApiProvider {
     pub fn get_notify(&self) -> Arc<Notify> {
         self.notify.clone()
     }
+
+    /// Set how long to wait for the editor to handle a request
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
 }
 
 impl CuprumApiProvider for BuiltinApiProvider {
-    async fn send_message(
-        &mut self,
-        msg: CuprumApiRequestKind,
-    ) -> anyhow::Result<Option<CuprumApiResponseKind>> {
+    async fn send_message(&mut self, msg: CuprumApiRequestKind) -> CuprumApiResult {
+        let id = RequestId(self.next_index);
+        let name = format!("{:?}", msg);
         let notify = Arc::new(Notify::new());
-        let state = Arc::new(Mutex::new(None));
+        let state = Arc::new(Mutex::new(Ok(None)));
         {
             let mut messages = self.messages.lock().await;
             messages.push((notify.clone(), state.clone(), msg));
         }
         self.next_index += 1;
         self.notify.notify_one();
-        notify.notified().await;
+        if timeout(self.timeout, notify.notified()).await.is_err() {
+            log::error!("request {:?} timed out: {}", id, name);
+            return Err(CuprumApiError::Timeout);
+        }
         let state = state.lock().await;
-        Ok(state.clone())
+        state.clone()
     }
 }
 
@@ -68,6 +94,11 @@ impl Builtin {
         self.messages.clone()
     }
 
+    /// Set how long to wait for the editor to handle an action's requests
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.api.provider.set_timeout(timeout);
+    }
+
     pub async fn on_action(&mut self, action: BuiltinAction) -> anyhow::Result<()> {
         match action {
             BuiltinAction::Save => {
@@ -85,61 +116,61 @@ impl Builtin {
             BuiltinAction::MoveToY(pos) => {
                 self.api.move_to_y(None, pos).await?;
             }
+            BuiltinAction::GotoLine(y) => {
+                self.api.move_to_y(None, Position::Number(y)).await?;
+
+                // Land on the first non-blank character
+                let cursor = self.api.get_cursor(None).await?;
+                let line = self.api.get_line(None, cursor.y).await?;
+                let x = line.chars().position(|ch| !ch.is_whitespace()).unwrap_or(0);
+                self.api.move_to_x(None, Position::Number(x)).await?;
+            }
             BuiltinAction::RemoveChar => {
+                // The whole grapheme cluster under the cursor
                 let pos = self.api.get_cursor(None).await?;
-                self.api.remove_char(None, pos).await?;
+                let line = self.api.get_line(None, pos.y).await?;
+                let end = grapheme::next(&line, pos.x);
+                if end == pos.x {
+                    return Ok(());
+                }
+                self.api
+                    .apply_text_edits(
+                        None,
+                        vec![RangeEdit {
+                            range: TextRange {
+                                start: pos,
+                                end: UVec2::new(end, pos.y),
+                            },
+                            text: String::new(),
+                        }],
+                    )
+                    .await?;
+                // Back onto the last character after removing it
+                self.api.move_to_x(None, Position::Number(pos.x)).await?;
             }
             BuiltinAction::RemoveLine => {
                 let pos = self.api.get_cursor(None).await?;
                 self.api.remove_line(None, pos.y).await?;
             }
             BuiltinAction::RemoveSelection => {
-                let cursor = self.api.get_cursor(None).await?;
-                let visual_start = self.api.get_visual_start(None).await?;
-
-                let (left, right, x) = if cursor < visual_start {
-                    (cursor, visual_start, true)
-                } else {
-                    (visual_start, cursor, false)
-                };
-
-                let z = if left.y != right.y {
-                    for _ in 0..right.x + 1 {
-                        self.api.remove_char(None, UVec2::new(0, right.y)).await?;
-                    }
-
-                    let z = right.y - left.y - 1;
-
-                    for _ in left.y + 1..right.y {
-                        self.api.remove_line(None, left.y + 1).await?;
-                    }
-
-                    let line_len = self.api.get_line_length(None, left.y).await?;
-                    for _ in left.x..line_len {
-                        self.api.remove_char(None, UVec2::new(0, left.y)).await?;
-                    }
-
-                    self.api.join_lines(None, left.y).await?;
-
-                    if x { z } else { 0 }
-                } else {
-                    for _ in left.x..right.x {
-                        self.api
-                            .remove_char(None, UVec2::new(left.x, left.y))
-                            .await?;
-                    }
-
-                    0
-                };
+                let range = self.api.get_selection(None).await?;
+                self.api
+                    .apply_text_edits(
+                        None,
+                        vec![RangeEdit {
+                            range,
+                            text: String::new(),
+                        }],
+                    )
+                    .await?;
 
+                self.api.change_mode(Mode::Normal).await?;
                 self.api
-                    .move_to_y(None, Position::Number(visual_start.y - z))
+                    .move_to_y(None, Position::Number(range.start.y))
                     .await?;
                 self.api
-                    .move_to_x(None, Position::Number(visual_start.x))
+                    .move_to_x(None, Position::Number(range.start.x))
                     .await?;
-
-                self.api.change_mode(Mode::Normal).await?;
             }
             BuiltinAction::OpenLineBelow => {
                 let pos = self.api.get_cursor(None).await?;
@@ -184,6 +215,8 @@ pub enum BuiltinAction {
     MoveBy(IVec2),
     MoveToX(Position),
     MoveToY(Position),
+    /// Move to a 0-based line and its first non-blank character
+    GotoLine(usize),
     RemoveChar,
     RemoveLine,
     RemoveSelection,
@@ -192,3 +225,20 @@ pub enum BuiltinAction {
     InsertLineStart,
     AppendLineEnd,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        // Nothing handles the requests
+        let mut builtin = Builtin::default();
+        builtin.set_timeout(Duration::from_millis(10));
+        let err = builtin.on_action(BuiltinAction::Save).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CuprumApiError>(),
+            Some(&CuprumApiError::Timeout)
+        );
+    }
+}
@@ -0,0 +1,12 @@
+;; A WASM plugin that never returns from `handle_message`, for testing that it can be
+;; stopped anyway
+(module
+  (memory (export "memory") 1)
+
+  (func (export "alloc") (param $len i32) (result i32)
+    i32.const 0)
+
+  (func (export "handle_message") (param $ptr i32) (param $len i32)
+    (loop $forever
+      br $forever))
+)
@@ -0,0 +1,280 @@
+//! `cuprum plugin install` and `cuprum plugin remove`.
+//!
+//! An installed plugin keeps its source in `src/{name}` of the plugin directory, either a
+//! clone of a git repository or a link to a local directory, and gets a `{name}.toml`
+//! manifest pointing at the command to run. The manifest is written last, so an install
+//! that fails leaves no plugin behind.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, bail};
+
+use crate::{InstalledPlugin, PluginKind, PluginManifest};
+
+/// What WASM plugins are built for
+const WASM_TARGET: &str = "wasm32-wasip1";
+
+/// Whether `source` should be cloned rather than read as a local directory
+fn is_git_url(source: &str) -> bool {
+    source.contains("://") || source.starts_with("git@") || source.ends_with(".git")
+}
+
+/// The plugin name of a source: the last part of its path, without `.git`
+fn get_name(source: &str) -> anyhow::Result<String> {
+    let name = source
+        .trim_end_matches('/')
+        .rsplit(['/', ':'])
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches(".git");
+    if name.is_empty() || name.starts_with('.') {
+        bail!("Cannot tell the plugin name from {}", source);
+    }
+    Ok(name.to_string())
+}
+
+fn run(command: &mut Command) -> anyhow::Result<()> {
+    let status = command.stdin(Stdio::null()).status()?;
+    if !status.success() {
+        bail!("{:?} failed with {}", command.get_program(), status);
+    }
+    Ok(())
+}
+
+/// Build a Rust plugin, returning the path of its binary or WASM module
+fn build(dir: &Path, kind: PluginKind) -> anyhow::Result<PathBuf> {
+    let target_dir = dir.join("target");
+    let mut command = Command::new("cargo");
+    command.args(["build", "--release"]);
+    if kind == PluginKind::Wasm {
+        command.args(["--target", WASM_TARGET]);
+    }
+    run(command
+        .current_dir(dir)
+        .env("CARGO_TARGET_DIR", &target_dir))?;
+
+    let cargo_toml: toml::Table = toml::from_str(&fs::read_to_string(dir.join("Cargo.toml"))?)?;
+    let package = cargo_toml
+        .get("package")
+        .and_then(|package| package.get("name"))
+        .and_then(|name| name.as_str())
+        .ok_or_else(|| anyhow!("Cargo.toml has no package name"))?;
+    Ok(match kind {
+        PluginKind::Process => target_dir.join("release").join(package),
+        PluginKind::Wasm => target_dir
+            .join(WASM_TARGET)
+            .join("release")
+            .join(format!("{}.wasm", package.replace('-', "_"))),
+    })
+}
+
+/// Read the manifest of a fetched source and point it at the command, building it if needed
+fn prepare(plugin_dir: &Path, src_dir: &Path, name: &str) -> anyhow::Result<PluginManifest> {
+    let mut manifest = PluginManifest::read(&src_dir.join(format!("{}.toml", name)))?;
+    let command = match &manifest.command {
+        Some(command) => src_dir.join(command),
+        None if src_dir.join("Cargo.toml").is_file() => build(src_dir, manifest.kind)?,
+        None => bail!(
+            "{}.toml does not name a command and there is no Cargo.toml",
+            name
+        ),
+    };
+    if !command.is_file() {
+        bail!("{} does not exist", command.display());
+    }
+
+    manifest.command = Some(command.strip_prefix(plugin_dir)?.to_path_buf());
+    Ok(manifest)
+}
+
+/// Install a plugin from a local directory or a git URL
+pub fn install(plugin_dir: &Path, source: &str) -> anyhow::Result<InstalledPlugin> {
+    let name = get_name(source)?;
+    let manifest_path = plugin_dir.join(format!("{}.toml", name));
+    let src_dir = plugin_dir.join("src").join(&name);
+    if manifest_path.exists()
+        || plugin_dir.join(&name).exists()
+        || src_dir.symlink_metadata().is_ok()
+    {
+        bail!("{} is already installed", name);
+    }
+    fs::create_dir_all(plugin_dir.join("src"))?;
+
+    if is_git_url(source) {
+        run(Command::new("git")
+            .args(["clone", "--depth", "1", "--quiet", "--", source])
+            .arg(&src_dir))?;
+    } else {
+        let source = fs::canonicalize(source)?;
+        if !source.is_dir() {
+            bail!("{} is not a directory", source.display());
+        }
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(source, &src_dir)?;
+        #[cfg(windows)]
+        std::os::windows::fs::symlink_dir(source, &src_dir)?;
+    }
+
+    // A partly written manifest would be loaded as a broken plugin, and files in `src`
+    // are not loaded at all
+    let temp_path = plugin_dir.join("src").join(format!("{}.toml.tmp", name));
+    let installed = prepare(plugin_dir, &src_dir, &name).and_then(|manifest| {
+        fs::write(&temp_path, toml::to_string(&manifest)?)?;
+        fs::rename(&temp_path, &manifest_path)?;
+        Ok(manifest)
+    });
+    match installed {
+        Ok(manifest) => Ok(InstalledPlugin {
+            command: plugin_dir.join(manifest.command.as_ref().unwrap()),
+            name,
+            manifest,
+        }),
+        Err(err) => {
+            fs::remove_file(&temp_path).ok();
+            remove_source(&src_dir).ok();
+            Err(err)
+        }
+    }
+}
+
+/// Remove a clone, or only the link to a local directory
+fn remove_source(src_dir: &Path) -> anyhow::Result<()> {
+    if src_dir.symlink_metadata()?.is_symlink() {
+        fs::remove_file(src_dir)?;
+    } else {
+        fs::remove_dir_all(src_dir)?;
+    }
+    Ok(())
+}
+
+/// Remove a plugin with its manifest and source
+pub fn remove(plugin_dir: &Path, name: &str) -> anyhow::Result<()> {
+    let plugin = plugin_dir.join(name);
+    let src_dir = plugin_dir.join("src").join(name);
+    let mut found = false;
+
+    // The manifest goes first, so a plugin is never left pointing at a removed command
+    for path in [PluginManifest::get_path(&plugin), plugin] {
+        if path.is_file() {
+            fs::remove_file(path)?;
+            found = true;
+        }
+    }
+    if src_dir.symlink_metadata().is_ok() {
+        remove_source(&src_dir)?;
+        found = true;
+    }
+
+    if !found {
+        bail!("No such plugin: {}", name);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use api::Capability;
+
+    use super::*;
+    use crate::find_plugins;
+
+    /// An empty directory in the temporary directory
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cuprum-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A plugin source with a script and a manifest naming it
+    fn write_source(dir: &Path, name: &str) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("run.sh"), "#!/bin/sh\n").unwrap();
+        fs::write(
+            dir.join(format!("{}.toml", name)),
+            "command = \"run.sh\"\ncapabilities = [\"write_buffers\"]\n",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_get_name() {
+        assert_eq!(get_name("https://example.com/a/foo.git").unwrap(), "foo");
+        assert_eq!(get_name("git@example.com:foo").unwrap(), "foo");
+        assert_eq!(get_name("../plugins/foo/").unwrap(), "foo");
+        assert!(get_name("..").is_err());
+    }
+
+    #[test]
+    fn test_install_local_and_remove() {
+        let dir = temp_dir("install-local");
+        let plugin_dir = dir.join("plugins");
+        write_source(&dir.join("foo"), "foo");
+
+        let installed = install(&plugin_dir, dir.join("foo").to_str().unwrap()).unwrap();
+        assert_eq!(installed.command, plugin_dir.join("src/foo/run.sh"));
+        assert_eq!(find_plugins(&plugin_dir).unwrap(), vec![installed]);
+        assert_eq!(
+            fs::read_to_string(plugin_dir.join("foo.toml")).unwrap(),
+            "command = \"src/foo/run.sh\"\ncapabilities = [\"write_buffers\"]\n"
+        );
+        assert!(install(&plugin_dir, dir.join("foo").to_str().unwrap()).is_err());
+
+        remove(&plugin_dir, "foo").unwrap();
+        assert_eq!(find_plugins(&plugin_dir).unwrap(), Vec::new());
+        assert!(!plugin_dir.join("src/foo").exists());
+        // Only the link was removed
+        assert!(dir.join("foo/run.sh").exists());
+        assert!(remove(&plugin_dir, "foo").is_err());
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_failed_install_leaves_nothing() {
+        let dir = temp_dir("install-failed");
+        let plugin_dir = dir.join("plugins");
+        fs::create_dir_all(dir.join("bar")).unwrap();
+
+        assert!(install(&plugin_dir, dir.join("bar").to_str().unwrap()).is_err());
+        assert!(!plugin_dir.join("bar.toml").exists());
+        assert!(!plugin_dir.join("src/bar").exists());
+        assert!(dir.join("bar").exists());
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_install_git() {
+        let dir = temp_dir("install-git");
+        let plugin_dir = dir.join("plugins");
+        let repo = dir.join("baz.git");
+        write_source(&repo, "baz");
+        let git = |args: &[&str]| {
+            run(Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .current_dir(&repo)
+                .stdout(Stdio::null()))
+            .unwrap()
+        };
+        git(&["init", "--quiet"]);
+        git(&["add", "."]);
+        git(&["commit", "--quiet", "-m", "init"]);
+
+        let url = format!("file://{}", repo.display());
+        let installed = install(&plugin_dir, &url).unwrap();
+        assert_eq!(installed.name, "baz");
+        assert_eq!(
+            installed.manifest.capabilities,
+            vec![Capability::WriteBuffers]
+        );
+        assert!(plugin_dir.join("src/baz/.git").is_dir());
+
+        remove(&plugin_dir, "baz").unwrap();
+        assert!(!plugin_dir.join("src/baz").exists());
+        fs::remove_dir_all(dir).ok();
+    }
+}
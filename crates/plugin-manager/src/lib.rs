@@ -1,21 +1,535 @@
-use std::{path::PathBuf, process::Stdio, sync::Arc};
+pub mod install;
+mod wasm;
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::{anyhow, bail};
-use api::{CuprumApiRequest, CuprumApiResponse};
+use api::{
+    Capability, CuprumApiError, CuprumApiRequest, CuprumApiResponse, CuprumNotification,
+    DEFAULT_REQUEST_TIMEOUT, Hello, HelloReply, PLUGIN_ID_ENV, PROTOCOL_VERSION, PluginMessage,
+    PluginRequest, PluginRequestId, PluginRequestKind, PluginResponse, PluginResponseKind,
+    SOCKET_ENV,
+};
+use serde::{Deserialize, Serialize};
+#[cfg(unix)]
+use tokio::net::UnixListener;
 use tokio::{
-    fs::read_dir,
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    process::{ChildStdin, ChildStdout, Command},
-    sync::{Mutex, Notify},
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, duplex,
+        split,
+    },
+    process::{Child, Command},
+    sync::{Mutex, Notify, mpsc, oneshot, watch},
+    task::{JoinHandle, JoinSet},
+    time::timeout,
 };
 
+/// Bytes buffered between the editor and a WASM plugin in each direction
+const WASM_PIPE_SIZE: usize = 64 * 1024;
+/// Messages waiting to be written to a plugin before sending more times out
+const WRITE_QUEUE_SIZE: usize = 64;
+/// A plugin is disabled after this many timeouts in a row
+const MAX_CONSECUTIVE_TIMEOUTS: usize = 3;
+/// How long a plugin has to exit by itself once its input is closed
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(1);
+
+/// Editor side of the handshake: send `Hello` and wait for the reply.
+/// Other lines are skipped, as plugins using the socket may print to stdout.
+pub async fn handshake<R, W>(reader: &mut R, writer: &mut W) -> anyhow::Result<HelloReply>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let hello = serde_json::to_string(&Hello {
+        protocol_version: PROTOCOL_VERSION,
+        editor_version: env!("CARGO_PKG_VERSION").to_string(),
+    })?;
+    writer.write_all(hello.as_bytes()).await?;
+    writer.write_all(b"\n").await?;
+    writer.flush().await?;
+
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        if line.is_empty() {
+            bail!("exited during the handshake");
+        }
+        if let Ok(reply) = serde_json::from_str(&line) {
+            return Ok(reply);
+        }
+    }
+}
+
+fn check_version(reply: &HelloReply) -> anyhow::Result<()> {
+    if reply.protocol_version != PROTOCOL_VERSION {
+        bail!(
+            "speaks protocol version {}, but the editor speaks version {}",
+            reply.protocol_version,
+            PROTOCOL_VERSION
+        );
+    }
+    Ok(())
+}
+
+/// A plugin that connected to the editor's socket and answered the handshake
+struct SocketConnection {
+    reader: BufReader<Box<dyn AsyncRead + Send + Unpin>>,
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+    reply: HelloReply,
+}
+
+impl std::fmt::Debug for SocketConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SocketConnection")
+            .field("reply", &self.reply)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Where a plugin started by the editor can connect instead of using stdio
+#[derive(Debug)]
+struct PluginSocket {
+    path: PathBuf,
+    id: u64,
+    connection: oneshot::Receiver<SocketConnection>,
+}
+
+/// Plugins that may still connect to the socket, by id
+type SocketRoutes = Arc<Mutex<HashMap<u64, oneshot::Sender<SocketConnection>>>>;
+
+/// Removes the socket file when the listener stops
+#[cfg(unix)]
+struct SocketFile(PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.0).ok();
+    }
+}
+
+/// Hand the connections to the editor's socket to the plugins they belong to
+#[cfg(unix)]
+async fn accept_plugins(listener: UnixListener, routes: SocketRoutes, deadline: Duration) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                log::error!("{}", err);
+                continue;
+            }
+        };
+
+        let routes = routes.clone();
+        tokio::spawn(async move {
+            let (reader, writer) = stream.into_split();
+            let mut reader = BufReader::new(Box::new(reader) as Box<dyn AsyncRead + Send + Unpin>);
+            let mut writer = Box::new(writer) as Box<dyn AsyncWrite + Send + Unpin>;
+            let reply = match timeout(deadline, handshake(&mut reader, &mut writer)).await {
+                Ok(Ok(reply)) => reply,
+                Ok(Err(err)) => return log::error!("plugin connection: {}", err),
+                Err(_) => return log::error!("plugin connection: did not answer the handshake"),
+            };
+
+            let route = match reply.plugin_id {
+                Some(id) => routes.lock().await.remove(&id),
+                None => None,
+            };
+            match route {
+                Some(route) => {
+                    let connection = SocketConnection {
+                        reader,
+                        writer,
+                        reply,
+                    };
+                    route.send(connection).ok();
+                }
+                None => log::error!("rejected a connection from an unknown plugin"),
+            }
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PluginStatus {
+    Running,
+    /// The process exited or stopped responding
+    Crashed,
+    /// Stopped with `:plugin disable`
+    Disabled,
+}
+
+impl Display for PluginStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = match self {
+            Self::Running => "running",
+            Self::Crashed => "crashed",
+            Self::Disabled => "disabled",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+/// What the editor knows about a plugin, updated while it runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginInfo {
+    pub status: PluginStatus,
+    /// Protocol version from the handshake, if it got that far
+    pub protocol_version: Option<u32>,
+    /// API requests received since the editor started
+    pub request_count: usize,
+}
+
+/// `{plugin}.toml` next to a plugin, stating what it may do
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PluginManifest {
+    /// What to run, relative to the plugin directory, if not the file next to the manifest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<PathBuf>,
+    /// How to run the plugin, `process` unless stated
+    #[serde(
+        default,
+        rename = "type",
+        skip_serializing_if = "PluginKind::is_process"
+    )]
+    pub kind: PluginKind,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+}
+
+/// Plugins without a manifest may only read buffers
+impl Default for PluginManifest {
+    fn default() -> Self {
+        Self {
+            command: None,
+            kind: PluginKind::Process,
+            capabilities: vec![Capability::ReadBuffers],
+        }
+    }
+}
+
+impl PluginManifest {
+    /// Where the manifest of the plugin at `plugin` is
+    pub fn get_path(plugin: &Path) -> PathBuf {
+        plugin.with_extension("toml")
+    }
+
+    /// Read the manifest of a plugin, or the default if it has none
+    pub fn load(plugin: &Path) -> anyhow::Result<Self> {
+        Self::read(&Self::get_path(plugin))
+    }
+
+    /// Read a manifest file, or the default if it does not exist
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text).map_err(|err| anyhow!("{}: {}", path.display(), err)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(anyhow!("{}: {}", path.display(), err)),
+        }
+    }
+}
+
+/// A plugin found in the plugin directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstalledPlugin {
+    pub name: String,
+    /// The executable or WASM module to run
+    pub command: PathBuf,
+    pub manifest: PluginManifest,
+}
+
+/// Where plugins are installed
+pub fn get_plugin_dir() -> PathBuf {
+    let home_dir = home::home_dir().unwrap();
+
+    #[cfg(debug_assertions)]
+    let plugin_dir = home_dir.join(".cuprum/debug/plugins");
+    #[cfg(not(debug_assertions))]
+    let plugin_dir = home_dir.join(".cuprum/plugins");
+
+    plugin_dir
+}
+
+/// Find the plugins of a directory in name order: files with an optional manifest next to
+/// them, and manifests whose `command` points elsewhere. A broken manifest is logged and
+/// leaves its plugin only able to read.
+pub fn find_plugins(dir: &Path) -> anyhow::Result<Vec<InstalledPlugin>> {
+    let mut paths = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+
+    let mut plugins = Vec::new();
+    for path in paths {
+        // Sources of installed plugins are directories
+        if !path.is_file() {
+            continue;
+        }
+        let name = |path: &Path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default()
+        };
+
+        if path.extension().is_some_and(|ext| ext == "toml") {
+            match PluginManifest::read(&path) {
+                Ok(manifest) => {
+                    if let Some(command) = &manifest.command {
+                        plugins.push(InstalledPlugin {
+                            name: name(&path.with_extension("")),
+                            command: dir.join(command),
+                            manifest,
+                        });
+                    }
+                }
+                Err(err) => log::error!("{}", err),
+            }
+            continue;
+        }
+
+        // Already reported if broken
+        let manifest = PluginManifest::load(&path).unwrap_or_default();
+        if manifest.command.is_some() {
+            continue;
+        }
+        plugins.push(InstalledPlugin {
+            name: name(&path),
+            command: path,
+            manifest,
+        });
+    }
+    Ok(plugins)
+}
+
+/// Requests sent to a plugin that wait for its answer
+type PendingRequests = Arc<
+    Mutex<HashMap<PluginRequestId, oneshot::Sender<Result<PluginResponseKind, CuprumApiError>>>>,
+>;
+
+/// Sends editor-initiated messages to a plugin
+#[derive(Debug, Clone)]
+pub struct PluginChannel {
+    notifications: Arc<Mutex<Vec<CuprumNotification>>>,
+    requests: Arc<Mutex<Vec<PluginRequest>>>,
+    pending: PendingRequests,
+    next_request_id: Arc<Mutex<usize>>,
+    notify: Arc<Notify>,
+    exited: watch::Receiver<bool>,
+    timeout: Duration,
+    name: Arc<str>,
+    capabilities: Arc<HashSet<Capability>>,
+}
+
+/// Channels are equal when they lead to the same plugin
+impl PartialEq for PluginChannel {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.notifications, &other.notifications)
+    }
+}
+
+impl PluginChannel {
+    /// The file name of the plugin
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether the plugin may make requests that need `capability`
+    pub fn is_allowed(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    pub async fn send(&self, notification: CuprumNotification) {
+        let mut notifications = self.notifications.lock().await;
+        notifications.push(notification);
+        self.notify.notify_one();
+    }
+
+    /// Send a request and wait for the plugin's answer
+    pub async fn request(
+        &self,
+        request: PluginRequestKind,
+    ) -> Result<PluginResponseKind, CuprumApiError> {
+        let request_id = {
+            let mut next_request_id = self.next_request_id.lock().await;
+            *next_request_id += 1;
+            PluginRequestId(*next_request_id - 1)
+        };
+        let (answer, response) = oneshot::channel();
+        self.pending.lock().await.insert(request_id, answer);
+        self.requests.lock().await.push(PluginRequest {
+            request_id,
+            request: request.clone(),
+        });
+        self.notify.notify_one();
+
+        tokio::select! {
+            response = timeout(self.timeout, response) => match response {
+                Ok(Ok(result)) => result,
+                Ok(Err(_)) => Err(CuprumApiError::Transport("no response".to_string())),
+                Err(_) => {
+                    self.pending.lock().await.remove(&request_id);
+                    log::error!("plugin request {:?} timed out: {:?}", request_id, request);
+                    Err(CuprumApiError::Timeout)
+                }
+            },
+            _ = self.closed() => Err(CuprumApiError::Transport("the plugin exited".to_string())),
+        }
+    }
+
+    /// Wait until the plugin process has exited
+    pub async fn closed(&self) {
+        let mut exited = self.exited.clone();
+        // An error means the plugin was dropped, which counts as exited too
+        exited.wait_for(|exited| *exited).await.ok();
+    }
+
+    /// Take the notifications that have not been written to the plugin yet
+    pub async fn drain(&self) -> Vec<CuprumNotification> {
+        self.notifications.lock().await.drain(..).collect()
+    }
+}
+
+/// The editor's end of a plugin that runs inside the editor, such as the scripts
+#[derive(Debug)]
+pub struct InProcessPlugin {
+    pub channel: PluginChannel,
+    /// Fires when something is sent through the channel
+    pub notify: Arc<Notify>,
+    /// Dropping the plugin closes the channel
+    _exited: watch::Sender<bool>,
+}
+
+impl InProcessPlugin {
+    /// A plugin with every capability, as it is part of the editor or its configuration
+    pub fn new() -> Self {
+        let notify = Arc::new(Notify::new());
+        let (exited, receiver) = watch::channel(false);
+        Self {
+            channel: PluginChannel {
+                notifications: Default::default(),
+                requests: Default::default(),
+                pending: Default::default(),
+                next_request_id: Default::default(),
+                notify: notify.clone(),
+                exited: receiver,
+                timeout: DEFAULT_REQUEST_TIMEOUT,
+                name: "in-process".into(),
+                capabilities: Arc::new(Capability::ALL.iter().copied().collect()),
+            },
+            notify,
+            _exited: exited,
+        }
+    }
+
+    pub fn with_capabilities(mut self, name: &str, capabilities: &[Capability]) -> Self {
+        self.channel.name = name.into();
+        self.channel.capabilities = Arc::new(capabilities.iter().copied().collect());
+        self
+    }
+
+    /// Take the requests sent to the plugin, to be answered with `respond`
+    pub async fn take_requests(&self) -> Vec<PluginRequest> {
+        self.channel.requests.lock().await.drain(..).collect()
+    }
+
+    pub async fn respond(&self, response: PluginResponse) {
+        match self
+            .channel
+            .pending
+            .lock()
+            .await
+            .remove(&response.request_id)
+        {
+            Some(answer) => {
+                answer.send(response.result).ok();
+            }
+            None => log::error!("unexpected response to {:?}", response.request_id),
+        }
+    }
+}
+
+impl Default for InProcessPlugin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How a plugin is run, the `type` of its manifest
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PluginKind {
+    /// An executable started as a child process
+    #[default]
+    Process,
+    /// A WebAssembly module run inside the editor
+    Wasm,
+}
+
+impl PluginKind {
+    fn is_process(&self) -> bool {
+        *self == Self::Process
+    }
+}
+
+/// A running plugin, whichever way it was started
+#[derive(Debug)]
+enum PluginProcess {
+    Child(Child),
+    Wasm(wasm::WasmTask),
+}
+
+impl PluginProcess {
+    /// Wait until the plugin stops by itself
+    async fn wait(&mut self) {
+        match self {
+            PluginProcess::Child(child) => {
+                child.wait().await.ok();
+            }
+            PluginProcess::Wasm(task) => {
+                if let Err(err) = task.wait().await {
+                    log::error!("{}", err);
+                }
+            }
+        }
+    }
+
+    async fn kill(&mut self) -> anyhow::Result<()> {
+        match self {
+            PluginProcess::Child(child) => child.kill().await?,
+            PluginProcess::Wasm(task) => task.kill(),
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct Plugin {
     command: PathBuf,
+    kind: PluginKind,
     requests: Arc<Mutex<Vec<CuprumApiRequest>>>,
     request_notify: Arc<Notify>,
     responses: Arc<Mutex<Vec<CuprumApiResponse>>>,
     response_notify: Arc<Notify>,
+    notifications: Arc<Mutex<Vec<CuprumNotification>>>,
+    plugin_requests: Arc<Mutex<Vec<PluginRequest>>>,
+    pending: PendingRequests,
+    next_request_id: Arc<Mutex<usize>>,
+    exited: watch::Sender<bool>,
+    /// Set to close the input of the plugin, which is killed if it does not exit
+    stopping: watch::Sender<bool>,
+    info: Arc<Mutex<PluginInfo>>,
+    /// How long the plugin has to answer the handshake and to take each message
+    timeout: Duration,
+    socket: Option<PluginSocket>,
+    capabilities: Arc<HashSet<Capability>>,
 }
 
 type Arcs = (
@@ -23,94 +537,322 @@ type Arcs = (
     Arc<Notify>,
     Arc<Mutex<Vec<CuprumApiResponse>>>,
     Arc<Notify>,
+    PluginChannel,
 );
 
 impl Plugin {
     pub fn new(command: PathBuf) -> Self {
         Self {
             command,
+            kind: PluginKind::Process,
             requests: Default::default(),
             request_notify: Default::default(),
             responses: Default::default(),
             response_notify: Default::default(),
+            notifications: Default::default(),
+            plugin_requests: Default::default(),
+            pending: Default::default(),
+            next_request_id: Default::default(),
+            exited: watch::channel(false).0,
+            stopping: watch::channel(false).0,
+            info: Arc::new(Mutex::new(PluginInfo {
+                status: PluginStatus::Crashed,
+                protocol_version: None,
+                request_count: 0,
+            })),
+            timeout: DEFAULT_REQUEST_TIMEOUT,
+            socket: None,
+            capabilities: Arc::new(Capability::ALL.iter().copied().collect()),
         }
     }
 
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_kind(mut self, kind: PluginKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    /// Allow only these capabilities instead of all of them
+    pub fn with_capabilities(mut self, capabilities: &[Capability]) -> Self {
+        self.capabilities = Arc::new(capabilities.iter().copied().collect());
+        self
+    }
+
+    fn get_name(&self) -> String {
+        self.command
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
     pub fn get(&self) -> Arcs {
         (
             self.requests.clone(),
             self.request_notify.clone(),
             self.responses.clone(),
             self.response_notify.clone(),
+            PluginChannel {
+                notifications: self.notifications.clone(),
+                requests: self.plugin_requests.clone(),
+                pending: self.pending.clone(),
+                next_request_id: self.next_request_id.clone(),
+                notify: self.response_notify.clone(),
+                exited: self.exited.subscribe(),
+                timeout: self.timeout,
+                name: self.get_name().into(),
+                capabilities: self.capabilities.clone(),
+            },
         )
     }
 
+    /// Queue the responses, notifications and requests for the writer, counting the ones
+    /// the plugin did not take in time
     async fn process_response(
-        stdin: &mut ChildStdin,
+        writer: &mpsc::Sender<String>,
         queue: &Arc<Mutex<Vec<CuprumApiResponse>>>,
+        notifications: &Arc<Mutex<Vec<CuprumNotification>>>,
+        requests: &Arc<Mutex<Vec<PluginRequest>>>,
         notify: &Arc<Notify>,
+        deadline: Duration,
+        timeouts: &mut usize,
     ) -> anyhow::Result<()> {
         notify.notified().await;
-        let queue = queue.lock().await;
-        for response in queue.clone() {
-            let response = serde_json::to_string(&response)?;
-            stdin.write_all(response.as_bytes()).await?;
-            stdin.write_all(b"\n").await?;
-            stdin.flush().await?;
+
+        let mut lines = Vec::new();
+        for response in queue.lock().await.drain(..) {
+            let line = serde_json::to_string(&response)?;
+            lines.push((format!("response to {:?}", response.id), line));
+        }
+        for notification in notifications.lock().await.drain(..) {
+            lines.push((
+                "notification".to_string(),
+                serde_json::to_string(&notification)?,
+            ));
+        }
+        for request in requests.lock().await.drain(..) {
+            let line = serde_json::to_string(&request)?;
+            lines.push((format!("request {:?}", request.request_id), line));
+        }
+
+        for (name, line) in lines {
+            match writer.send_timeout(line, deadline).await {
+                Ok(()) => *timeouts = 0,
+                Err(mpsc::error::SendTimeoutError::Timeout(_)) => {
+                    log::error!("plugin did not read the {} in time", name);
+                    *timeouts += 1;
+                    if *timeouts >= MAX_CONSECUTIVE_TIMEOUTS {
+                        bail!("disabled after {} timeouts in a row", timeouts);
+                    }
+                }
+                Err(mpsc::error::SendTimeoutError::Closed(_)) => bail!("stdin was closed"),
+            }
         }
         Ok(())
     }
 
-    async fn process_request(
-        stdout: &mut BufReader<ChildStdout>,
+    async fn write_lines<W>(writer: &mut W, lines: &mut mpsc::Receiver<String>)
+    where
+        W: AsyncWrite + Unpin,
+    {
+        while let Some(line) = lines.recv().await {
+            let result = async {
+                writer.write_all(line.as_bytes()).await?;
+                writer.write_all(b"\n").await?;
+                writer.flush().await
+            }
+            .await;
+            if let Err(err) = result {
+                log::error!("{}", err);
+                break;
+            }
+        }
+    }
+
+    async fn process_request<R>(
+        reader: &mut R,
         queue: &Arc<Mutex<Vec<CuprumApiRequest>>>,
         notify: &Arc<Notify>,
-    ) -> anyhow::Result<()> {
+        pending: &PendingRequests,
+        info: &Arc<Mutex<PluginInfo>>,
+    ) -> anyhow::Result<()>
+    where
+        R: AsyncBufRead + Unpin,
+    {
         let mut request = String::new();
-        stdout.read_line(&mut request).await?;
+        reader.read_line(&mut request).await?;
 
         if request.is_empty() {
             bail!("Error: Empty request")
         }
 
-        let request = serde_json::from_str(&request)?;
-        let mut queue = queue.lock().await;
-        queue.push(request);
-        notify.notify_one();
+        match serde_json::from_str(&request)? {
+            PluginMessage::Request(request) => {
+                info.lock().await.request_count += 1;
+                let mut queue = queue.lock().await;
+                queue.push(request);
+                notify.notify_one();
+            }
+            PluginMessage::Response(response) => {
+                match pending.lock().await.remove(&response.request_id) {
+                    Some(answer) => {
+                        answer.send(response.result).ok();
+                    }
+                    None => log::error!("unexpected response to {:?}", response.request_id),
+                }
+            }
+        }
 
         Ok(())
     }
 
     pub async fn run(&mut self) -> anyhow::Result<()> {
-        let mut child = Command::new(&self.command)
+        self.exited.send_replace(false);
+        let result = self.run_process().await;
+        self.info.lock().await.status = PluginStatus::Crashed;
+        self.exited.send_replace(true);
+        result
+    }
+
+    async fn run_process(&mut self) -> anyhow::Result<()> {
+        match self.kind {
+            PluginKind::Process => self.run_command().await,
+            PluginKind::Wasm => self.run_wasm().await,
+        }
+    }
+
+    async fn run_wasm(&mut self) -> anyhow::Result<()> {
+        let (editor, plugin) = duplex(WASM_PIPE_SIZE);
+        let mut task = PluginProcess::Wasm(wasm::spawn(&self.command, plugin)?);
+
+        let (reader, mut writer) = split(editor);
+        let mut reader = BufReader::new(reader);
+        let connected = timeout(self.timeout, handshake(&mut reader, &mut writer))
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("did not answer the handshake")))
+            .and_then(|reply| check_version(&reply).map(|_| reply));
+        let reply = match connected {
+            Ok(reply) => reply,
+            Err(err) => {
+                task.kill().await.ok();
+                bail!("{}: {}", self.command.to_string_lossy(), err);
+            }
+        };
+        self.info.lock().await.protocol_version = Some(reply.protocol_version);
+        self.serve(task, reader, writer).await
+    }
+
+    async fn run_command(&mut self) -> anyhow::Result<()> {
+        let mut command = Command::new(&self.command);
+        command
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::null())
-            .spawn()?;
+            .kill_on_drop(true);
+        if let Some(socket) = &self.socket {
+            command
+                .env(SOCKET_ENV, &socket.path)
+                .env(PLUGIN_ID_ENV, socket.id.to_string());
+        }
+        let mut child = command.spawn()?;
 
         let mut stdin = child.stdin.take().ok_or(anyhow!("Failed to get stdin"))?;
+        let stdout = child.stdout.take().ok_or(anyhow!("Failed to get stdout"))?;
+        let mut stdout = BufReader::new(stdout);
+
+        // The plugin either answers the handshake on stdout or connects to the socket
+        let socket = self.socket.take().map(|socket| socket.connection);
+        let connected = timeout(self.timeout, async {
+            tokio::select! {
+                reply = handshake(&mut stdout, &mut stdin) => reply.map(|reply| (reply, None)),
+                Some(Ok(connection)) = async {
+                    match socket {
+                        Some(socket) => Some(socket.await),
+                        None => None,
+                    }
+                } => Ok((connection.reply.clone(), Some(connection))),
+            }
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("did not answer the handshake")))
+        .and_then(|(reply, connection)| check_version(&reply).map(|_| (reply, connection)));
+
+        let (reply, connection) = match connected {
+            Ok(connected) => connected,
+            Err(err) => {
+                child.kill().await.ok();
+                bail!("{}: {}", self.command.to_string_lossy(), err);
+            }
+        };
+        self.info.lock().await.protocol_version = Some(reply.protocol_version);
+        let child = PluginProcess::Child(child);
+        match connection {
+            None => self.serve(child, stdout, stdin).await,
+            Some(connection) => {
+                self.serve(child, connection.reader, connection.writer)
+                    .await
+            }
+        }
+    }
 
+    /// Exchange messages with the plugin until it exits or stops responding
+    async fn serve<R, W>(
+        &mut self,
+        mut process: PluginProcess,
+        mut reader: R,
+        mut writer: W,
+    ) -> anyhow::Result<()>
+    where
+        R: AsyncBufRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        // Dropping the set stops the tasks, so none outlive the plugin
+        let mut tasks = JoinSet::new();
+
+        let (line_sender, mut lines) = mpsc::channel(WRITE_QUEUE_SIZE);
+        tasks.spawn(async move {
+            Self::write_lines(&mut writer, &mut lines).await;
+        });
+
+        let command = self.command.to_string_lossy().to_string();
+        let deadline = self.timeout;
         let response_queue = self.responses.clone();
         let response_notify = self.response_notify.clone();
-        let response_task = tokio::spawn(async move {
+        let notifications = self.notifications.clone();
+        let plugin_requests = self.plugin_requests.clone();
+        tasks.spawn(async move {
+            let mut timeouts = 0;
             loop {
-                match Self::process_response(&mut stdin, &response_queue, &response_notify).await {
+                match Self::process_response(
+                    &line_sender,
+                    &response_queue,
+                    &notifications,
+                    &plugin_requests,
+                    &response_notify,
+                    deadline,
+                    &mut timeouts,
+                )
+                .await
+                {
                     Ok(_) => {}
                     Err(err) => {
-                        log::error!("{}", err);
+                        log::error!("{}: {}", command, err);
                         break;
                     }
                 };
             }
         });
 
-        let stdout = child.stdout.take().ok_or(anyhow!("Failed to get stdout"))?;
-        let mut stdout = BufReader::new(stdout);
         let queue = self.requests.clone();
         let notify = self.request_notify.clone();
-        let request_task = tokio::spawn(async move {
+        let pending = self.pending.clone();
+        let info = self.info.clone();
+        tasks.spawn(async move {
             loop {
-                match Self::process_request(&mut stdout, &queue, &notify).await {
+                match Self::process_request(&mut reader, &queue, &notify, &pending, &info).await {
                     Ok(_) => {}
                     Err(err) => {
                         log::error!("{}", err);
@@ -120,85 +862,664 @@ impl Plugin {
             }
         });
 
+        let mut stopping = self.stopping.subscribe();
         tokio::select! {
-            _ = response_task => {
-                child.kill().await?
-            },
-            _ = request_task => {
-                child.kill().await?
+            _ = tasks.join_next() => {
+                process.kill().await?
             },
-            _ = child.wait() => {
+            _ = process.wait() => {
                 log::error!("{} finished", self.command.to_string_lossy())
             }
+            _ = async { stopping.wait_for(|stopping| *stopping).await.is_ok() } => {
+                // Dropping the writer closes the input, the plugin's cue to exit
+                tasks.shutdown().await;
+                if timeout(SHUTDOWN_GRACE, process.wait()).await.is_err() {
+                    process.kill().await?
+                }
+            }
         }
 
         Ok(())
     }
 }
 
+/// A loaded plugin and the task running it
+#[derive(Debug)]
+struct ManagedPlugin {
+    name: String,
+    plugin: Arc<Mutex<Plugin>>,
+    info: Arc<Mutex<PluginInfo>>,
+    channel: PluginChannel,
+    stopping: watch::Sender<bool>,
+    task: Option<JoinHandle<()>>,
+}
+
 #[derive(Debug, Default)]
 pub struct PluginManager {
-    plugins: Vec<Arc<Mutex<Plugin>>>,
+    plugins: Vec<ManagedPlugin>,
+    #[cfg(unix)]
+    listener: Option<UnixListener>,
+    socket_path: Option<PathBuf>,
+    routes: SocketRoutes,
+    /// Accepts plugins on the socket, removing the socket file when stopped
+    accept_task: Option<JoinHandle<()>>,
 }
 
 impl PluginManager {
-    fn get_plugin_dir(&self) -> PathBuf {
+    #[cfg(unix)]
+    fn get_socket_path(&self) -> PathBuf {
         let home_dir = home::home_dir().unwrap();
+        home_dir
+            .join(".cuprum/run")
+            .join(format!("{}.sock", std::process::id()))
+    }
 
-        #[cfg(debug_assertions)]
-        let plugin_dir = home_dir.join(".cuprum/debug/plugins");
-        #[cfg(not(debug_assertions))]
-        let plugin_dir = home_dir.join(".cuprum/plugins");
+    /// Listen on the socket that plugins can connect to instead of using stdio
+    #[cfg(unix)]
+    fn listen(&mut self) -> anyhow::Result<()> {
+        let path = self.get_socket_path();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        // Left behind by an editor with the same pid that did not exit cleanly
+        std::fs::remove_file(&path).ok();
 
-        plugin_dir
+        self.listener = Some(UnixListener::bind(&path)?);
+        self.socket_path = Some(path);
+        Ok(())
     }
 
-    async fn get_plugin_paths(&self, plugin_dir: PathBuf) -> anyhow::Result<Vec<PathBuf>> {
-        let mut entries = read_dir(plugin_dir).await?;
-        let mut paths = Vec::new();
+    /// Without Unix domain sockets, plugins can only use stdio
+    #[cfg(not(unix))]
+    fn listen(&mut self) -> anyhow::Result<()> {
+        bail!("sockets are only supported on Unix")
+    }
 
-        while let Some(entry) = entries.next_entry().await? {
-            paths.push(entry.path());
+    pub async fn init(&mut self) -> anyhow::Result<Vec<Arcs>> {
+        let plugins = find_plugins(&get_plugin_dir())?;
+        if let Err(err) = self.listen() {
+            log::error!("Plugins can only use stdio: {}", err);
         }
 
-        Ok(paths)
+        let arcs = plugins.into_iter().map(|plugin| self.add(plugin)).collect();
+        log::info!("{} plugins loaded", self.plugins.len());
+        Ok(arcs)
+    }
+
+    /// Load a plugin with the capabilities of its manifest, to be started by `run`
+    fn add(&mut self, installed: InstalledPlugin) -> Arcs {
+        let plugin = Plugin::new(installed.command)
+            .with_kind(installed.manifest.kind)
+            .with_capabilities(&installed.manifest.capabilities);
+        let arcs = plugin.get();
+        self.plugins.push(ManagedPlugin {
+            name: installed.name,
+            info: plugin.info.clone(),
+            stopping: plugin.stopping.clone(),
+            plugin: Arc::new(Mutex::new(plugin)),
+            channel: arcs.4.clone(),
+            task: None,
+        });
+        arcs
     }
 
-    async fn get_plugins(&self) -> anyhow::Result<Vec<PathBuf>> {
-        let plugin_dir = self.get_plugin_dir();
-        let plugin_paths = self.get_plugin_paths(plugin_dir).await?;
+    pub async fn run(&mut self) -> anyhow::Result<()> {
+        #[cfg(unix)]
+        if let Some(listener) = self.listener.take()
+            && let Some(path) = &self.socket_path
+        {
+            let socket_file = SocketFile(path.clone());
+            let routes = self.routes.clone();
+            self.accept_task = Some(tokio::spawn(async move {
+                let _socket_file = socket_file;
+                accept_plugins(listener, routes, DEFAULT_REQUEST_TIMEOUT).await;
+            }));
+        }
+
+        for index in 0..self.plugins.len() {
+            self.start(index).await;
+        }
 
-        Ok(plugin_paths)
+        Ok(())
     }
 
-    pub async fn init(&mut self) -> anyhow::Result<Vec<Arcs>> {
-        let plugins = self.get_plugins().await?;
+    /// Start the process of a plugin, letting it connect to the socket if the editor listens
+    async fn start(&mut self, index: usize) {
+        let socket = match &self.socket_path {
+            Some(path) => {
+                let (route, connection) = oneshot::channel();
+                self.routes.lock().await.insert(index as u64, route);
+                Some(PluginSocket {
+                    path: path.clone(),
+                    id: index as u64,
+                    connection,
+                })
+            }
+            None => None,
+        };
 
-        let mut arcs = Vec::new();
-        for plugin in plugins {
-            let plugin = Plugin::new(plugin);
-            arcs.push(plugin.get());
-            self.plugins.push(Arc::new(Mutex::new(plugin)));
+        let managed = &mut self.plugins[index];
+        managed.info.lock().await.status = PluginStatus::Running;
+        let plugin = managed.plugin.clone();
+        managed.task = Some(tokio::spawn(async move {
+            let mut plugin = plugin.lock().await;
+            plugin.socket = socket;
+            match plugin.run().await {
+                Ok(_) => {}
+                Err(err) => {
+                    log::error!("{}", err);
+                }
+            }
+        }));
+    }
+
+    fn find(&self, name: &str) -> anyhow::Result<usize> {
+        self.plugins
+            .iter()
+            .position(|managed| managed.name == name)
+            .ok_or_else(|| anyhow!("No such plugin: {}", name))
+    }
+
+    /// Names and states of the plugins, in load order
+    pub async fn list(&self) -> Vec<(String, PluginInfo)> {
+        let mut list = Vec::new();
+        for managed in &self.plugins {
+            list.push((managed.name.clone(), managed.info.lock().await.clone()));
         }
+        list
+    }
 
-        log::info!("{} plugins loaded", self.plugins.len());
-        Ok(arcs)
+    /// Stop a plugin, returning its channel so the editor can drop what it registered
+    pub async fn disable(&mut self, name: &str) -> anyhow::Result<PluginChannel> {
+        let index = self.find(name)?;
+        let managed = &mut self.plugins[index];
+        if managed.info.lock().await.status == PluginStatus::Disabled {
+            bail!("Plugin {} is already disabled", name);
+        }
+
+        // Dropping the running plugin kills its process
+        if let Some(task) = managed.task.take() {
+            task.abort();
+            task.await.ok();
+        }
+        managed.plugin.lock().await.exited.send_replace(true);
+        managed.info.lock().await.status = PluginStatus::Disabled;
+        Ok(managed.channel.clone())
     }
 
-    pub async fn run(&mut self) -> anyhow::Result<()> {
-        for plugin in &self.plugins {
-            let plugin = plugin.clone();
-            tokio::spawn(async move {
-                let mut plugin = plugin.lock().await;
-                match plugin.run().await {
-                    Ok(_) => {}
-                    Err(err) => {
-                        log::error!("{}", err);
-                    }
+    /// Close the input of every plugin and wait for them to exit, killing the ones that
+    /// take longer than the grace period
+    pub async fn shutdown(&mut self) {
+        for managed in &self.plugins {
+            managed.stopping.send_replace(true);
+        }
+        for managed in &mut self.plugins {
+            let Some(mut task) = managed.task.take() else {
+                continue;
+            };
+            // A plugin still in its handshake does not see the request to stop
+            if timeout(SHUTDOWN_GRACE * 2, &mut task).await.is_err() {
+                task.abort();
+                task.await.ok();
+            }
+            managed.plugin.lock().await.exited.send_replace(true);
+            managed.info.lock().await.status = PluginStatus::Disabled;
+        }
+
+        if let Some(task) = self.accept_task.take() {
+            task.abort();
+            task.await.ok();
+        }
+    }
+
+    /// Start a plugin that was disabled or crashed
+    pub async fn enable(&mut self, name: &str) -> anyhow::Result<()> {
+        let index = self.find(name)?;
+        if self.plugins[index].info.lock().await.status == PluginStatus::Running {
+            bail!("Plugin {} is already running", name);
+        }
+        self.start(index).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use api::{
+        CuprumApi, CuprumApiRequestKind, CuprumApiResponseKind, DefaultCuprumApiProvider, Mode,
+        RequestId,
+    };
+    use tokio::io::{duplex, split};
+
+    use super::*;
+
+    /// Write an executable shell script to the temporary directory
+    #[cfg(unix)]
+    fn write_script(name: &str, body: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("cuprum-{}-{}", name, std::process::id()));
+        std::fs::write(&path, format!("#!/bin/sh\n{}", body)).unwrap();
+        std::fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        path
+    }
+
+    /// Answer the first request of a plugin with `response`
+    async fn answer_request(arcs: &Arcs, response: CuprumApiResponseKind) -> CuprumApiRequest {
+        let (requests, request_notify, responses, response_notify, _) = arcs;
+        timeout(Duration::from_secs(5), request_notify.notified())
+            .await
+            .expect("no request");
+        let request = requests.lock().await.remove(0);
+        responses
+            .lock()
+            .await
+            .push(CuprumApiResponse::new(request.id, Ok(Some(response))));
+        response_notify.notify_one();
+        request
+    }
+
+    /// A plugin that answers the handshake with `version`
+    async fn fake_plugin(version: u32) -> anyhow::Result<()> {
+        let (editor, plugin) = duplex(1024);
+        let (plugin_reader, mut plugin_writer) = split(plugin);
+        let plugin = tokio::spawn(async move {
+            let mut reader = BufReader::new(plugin_reader);
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            let hello: Hello = serde_json::from_str(&line).unwrap();
+            assert_eq!(hello.protocol_version, PROTOCOL_VERSION);
+
+            let reply = serde_json::to_string(&HelloReply {
+                protocol_version: version,
+                plugin_id: None,
+            })
+            .unwrap();
+            plugin_writer
+                .write_all(format!("{}\n", reply).as_bytes())
+                .await
+                .unwrap();
+        });
+
+        let (editor_reader, mut editor_writer) = split(editor);
+        let result = handshake(&mut BufReader::new(editor_reader), &mut editor_writer).await;
+        plugin.await.unwrap();
+        check_version(&result?)
+    }
+
+    #[test]
+    fn test_manifest() {
+        let plugin = std::env::temp_dir().join(format!("cuprum-manifest-{}", std::process::id()));
+        let manifest = PluginManifest::get_path(&plugin);
+        assert_eq!(
+            PluginManifest::load(&plugin).unwrap(),
+            PluginManifest::default()
+        );
+
+        std::fs::write(
+            &manifest,
+            "capabilities = [\"read_buffers\", \"write_buffers\"]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            PluginManifest::load(&plugin).unwrap().capabilities,
+            vec![Capability::ReadBuffers, Capability::WriteBuffers]
+        );
+
+        std::fs::write(&manifest, "type = \"wasm\"\n").unwrap();
+        assert_eq!(
+            PluginManifest::load(&plugin).unwrap().kind,
+            PluginKind::Wasm
+        );
+
+        std::fs::write(&manifest, "capabilities = [\"everything\"]\n").unwrap();
+        assert!(PluginManifest::load(&plugin).is_err());
+        std::fs::remove_file(manifest).ok();
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        assert!(fake_plugin(PROTOCOL_VERSION).await.is_ok());
+
+        let err = fake_plugin(PROTOCOL_VERSION + 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "speaks protocol version {}, but the editor speaks version {}",
+                PROTOCOL_VERSION + 1,
+                PROTOCOL_VERSION
+            )
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unresponsive_plugin_is_disabled() {
+        // Answers the handshake, then never reads another message
+        let path = write_script(
+            "stuck-plugin",
+            &format!(
+                "read hello\necho '{{\"protocol_version\":{}}}'\nsleep 30\n",
+                PROTOCOL_VERSION
+            ),
+        );
+
+        let mut plugin = Plugin::new(path.clone()).with_timeout(Duration::from_millis(100));
+        let (.., channel) = plugin.get();
+        let run = tokio::spawn(async move { plugin.run().await });
+
+        // Enough to fill the pipe and the write queue
+        let args = "x".repeat(64 * 1024);
+        for _ in 0..WRITE_QUEUE_SIZE + MAX_CONSECUTIVE_TIMEOUTS + 4 {
+            channel
+                .send(CuprumNotification::RunCommand {
+                    name: "stuck".to_string(),
+                    bang: false,
+                    range: None,
+                    args: args.clone(),
+                })
+                .await;
+        }
+
+        timeout(Duration::from_secs(10), channel.closed())
+            .await
+            .expect("the plugin was not disabled");
+        run.await.unwrap().unwrap();
+        std::fs::remove_file(path).ok();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_stdio_transport() {
+        let request = CuprumApiRequest::new(RequestId(0), CuprumApiRequestKind::GetMode {});
+        let output = std::env::temp_dir().join(format!("cuprum-stdio-{}", std::process::id()));
+        let path = write_script(
+            "stdio-plugin",
+            &format!(
+                "read hello\necho '{{\"protocol_version\":{}}}'\necho '{}'\nread response\necho \"$response\" > {}\nsleep 5\n",
+                PROTOCOL_VERSION,
+                serde_json::to_string(&request).unwrap(),
+                output.display()
+            ),
+        );
+
+        let mut plugin = Plugin::new(path.clone());
+        let arcs = plugin.get();
+        let run = tokio::spawn(async move { plugin.run().await });
+
+        let request = answer_request(&arcs, CuprumApiResponseKind::GetMode(Mode::Normal)).await;
+        assert!(matches!(request.kind, CuprumApiRequestKind::GetMode {}));
+
+        let response = timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(response) = std::fs::read_to_string(&output)
+                    && response.ends_with('\n')
+                {
+                    return response;
                 }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("no response");
+        let response: CuprumApiResponse = serde_json::from_str(&response).unwrap();
+        assert_eq!(response.id, RequestId(0));
+        assert!(matches!(
+            response.into_result(),
+            Ok(Some(CuprumApiResponseKind::GetMode(Mode::Normal)))
+        ));
+
+        run.abort();
+        std::fs::remove_file(path).ok();
+        std::fs::remove_file(output).ok();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_socket_transport() {
+        let socket_path =
+            std::env::temp_dir().join(format!("cuprum-test-{}.sock", std::process::id()));
+        std::fs::remove_file(&socket_path).ok();
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let routes = SocketRoutes::default();
+        let (route, connection) = oneshot::channel();
+        routes.lock().await.insert(0, route);
+        tokio::spawn(accept_plugins(listener, routes, Duration::from_secs(5)));
+
+        // The process ignores stdio, and this test connects to the socket in its place
+        let path = write_script("socket-plugin", "sleep 5\n");
+        let mut plugin = Plugin::new(path.clone());
+        plugin.socket = Some(PluginSocket {
+            path: socket_path.clone(),
+            id: 0,
+            connection,
+        });
+        let arcs = plugin.get();
+        let run = tokio::spawn(async move { plugin.run().await });
+
+        let client_path = socket_path.clone();
+        let client = tokio::spawn(async move {
+            let mut api =
+                CuprumApi::new(DefaultCuprumApiProvider::with_socket(client_path, Some(0)));
+            api.get_mode().await
+        });
+        answer_request(&arcs, CuprumApiResponseKind::GetMode(Mode::Visual)).await;
+        assert_eq!(client.await.unwrap(), Ok(Mode::Visual));
+
+        // The provider answers pings itself
+        let (.., channel) = arcs;
+        assert_eq!(
+            channel.request(PluginRequestKind::Ping).await,
+            Ok(PluginResponseKind::Pong)
+        );
+
+        run.abort();
+        std::fs::remove_file(path).ok();
+        std::fs::remove_file(socket_path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_wasm_plugin() {
+        // Built from plugins/wasm-plugin for wasm32-wasip1
+        let dir = std::env::temp_dir().join(format!("cuprum-wasm-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures/wasm_plugin.wasm");
+        std::fs::copy(&fixture, dir.join("wasm-plugin.wasm")).unwrap();
+        std::fs::copy(
+            PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("../../plugins/wasm-plugin/wasm-plugin.toml"),
+            dir.join("wasm-plugin.toml"),
+        )
+        .unwrap();
+        let installed = find_plugins(&dir).unwrap().remove(0);
+        std::fs::remove_dir_all(&dir).ok();
+        assert_eq!(installed.manifest.kind, PluginKind::Wasm);
+
+        let mut plugin = Plugin::new(fixture)
+            .with_kind(installed.manifest.kind)
+            .with_capabilities(&installed.manifest.capabilities);
+        let (requests, request_notify, ..) = plugin.get();
+        let info = plugin.info.clone();
+        let run = tokio::spawn(async move { plugin.run().await });
+
+        timeout(Duration::from_secs(5), request_notify.notified())
+            .await
+            .expect("no request");
+        let request = requests.lock().await.remove(0);
+        assert_eq!(request.version, PROTOCOL_VERSION);
+        assert!(matches!(
+            request.kind,
+            CuprumApiRequestKind::ChangeMode {
+                mode: Mode::Insert(false)
+            }
+        ));
+        assert_eq!(info.lock().await.protocol_version, Some(PROTOCOL_VERSION));
+
+        run.abort();
+    }
+
+    #[tokio::test]
+    async fn test_kill_wasm_plugin() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures/busy-loop.wat");
+        let (editor, plugin) = duplex(1024);
+        let task = wasm::spawn(&path, plugin).unwrap();
+        let (reader, mut writer) = split(editor);
+        writer.write_all(b"{}\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The module drops its end of the stream once it is stopped
+        task.kill();
+        let mut line = String::new();
+        let read = timeout(
+            Duration::from_secs(5),
+            BufReader::new(reader).read_line(&mut line),
+        )
+        .await
+        .expect("the module kept running");
+        assert_eq!(read.unwrap(), 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_disable_and_enable() {
+        let path = write_script(
+            "idle-plugin",
+            &format!(
+                "read hello\necho '{{\"protocol_version\":{}}}'\nsleep 30\n",
+                PROTOCOL_VERSION
+            ),
+        );
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+
+        let mut manager = PluginManager::default();
+        let (.., channel) = manager.add(InstalledPlugin {
+            name: name.clone(),
+            command: path.clone(),
+            manifest: PluginManifest::default(),
+        });
+        manager.run().await.unwrap();
+        let status = |manager: &PluginManager| {
+            let info = manager.plugins[0].info.clone();
+            async move { info.lock().await.status }
+        };
+        assert_eq!(status(&manager).await, PluginStatus::Running);
+
+        let disabled = manager.disable(&name).await.unwrap();
+        assert_eq!(disabled, channel);
+        timeout(Duration::from_secs(1), channel.closed())
+            .await
+            .expect("the plugin did not stop");
+        assert_eq!(status(&manager).await, PluginStatus::Disabled);
+        assert!(manager.disable(&name).await.is_err());
+
+        manager.enable(&name).await.unwrap();
+        assert_eq!(status(&manager).await, PluginStatus::Running);
+        assert!(manager.enable(&name).await.is_err());
+        assert!(manager.enable("missing").await.is_err());
+
+        manager.disable(&name).await.unwrap();
+        std::fs::remove_file(path).ok();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_shutdown() {
+        // One plugin exits once its input is closed while waiting for a response, the
+        // other ignores it and has to be killed
+        let request = CuprumApiRequest::new(RequestId(0), CuprumApiRequestKind::GetMode {});
+        let mut manager = PluginManager::default();
+        let mut plugins = Vec::new();
+        for (name, rest) in [
+            (
+                "polite-plugin",
+                format!(
+                    "echo '{}'\nread response\n",
+                    serde_json::to_string(&request).unwrap()
+                ),
+            ),
+            ("stubborn-plugin", "exec sleep 30\n".to_string()),
+        ] {
+            let pid_file =
+                std::env::temp_dir().join(format!("cuprum-{}-{}.pid", name, std::process::id()));
+            std::fs::remove_file(&pid_file).ok();
+            let path = write_script(
+                name,
+                &format!(
+                    "read hello\necho '{{\"protocol_version\":{}}}'\necho $$ > {}\n{}",
+                    PROTOCOL_VERSION,
+                    pid_file.display(),
+                    rest
+                ),
+            );
+            let arcs = manager.add(InstalledPlugin {
+                name: name.to_string(),
+                command: path.clone(),
+                manifest: PluginManifest::default(),
             });
+            plugins.push((path, pid_file, arcs));
         }
+        manager.run().await.unwrap();
 
-        Ok(())
+        let (requests, request_notify, ..) = &plugins[0].2;
+        timeout(Duration::from_secs(5), request_notify.notified())
+            .await
+            .expect("no request");
+        assert_eq!(requests.lock().await.len(), 1);
+        let mut pids = Vec::new();
+        for (_, pid_file, _) in &plugins {
+            let pid = timeout(Duration::from_secs(5), async {
+                loop {
+                    if let Ok(pid) = std::fs::read_to_string(pid_file)
+                        && pid.ends_with('\n')
+                    {
+                        return pid.trim().to_string();
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("the plugin did not start");
+            pids.push(pid);
+        }
+
+        timeout(Duration::from_secs(5), manager.shutdown())
+            .await
+            .expect("the shutdown did not finish");
+        for (pid, (path, pid_file, arcs)) in pids.iter().zip(plugins) {
+            assert!(!Path::new("/proc").join(pid).exists(), "{}", path.display());
+            timeout(Duration::from_secs(1), arcs.4.closed())
+                .await
+                .expect("the channel was not closed");
+            std::fs::remove_file(path).ok();
+            std::fs::remove_file(pid_file).ok();
+        }
+        assert!(
+            manager
+                .list()
+                .await
+                .iter()
+                .all(|(_, info)| info.status == PluginStatus::Disabled)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_answer_hello() {
+        let (editor, plugin) = duplex(1024);
+        let (plugin_reader, mut plugin_writer) = split(plugin);
+        let plugin = tokio::spawn(async move {
+            api::answer_hello(
+                &mut BufReader::new(plugin_reader),
+                &mut plugin_writer,
+                Some(7),
+            )
+            .await
+        });
+
+        let (editor_reader, mut editor_writer) = split(editor);
+        let reply = handshake(&mut BufReader::new(editor_reader), &mut editor_writer)
+            .await
+            .unwrap();
+        assert_eq!(reply.plugin_id, Some(7));
+        let hello = plugin.await.unwrap().unwrap();
+        assert_eq!(hello.editor_version, env!("CARGO_PKG_VERSION"));
     }
 }
@@ -0,0 +1,138 @@
+//! Plugins compiled to WebAssembly, run inside the editor instead of as a process.
+//!
+//! A module exports its `memory`, `alloc(len) -> ptr` and `handle_message(ptr, len)`.
+//! Each line the editor writes is copied into the memory `alloc` returns and passed to
+//! `handle_message`. The module answers by calling the imported `cuprum.send(ptr, len)`,
+//! one line per call. The lines are the same JSON messages process plugins exchange over
+//! stdio. Besides that, modules get WASI preview 1 without any directories, environment
+//! or stdio, so a plugin built for `wasm32-wasip1` runs but cannot touch files or the
+//! network.
+
+use std::path::Path;
+
+use anyhow::anyhow;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream, WriteHalf, split},
+    runtime::Handle,
+    task::JoinHandle,
+};
+use wasmtime::{Caller, Config, Engine, Linker, Module, Store};
+use wasmtime_wasi::{WasiCtxBuilder, preview1::WasiP1Ctx};
+
+/// What `cuprum.send` writes to
+struct Host {
+    writer: WriteHalf<DuplexStream>,
+    runtime: Handle,
+    wasi: WasiP1Ctx,
+}
+
+/// A module running on a blocking thread
+#[derive(Debug)]
+pub(crate) struct WasmTask {
+    engine: Engine,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+impl WasmTask {
+    /// Wait until the module stops, by itself or by `kill`
+    pub(crate) async fn wait(&mut self) -> anyhow::Result<()> {
+        (&mut self.task).await?
+    }
+
+    /// Stop the module, interrupting it if it is running. Aborting the task alone would
+    /// leave a module stuck in a loop running on its thread.
+    pub(crate) fn kill(&self) {
+        self.engine.increment_epoch();
+        self.task.abort();
+    }
+}
+
+impl Drop for WasmTask {
+    fn drop(&mut self) {
+        self.engine.increment_epoch();
+    }
+}
+
+/// Load the module at `path` and run it on a blocking thread, exchanging lines through
+/// `stream`. It stops when the other end of the stream is dropped.
+pub(crate) fn spawn(path: &Path, stream: DuplexStream) -> anyhow::Result<WasmTask> {
+    let mut config = Config::new();
+    config.epoch_interruption(true);
+    let engine = Engine::new(&config)?;
+    let module = Module::from_file(&engine, path)?;
+    let runtime = Handle::current();
+    let task = tokio::task::spawn_blocking({
+        let engine = engine.clone();
+        move || run(&engine, &module, stream, runtime)
+    });
+    Ok(WasmTask { engine, task })
+}
+
+fn run(
+    engine: &Engine,
+    module: &Module,
+    stream: DuplexStream,
+    runtime: Handle,
+) -> anyhow::Result<()> {
+    let (reader, writer) = split(stream);
+    let mut reader = BufReader::new(reader);
+
+    let mut linker = Linker::new(engine);
+    wasmtime_wasi::preview1::add_to_linker_sync(&mut linker, |host: &mut Host| &mut host.wasi)?;
+    linker.func_wrap(
+        "cuprum",
+        "send",
+        |mut caller: Caller<'_, Host>, ptr: u32, len: u32| -> anyhow::Result<()> {
+            let memory = caller
+                .get_export("memory")
+                .and_then(|export| export.into_memory())
+                .ok_or_else(|| anyhow!("the plugin does not export its memory"))?;
+            let mut line = vec![0; len as usize];
+            memory.read(&caller, ptr as usize, &mut line)?;
+            line.push(b'\n');
+
+            let host = caller.data_mut();
+            host.runtime.block_on(async {
+                host.writer.write_all(&line).await?;
+                host.writer.flush().await
+            })?;
+            Ok(())
+        },
+    )?;
+
+    let runtime_handle = runtime.clone();
+    let wasi = WasiCtxBuilder::new().build_p1();
+    let mut store = Store::new(
+        engine,
+        Host {
+            writer,
+            runtime,
+            wasi,
+        },
+    );
+    // The epoch only moves on `kill`, which traps whatever the module is running
+    store.set_epoch_deadline(1);
+    let instance = linker.instantiate(&mut store, module)?;
+    // WASI reactors set themselves up in `_initialize`
+    if let Ok(initialize) = instance.get_typed_func::<(), ()>(&mut store, "_initialize") {
+        initialize.call(&mut store, ())?;
+    }
+    let memory = instance
+        .get_memory(&mut store, "memory")
+        .ok_or_else(|| anyhow!("the plugin does not export its memory"))?;
+    let alloc = instance.get_typed_func::<u32, u32>(&mut store, "alloc")?;
+    let handle_message = instance.get_typed_func::<(u32, u32), ()>(&mut store, "handle_message")?;
+
+    loop {
+        let mut line = String::new();
+        if runtime_handle.block_on(reader.read_line(&mut line))? == 0 {
+            return Ok(());
+        }
+        let message = line.trim_end_matches('\n').as_bytes();
+        let len = u32::try_from(message.len())?;
+
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, ptr as usize, message)?;
+        handle_message.call(&mut store, (ptr, len))?;
+    }
+}
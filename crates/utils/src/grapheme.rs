@@ -0,0 +1,140 @@
+//! Grapheme clusters of a line, as char indices.
+//!
+//! The cursor counts chars, but stops only at the start of a cluster, so a character
+//! made of several chars, such as an emoji sequence or a letter with a combining
+//! accent, is moved over and deleted as one.
+
+use unicode_segmentation::{GraphemeCursor, UnicodeSegmentation};
+use unicode_width::UnicodeWidthStr;
+
+/// Byte index of char `x`, if the line has one
+fn get_byte(line: &str, x: usize) -> Option<usize> {
+    // Chars are bytes in ASCII, and there are never more chars than bytes
+    if x >= line.len() || line.is_ascii() {
+        return (x < line.len()).then_some(x);
+    }
+    line.char_indices().nth(x).map(|(i, _)| i)
+}
+
+/// Char index of byte `i`
+fn get_char(line: &str, i: usize) -> usize {
+    line[..i].chars().count()
+}
+
+/// The start of the cluster containing byte `i`. Only the text around it is looked at, so
+/// this stays cheap on long lines.
+fn floor_byte(line: &str, i: usize) -> usize {
+    let mut cursor = GraphemeCursor::new(i, line.len(), true);
+    if cursor.is_boundary(line, 0).unwrap_or(true) {
+        return i;
+    }
+    cursor.prev_boundary(line, 0).ok().flatten().unwrap_or(0)
+}
+
+/// The start of the cluster containing char `x`, or the line length past the end
+pub fn floor(line: &str, x: usize) -> usize {
+    match get_byte(line, x) {
+        Some(i) => x - line[floor_byte(line, i)..i].chars().count(),
+        None => line.chars().count(),
+    }
+}
+
+/// The start of the cluster containing char `x`, or of the last one past the end
+pub fn clamp(line: &str, x: usize) -> usize {
+    match get_byte(line, x) {
+        Some(_) => floor(line, x),
+        None => last(line),
+    }
+}
+
+/// The start of the cluster before char `x`
+pub fn prev(line: &str, x: usize) -> usize {
+    let i = get_byte(line, x).unwrap_or(line.len());
+    let mut cursor = GraphemeCursor::new(i, line.len(), true);
+    match cursor.prev_boundary(line, 0).ok().flatten() {
+        Some(prev) => get_char(line, prev),
+        None => 0,
+    }
+}
+
+/// The start of the cluster after the one at char `x`, or the line length
+pub fn next(line: &str, x: usize) -> usize {
+    next_start(line, x).unwrap_or_else(|| line.chars().count())
+}
+
+/// The start of the cluster after the one at char `x`, if there is one
+pub fn next_start(line: &str, x: usize) -> Option<usize> {
+    let i = get_byte(line, x)?;
+    let mut cursor = GraphemeCursor::new(i, line.len(), true);
+    let next = cursor.next_boundary(line, 0).ok().flatten()?;
+    (next < line.len()).then(|| x + get_char(&line[i..], next - i))
+}
+
+/// The start of the last cluster
+pub fn last(line: &str) -> usize {
+    let mut cursor = GraphemeCursor::new(line.len(), line.len(), true);
+    match cursor.prev_boundary(line, 0).ok().flatten() {
+        Some(last) => get_char(line, last),
+        None => 0,
+    }
+}
+
+/// The display column char `x` starts at, where wide characters take two columns and a
+/// tab reaches the next multiple of `tabstop`
+pub fn display_col(line: &str, x: usize, tabstop: usize) -> usize {
+    let tabstop = tabstop.max(1);
+    let mut col = 0;
+    let mut chars = 0;
+    for cluster in line.graphemes(true) {
+        if chars >= x {
+            break;
+        }
+        chars += cluster.chars().count();
+        col += match cluster {
+            "\t" => tabstop - col % tabstop,
+            _ => cluster.width(),
+        };
+    }
+    col
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clusters() {
+        // Precomposed, decomposed, a ZWJ family and a combining accent
+        let line = "が\u{304b}\u{3099}👨\u{200d}👩\u{200d}👧e\u{301}";
+        assert_eq!(next(line, 0), 1);
+        assert_eq!(next(line, 1), 3);
+        assert_eq!(next(line, 3), 8);
+        assert_eq!(next(line, 8), 10);
+        assert_eq!(prev(line, 10), 8);
+        assert_eq!(prev(line, 8), 3);
+        assert_eq!(prev(line, 3), 1);
+        assert_eq!(prev(line, 0), 0);
+        assert_eq!(floor(line, 2), 1);
+        assert_eq!(floor(line, 6), 3);
+        assert_eq!(floor(line, 12), 10);
+        assert_eq!(clamp(line, 9), 8);
+        assert_eq!(clamp(line, 12), 8);
+        assert_eq!(clamp("", 3), 0);
+        assert_eq!(next_start(line, 8), None);
+        assert_eq!(last(line), 8);
+        assert_eq!(last(""), 0);
+    }
+
+    #[test]
+    fn test_display_col() {
+        assert_eq!(display_col("abc", 2, 4), 2);
+        assert_eq!(display_col("\tx\ty", 1, 4), 4);
+        assert_eq!(display_col("\tx\ty", 3, 4), 8);
+        assert_eq!(display_col("x\t", 2, 8), 8);
+        // Wide characters and clusters of several chars
+        assert_eq!(display_col("日本\t", 2, 8), 4);
+        assert_eq!(display_col("日本\t", 3, 8), 8);
+        assert_eq!(display_col("e\u{301}x", 2, 4), 1);
+        assert_eq!(display_col("ab", 5, 4), 2);
+    }
+}
@@ -1,2 +1,4 @@
+pub mod grapheme;
+pub mod hash;
 pub mod term;
 pub mod vec2;
@@ -6,3 +6,8 @@ pub fn get_terminal_size() -> anyhow::Result<UVec2> {
     let (w, h) = terminal::size()?;
     Ok(UVec2::new(w as usize, h as usize))
 }
+
+/// The terminal size, or 80x24 when not attached to a terminal
+pub fn get_terminal_size_or_default() -> UVec2 {
+    get_terminal_size().unwrap_or(UVec2::new(80, 24))
+}
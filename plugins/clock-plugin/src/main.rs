@@ -0,0 +1,28 @@
+use std::time::Duration;
+
+use api::{CuprumApi, DefaultCuprumApiProvider, Popup};
+use chrono::Local;
+use tokio::time::sleep;
+use utils::vec2::UVec2;
+
+fn clock_popup() -> Popup {
+    Popup {
+        lines: vec![Local::now().format(" %H:%M:%S").to_string()],
+        position: UVec2::new(1, 1),
+        size: UVec2::new(12, 3),
+        border: true,
+        title: Some("Clock".to_string()),
+        dismissable: false,
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let mut api = CuprumApi::new(DefaultCuprumApiProvider::new());
+    let id = api.open_popup(clock_popup()).await?;
+
+    loop {
+        sleep(Duration::from_secs(1)).await;
+        api.update_popup(id, clock_popup()).await?;
+    }
+}
@@ -0,0 +1,91 @@
+use api::{
+    CompletionItem, CuprumApi, CuprumApiError, DefaultCuprumApiProvider, PluginRequestKind,
+    PluginResponseKind,
+};
+
+/// Where most Unix systems keep a word list
+const WORDS_PATH: &str = "/usr/share/dict/words";
+/// Used when there is no word list
+const FALLBACK_WORDS: &[&str] = &[
+    "about",
+    "above",
+    "across",
+    "after",
+    "again",
+    "against",
+    "always",
+    "another",
+    "because",
+    "before",
+    "between",
+    "change",
+    "different",
+    "during",
+    "enough",
+    "example",
+    "follow",
+    "however",
+    "important",
+    "little",
+    "number",
+    "people",
+    "please",
+    "problem",
+    "question",
+    "really",
+    "something",
+    "sometimes",
+    "through",
+    "together",
+    "without",
+    "world",
+];
+const MAX_ITEMS: usize = 20;
+
+fn load_words() -> Vec<String> {
+    match std::fs::read_to_string(WORDS_PATH) {
+        Ok(words) => words.lines().map(|word| word.to_string()).collect(),
+        Err(_) => FALLBACK_WORDS.iter().map(|word| word.to_string()).collect(),
+    }
+}
+
+/// Words that start with `prefix` and are longer than it
+fn complete(words: &[String], prefix: &str) -> Vec<CompletionItem> {
+    if prefix.is_empty() {
+        return Vec::new();
+    }
+
+    words
+        .iter()
+        .filter(|word| word.len() > prefix.len() && word.starts_with(prefix))
+        .take(MAX_ITEMS)
+        .map(|word| CompletionItem {
+            label: word.clone(),
+            insert_text: word.clone(),
+            detail: None,
+        })
+        .collect()
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let words = load_words();
+    let mut api = CuprumApi::new(DefaultCuprumApiProvider::new());
+    api.register_completion_source("dict".to_string(), Vec::new())
+        .await?;
+
+    let provider = api.provider.clone();
+    loop {
+        let request = provider.next_request().await;
+        let result = match request.request {
+            PluginRequestKind::Complete { prefix, .. } => {
+                Ok(PluginResponseKind::Completions(complete(&words, &prefix)))
+            }
+            request => Err(CuprumApiError::InvalidArgument(format!(
+                "unexpected request {:?}",
+                request
+            ))),
+        };
+        provider.respond(request.request_id, result).await;
+    }
+}
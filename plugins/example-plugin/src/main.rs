@@ -1,9 +1,77 @@
-use api::{CuprumApi, DefaultCuprumApiProvider, Mode};
+use api::{
+    BufferInfo, CuprumApi, CuprumApiError, CuprumNotification, DefaultCuprumApiProvider, Mode,
+    Popup,
+};
+use chrono::Local;
+use utils::vec2::UVec2;
+
+const GREETING: &str = "Hello from example-plugin!";
+
+fn buffer_info_popup(info: &BufferInfo) -> Popup {
+    let lines = vec![
+        format!(" name:  {}", info.name),
+        format!(" path:  {}", info.path.as_deref().unwrap_or("-")),
+        format!(" lines: {}", info.line_count),
+        format!(" dirty: {}", info.dirty),
+        format!(" read-only: {}", info.read_only),
+    ];
+    let width = lines.iter().map(|line| line.len()).max().unwrap_or(0) + 3;
+
+    Popup {
+        size: UVec2::new(width, lines.len() + 2),
+        lines,
+        position: UVec2::new(1, 1),
+        border: true,
+        title: Some("Buffer".to_string()),
+        dismissable: true,
+    }
+}
+
+/// Insert text at the cursor of the active window
+async fn insert_at_cursor(
+    api: &mut CuprumApi<DefaultCuprumApiProvider>,
+    text: &str,
+) -> Result<(), CuprumApiError> {
+    let cursor = api.get_cursor(None).await?;
+    let line = api.get_line(None, cursor.y).await?;
+
+    let mut chars: Vec<char> = line.chars().collect();
+    let x = cursor.x.min(chars.len());
+    chars.splice(x..x, text.chars());
+
+    api.replace_line(None, cursor.y, chars.into_iter().collect())
+        .await?;
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let mut api = CuprumApi::new(DefaultCuprumApiProvider::new());
-    api.change_mode(Mode::Insert(false)).await?;
+    api.register_command("hello".to_string()).await?;
+    api.register_command("bufinfo".to_string()).await?;
+    api.register_command("greet".to_string()).await?;
+    api.register_keymap(Mode::Normal, "<leader>d".to_string(), "date".to_string())
+        .await?;
 
-    Ok(())
+    loop {
+        match api.provider.next_notification().await {
+            CuprumNotification::RunCommand { name, .. } if name == "hello" => {
+                insert_at_cursor(&mut api, GREETING).await?;
+            }
+            CuprumNotification::RunCommand { name, .. } if name == "greet" => {
+                if let Some(name) = api.prompt_input("Name: ".to_string()).await? {
+                    insert_at_cursor(&mut api, &format!("Hello, {}!", name)).await?;
+                }
+            }
+            CuprumNotification::Keymap { name } if name == "date" => {
+                let date = Local::now().format("%Y-%m-%d").to_string();
+                insert_at_cursor(&mut api, &date).await?;
+            }
+            CuprumNotification::RunCommand { name, .. } if name == "bufinfo" => {
+                let info = api.get_buffer_info(None).await?;
+                api.open_popup(buffer_info_popup(&info)).await?;
+            }
+            _ => {}
+        }
+    }
 }
@@ -0,0 +1,61 @@
+use api::{
+    CuprumApi, CuprumApiError, DefaultCuprumApiProvider, EventKind, PluginRequestKind,
+    PluginResponseKind, RangeEdit, TextRange,
+};
+use utils::vec2::UVec2;
+
+/// Edits removing trailing whitespace and blank lines at the end of the buffer
+fn format(content: &str) -> Vec<RangeEdit> {
+    let lines: Vec<&str> = content.split('\n').collect();
+    let remove = |start: UVec2, end: UVec2| RangeEdit {
+        range: TextRange { start, end },
+        text: String::new(),
+    };
+
+    // One blank line is kept at the end, the ones after it go
+    let mut kept = lines.len();
+    while kept > 1 && lines[kept - 1].trim_end().is_empty() && lines[kept - 2].trim_end().is_empty()
+    {
+        kept -= 1;
+    }
+
+    let mut edits: Vec<RangeEdit> = lines[..kept]
+        .iter()
+        .enumerate()
+        .filter_map(|(y, line)| {
+            let len = line.chars().count();
+            let trimmed = line.trim_end().chars().count();
+            (trimmed < len).then(|| remove(UVec2::new(trimmed, y), UVec2::new(len, y)))
+        })
+        .collect();
+    if kept < lines.len() {
+        // The last kept line is blank, and was emptied above
+        let last = lines.len() - 1;
+        edits.push(remove(
+            UVec2::new(lines[kept - 1].chars().count(), kept - 1),
+            UVec2::new(lines[last].chars().count(), last),
+        ));
+    }
+    edits
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let mut api = CuprumApi::new(DefaultCuprumApiProvider::new());
+    api.subscribe(vec![EventKind::BufWritePre]).await?;
+
+    let provider = api.provider.clone();
+    loop {
+        let request = provider.next_request().await;
+        let result = match request.request {
+            PluginRequestKind::BufWritePre { content, .. } => {
+                Ok(PluginResponseKind::Edits(format(&content)))
+            }
+            request => Err(CuprumApiError::InvalidArgument(format!(
+                "unexpected request {:?}",
+                request
+            ))),
+        };
+        provider.respond(request.request_id, result).await;
+    }
+}
@@ -0,0 +1,69 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use api::{
+    BufferId, CuprumApi, CuprumApiError, CuprumNotification, DefaultCuprumApiProvider, EditorEvent,
+    EventKind, StatusAlign,
+};
+
+const SEGMENT_ID: &str = "git-branch";
+
+/// Read the branch name from the `.git/HEAD` of `dir` or one of its parents
+fn find_branch(dir: &Path) -> Option<String> {
+    let head = dir
+        .ancestors()
+        .map(|dir| dir.join(".git/HEAD"))
+        .find(|head| head.is_file())?;
+    let head = fs::read_to_string(head).ok()?;
+
+    match head.trim().strip_prefix("ref: refs/heads/") {
+        Some(branch) => Some(branch.to_string()),
+        // Detached HEAD
+        None => Some(head.trim().chars().take(7).collect()),
+    }
+}
+
+async fn update_segment(
+    api: &mut CuprumApi<DefaultCuprumApiProvider>,
+    buf: Option<BufferId>,
+) -> Result<(), CuprumApiError> {
+    let info = api.get_buffer_info(buf).await?;
+    let dir = match info.path {
+        Some(path) => PathBuf::from(path)
+            .canonicalize()
+            .ok()
+            .and_then(|path| path.parent().map(Path::to_path_buf)),
+        None => env::current_dir().ok(),
+    };
+
+    match dir.and_then(|dir| find_branch(&dir)) {
+        Some(branch) => {
+            api.set_statusline_segment(SEGMENT_ID.to_string(), branch, StatusAlign::Right)
+                .await
+        }
+        None => api
+            .remove_statusline_segment(SEGMENT_ID.to_string())
+            .await
+            .or_else(|err| match err {
+                CuprumApiError::NotFound(_) => Ok(()),
+                err => Err(err),
+            }),
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let mut api = CuprumApi::new(DefaultCuprumApiProvider::new());
+    api.subscribe(vec![EventKind::BufEnter]).await?;
+    update_segment(&mut api, None).await?;
+
+    loop {
+        if let CuprumNotification::Event(EditorEvent::BufEnter { buf }) =
+            api.provider.next_notification().await
+        {
+            update_segment(&mut api, Some(buf)).await?;
+        }
+    }
+}
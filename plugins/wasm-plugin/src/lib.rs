@@ -0,0 +1,56 @@
+//! A plugin compiled to WebAssembly that asks the editor to enter insert mode once it is
+//! loaded.
+//!
+//! The editor copies each line it sends into memory from `alloc` and passes it to
+//! `handle_message`. The plugin answers through the imported `cuprum.send`, one line per
+//! call. The lines are the JSON messages process plugins exchange over stdio.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde_json::{Value, json};
+
+/// `api::PROTOCOL_VERSION`, which the plugin has to be rebuilt for when it changes
+const PROTOCOL_VERSION: u32 = 17;
+
+#[link(wasm_import_module = "cuprum")]
+unsafe extern "C" {
+    #[link_name = "send"]
+    fn cuprum_send(ptr: *const u8, len: usize);
+}
+
+static GREETED: AtomicBool = AtomicBool::new(false);
+
+fn send(message: &Value) {
+    let line = message.to_string();
+    unsafe { cuprum_send(line.as_ptr(), line.len()) }
+}
+
+/// Space for a message of `len` bytes, freed by `handle_message`
+#[unsafe(no_mangle)]
+pub extern "C" fn alloc(len: usize) -> *mut u8 {
+    let mut buf = Vec::<u8>::with_capacity(len);
+    let ptr = buf.as_mut_ptr();
+    std::mem::forget(buf);
+    ptr
+}
+
+/// # Safety
+///
+/// `ptr` comes from `alloc(len)` and holds the `len` bytes of one message.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn handle_message(ptr: *mut u8, len: usize) {
+    let message = unsafe { Vec::from_raw_parts(ptr, len, len) };
+    let Ok(message) = serde_json::from_slice::<Value>(&message) else {
+        return;
+    };
+
+    // The editor's `Hello`; responses to the request are ignored
+    if message.get("protocol_version").is_some() && !GREETED.swap(true, Ordering::Relaxed) {
+        send(&json!({ "protocol_version": PROTOCOL_VERSION }));
+        send(&json!({
+            "version": PROTOCOL_VERSION,
+            "id": 0,
+            "kind": { "change_mode": { "mode": { "Insert": false } } },
+        }));
+    }
+}
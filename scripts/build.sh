@@ -5,11 +5,22 @@ plugins=$(ls plugins)
 copy_plugin() {
   name=$1
   cp target/debug/$name ~/.cuprum/debug/plugins
+  # Plugins without a manifest may only read buffers
+  if [ -f plugins/$name/$name.toml ]; then
+    cp plugins/$name/$name.toml ~/.cuprum/debug/plugins
+  fi
 }
 
 cargo build --all
+cargo build --release --target wasm32-wasip1 --manifest-path plugins/wasm-plugin/Cargo.toml
 
 mkdir -p ~/.cuprum/debug/plugins
-for plugin in "$plugins"; do
-  copy_plugin $plugin
+for plugin in $plugins; do
+  if [ "$plugin" != wasm-plugin ]; then
+    copy_plugin $plugin
+  fi
 done
+# Named after its manifest, which says it is a WASM plugin
+cp plugins/wasm-plugin/target/wasm32-wasip1/release/wasm_plugin.wasm \
+  ~/.cuprum/debug/plugins/wasm-plugin.wasm
+cp plugins/wasm-plugin/wasm-plugin.toml ~/.cuprum/debug/plugins
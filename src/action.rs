@@ -1,7 +1,223 @@
+use api::{Mode, Position};
 use builtin::BuiltinAction;
+use plugin_manager::PluginChannel;
+use utils::vec2::IVec2;
+
+use crate::{
+    buffer::undo::UndoAmount,
+    sort::SortOptions,
+    ui::input::{Key, MapMode},
+};
+
+/// What `:plugin` does
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PluginAction {
+    List,
+    Enable(String),
+    Disable(String),
+}
+
+/// Which diagnostic `:ll`, `:lnext` and `:lprevious` go to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocationJump {
+    /// The one at this index of the location list
+    Nth(usize),
+    /// The first after the cursor, or the last before it if false
+    Next(bool),
+}
 
 #[derive(Debug, Clone)]
 pub enum Action {
-    Quit,
+    /// Close the active window, quitting if it is the last one.
+    /// `true` quits even with unsaved changes.
+    Quit(bool),
+    /// Quit, even with unsaved changes if `true`
+    QuitAll(bool),
+    /// Save the buffer, then quit
+    WriteQuit,
+    /// Delete an inclusive range of lines into the unnamed register
+    DeleteLines(usize, usize),
+    /// Yank an inclusive range of lines into the unnamed register
+    YankLines(usize, usize),
+    /// Put the unnamed register after the cursor, or before it if false
+    Paste(bool),
+    /// Apply `:set` arguments
+    SetOptions(String),
+    /// Run a shell command and show its output
+    Shell(String),
+    /// Insert the lines of a file before the given line
+    ReadFile(usize, String),
+    /// Insert the output of a shell command before the given line
+    ReadShell(usize, String),
+    /// Replace an inclusive range of lines with their output through a shell command
+    Filter(usize, usize, String),
+    /// Sort an inclusive range of lines
+    Sort(usize, usize, SortOptions),
+    /// Replace `pattern` in an inclusive range of lines, every match if `all`
+    Substitute {
+        start: usize,
+        end: usize,
+        pattern: String,
+        replacement: String,
+        all: bool,
+    },
+    /// Run a command on each line in the range that matches (or does not match) a pattern
+    Global {
+        start: usize,
+        end: usize,
+        pattern: String,
+        invert: bool,
+        command: String,
+    },
+    /// Move the cursor a window height down, or up if false
+    Page(bool),
+    /// Start insert mode completion, or move the selection by the offset
+    Complete(isize),
+    /// `<C-w>` in insert mode
+    DeleteWordBack,
+    /// `<C-u>` in insert mode
+    DeleteToLineStart,
+    /// Open the search prompt
+    StartSearch,
+    /// Jump to the next match of the last search, or the previous one if false
+    SearchNext(bool),
+    /// Hide search highlights
+    NoHighlight,
+    /// List the diagnostics of the active buffer
+    LocationList,
+    /// Move the cursor to a diagnostic of the active buffer
+    JumpToLocation(LocationJump),
+    /// Go to the window of the next file, or the previous one if false
+    NextBuffer(bool),
+    /// Open the help, optionally at a topic
+    Help(Option<String>),
+    /// Show the message history
+    Messages,
+    /// `:earlier` (false) and `:later` (true)
+    TimeTravel(UndoAmount, bool),
+    /// Add a mapping from `:map` and its variants
+    Map {
+        modes: Vec<MapMode>,
+        lhs: Key,
+        rhs: String,
+        noremap: bool,
+    },
+    /// Remove a mapping
+    Unmap {
+        modes: Vec<MapMode>,
+        lhs: Key,
+    },
+    /// Replay keys as if typed, looking up user mappings if the flag is set
+    Feed(Key, bool),
     Builtin(BuiltinAction),
+    /// Tell a plugin that one of its mappings was typed
+    PluginKeymap(PluginChannel, String),
+    Plugin(PluginAction),
+}
+
+/// Actions that can be mapped by name, such as `save` or `move_to_line_end`
+fn named_actions() -> Vec<(&'static str, Action)> {
+    let builtin = Action::Builtin;
+    vec![
+        ("move_left", builtin(BuiltinAction::MoveBy(IVec2::left()))),
+        ("move_down", builtin(BuiltinAction::MoveBy(IVec2::down()))),
+        ("move_up", builtin(BuiltinAction::MoveBy(IVec2::up()))),
+        ("move_right", builtin(BuiltinAction::MoveBy(IVec2::right()))),
+        (
+            "move_to_line_start",
+            builtin(BuiltinAction::MoveToX(Position::Start)),
+        ),
+        (
+            "move_to_line_end",
+            builtin(BuiltinAction::MoveToX(Position::End)),
+        ),
+        (
+            "move_to_first_line",
+            builtin(BuiltinAction::MoveToY(Position::Start)),
+        ),
+        (
+            "move_to_last_line",
+            builtin(BuiltinAction::MoveToY(Position::End)),
+        ),
+        ("page_up", Action::Page(false)),
+        ("page_down", Action::Page(true)),
+        (
+            "normal_mode",
+            builtin(BuiltinAction::ChangeMode(Mode::Normal)),
+        ),
+        (
+            "insert",
+            builtin(BuiltinAction::ChangeMode(Mode::Insert(false))),
+        ),
+        (
+            "append",
+            builtin(BuiltinAction::ChangeMode(Mode::Insert(true))),
+        ),
+        ("insert_line_start", builtin(BuiltinAction::InsertLineStart)),
+        ("append_line_end", builtin(BuiltinAction::AppendLineEnd)),
+        ("open_line_below", builtin(BuiltinAction::OpenLineBelow)),
+        ("open_line_above", builtin(BuiltinAction::OpenLineAbove)),
+        (
+            "visual_mode",
+            builtin(BuiltinAction::ChangeMode(Mode::Visual)),
+        ),
+        (
+            "command_mode",
+            builtin(BuiltinAction::ChangeMode(Mode::Command)),
+        ),
+        ("remove_char", builtin(BuiltinAction::RemoveChar)),
+        ("remove_line", builtin(BuiltinAction::RemoveLine)),
+        ("remove_selection", builtin(BuiltinAction::RemoveSelection)),
+        ("paste_after", Action::Paste(true)),
+        ("paste_before", Action::Paste(false)),
+        ("save", builtin(BuiltinAction::Save)),
+        ("quit", Action::Quit(false)),
+        ("write_quit", Action::WriteQuit),
+        ("search", Action::StartSearch),
+        ("search_next", Action::SearchNext(true)),
+        ("search_prev", Action::SearchNext(false)),
+        ("no_highlight", Action::NoHighlight),
+        ("complete_next", Action::Complete(1)),
+        ("complete_prev", Action::Complete(-1)),
+        ("delete_word_back", Action::DeleteWordBack),
+        ("delete_to_line_start", Action::DeleteToLineStart),
+        ("messages", Action::Messages),
+        ("help", Action::Help(None)),
+    ]
+}
+
+/// Find an action by its name
+pub fn find_action(name: &str) -> Option<Action> {
+    named_actions()
+        .into_iter()
+        .find(|(action_name, _)| *action_name == name)
+        .map(|(_, action)| action)
+}
+
+/// Whether a right-hand side that names no action was still meant as one: words of
+/// lowercase letters joined by `_`, like `remove_lines`, rather than keys like `dd`
+pub fn looks_like_action_name(rhs: &str) -> bool {
+    let mut words = rhs.split('_');
+    rhs.contains('_')
+        && words.all(|word| word.len() > 1 && word.bytes().all(|b| b.is_ascii_lowercase()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_action_names() {
+        assert!(matches!(
+            find_action("move_to_line_end"),
+            Some(Action::Builtin(BuiltinAction::MoveToX(Position::End)))
+        ));
+        assert!(find_action("remove_lines").is_none());
+        // Keys to replay rather than names
+        for keys in ["dd", "yyp", "gg", "d_", ":w<CR>", "x"] {
+            assert!(find_action(keys).is_none(), "{}", keys);
+            assert!(!looks_like_action_name(keys), "{}", keys);
+        }
+        assert!(looks_like_action_name("remove_lines"));
+    }
 }
@@ -26,10 +26,11 @@ impl EditorFile {
     pub fn write(&mut self, content: String) -> anyhow::Result<()> {
         self.file.seek(std::io::SeekFrom::Start(0))?;
         self.file.write_all(content.as_bytes())?;
+        // Drop what is left of longer content written before
+        self.file.set_len(content.len() as u64)?;
         Ok(())
     }
 
-    #[allow(dead_code)] // TODO
     pub fn get_path(&self) -> &Path {
         &self.path
     }
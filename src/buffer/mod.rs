@@ -1,46 +1,176 @@
 pub mod file;
-
-use std::path::PathBuf;
-
-use utils::vec2::UVec2;
-
-use crate::buffer::file::EditorFile;
+pub mod undo;
+
+use std::{
+    cell::Cell,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use api::{Diagnostic, RangeEdit, TextEdit, TextRange};
+use utils::{grapheme, vec2::UVec2};
+
+use crate::{
+    buffer::{
+        file::EditorFile,
+        undo::{UndoAmount, UndoHistory},
+    },
+    filetype,
+    syntax::{self, SyntaxCache},
+};
+
+/// A change of the content, see `EditorEvent::BufferChanged`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BufferChange {
+    pub revision: u64,
+    pub start: usize,
+    pub old_end: usize,
+    pub new_text: String,
+}
 
 #[derive(Debug)]
 pub struct Buffer {
     file: Option<EditorFile>,
     content: Vec<String>,
     dirty: bool,
+    touched: bool,
+    readonly: bool,
+    undo: UndoHistory,
+    /// Incremented on every change, to tell if an undo state is needed
+    changes: usize,
+    recorded_changes: usize,
+    revision: u64,
+    /// Changes not yet sent to plugins
+    pending_changes: Vec<BufferChange>,
+    last_change: Instant,
+    /// Published by plugins, replaced as a whole
+    diagnostics: Vec<Diagnostic>,
+    /// Whether the content ends with a line break. It terminates the last line rather
+    /// than starting another one.
+    end_of_line: bool,
+    /// The line whose start offset was looked up last, with that offset. Lines around the
+    /// cursor are looked up over and over, so they are not summed from the top each time.
+    line_offset_hint: Cell<(usize, usize)>,
+    /// The lines changed since `take_edited_lines`
+    edited_lines: Option<Range<usize>>,
+    /// Detected when the file is opened, or set with `:set filetype`
+    filetype: Option<String>,
+    /// Highlighted for the filetype, up to the lines shown so far
+    syntax: SyntaxCache,
 }
 
 impl Buffer {
     pub fn open(path: PathBuf) -> anyhow::Result<Self> {
         let mut file = EditorFile::open(path)?;
 
-        let content = file.read()?;
-        let content = content
-            .split("\n")
-            .map(|line| line.chars().collect())
-            .collect();
-
+        let (content, end_of_line) = split_lines(&file.read()?);
+        let filetype = filetype::detect(file.get_path(), &content[0]);
         Ok(Self {
             file: Some(file),
+            filetype,
+            undo: UndoHistory::new(content.clone()),
             content,
+            end_of_line,
             ..Default::default()
         })
     }
 
     pub fn save(&mut self) -> anyhow::Result<()> {
         let content = self.get_content();
-        if let Some(file) = &mut self.file {
-            file.write(content)?;
-            self.dirty = false;
-        }
+        let Some(file) = &mut self.file else {
+            bail!("No file name");
+        };
+        file.write(content)?;
+        self.dirty = false;
         Ok(())
     }
 
-    pub fn mark_dirty(&mut self) {
-        self.dirty = true;
+    /// Create a read-only buffer without a file
+    pub fn read_only(lines: Vec<String>) -> Self {
+        Self {
+            undo: UndoHistory::new(lines.clone()),
+            content: lines,
+            readonly: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_readonly(&self) -> bool {
+        self.readonly
+    }
+
+    /// Record the content as an undo state if it changed since the last one
+    pub fn checkpoint(&mut self) {
+        if self.readonly || self.changes == self.recorded_changes {
+            return;
+        }
+        self.undo.push(self.content.clone(), Instant::now());
+        self.recorded_changes = self.changes;
+    }
+
+    /// `:earlier` and `:later`. Returns false if there is no state to move to.
+    pub fn time_travel(&mut self, amount: UndoAmount, forward: bool) -> bool {
+        self.checkpoint();
+        let Some(lines) = self.undo.travel(amount, forward) else {
+            return false;
+        };
+        let old_end = self.get_char_count();
+        self.content = lines;
+        self.edit(0, old_end, self.get_content());
+        self.recorded_changes = self.changes;
+        true
+    }
+
+    /// Whether there are unsaved changes
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Whether the buffer has ever been modified (unlike `dirty`, saving does not reset this)
+    pub fn is_touched(&self) -> bool {
+        self.touched
+    }
+
+    /// Whether the buffer is not associated with a file
+    pub fn is_scratch(&self) -> bool {
+        self.file.is_none()
+    }
+
+    pub fn get_path(&self) -> Option<&Path> {
+        self.file.as_ref().map(|file| file.get_path())
+    }
+
+    /// The file name, or `[No Name]`
+    pub fn get_name(&self) -> String {
+        self.get_path()
+            .and_then(|path| path.file_name())
+            .map_or("[No Name]".to_string(), |name| {
+                name.to_string_lossy().to_string()
+            })
+    }
+
+    pub fn get_filetype(&self) -> Option<&str> {
+        self.filetype.as_deref()
+    }
+
+    pub fn set_filetype(&mut self, filetype: Option<String>) {
+        if filetype != self.filetype {
+            self.syntax = SyntaxCache::default();
+        }
+        self.filetype = filetype;
+    }
+
+    /// Bring the syntax highlighting of the lines before `end` up to date with the edits
+    /// made since the last call. `None` if the filetype has no highlighter.
+    pub fn highlight_syntax(&mut self, end: usize) -> Option<&SyntaxCache> {
+        let highlighter = syntax::get_highlighter(self.filetype.as_deref()?)?;
+        if let Some(edited) = self.take_edited_lines() {
+            self.syntax.edit(edited, self.content.len());
+        }
+        self.syntax.highlight(highlighter, &self.content, end);
+        Some(&self.syntax)
     }
 
     pub fn get_line_count(&self) -> usize {
@@ -55,14 +185,191 @@ impl Buffer {
         self.content.clone()
     }
 
+    /// Up to `count` lines from line `start`, without copying them
+    pub fn get_lines_range(&self, start: usize, count: usize) -> &[String] {
+        let start = start.min(self.content.len());
+        let end = start.saturating_add(count).min(self.content.len());
+        &self.content[start..end]
+    }
+
+    /// Number of characters of the content, counting line breaks
+    pub fn get_char_count(&self) -> usize {
+        self.get_lines_end() + usize::from(self.end_of_line)
+    }
+
+    /// Character offset of the end of the last line
+    fn get_lines_end(&self) -> usize {
+        self.get_line_offset(self.content.len()).saturating_sub(1)
+    }
+
+    /// Character offset of the start of line `y` in the content
+    fn get_line_offset(&self, y: usize) -> usize {
+        let sum =
+            |lines: &[String]| -> usize { lines.iter().map(|line| line.chars().count() + 1).sum() };
+        let (hint_y, hint) = self.line_offset_hint.get();
+        let offset = if hint_y > self.content.len() {
+            sum(&self.content[..y])
+        } else if y >= hint_y {
+            hint + sum(&self.content[hint_y..y])
+        } else if hint_y - y <= y {
+            hint - sum(&self.content[y..hint_y])
+        } else {
+            sum(&self.content[..y])
+        };
+        self.line_offset_hint.set((y, offset));
+        offset
+    }
+
+    /// The line that character `offset` of the content is on
+    fn get_line_at(&self, offset: usize) -> usize {
+        let (mut y, mut start) = self.line_offset_hint.get();
+        if start > offset || y > self.content.len() {
+            (y, start) = (0, 0);
+        }
+        while y + 1 < self.content.len() {
+            let next = start + self.content[y].chars().count() + 1;
+            if next > offset {
+                break;
+            }
+            (y, start) = (y + 1, next);
+        }
+        y
+    }
+
+    /// Character offset of a position in the content
+    pub fn get_char_offset(&self, pos: UVec2) -> usize {
+        self.get_line_offset(pos.y) + pos.x
+    }
+
+    /// Character offsets of a range, failing if either end is outside the content
+    pub fn get_range_offsets(&self, range: TextRange) -> anyhow::Result<Range<usize>> {
+        for pos in [range.start, range.end] {
+            if self.get_line_length(pos.y).is_none_or(|len| pos.x > len) {
+                bail!("position {}:{} is out of range", pos.y, pos.x);
+            }
+        }
+        let (start, end) = (
+            self.get_char_offset(range.start),
+            self.get_char_offset(range.end),
+        );
+        if start > end {
+            bail!(
+                "range {}:{}..{}:{} ends before it starts",
+                range.start.y,
+                range.start.x,
+                range.end.y,
+                range.end.x
+            );
+        }
+        Ok(start..end)
+    }
+
+    /// Mark the buffer as modified and queue the change to send to plugins, merging text
+    /// typed right after the previous one. Every change of the content goes through here.
+    fn edit(&mut self, start: usize, old_end: usize, new_text: String) {
+        // Lines after an earlier change move with the lines this one adds. Those it
+        // removes are not counted, which only makes the range wider.
+        let y = self.get_line_at(start);
+        let added = new_text.matches('\n').count();
+        let end = y + added + 1;
+        self.edited_lines = Some(match self.edited_lines.take() {
+            Some(edited) if edited.end > y => edited.start.min(y)..(edited.end + added).max(end),
+            Some(edited) => edited.start.min(y)..end,
+            None => y..end,
+        });
+
+        // Lines starting before the change keep their offsets
+        if self.line_offset_hint.get().1 > start {
+            self.line_offset_hint.set((0, 0));
+        }
+        self.changes += 1;
+        self.dirty = true;
+        self.touched = true;
+
+        self.last_change = Instant::now();
+        if let Some(last) = self.pending_changes.last_mut()
+            && start == old_end
+            && start == last.start + last.new_text.chars().count()
+        {
+            last.new_text.push_str(&new_text);
+            return;
+        }
+
+        self.revision += 1;
+        self.pending_changes.push(BufferChange {
+            revision: self.revision,
+            start,
+            old_end,
+            new_text,
+        });
+    }
+
+    /// Take the range of the lines changed since the last call, as lines of the content now
+    pub fn take_edited_lines(&mut self) -> Option<Range<usize>> {
+        self.edited_lines.take()
+    }
+
+    /// Take the changes not yet sent, unless the last one is more recent than `debounce`
+    pub fn take_changes(&mut self, debounce: Duration) -> Vec<BufferChange> {
+        if self.last_change.elapsed() < debounce {
+            return Vec::new();
+        }
+        std::mem::take(&mut self.pending_changes)
+    }
+
+    pub fn get_diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// The diagnostics in position order, as the location list shows them
+    pub fn get_location_list(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = self.diagnostics.clone();
+        diagnostics.sort_by_key(|diagnostic| (diagnostic.range.start.y, diagnostic.range.start.x));
+        diagnostics
+    }
+
+    pub fn set_diagnostics(&mut self, diagnostics: Vec<Diagnostic>) {
+        self.diagnostics = diagnostics;
+    }
+
+    /// The lines joined with line breaks, ending with one if the file did
     pub fn get_content(&self) -> String {
-        self.content.join("\n")
+        let mut content = self.content.join("\n");
+        if self.end_of_line {
+            content.push('\n');
+        }
+        content
+    }
+
+    /// Characters `start..end` of the content, copying only that part
+    pub fn get_text(&self, start: usize, end: usize) -> Option<String> {
+        if start > end || end > self.get_char_count() {
+            return None;
+        }
+        let last = self.content.len() - 1;
+        let text = self
+            .content
+            .iter()
+            .enumerate()
+            .flat_map(|(y, line)| {
+                let line_break = (y < last || self.end_of_line).then_some('\n');
+                line.chars().chain(line_break)
+            })
+            .skip(start)
+            .take(end - start)
+            .collect();
+        Some(text)
     }
 
     pub fn get_line(&self, y: usize) -> Option<String> {
         self.content.get(y).cloned()
     }
 
+    /// Line `y` without copying it, for lines that may be long
+    pub fn get_line_ref(&self, y: usize) -> Option<&str> {
+        self.content.get(y).map(String::as_str)
+    }
+
     pub fn get_char(&self, pos: UVec2) -> Option<char> {
         self.content
             .get(pos.y)
@@ -70,51 +377,80 @@ impl Buffer {
     }
 
     pub fn insert_char(&mut self, pos: UVec2, ch: char) {
-        self.mark_dirty();
-        if let Some(line) = self.content.get_mut(pos.y) {
-            line.insert(pos.x, ch);
+        if pos.y < self.content.len() {
+            let start = self.get_char_offset(pos);
+            self.edit(start, start, ch.to_string());
+            let index = char_to_byte(&self.content[pos.y], pos.x);
+            self.content[pos.y].insert(index, ch);
         }
     }
 
     pub fn replace_char(&mut self, pos: UVec2, ch: char) -> Option<char> {
-        self.mark_dirty();
-        if let Some(line) = self.content.get(pos.y) {
-            let mut chars = line.chars().collect::<Vec<char>>();
-            let old = chars[pos.x];
-            chars[pos.x] = ch;
-            self.content[pos.y] = chars.iter().collect();
-            return Some(old);
-        }
-        None
+        let old = self.get_char(pos)?;
+        let start = self.get_line_offset(pos.y) + pos.x;
+        self.edit(start, start + 1, ch.to_string());
+        self.content[pos.y] = self.content[pos.y]
+            .chars()
+            .enumerate()
+            .map(|(x, old)| if x == pos.x { ch } else { old })
+            .collect();
+        Some(old)
     }
 
     pub fn replace_content(&mut self, content: String) -> String {
-        let old = self.content.clone();
-        self.content = content.split('\n').map(|line| line.to_string()).collect();
-        old.join("\n")
+        self.edit(0, self.get_char_count(), content.clone());
+        let old = self.get_content();
+        (self.content, self.end_of_line) = split_lines(&content);
+        old
     }
 
+    /// Remove the character at `pos`, or the line break after the last one, joining the
+    /// next line. Returns `None` at the end of the buffer or past the end of a line.
     pub fn remove_char(&mut self, pos: UVec2) -> Option<char> {
-        self.mark_dirty();
-        if let Some(line) = self.content.get_mut(pos.y) {
-            if pos.x < line.len() {
-                return Some(line.remove(pos.x));
-            } else if pos.x == line.len() {
-                self.join_lines(pos.y);
-                return Some('\n');
-            }
+        let line = self.content.get(pos.y)?;
+        let line_len = line.chars().count();
+        if let Some((index, _)) = line.char_indices().nth(pos.x) {
+            let start = self.get_line_offset(pos.y) + pos.x;
+            self.edit(start, start + 1, String::new());
+            Some(self.content[pos.y].remove(index))
+        } else if pos.x == line_len && pos.y + 1 < self.content.len() {
+            self.join_lines(pos.y);
+            Some('\n')
+        } else {
+            None
         }
-        None
+    }
+
+    /// Remove the grapheme cluster at `pos`, or join the next line at the end of a line
+    pub fn remove_grapheme(&mut self, pos: UVec2) -> Option<String> {
+        let line = self.content.get(pos.y)?;
+        if pos.x >= line.chars().count() {
+            return self.remove_char(pos).map(String::from);
+        }
+
+        let end = grapheme::next(line, pos.x);
+        let bytes = char_to_byte(line, pos.x)..char_to_byte(line, end);
+        let start = self.get_line_offset(pos.y) + pos.x;
+        self.edit(start, start + end - pos.x, String::new());
+        Some(self.content[pos.y].drain(bytes).collect())
     }
 
     pub fn insert_line(&mut self, y: usize, line: String) {
-        self.mark_dirty();
+        let (start, new_text) = if self.content.is_empty() {
+            (0, line.clone())
+        } else if y < self.content.len() {
+            (self.get_line_offset(y), format!("{}\n", line))
+        } else {
+            (self.get_lines_end(), format!("\n{}", line))
+        };
+        self.edit(start, start, new_text);
         self.content.insert(y, line);
     }
 
     pub fn replace_line(&mut self, y: usize, line: String) -> Option<String> {
         if let Some(old_line) = self.get_line(y) {
-            self.mark_dirty();
+            let start = self.get_line_offset(y);
+            self.edit(start, start + old_line.chars().count(), line.clone());
             self.content[y] = line;
             Some(old_line)
         } else {
@@ -122,16 +458,96 @@ impl Buffer {
         }
     }
 
-    pub fn replace_all_lines(&mut self, lines: Vec<String>) -> Vec<String> {
-        let old = self.content.clone();
-        self.content = lines;
+    /// Replace every line, leaving one empty line if `lines` is empty
+    pub fn replace_all_lines(&mut self, mut lines: Vec<String>) -> Vec<String> {
+        if lines.is_empty() {
+            lines.push(String::new());
+        }
+        let old_end = self.get_char_count();
+        let old = std::mem::replace(&mut self.content, lines);
+        self.edit(0, old_end, self.get_content());
         old
     }
 
+    /// `apply_edits` with line/character ranges
+    pub fn apply_range_edits(&mut self, edits: Vec<RangeEdit>) -> anyhow::Result<()> {
+        let edits = edits
+            .into_iter()
+            .map(|edit| {
+                let range = self.get_range_offsets(edit.range)?;
+                Ok(TextEdit {
+                    start: range.start,
+                    end: range.end,
+                    text: edit.text,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        self.apply_edits(edits)
+    }
+
+    /// Apply edits that must not overlap as one change and one undo step.
+    /// They are applied back to front, so every offset refers to the content before the edits.
+    pub fn apply_edits(&mut self, mut edits: Vec<TextEdit>) -> anyhow::Result<()> {
+        let mut content = self.get_content();
+        let char_count = content.chars().count();
+
+        // Insertions come before the edit starting where they are, whichever order they were
+        // given in, and keep their order among themselves
+        edits.sort_by_key(|edit| (edit.start, edit.end));
+        for (i, edit) in edits.iter().enumerate() {
+            if edit.start > edit.end || edit.end > char_count {
+                bail!("edit {}..{} is out of range", edit.start, edit.end);
+            }
+            if let Some(next) = edits.get(i + 1)
+                && next.start < edit.end
+            {
+                bail!(
+                    "edits {}..{} and {}..{} overlap",
+                    edit.start,
+                    edit.end,
+                    next.start,
+                    next.end
+                );
+            }
+        }
+
+        let byte_offsets: Vec<usize> = content
+            .char_indices()
+            .map(|(offset, _)| offset)
+            .chain(std::iter::once(content.len()))
+            .collect();
+        for edit in edits.iter().rev() {
+            content.replace_range(byte_offsets[edit.start]..byte_offsets[edit.end], &edit.text);
+        }
+
+        self.checkpoint();
+        for edit in edits.into_iter().rev() {
+            self.edit(edit.start, edit.end, edit.text);
+        }
+        (self.content, self.end_of_line) = split_lines(&content);
+        self.checkpoint();
+        Ok(())
+    }
+
+    /// Remove a line, returning it. Removing the only line leaves an empty one.
     pub fn remove_line(&mut self, y: usize) -> Option<String> {
         let line_count = self.get_line_count();
-        if line_count != 0 && y < line_count {
-            self.mark_dirty();
+        if y < line_count {
+            let len = self.content[y].chars().count();
+            if line_count == 1 {
+                self.edit(0, len, String::new());
+                return Some(std::mem::take(&mut self.content[0]));
+            }
+
+            // Remove the line break after the line, or before it for the last line
+            let (start, old_end) = if y + 1 < line_count {
+                let start = self.get_line_offset(y);
+                (start, start + len + 1)
+            } else {
+                let start = self.get_line_offset(y) - 1;
+                (start, start + len + 1)
+            };
+            self.edit(start, old_end, String::new());
             Some(self.content.remove(y))
         } else {
             None
@@ -139,19 +555,21 @@ impl Buffer {
     }
 
     pub fn split_line(&mut self, pos: UVec2) {
-        self.mark_dirty();
+        let start = self.get_char_offset(pos);
+        self.edit(start, start, "\n".to_string());
 
         let original = self.content[pos.y].clone();
-        let (p0, p1) = original.split_at(pos.x);
+        let (p0, p1) = original.split_at(char_to_byte(&original, pos.x));
         self.content[pos.y] = p0.to_string();
         self.content.insert(pos.y + 1, p1.to_string());
     }
 
     pub fn join_lines(&mut self, y: usize) {
         if y + 1 < self.get_line_count() {
-            self.mark_dirty();
+            let start = self.get_line_offset(y) + self.content[y].chars().count();
+            self.edit(start, start + 1, String::new());
 
-            let combined = self.content[y].clone() + &self.content[y + 1];
+            let combined = self.content[y].clone() + self.content[y + 1].as_str();
             self.content[y] = combined;
             self.content.remove(y + 1);
         }
@@ -164,10 +582,45 @@ impl Default for Buffer {
             file: None,
             content: vec![String::new()],
             dirty: false,
+            touched: false,
+            readonly: false,
+            undo: UndoHistory::new(vec![String::new()]),
+            changes: 0,
+            recorded_changes: 0,
+            revision: 0,
+            pending_changes: Vec::new(),
+            last_change: Instant::now(),
+            diagnostics: Vec::new(),
+            end_of_line: false,
+            line_offset_hint: Cell::new((0, 0)),
+            edited_lines: None,
+            filetype: None,
+            syntax: SyntaxCache::default(),
         }
     }
 }
 
+/// Byte index of character `x` of a line, clamped to the end of the line
+pub fn char_to_byte(line: &str, x: usize) -> usize {
+    if x >= line.len() || line.is_ascii() {
+        return x.min(line.len());
+    }
+    line.char_indices().nth(x).map_or(line.len(), |(i, _)| i)
+}
+
+/// Split text into lines, and tell if it ends with a line break. A trailing line break
+/// does not start another line.
+fn split_lines(text: &str) -> (Vec<String>, bool) {
+    let (text, end_of_line) = match text.strip_suffix('\n') {
+        Some(text) => (text, true),
+        None => (text, false),
+    };
+    (
+        text.split('\n').map(|line| line.to_string()).collect(),
+        end_of_line,
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -189,6 +642,125 @@ mod tests {
         assert_eq!(buf.remove_char(UVec2::new(10, 0)), None);
     }
 
+    #[test]
+    fn test_dirty_after_each_change() {
+        let path =
+            std::env::temp_dir().join(format!("cuprum_test_dirty_{}.txt", std::process::id()));
+        std::fs::write(&path, "ab\ncd").unwrap();
+        let mut buf = Buffer::open(path.clone()).unwrap();
+        assert!(!buf.is_dirty());
+
+        type Change = (&'static str, fn(&mut Buffer));
+        let changes: [Change; 11] = [
+            ("insert_char", |buf| buf.insert_char(UVec2::new(0, 0), 'x')),
+            ("replace_char", |buf| {
+                buf.replace_char(UVec2::new(0, 0), 'y');
+            }),
+            ("remove_char", |buf| {
+                buf.remove_char(UVec2::new(0, 0));
+            }),
+            ("insert_line", |buf| buf.insert_line(0, "x".to_string())),
+            ("replace_line", |buf| {
+                buf.replace_line(0, "y".to_string());
+            }),
+            ("remove_line", |buf| {
+                buf.remove_line(0);
+            }),
+            ("split_line", |buf| buf.split_line(UVec2::new(1, 0))),
+            ("join_lines", |buf| buf.join_lines(0)),
+            ("replace_all_lines", |buf| {
+                buf.replace_all_lines(vec!["ab".to_string(), "cd".to_string()]);
+            }),
+            ("replace_content", |buf| {
+                buf.replace_content("ab\ncd".to_string());
+            }),
+            ("apply_edits", |buf| {
+                buf.apply_edits(vec![TextEdit {
+                    start: 0,
+                    end: 1,
+                    text: "x".to_string(),
+                }])
+                .unwrap();
+            }),
+        ];
+        for (name, change) in changes {
+            change(&mut buf);
+            assert!(buf.is_dirty(), "{}", name);
+            buf.save().unwrap();
+            assert!(!buf.is_dirty(), "{}", name);
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), buf.get_content());
+
+        // Changes that do nothing leave the buffer clean
+        buf.remove_char(UVec2::new(9, 9));
+        buf.replace_char(UVec2::new(9, 0), 'x');
+        buf.join_lines(9);
+        assert!(!buf.is_dirty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_trailing_line_break() {
+        for (text, lines) in [
+            ("", vec![""]),
+            ("\n", vec![""]),
+            ("a", vec!["a"]),
+            ("a\n", vec!["a"]),
+            ("a\nb", vec!["a", "b"]),
+            ("a\nb\n", vec!["a", "b"]),
+            ("a\n\n", vec!["a", ""]),
+        ] {
+            let mut buf = Buffer::default();
+            buf.replace_content(text.to_string());
+            assert_eq!(buf.get_all_lines(), lines, "{:?}", text);
+            assert_eq!(buf.get_line_count(), lines.len(), "{:?}", text);
+            assert_eq!(buf.get_content(), text);
+            assert_eq!(buf.get_char_count(), text.chars().count());
+        }
+
+        // Lines added at the end go before the final line break
+        let mut buf = Buffer::default();
+        buf.replace_content("a\n".to_string());
+        buf.insert_line(1, "b".to_string());
+        assert_eq!(buf.get_content(), "a\nb\n");
+    }
+
+    #[test]
+    fn test_remove_char_edges() {
+        let mut buf = Buffer::default();
+        buf.replace_content("añb\ncd".to_string());
+        buf.dirty = false;
+
+        assert_eq!(buf.remove_char(UVec2::new(1, 0)), Some('ñ'));
+        assert_eq!(buf.get_all_lines(), vec!["ab", "cd"]);
+        assert!(buf.is_dirty());
+
+        // The line break at the end of a line joins the next one
+        assert_eq!(buf.remove_char(UVec2::new(2, 0)), Some('\n'));
+        assert_eq!(buf.get_all_lines(), vec!["abcd"]);
+
+        // Nothing follows the end of the buffer
+        buf.dirty = false;
+        assert_eq!(buf.remove_char(UVec2::new(4, 0)), None);
+        assert_eq!(buf.remove_char(UVec2::new(0, 1)), None);
+        assert_eq!(buf.get_all_lines(), vec!["abcd"]);
+        assert!(!buf.is_dirty());
+
+        // Positions count characters, not the bytes of multi-byte ones
+        let mut buf = Buffer::default();
+        buf.replace_content("日本語".to_string());
+        assert_eq!(buf.remove_char(UVec2::new(1, 0)), Some('本'));
+        for x in [2, 3, 5] {
+            assert_eq!(buf.remove_char(UVec2::new(x, 0)), None);
+        }
+        assert_eq!(buf.get_all_lines(), vec!["日語"]);
+
+        let mut empty = Buffer::default();
+        assert_eq!(empty.remove_char(UVec2::new(0, 0)), None);
+        assert_eq!(empty.get_all_lines(), vec![""]);
+        assert!(!empty.is_dirty());
+    }
+
     #[test]
     fn test_insert_remove_line() {
         let mut buf = Buffer::default();
@@ -198,14 +770,71 @@ mod tests {
         buf.insert_line(0, "first line".to_string());
         buf.insert_line(1, "second line".to_string());
 
-        assert_eq!(buf.get_line_count(), 2);
+        assert_eq!(buf.get_line_count(), 3);
         assert_eq!(buf.get_line(0), Some("first line".to_string()));
         assert_eq!(buf.get_line(1), Some("second line".to_string()));
 
+        assert_eq!(buf.remove_line(2), Some("".to_string()));
         assert_eq!(buf.remove_line(0), Some("first line".to_string()));
         assert_eq!(buf.remove_line(0), Some("second line".to_string()));
-        assert_eq!(buf.remove_line(0), None);
-        assert_eq!(buf.get_line_count(), 0);
+        assert_eq!(buf.get_all_lines(), vec![""]);
+        assert_eq!(buf.remove_line(0), Some("".to_string()));
+        assert_eq!(buf.remove_line(1), None);
+        assert_eq!(buf.get_line_count(), 1);
+
+        buf.replace_all_lines(Vec::new());
+        assert_eq!(buf.get_all_lines(), vec![""]);
+    }
+
+    #[test]
+    fn test_line_offsets_after_edits() {
+        let mut buf = Buffer::default();
+        buf.replace_content("one\ntwo\nthree\nfour\n".to_string());
+        let check = |buf: &Buffer| {
+            let lines = buf.get_all_lines();
+            // Looked up out of order, so the hint is used in both directions
+            for y in [2, 3, 0, 4, 1, 3].into_iter().filter(|&y| y <= lines.len()) {
+                let expected: usize = lines[..y].iter().map(|line| line.chars().count() + 1).sum();
+                assert_eq!(buf.get_line_offset(y), expected, "line {}", y);
+            }
+        };
+
+        check(&buf);
+        buf.insert_char(UVec2::new(1, 1), 'x');
+        check(&buf);
+        buf.split_line(UVec2::new(2, 0));
+        check(&buf);
+        buf.get_line_offset(4);
+        buf.remove_line(1);
+        check(&buf);
+        buf.get_line_offset(3);
+        buf.join_lines(2);
+        check(&buf);
+        buf.replace_all_lines(vec!["a".to_string(); 3]);
+        check(&buf);
+    }
+
+    #[test]
+    fn test_get_text() {
+        let mut buf = Buffer::default();
+        buf.replace_content("ab\ncd\n".to_string());
+        let content = buf.get_content();
+        for (start, end) in [(0, 0), (1, 4), (0, 6), (3, 6)] {
+            assert_eq!(buf.get_text(start, end).unwrap(), content[start..end]);
+        }
+        assert_eq!(buf.get_text(0, 7), None);
+        assert_eq!(buf.get_text(3, 2), None);
+    }
+
+    #[test]
+    fn test_get_lines_range() {
+        let mut buf = Buffer::default();
+        buf.replace_content("a\nb\nc\nd".to_string());
+        let all = buf.get_all_lines();
+        for (start, count) in [(0, 2), (1, 3), (2, 10), (4, 1), (9, 1), (1, usize::MAX)] {
+            let expected: Vec<String> = all.iter().skip(start).take(count).cloned().collect();
+            assert_eq!(buf.get_lines_range(start, count), expected);
+        }
     }
 
     #[test]
@@ -222,4 +851,118 @@ mod tests {
         assert_eq!(buf.get_line_count(), 1);
         assert_eq!(buf.get_line(0), Some("HelloWorld".to_string()));
     }
+
+    fn edit(start: usize, end: usize, text: &str) -> TextEdit {
+        TextEdit {
+            start,
+            end,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_apply_edits() {
+        let mut buf = Buffer::default();
+        buf.replace_content("let a = 1;\nlet b = 2;".to_string());
+
+        // Disjoint, given in any order, and across a line break
+        buf.apply_edits(vec![edit(19, 20, "20"), edit(4, 5, "x"), edit(10, 11, " ")])
+            .unwrap();
+        assert_eq!(buf.get_content(), "let x = 1; let b = 20;");
+
+        // Adjacent, including an insertion between them
+        buf.apply_edits(vec![edit(0, 3, "const"), edit(3, 3, "!"), edit(3, 4, "_")])
+            .unwrap();
+        assert_eq!(buf.get_content(), "const!_x = 1; let b = 20;");
+
+        // One undo step
+        assert!(buf.time_travel(UndoAmount::Steps(1), false));
+        assert_eq!(buf.get_content(), "let x = 1; let b = 20;");
+    }
+
+    #[test]
+    fn test_apply_edits_at_the_same_start() {
+        let apply = |edits: Vec<TextEdit>| {
+            let mut buf = Buffer::default();
+            buf.replace_content("abcd".to_string());
+            buf.apply_edits(edits).map(|_| buf.get_content())
+        };
+
+        // An insertion goes before the edit replacing what follows it
+        for edits in [
+            vec![edit(1, 3, "X"), edit(1, 1, "<")],
+            vec![edit(1, 1, "<"), edit(1, 3, "X")],
+        ] {
+            assert_eq!(apply(edits).unwrap(), "a<Xd");
+        }
+        // Insertions at the same place keep their order
+        assert_eq!(
+            apply(vec![edit(4, 4, "1"), edit(0, 0, "0"), edit(4, 4, "2")]).unwrap(),
+            "0abcd12"
+        );
+        // Edits replacing text from the same start overlap, in either order
+        assert!(apply(vec![edit(1, 2, "x"), edit(1, 3, "y")]).is_err());
+        assert!(apply(vec![edit(1, 3, "y"), edit(1, 2, "x")]).is_err());
+    }
+
+    #[test]
+    fn test_apply_overlapping_edits() {
+        let mut buf = Buffer::default();
+        buf.replace_content("äbc".to_string());
+
+        assert!(
+            buf.apply_edits(vec![edit(0, 2, "x"), edit(1, 3, "y")])
+                .is_err()
+        );
+        assert!(buf.apply_edits(vec![edit(2, 4, "x")]).is_err());
+        assert!(buf.apply_edits(vec![edit(2, 1, "x")]).is_err());
+        assert_eq!(buf.get_content(), "äbc");
+
+        buf.apply_edits(vec![edit(1, 2, "ß")]).unwrap();
+        assert_eq!(buf.get_content(), "äßc");
+    }
+
+    #[test]
+    fn test_apply_range_edits() {
+        let mut buf = Buffer::default();
+        buf.replace_content("fn ä() {\n    x\n}".to_string());
+        let range = |start: (usize, usize), end: (usize, usize)| TextRange {
+            start: UVec2::new(start.1, start.0),
+            end: UVec2::new(end.1, end.0),
+        };
+        assert_eq!(buf.get_range_offsets(range((0, 3), (0, 4))).unwrap(), 3..4);
+        assert_eq!(
+            buf.get_range_offsets(range((1, 4), (2, 0))).unwrap(),
+            13..15
+        );
+        assert!(buf.get_range_offsets(range((0, 9), (0, 9))).is_err());
+        assert!(buf.get_range_offsets(range((3, 0), (3, 0))).is_err());
+        assert!(buf.get_range_offsets(range((1, 0), (0, 0))).is_err());
+
+        buf.apply_range_edits(vec![
+            RangeEdit {
+                range: range((1, 4), (1, 5)),
+                text: "y;".to_string(),
+            },
+            RangeEdit {
+                range: range((0, 3), (0, 4)),
+                text: "main".to_string(),
+            },
+        ])
+        .unwrap();
+        assert_eq!(buf.get_content(), "fn main() {\n    y;\n}");
+    }
+
+    #[test]
+    fn test_touched() {
+        let mut buf = Buffer::default();
+        assert!(buf.is_scratch());
+        assert!(!buf.is_touched());
+
+        buf.insert_char(UVec2::new(0, 0), 'a');
+        assert!(buf.is_touched());
+
+        buf.remove_char(UVec2::new(0, 0));
+        assert!(buf.is_touched());
+    }
 }
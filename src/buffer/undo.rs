@@ -0,0 +1,183 @@
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+
+/// How far `:earlier` and `:later` move
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UndoAmount {
+    Steps(usize),
+    Time(Duration),
+}
+
+impl UndoAmount {
+    /// Parse `{N}`, `{N}s`, `{N}m` or `{N}h`. An empty string is one step.
+    pub fn parse(args: &str) -> anyhow::Result<Self> {
+        let args = args.trim();
+        if args.is_empty() {
+            return Ok(Self::Steps(1));
+        }
+
+        let (count, unit) = args.split_at(
+            args.find(|ch: char| !ch.is_ascii_digit())
+                .unwrap_or(args.len()),
+        );
+        let Ok(count) = count.parse::<u64>() else {
+            bail!("E475: Invalid argument: {}", args);
+        };
+        let seconds = match unit {
+            "" => return Ok(Self::Steps(usize::try_from(count).unwrap_or(usize::MAX))),
+            "s" => Some(count),
+            "m" => count.checked_mul(60),
+            "h" => count.checked_mul(60 * 60),
+            _ => bail!("E475: Invalid argument: {}", args),
+        };
+        let Some(seconds) = seconds else {
+            bail!("E475: Invalid argument: {}", args);
+        };
+        Ok(Self::Time(Duration::from_secs(seconds)))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct UndoState {
+    time: Instant,
+    lines: Vec<String>,
+}
+
+/// A linear history of buffer snapshots, each taken when a change is finished
+#[derive(Debug, Clone)]
+pub struct UndoHistory {
+    states: Vec<UndoState>,
+    current: usize,
+}
+
+impl UndoHistory {
+    pub fn new(lines: Vec<String>) -> Self {
+        Self {
+            states: vec![UndoState {
+                time: Instant::now(),
+                lines,
+            }],
+            current: 0,
+        }
+    }
+
+    /// Record a new state, dropping the states that were undone
+    pub fn push(&mut self, lines: Vec<String>, time: Instant) {
+        self.states.truncate(self.current + 1);
+        self.states.push(UndoState { time, lines });
+        self.current += 1;
+    }
+
+    /// Index of the state `amount` before (or after, if `forward`) the current one
+    pub fn target(&self, amount: UndoAmount, forward: bool) -> usize {
+        let last = self.states.len() - 1;
+        match amount {
+            UndoAmount::Steps(steps) if forward => self.current.saturating_add(steps).min(last),
+            UndoAmount::Steps(steps) => self.current.saturating_sub(steps),
+            UndoAmount::Time(duration) => {
+                let now = self.states[self.current].time;
+                // Going further than an `Instant` reaches goes to the first or last state
+                if forward {
+                    let Some(until) = now.checked_add(duration) else {
+                        return last;
+                    };
+                    (self.current..=last)
+                        .rfind(|&i| self.states[i].time <= until)
+                        .unwrap_or(self.current)
+                } else {
+                    let Some(until) = now.checked_sub(duration) else {
+                        return 0;
+                    };
+                    (0..=self.current)
+                        .rfind(|&i| self.states[i].time <= until)
+                        .unwrap_or(0)
+                }
+            }
+        }
+    }
+
+    /// Move by `amount`, returning the lines of the new state if it differs from the current one
+    pub fn travel(&mut self, amount: UndoAmount, forward: bool) -> Option<Vec<String>> {
+        let target = self.target(amount, forward);
+        (target != self.current).then(|| self.go_to(target))
+    }
+
+    /// Move to another state, returning its lines
+    pub fn go_to(&mut self, index: usize) -> Vec<String> {
+        self.current = index.min(self.states.len() - 1);
+        self.states[self.current].lines.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_amount() {
+        assert_eq!(UndoAmount::parse("").unwrap(), UndoAmount::Steps(1));
+        assert_eq!(UndoAmount::parse("5").unwrap(), UndoAmount::Steps(5));
+        assert_eq!(
+            UndoAmount::parse("10s").unwrap(),
+            UndoAmount::Time(Duration::from_secs(10))
+        );
+        assert_eq!(
+            UndoAmount::parse("2m").unwrap(),
+            UndoAmount::Time(Duration::from_secs(120))
+        );
+        assert_eq!(
+            UndoAmount::parse("99999999999h").unwrap(),
+            UndoAmount::Time(Duration::from_secs(99999999999 * 60 * 60))
+        );
+        assert!(UndoAmount::parse("9999999999999999999h").is_err());
+        assert!(UndoAmount::parse("99999999999999999999").is_err());
+        assert!(UndoAmount::parse("3d").is_err());
+        assert!(UndoAmount::parse("s").is_err());
+    }
+
+    #[test]
+    fn test_time_target() {
+        // Changes at 0s, 5s, 20s, 21s and 40s
+        let start = Instant::now();
+        let mut history = UndoHistory {
+            states: vec![UndoState {
+                time: start,
+                lines: vec!["0".to_string()],
+            }],
+            current: 0,
+        };
+        for (i, secs) in [5, 20, 21, 40].into_iter().enumerate() {
+            history.push(vec![(i + 1).to_string()], start + Duration::from_secs(secs));
+        }
+
+        let secs = |n| UndoAmount::Time(Duration::from_secs(n));
+        assert_eq!(history.target(secs(10), false), 3);
+        assert_eq!(history.target(secs(20), false), 2);
+        assert_eq!(history.target(secs(30), false), 1);
+        assert_eq!(history.target(secs(100), false), 0);
+        assert_eq!(history.target(UndoAmount::Steps(2), false), 2);
+
+        assert_eq!(history.go_to(1), vec!["1"]);
+        assert_eq!(history.target(secs(10), true), 1);
+        assert_eq!(history.target(secs(16), true), 3);
+        assert_eq!(history.target(secs(100), true), 4);
+        assert_eq!(history.target(UndoAmount::Steps(10), true), 4);
+
+        // `:earlier 99999999999h` and `:later 99999999999h`
+        let forever = UndoAmount::parse("99999999999h").unwrap();
+        assert_eq!(history.target(forever, false), 0);
+        assert_eq!(history.target(forever, true), 4);
+        assert_eq!(history.target(UndoAmount::Steps(usize::MAX), true), 4);
+    }
+
+    #[test]
+    fn test_push_drops_undone_states() {
+        let mut history = UndoHistory::new(vec!["a".to_string()]);
+        history.push(vec!["b".to_string()], Instant::now());
+        history.go_to(0);
+        history.push(vec!["c".to_string()], Instant::now());
+        assert_eq!(history.target(UndoAmount::Steps(5), true), 1);
+        assert_eq!(history.go_to(1), vec!["c"]);
+    }
+}
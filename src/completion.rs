@@ -0,0 +1,364 @@
+use std::{collections::HashMap, fmt::Debug, time::Duration};
+
+use api::{BufferId, PluginRequestKind, PluginResponseKind, Popup, PopupId};
+use plugin_manager::PluginChannel;
+use tokio::{task::JoinSet, time::timeout};
+use utils::vec2::UVec2;
+
+use crate::buffer::Buffer;
+
+/// Maximum number of candidates taken from each source
+const MAX_CANDIDATES: usize = 10;
+/// How long completion waits for plugin sources, so a slow one does not hold up typing
+pub const PLUGIN_SOURCE_DEADLINE: Duration = Duration::from_millis(200);
+
+/// A provider of completion candidates
+pub trait CompletionSource: Debug + Send + Sync {
+    /// Shown next to its candidates
+    fn name(&self) -> &str;
+    /// Get the candidates starting with `prefix`, best first
+    fn candidates(&self, buffer: &Buffer, prefix: &str) -> Vec<String>;
+}
+
+/// Completes words that appear in the current buffer, most frequent first
+#[derive(Debug, Default)]
+pub struct BufferWordSource;
+
+impl CompletionSource for BufferWordSource {
+    fn name(&self) -> &str {
+        "buffer"
+    }
+
+    fn candidates(&self, buffer: &Buffer, prefix: &str) -> Vec<String> {
+        rank_words(&buffer.get_all_lines(), prefix)
+    }
+}
+
+/// A completion source registered by a plugin
+#[derive(Debug, Clone)]
+pub struct PluginSource {
+    pub name: String,
+    pub trigger_chars: Vec<char>,
+    pub plugin: PluginChannel,
+}
+
+impl PluginSource {
+    /// Ask the plugin for candidates, giving up on it after `PLUGIN_SOURCE_DEADLINE`
+    async fn request(self, buf: BufferId, cursor: UVec2, prefix: String) -> Vec<Candidate> {
+        let request = PluginRequestKind::Complete {
+            source: self.name.clone(),
+            buf,
+            cursor,
+            prefix,
+        };
+        match timeout(PLUGIN_SOURCE_DEADLINE, self.plugin.request(request)).await {
+            Ok(Ok(PluginResponseKind::Completions(items))) => items
+                .into_iter()
+                .take(MAX_CANDIDATES)
+                .map(|item| Candidate {
+                    label: item.label,
+                    insert_text: item.insert_text,
+                    detail: item.detail,
+                    source: self.name.clone(),
+                })
+                .collect(),
+            Ok(Ok(response)) => {
+                log::error!("completion source {}: unexpected {:?}", self.name, response);
+                Vec::new()
+            }
+            Ok(Err(err)) => {
+                log::error!("completion source {}: {}", self.name, err);
+                Vec::new()
+            }
+            Err(_) => {
+                log::error!("completion source {} did not answer in time", self.name);
+                Vec::new()
+            }
+        }
+    }
+}
+
+/// An entry of the completion popup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub label: String,
+    /// Replaces the prefix when the candidate is accepted
+    pub insert_text: String,
+    pub detail: Option<String>,
+    /// Name of the source it came from
+    pub source: String,
+}
+
+impl Candidate {
+    fn word(word: String, source: &str) -> Self {
+        Self {
+            label: word.clone(),
+            insert_text: word,
+            detail: None,
+            source: source.to_string(),
+        }
+    }
+}
+
+/// Completion about to be shown, waiting for the plugin sources
+#[derive(Debug)]
+pub struct CompletionRequest {
+    pub buf: BufferId,
+    pub start: UVec2,
+    pub cursor: UVec2,
+    pub prefix: String,
+    pub anchor: UVec2,
+    /// Candidates of the built-in sources
+    pub candidates: Vec<Candidate>,
+    pub plugin_sources: Vec<PluginSource>,
+}
+
+impl CompletionRequest {
+    /// Ask every plugin source at once and add their candidates, grouped by source in the
+    /// order the sources were registered
+    pub async fn query_plugins(&mut self) {
+        let mut tasks = JoinSet::new();
+        for (index, source) in self.plugin_sources.drain(..).enumerate() {
+            let request = source.request(self.buf, self.cursor, self.prefix.clone());
+            tasks.spawn(async move { (index, request.await) });
+        }
+
+        let mut groups = tasks.join_all().await;
+        groups.sort_by_key(|(index, _)| *index);
+        self.candidates
+            .extend(groups.into_iter().flat_map(|(_, candidates)| candidates));
+    }
+}
+
+pub fn is_word_char(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '_'
+}
+
+/// Get the word characters immediately before column `x` (in chars)
+pub fn get_prefix(line: &str, x: usize) -> String {
+    let chars: Vec<char> = line.chars().take(x).collect();
+    let start = chars
+        .iter()
+        .rposition(|ch| !is_word_char(*ch))
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    chars[start..].iter().collect()
+}
+
+/// Collect the words starting with `prefix`, ordered by frequency and then alphabetically
+pub fn rank_words(lines: &[String], prefix: &str) -> Vec<String> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for line in lines {
+        for word in line.split(|ch: char| !is_word_char(ch)) {
+            if word.len() > prefix.len() && word.starts_with(prefix) {
+                *counts.entry(word).or_default() += 1;
+            }
+        }
+    }
+
+    let mut words: Vec<(&str, usize)> = counts.into_iter().collect();
+    words.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+    words
+        .into_iter()
+        .map(|(word, _)| word.to_string())
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct Completion {
+    /// Position of the first character of the prefix
+    pub start: UVec2,
+    pub prefix: String,
+    pub candidates: Vec<Candidate>,
+    pub selected: usize,
+    /// Screen position of the popup
+    pub anchor: UVec2,
+    pub popup: PopupId,
+}
+
+impl Completion {
+    pub fn get_selected(&self) -> &Candidate {
+        &self.candidates[self.selected]
+    }
+
+    /// Move the selection by `offset`, wrapping around
+    pub fn select_by(&mut self, offset: isize) {
+        let len = self.candidates.len() as isize;
+        self.selected = (self.selected as isize + offset).rem_euclid(len) as usize;
+    }
+
+    pub fn to_popup(&self) -> Popup {
+        let lines = self.get_lines();
+        let width = lines
+            .iter()
+            .map(|line| line.chars().count())
+            .max()
+            .unwrap_or(0);
+        Popup {
+            size: UVec2::new(width + 2, lines.len() + 2),
+            lines,
+            position: self.anchor,
+            border: true,
+            title: None,
+            dismissable: false,
+        }
+    }
+
+    /// Lines shown in the popup, with the source named on the first line of each group
+    pub fn get_lines(&self) -> Vec<String> {
+        let width = |text: Option<&String>| text.map_or(0, |text| text.chars().count());
+        let label_width = self
+            .candidates
+            .iter()
+            .map(|candidate| width(Some(&candidate.label)))
+            .max()
+            .unwrap_or(0);
+        let detail_width = self
+            .candidates
+            .iter()
+            .map(|candidate| width(candidate.detail.as_ref()))
+            .max()
+            .unwrap_or(0);
+
+        self.candidates
+            .iter()
+            .enumerate()
+            .map(|(i, candidate)| {
+                let marker = if i == self.selected { '>' } else { ' ' };
+                let first_of_group = i == 0 || self.candidates[i - 1].source != candidate.source;
+                let source = if first_of_group {
+                    candidate.source.as_str()
+                } else {
+                    ""
+                };
+                let line = format!(
+                    "{} {:label_width$}  {:detail_width$}  {}",
+                    marker,
+                    candidate.label,
+                    candidate.detail.as_deref().unwrap_or(""),
+                    source
+                );
+                line.trim_end().to_string()
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct CompletionManager {
+    sources: Vec<Box<dyn CompletionSource>>,
+    plugin_sources: Vec<PluginSource>,
+    pub active: Option<Completion>,
+}
+
+impl CompletionManager {
+    /// Add a plugin source, replacing the one with the same name
+    pub fn add_plugin_source(&mut self, source: PluginSource) {
+        match self
+            .plugin_sources
+            .iter_mut()
+            .find(|existing| existing.name == source.name)
+        {
+            Some(existing) => *existing = source,
+            None => self.plugin_sources.push(source),
+        }
+    }
+
+    /// Drop the sources of a plugin that was stopped
+    pub fn forget_plugin(&mut self, plugin: &PluginChannel) {
+        self.plugin_sources
+            .retain(|source| &source.plugin != plugin);
+    }
+
+    /// The plugin sources to ask: all of them, or those started by `trigger`
+    pub fn get_plugin_sources(&self, trigger: Option<char>) -> Vec<PluginSource> {
+        self.plugin_sources
+            .iter()
+            .filter(|source| trigger.is_none_or(|ch| source.trigger_chars.contains(&ch)))
+            .cloned()
+            .collect()
+    }
+
+    pub fn is_trigger(&self, ch: char) -> bool {
+        self.plugin_sources
+            .iter()
+            .any(|source| source.trigger_chars.contains(&ch))
+    }
+
+    /// Collect candidates from the built-in sources, without duplicates
+    pub fn collect(&self, buffer: &Buffer, prefix: &str) -> Vec<Candidate> {
+        let mut candidates: Vec<Candidate> = Vec::new();
+        for source in &self.sources {
+            let words = source
+                .candidates(buffer, prefix)
+                .into_iter()
+                .filter(|word| !candidates.iter().any(|candidate| &candidate.label == word))
+                .take(MAX_CANDIDATES)
+                .map(|word| Candidate::word(word, source.name()))
+                .collect::<Vec<_>>();
+            candidates.extend(words);
+        }
+        candidates
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.is_some()
+    }
+}
+
+impl Default for CompletionManager {
+    fn default() -> Self {
+        Self {
+            sources: vec![Box::new(BufferWordSource)],
+            plugin_sources: Vec::new(),
+            active: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_prefix() {
+        assert_eq!(get_prefix("let foo_ba", 10), "foo_ba");
+        assert_eq!(get_prefix("let foo_ba", 6), "fo");
+        assert_eq!(get_prefix("foo.bar", 7), "bar");
+        assert_eq!(get_prefix("foo ", 4), "");
+        assert_eq!(get_prefix("", 0), "");
+        assert_eq!(get_prefix("ひらがな", 3), "ひらが");
+    }
+
+    #[test]
+    fn test_rank_words() {
+        let lines = vec![
+            "apple banana apply".to_string(),
+            "apply(app, application)".to_string(),
+        ];
+        assert_eq!(
+            rank_words(&lines, "app"),
+            vec!["apply", "apple", "application"]
+        );
+        assert_eq!(rank_words(&lines, "ban"), vec!["banana"]);
+        assert!(rank_words(&lines, "xyz").is_empty());
+    }
+
+    #[test]
+    fn test_select_by() {
+        let mut completion = Completion {
+            start: UVec2::default(),
+            prefix: String::new(),
+            candidates: ["a", "b", "c"]
+                .map(|word| Candidate::word(word.to_string(), "buffer"))
+                .to_vec(),
+            selected: 0,
+            anchor: UVec2::default(),
+            popup: PopupId(0),
+        };
+        completion.select_by(-1);
+        assert_eq!(completion.get_selected().label, "c");
+        completion.select_by(2);
+        assert_eq!(completion.get_selected().label, "b");
+    }
+}
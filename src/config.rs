@@ -0,0 +1,281 @@
+//! `config.toml`: options, the theme, mappings and commands set when the editor starts.
+//!
+//! The file read is the first of:
+//! 1. the path given with `--config`
+//! 2. `$CUPRUM_CONFIG`
+//! 3. `~/.cuprum/config.toml`
+//!
+//! `--clean` reads none. Without a file the defaults are kept. A file with mistakes in it
+//! never keeps the editor from starting: what can be read is used, and the rest is reported
+//! as messages. `:set` and `:map` change what it set as usual. See `examples/config.toml`.
+
+use std::{
+    collections::BTreeMap,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail};
+use serde::Deserialize;
+
+use crate::ui::theme::Theme;
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Values of `:set` options by name
+    pub options: BTreeMap<String, toml::Value>,
+    /// `default` or `monochrome`
+    pub theme: Option<String>,
+    pub keymaps: KeymapConfig,
+    /// Options of the buffers of each filetype, over those of `options`
+    pub filetype: BTreeMap<String, BTreeMap<String, toml::Value>>,
+    /// User commands: an action name such as `save`, or keys to replay such as `ggdG`
+    pub commands: BTreeMap<String, String>,
+    /// Command lines run once plugins are loaded, such as `:set number`
+    pub startup: Vec<String>,
+}
+
+/// `{lhs} = {rhs}` mappings of each mode, like `:noremap`
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeymapConfig {
+    pub normal: BTreeMap<String, KeyBinding>,
+    pub visual: BTreeMap<String, KeyBinding>,
+    pub insert: BTreeMap<String, KeyBinding>,
+}
+
+/// The right-hand side of a mapping
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum KeyBinding {
+    /// An action name such as `save`, or keys to replay such as `:w<CR>`
+    Keys(String),
+    /// `false` takes the key away, built-in mappings included
+    Enabled(bool),
+}
+
+/// The config file to read, `path` being the one given with `--config`
+pub fn get_config_path(path: Option<PathBuf>) -> PathBuf {
+    path.or_else(|| std::env::var_os("CUPRUM_CONFIG").map(PathBuf::from))
+        .unwrap_or_else(|| home::home_dir().unwrap().join(".cuprum/config.toml"))
+}
+
+impl Config {
+    /// Read a config file, or `None` if there is none
+    pub fn read(path: &Path) -> anyhow::Result<Option<Self>> {
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => bail!("Error in {}: {}", path.display(), err),
+        };
+        toml::from_str(&source).map(Some).map_err(|err| {
+            let line = err
+                .span()
+                .map_or(1, |span| source[..span.start].matches('\n').count() + 1);
+            anyhow!(
+                "Error in {}: line {}: {}",
+                path.display(),
+                line,
+                err.message()
+            )
+        })
+    }
+
+    pub fn get_theme(&self) -> anyhow::Result<Theme> {
+        match self.theme.as_deref() {
+            None | Some("default") => Ok(Theme::default()),
+            Some("monochrome") => Ok(Theme::monochrome()),
+            Some(name) => bail!("Unknown theme: {}", name),
+        }
+    }
+}
+
+/// An option value as `:set` takes it
+pub fn option_value(value: &toml::Value) -> String {
+    match value {
+        toml::Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use api::{Mode, Position};
+    use builtin::BuiltinAction;
+
+    use super::*;
+    use crate::{
+        action::Action,
+        state::EditorState,
+        ui::input::{KeyCode, Keymap, parse_keys},
+    };
+
+    /// Write a config file to a temporary directory
+    fn write_config(name: &str, source: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("cuprum-config-{}-{}", name, std::process::id()));
+        fs::write(&path, source).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_read_example() {
+        let config = Config::read(Path::new("examples/config.toml"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.options["tabstop"], toml::Value::Integer(4));
+        assert_eq!(config.theme.as_deref(), Some("default"));
+        assert_eq!(
+            config.keymaps.normal["<C-s>"],
+            KeyBinding::Keys(":w<CR>".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_apply_config() {
+        let path = write_config(
+            "valid",
+            "theme = \"monochrome\"\n\
+             [options]\nnumber = true\nts = 2\ntimeoutlen = \"300\"\n\
+             [keymaps.insert]\njk = \"<Esc>\"\n",
+        );
+        let config = Config::read(&path).unwrap().unwrap();
+        fs::remove_file(path).ok();
+        assert!(config.get_theme().is_ok());
+
+        let mut state = EditorState::new(Vec::new()).unwrap();
+        state.apply_config(&config);
+        assert!(state.options.number);
+        assert_eq!(state.options.tabstop, 2);
+        assert_eq!(state.options.timeoutlen, 300);
+        let lhs = parse_keys("jk").unwrap();
+        assert!(state.user_keymaps.insert.get(&lhs).is_some());
+        assert!(state.messages.get_current().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_keymaps() {
+        let config = Config::read(Path::new("fixtures/keymaps.toml"))
+            .unwrap()
+            .unwrap();
+        let mut state = EditorState::new(Vec::new()).unwrap();
+        state.apply_config(&config);
+        assert!(state.messages.get_current().is_none());
+
+        let keymaps = &state.user_keymaps;
+        let get =
+            |keymap: &Keymap, notation: &str| keymap.get(&parse_keys(notation).unwrap()).cloned();
+        assert!(matches!(
+            get(&keymaps.normal, "<C-s>"),
+            Some(Action::Builtin(BuiltinAction::Save))
+        ));
+        assert!(matches!(
+            get(&keymaps.visual, "<C-s>"),
+            Some(Action::Builtin(BuiltinAction::Save))
+        ));
+        assert!(matches!(
+            get(&keymaps.normal, "L"),
+            Some(Action::Builtin(BuiltinAction::MoveToX(Position::End)))
+        ));
+        assert!(matches!(
+            get(&keymaps.insert, "jk"),
+            Some(Action::Builtin(BuiltinAction::ChangeMode(Mode::Normal)))
+        ));
+        // Keys are replayed without looking up other mappings
+        assert!(matches!(
+            get(&keymaps.normal, "<leader>w"),
+            Some(Action::Feed(keys, false)) if keys.len() == 3
+        ));
+        assert!(matches!(
+            get(&keymaps.normal, "Y"),
+            Some(Action::Feed(keys, false)) if keys.len() == 2
+        ));
+        // Lowercase keys that do not name an action
+        assert!(matches!(
+            get(&keymaps.normal, "D"),
+            Some(Action::Feed(keys, false)) if keys.len() == 2
+        ));
+        // Unbound keys do nothing instead of their built-in action
+        assert!(matches!(
+            get(&keymaps.normal, "x"),
+            Some(Action::Feed(keys, false)) if keys.is_empty()
+        ));
+        assert!(get(&keymaps.visual, "x").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_keymap_errors() {
+        let config: Config = toml::from_str(
+            "[keymaps.normal]\n\"<C-s>\" = \"save\"\n\"<c-s>\" = \"quit\"\n\
+             dd = \"remove_lines\"\nx = true\nX = \"remove_char\"\n",
+        )
+        .unwrap();
+        let mut state = EditorState::new(Vec::new()).unwrap();
+        state.apply_config(&config);
+        let errors: Vec<String> = state
+            .messages
+            .get_history()
+            .map(|entry| entry.message.text.clone())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "config.toml: mapping <c-s>: Same keys as <C-s>",
+                "config.toml: mapping dd: Unknown action: remove_lines",
+                "config.toml: mapping x: Only false can be given instead of keys",
+            ]
+        );
+        // The others are still mapped
+        assert!(
+            state
+                .user_keymaps
+                .normal
+                .get(&vec![KeyCode::Char('X')])
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_missing_config() {
+        let path = std::env::temp_dir().join("cuprum-config-missing.toml");
+        assert_eq!(Config::read(&path).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_config_errors() {
+        let path = write_config(
+            "type-error",
+            "theme = \"monochrome\"\n\n[keymaps]\nnormal = 3\n",
+        );
+        let err = Config::read(&path).unwrap_err().to_string();
+        fs::remove_file(&path).ok();
+        assert!(err.starts_with("Error in "), "{}", err);
+        assert!(err.contains("line 4: "), "{}", err);
+
+        // Values that are read but make no sense are reported, and the others still used
+        let config: Config = toml::from_str(
+            "theme = \"neon\"\n[options]\nnumber = true\ntabstop = \"four\"\nfoo = 1\n",
+        )
+        .unwrap();
+        assert!(config.get_theme().is_err());
+        let mut state = EditorState::new(Vec::new()).unwrap();
+        state.apply_config(&config);
+        assert!(state.options.number);
+        assert_eq!(state.options.tabstop, 8);
+        let errors: Vec<String> = state
+            .messages
+            .get_history()
+            .map(|entry| entry.message.text.clone())
+            .collect();
+        assert_eq!(errors.len(), 2);
+        assert!(
+            errors[0].contains("E518: Unknown option: foo"),
+            "{:?}",
+            errors
+        );
+        assert!(errors[1].contains("E521: "), "{:?}", errors);
+    }
+}
@@ -0,0 +1,222 @@
+//! Where the main loop takes input from: the terminal, a session written by `--record`, or
+//! the command lines of `-c`
+
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use crossterm::event::{self, Event};
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+use utils::vec2::UVec2;
+
+/// How long the terminal is waited for before the editor is told it is idle
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What the main loop handles next
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    /// A key press or another terminal event
+    Event(Event),
+    /// A command line, run as if typed after `:`
+    Command(String),
+    /// Nothing came in for a while
+    Idle,
+    /// There is nothing more to come
+    End,
+}
+
+#[async_trait]
+pub trait EventSource: Send {
+    async fn next_input(&mut self) -> anyhow::Result<Input>;
+}
+
+/// Events of the terminal, until drawing on it stops
+pub struct TerminalSource {
+    stopped: Arc<AtomicBool>,
+}
+
+impl TerminalSource {
+    pub fn new(stopped: Arc<AtomicBool>) -> Self {
+        Self { stopped }
+    }
+}
+
+#[async_trait]
+impl EventSource for TerminalSource {
+    async fn next_input(&mut self) -> anyhow::Result<Input> {
+        if self.stopped.load(Ordering::Relaxed) {
+            return Ok(Input::End);
+        }
+        if event::poll(POLL_INTERVAL)? {
+            Ok(Input::Event(event::read()?))
+        } else {
+            Ok(Input::Idle)
+        }
+    }
+}
+
+/// The command lines given with `-c`
+pub struct CommandSource {
+    commands: VecDeque<String>,
+}
+
+impl CommandSource {
+    pub fn new(commands: Vec<String>) -> Self {
+        Self {
+            commands: commands.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl EventSource for CommandSource {
+    async fn next_input(&mut self) -> anyhow::Result<Input> {
+        Ok(self.commands.pop_front().map_or(Input::End, Input::Command))
+    }
+}
+
+/// A line of a `--record` file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedEvent {
+    /// Milliseconds since the recording started
+    pub time: u64,
+    pub event: Event,
+}
+
+/// Appends the events the editor processes to a file, one JSON object per line
+pub struct Recorder {
+    file: File,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Start a session with a resize to `term_size`, so that a replay lays the windows out
+    /// as they were, whatever the size of the terminal it runs in
+    pub fn create(path: &Path, term_size: UVec2) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| anyhow!("{}: {}", path.display(), err))?;
+        let mut recorder = Self {
+            file,
+            start: Instant::now(),
+        };
+        recorder.record(&Event::Resize(term_size.x as u16, term_size.y as u16))?;
+        Ok(recorder)
+    }
+
+    pub fn record(&mut self, event: &Event) -> anyhow::Result<()> {
+        let recorded = RecordedEvent {
+            time: self.start.elapsed().as_millis() as u64,
+            event: event.clone(),
+        };
+        writeln!(self.file, "{}", serde_json::to_string(&recorded)?)?;
+        Ok(())
+    }
+}
+
+/// The events of a `--record` file, at the pace they were recorded unless `fast`
+pub struct ReplaySource {
+    events: VecDeque<RecordedEvent>,
+    fast: bool,
+    last_time: u64,
+}
+
+impl ReplaySource {
+    pub fn open(path: &Path, fast: bool) -> anyhow::Result<Self> {
+        let source =
+            std::fs::read_to_string(path).map_err(|err| anyhow!("{}: {}", path.display(), err))?;
+        let events = source
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(y, line)| {
+                serde_json::from_str(line)
+                    .map_err(|err| anyhow!("{}: line {}: {}", path.display(), y + 1, err))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            events,
+            fast,
+            last_time: 0,
+        })
+    }
+}
+
+#[async_trait]
+impl EventSource for ReplaySource {
+    async fn next_input(&mut self) -> anyhow::Result<Input> {
+        let Some(recorded) = self.events.pop_front() else {
+            return Ok(Input::End);
+        };
+        // Sessions appended to the same file each start from 0
+        let wait = recorded.time.saturating_sub(self.last_time);
+        self.last_time = recorded.time;
+        if !self.fast && wait > 0 {
+            sleep(Duration::from_millis(wait)).await;
+        }
+        Ok(Input::Event(recorded.event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("cuprum-record-{}", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let events = [
+            Event::Resize(100, 30),
+            Event::Key(KeyEvent::new(KeyCode::Char('x'), KeyModifiers::NONE)),
+            Event::Key(KeyEvent::new(KeyCode::Char('w'), KeyModifiers::CONTROL)),
+            Event::Resize(80, 24),
+            Event::FocusGained,
+        ];
+        // A second session is appended after the first, each starting with the size
+        for session in [&events[..3], &events[3..]] {
+            let Event::Resize(w, h) = session[0] else {
+                unreachable!();
+            };
+            let mut recorder = Recorder::create(&path, UVec2::new(w.into(), h.into())).unwrap();
+            for event in &session[1..] {
+                recorder.record(event).unwrap();
+            }
+        }
+
+        let mut source = ReplaySource::open(&path, true).unwrap();
+        std::fs::remove_file(&path).ok();
+        for event in events {
+            assert_eq!(source.next_input().await.unwrap(), Input::Event(event));
+        }
+        assert_eq!(source.next_input().await.unwrap(), Input::End);
+    }
+
+    #[test]
+    fn test_replay_errors() {
+        let path = std::env::temp_dir().join(format!("cuprum-replay-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "{\"time\":0,\"event\":\"FocusGained\"}\n\n{\"time\":1}\n",
+        )
+        .unwrap();
+        let err = ReplaySource::open(&path, true).err().unwrap().to_string();
+        std::fs::remove_file(&path).ok();
+        assert!(err.contains(": line 3: missing field `event`"), "{}", err);
+    }
+}
@@ -0,0 +1,135 @@
+//! Filetypes of buffers, from the file name or the `#!` line of scripts
+
+use std::path::Path;
+
+/// Filetypes of file extensions
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("rs", "rust"),
+    ("toml", "toml"),
+    ("md", "markdown"),
+    ("markdown", "markdown"),
+    ("txt", "text"),
+    ("json", "json"),
+    ("yaml", "yaml"),
+    ("yml", "yaml"),
+    ("c", "c"),
+    ("h", "c"),
+    ("cpp", "cpp"),
+    ("cc", "cpp"),
+    ("hpp", "cpp"),
+    ("go", "go"),
+    ("py", "python"),
+    ("js", "javascript"),
+    ("mjs", "javascript"),
+    ("ts", "typescript"),
+    ("html", "html"),
+    ("css", "css"),
+    ("lua", "lua"),
+    ("rb", "ruby"),
+    ("sh", "sh"),
+    ("bash", "sh"),
+    ("zsh", "sh"),
+    ("rhai", "rhai"),
+    ("wat", "wat"),
+];
+
+/// Filetypes of whole file names, for files without an extension
+const FILE_NAMES: &[(&str, &str)] = &[
+    ("Makefile", "make"),
+    ("makefile", "make"),
+    ("Dockerfile", "dockerfile"),
+    ("Cargo.lock", "toml"),
+];
+
+/// Filetypes of the programs that run scripts, without a version such as `3.12`
+const INTERPRETERS: &[(&str, &str)] = &[
+    ("sh", "sh"),
+    ("bash", "sh"),
+    ("zsh", "sh"),
+    ("dash", "sh"),
+    ("python", "python"),
+    ("node", "javascript"),
+    ("ruby", "ruby"),
+    ("perl", "perl"),
+    ("lua", "lua"),
+];
+
+fn lookup(table: &[(&str, &'static str)], key: &str) -> Option<&'static str> {
+    table
+        .iter()
+        .find(|(name, _)| *name == key)
+        .map(|(_, filetype)| *filetype)
+}
+
+/// The filetype of a file, by its name, or by its first line if that is a `#!` line
+pub fn detect(path: &Path, first_line: &str) -> Option<String> {
+    let by_name = path
+        .file_name()
+        .and_then(|name| lookup(FILE_NAMES, &name.to_string_lossy()))
+        .or_else(|| {
+            let extension = path.extension()?.to_string_lossy();
+            lookup(EXTENSIONS, &extension)
+        });
+    by_name
+        .or_else(|| detect_shebang(first_line))
+        .map(str::to_string)
+}
+
+/// The filetype of a script from its `#!` line, such as `#!/usr/bin/env python3`
+fn detect_shebang(line: &str) -> Option<&'static str> {
+    let mut words = line.strip_prefix("#!")?.split_whitespace();
+    let mut program = words.next()?.rsplit('/').next()?;
+    if program == "env" {
+        // Flags such as `-S` come before the program
+        program = words.find(|word| !word.starts_with('-'))?;
+    }
+    let program = program.trim_end_matches(|ch: char| ch.is_ascii_digit() || ch == '.');
+    lookup(INTERPRETERS, program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detect_path(path: &str) -> Option<String> {
+        detect(Path::new(path), "")
+    }
+
+    #[test]
+    fn test_detect_by_name() {
+        assert_eq!(detect_path("src/main.rs").as_deref(), Some("rust"));
+        assert_eq!(detect_path("/tmp/Cargo.toml").as_deref(), Some("toml"));
+        assert_eq!(detect_path("notes.yml").as_deref(), Some("yaml"));
+        assert_eq!(detect_path("Makefile").as_deref(), Some("make"));
+        assert_eq!(detect_path("Cargo.lock").as_deref(), Some("toml"));
+        assert_eq!(detect_path("photo.png"), None);
+        assert_eq!(detect_path("README"), None);
+    }
+
+    #[test]
+    fn test_detect_shebang() {
+        let detect_script = |line: &str| detect(Path::new("bin/run"), line);
+        assert_eq!(detect_script("#!/bin/sh").as_deref(), Some("sh"));
+        assert_eq!(detect_script("#!/usr/bin/bash -e").as_deref(), Some("sh"));
+        assert_eq!(
+            detect_script("#!/usr/bin/env python3").as_deref(),
+            Some("python")
+        );
+        assert_eq!(
+            detect_script("#!/usr/bin/env -S python3.12 -u").as_deref(),
+            Some("python")
+        );
+        assert_eq!(
+            detect_script("#!/usr/bin/env node").as_deref(),
+            Some("javascript")
+        );
+        assert_eq!(detect_script("#!/opt/unknown"), None);
+        assert_eq!(detect_script("echo hi"), None);
+
+        // The file name wins over the `#!` line
+        assert_eq!(
+            detect(Path::new("build.rs"), "#!/bin/sh").as_deref(),
+            Some("rust")
+        );
+    }
+}
@@ -0,0 +1,85 @@
+use crate::ui::{commands::CommandMap, input::Keymap};
+
+const HELP_INTRO: &[&str] = &[
+    "Cuprum help",
+    "",
+    "Cuprum is a modal editor. Keys move and edit text in normal mode, i enters",
+    "insert mode to type, and Esc goes back. Commands are typed after :.",
+    "",
+    "Use :help {key} or :help :{command} to jump to a topic, and :q to close this help.",
+];
+
+/// Width of the key or command column
+const COLUMN_WIDTH: usize = 14;
+
+fn help_entry(name: &str, description: &str) -> String {
+    format!("  {:<width$}{}", name, description, width = COLUMN_WIDTH)
+}
+
+/// Build the help text from the registered keymaps and commands
+pub fn help_lines(keymaps: &[(&str, &Keymap)], commands: &CommandMap) -> Vec<String> {
+    let mut lines: Vec<String> = HELP_INTRO.iter().map(|line| line.to_string()).collect();
+
+    for (mode, keymap) in keymaps {
+        lines.push(String::new());
+        lines.push(format!("{} mode", mode));
+        for (key, entry) in keymap.entries() {
+            lines.push(help_entry(&key, &entry.description));
+        }
+    }
+
+    lines.push(String::new());
+    lines.push("Commands".to_string());
+    if let Some(entry) = commands.get_entry("") {
+        lines.push(help_entry(":{number}", &entry.description));
+    }
+    for name in commands.names() {
+        if let Some(entry) = commands.get_entry(&name) {
+            lines.push(help_entry(&format!(":{}", entry.spec), &entry.description));
+        }
+    }
+
+    lines
+}
+
+/// Find the line documenting a key (`dd`) or a command (`:w`, `:write`)
+pub fn find_topic(lines: &[String], topic: &str, commands: &CommandMap) -> Option<usize> {
+    let find = |name: &str| {
+        lines
+            .iter()
+            .position(|line| line.starts_with("  ") && line.split_whitespace().next() == Some(name))
+    };
+
+    let command = |name: &str| {
+        let name = commands.resolve(name).ok()??;
+        let entry = commands.get_entry(&name)?;
+        find(&format!(":{}", entry.spec))
+    };
+
+    match topic.strip_prefix(':') {
+        Some(name) => command(name),
+        None => find(topic).or_else(|| command(topic)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_help_topics() {
+        let normal = Keymap::normal_default();
+        let commands = CommandMap::default();
+        let lines = help_lines(&[("Normal", &normal)], &commands);
+
+        let dd = find_topic(&lines, "dd", &commands).unwrap();
+        assert!(lines[dd].contains("delete the line"));
+
+        let write = find_topic(&lines, ":w", &commands).unwrap();
+        assert_eq!(find_topic(&lines, ":write", &commands), Some(write));
+        assert_eq!(find_topic(&lines, "write", &commands), Some(write));
+        assert!(lines[write].contains(":w[rite]"));
+
+        assert_eq!(find_topic(&lines, "nothing", &commands), None);
+    }
+}
@@ -0,0 +1,216 @@
+use std::ops::Range;
+
+use api::{Diagnostic, DiagnosticSeverity, TextRange};
+use utils::vec2::UVec2;
+
+use crate::{buffer::char_to_byte, search::find_byte_matches, syntax::SyntaxKind};
+
+/// Where a highlight came from, so each owner can clear only its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HighlightSource {
+    Search,
+    /// Placed by plugins with `add_highlight`
+    Plugin,
+    Diagnostic(DiagnosticSeverity),
+    /// Colors the text the other highlights leave uncovered
+    Syntax(SyntaxKind),
+}
+
+/// A highlighted span of a single line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Highlight {
+    pub y: usize,
+    /// Byte range within the line
+    pub range: Range<usize>,
+    pub source: HighlightSource,
+}
+
+#[derive(Debug, Default)]
+pub struct Highlights {
+    items: Vec<Highlight>,
+}
+
+impl Highlights {
+    pub fn add(&mut self, highlight: Highlight) {
+        self.items.push(highlight);
+    }
+
+    /// Remove all highlights placed by `source`
+    pub fn clear(&mut self, source: HighlightSource) {
+        self.items.retain(|highlight| highlight.source != source);
+    }
+
+    pub fn get_all(&self) -> &[Highlight] {
+        &self.items
+    }
+}
+
+/// Highlight every occurrence of `pattern` in `lines`, the lines of the buffer from line
+/// `first`, splitting matches that span lines
+pub fn find_matches(lines: &[String], first: usize, pattern: &str) -> Vec<Highlight> {
+    find_byte_matches(lines, pattern)
+        .into_iter()
+        .flat_map(|found| {
+            (found.start.y..=found.end.y).map(move |y| {
+                let start = if y == found.start.y { found.start.x } else { 0 };
+                let end = if y == found.end.y {
+                    found.end.x
+                } else {
+                    lines[y].len()
+                };
+                Highlight {
+                    y: first + y,
+                    range: start..end,
+                    source: HighlightSource::Search,
+                }
+            })
+        })
+        .filter(|highlight| !highlight.range.is_empty())
+        .collect()
+}
+
+/// Underline the part of each diagnostic in `lines`, the lines of the buffer from line
+/// `first`, widening empty ranges to the character after them
+pub fn diagnostic_highlights(
+    lines: &[String],
+    first: usize,
+    diagnostics: &[Diagnostic],
+) -> Vec<Highlight> {
+    let last = first + lines.len();
+    let byte_index = |y: usize, x: usize| char_to_byte(&lines[y - first], x);
+
+    diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.range.start.y < last && diagnostic.range.end.y >= first)
+        .flat_map(|diagnostic| {
+            let TextRange { start, end } = diagnostic.range;
+            (start.y.max(first)..end.y.min(last - 1) + 1).map(move |y| {
+                let from = if y == start.y {
+                    byte_index(y, start.x)
+                } else {
+                    0
+                };
+                let mut to = if y == end.y {
+                    byte_index(y, end.x)
+                } else {
+                    lines[y - first].len()
+                };
+                if start == end {
+                    to = byte_index(y, start.x + 1);
+                }
+                Highlight {
+                    y,
+                    range: from..to,
+                    source: HighlightSource::Diagnostic(diagnostic.severity),
+                }
+            })
+        })
+        .filter(|highlight| !highlight.range.is_empty())
+        .collect()
+}
+
+/// Find the first match after `cursor`, or before it when searching backward,
+/// wrapping around the buffer
+pub fn find_next(lines: &[String], pattern: &str, cursor: UVec2, forward: bool) -> Option<UVec2> {
+    let matches = find_byte_matches(lines, pattern);
+    let positions = matches.iter().map(|found| found.start);
+
+    if forward {
+        positions
+            .clone()
+            .find(|pos| (pos.y, pos.x) > (cursor.y, cursor.x))
+            .or_else(|| positions.clone().next())
+    } else {
+        positions
+            .clone()
+            .rfind(|pos| (pos.y, pos.x) < (cursor.y, cursor.x))
+            .or_else(|| positions.clone().next_back())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn test_find_matches() {
+        let matches = find_matches(&lines(&["foo bar foo", "baz", "foo"]), 0, "foo");
+        let ranges: Vec<(usize, Range<usize>)> =
+            matches.into_iter().map(|h| (h.y, h.range)).collect();
+        assert_eq!(ranges, vec![(0, 0..3), (0, 8..11), (2, 0..3)]);
+    }
+
+    #[test]
+    fn test_find_matches_across_lines() {
+        let matches = find_matches(&lines(&["a foo", "bar", "x"]), 10, "foo\nbar\n");
+        let ranges: Vec<(usize, Range<usize>)> =
+            matches.into_iter().map(|h| (h.y, h.range)).collect();
+        assert_eq!(ranges, vec![(10, 2..5), (11, 0..3)]);
+    }
+
+    #[test]
+    fn test_diagnostic_highlights() {
+        let diagnostic = |start: (usize, usize), end: (usize, usize)| Diagnostic {
+            range: TextRange {
+                start: UVec2::new(start.1, start.0),
+                end: UVec2::new(end.1, end.0),
+            },
+            severity: DiagnosticSeverity::Error,
+            message: String::new(),
+        };
+        let diagnostics = [
+            diagnostic((0, 4), (0, 5)),
+            diagnostic((0, 8), (1, 1)),
+            diagnostic((2, 0), (2, 0)),
+            diagnostic((9, 0), (9, 1)),
+        ];
+        let lines = lines(&["let ä = 1", "b", "c"]);
+        let ranges = |first: usize, count: usize| {
+            diagnostic_highlights(&lines[first..first + count], first, &diagnostics)
+                .into_iter()
+                .map(|h| (h.y, h.range))
+                .collect::<Vec<(usize, Range<usize>)>>()
+        };
+        assert_eq!(
+            ranges(0, 3),
+            vec![(0, 4..6), (0, 9..10), (1, 0..1), (2, 0..1)]
+        );
+        // Only the lines given are highlighted, including the end of a diagnostic above them
+        assert_eq!(ranges(1, 1), vec![(1, 0..1)]);
+        assert_eq!(ranges(0, 0), vec![]);
+    }
+
+    #[test]
+    fn test_find_next() {
+        let lines = lines(&["foo bar foo", "baz", "foo"]);
+        let next = |x, y, forward| find_next(&lines, "foo", UVec2::new(x, y), forward);
+        assert_eq!(next(0, 0, true), Some(UVec2::new(8, 0)));
+        assert_eq!(next(8, 0, true), Some(UVec2::new(0, 2)));
+        assert_eq!(next(0, 2, true), Some(UVec2::new(0, 0)));
+        assert_eq!(next(0, 2, false), Some(UVec2::new(8, 0)));
+        assert_eq!(next(0, 0, false), Some(UVec2::new(0, 2)));
+        assert_eq!(find_next(&lines, "qux", UVec2::default(), true), None);
+    }
+
+    #[test]
+    fn test_clear_by_source() {
+        let mut highlights = Highlights::default();
+        highlights.add(Highlight {
+            y: 0,
+            range: 0..1,
+            source: HighlightSource::Search,
+        });
+        highlights.add(Highlight {
+            y: 1,
+            range: 0..1,
+            source: HighlightSource::Plugin,
+        });
+        highlights.clear(HighlightSource::Search);
+        assert_eq!(highlights.get_all().len(), 1);
+        assert_eq!(highlights.get_all()[0].source, HighlightSource::Plugin);
+    }
+}
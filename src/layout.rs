@@ -0,0 +1,241 @@
+//! How the windows on the screen share it: split into rows or columns, each of which can
+//! be split again
+
+use api::{SplitDirection, WindowId};
+use utils::vec2::UVec2;
+
+/// The windows shown, as a tree of splits. Windows not in it are hidden until they are
+/// made active, which puts them in place of the active one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Layout {
+    Window(WindowId),
+    /// Above each other if horizontal, side by side if vertical, sharing the space evenly
+    Split(SplitDirection, Vec<Layout>),
+}
+
+/// A line drawn between split windows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Border {
+    pub position: UVec2,
+    pub length: usize,
+    /// Whether it separates windows side by side, and runs down rather than across
+    pub vertical: bool,
+}
+
+/// Where a window is placed on the screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Placement {
+    pub id: WindowId,
+    pub position: UVec2,
+    pub size: UVec2,
+}
+
+impl Layout {
+    pub fn contains(&self, id: WindowId) -> bool {
+        match self {
+            Layout::Window(window) => *window == id,
+            Layout::Split(_, children) => children.iter().any(|child| child.contains(id)),
+        }
+    }
+
+    /// The windows shown, from the top left
+    pub fn get_window_ids(&self) -> Vec<WindowId> {
+        match self {
+            Layout::Window(id) => vec![*id],
+            Layout::Split(_, children) => children
+                .iter()
+                .flat_map(|child| child.get_window_ids())
+                .collect(),
+        }
+    }
+
+    /// Show `new` after `target`, splitting the space of `target`, or of the split it is in
+    /// if that goes the same way. Returns false if `target` is not shown.
+    pub fn split(&mut self, target: WindowId, new: WindowId, direction: SplitDirection) -> bool {
+        match self {
+            Layout::Window(id) if *id == target => {
+                *self = Layout::Split(direction, vec![Layout::Window(target), Layout::Window(new)]);
+                true
+            }
+            Layout::Window(_) => false,
+            Layout::Split(split, children) => {
+                let index = children
+                    .iter()
+                    .position(|child| *child == Layout::Window(target));
+                if let Some(index) = index
+                    && *split == direction
+                {
+                    children.insert(index + 1, Layout::Window(new));
+                    return true;
+                }
+                children
+                    .iter_mut()
+                    .any(|child| child.split(target, new, direction))
+            }
+        }
+    }
+
+    /// Show `new` in place of `old`. Returns false if `old` is not shown.
+    pub fn replace(&mut self, old: WindowId, new: WindowId) -> bool {
+        match self {
+            Layout::Window(id) if *id == old => {
+                *id = new;
+                true
+            }
+            Layout::Window(_) => false,
+            Layout::Split(_, children) => children.iter_mut().any(|child| child.replace(old, new)),
+        }
+    }
+
+    /// Stop showing a window, giving its space to the others in its split. Returns false if
+    /// it is not shown, or is the only window shown.
+    pub fn remove(&mut self, id: WindowId) -> bool {
+        let Layout::Split(_, children) = self else {
+            return false;
+        };
+        if let Some(index) = children
+            .iter()
+            .position(|child| *child == Layout::Window(id))
+        {
+            children.remove(index);
+        } else if !children.iter_mut().any(|child| child.remove(id)) {
+            return false;
+        }
+
+        // A split of a single window is that window
+        if children.len() == 1 {
+            *self = children.remove(0);
+        }
+        true
+    }
+
+    /// Place the windows in the rectangle at `position` of `size`, with a border between
+    /// each two of them
+    pub fn arrange(&self, position: UVec2, size: UVec2) -> (Vec<Placement>, Vec<Border>) {
+        let mut placements = Vec::new();
+        let mut borders = Vec::new();
+        self.arrange_into(position, size, &mut placements, &mut borders);
+        (placements, borders)
+    }
+
+    fn arrange_into(
+        &self,
+        position: UVec2,
+        size: UVec2,
+        placements: &mut Vec<Placement>,
+        borders: &mut Vec<Border>,
+    ) {
+        let (direction, children) = match self {
+            Layout::Window(id) => {
+                placements.push(Placement {
+                    id: *id,
+                    position,
+                    size,
+                });
+                return;
+            }
+            Layout::Split(direction, children) => (*direction, children),
+        };
+
+        let vertical = direction == SplitDirection::Vertical;
+        let (total, across) = if vertical {
+            (size.x, size.y)
+        } else {
+            (size.y, size.x)
+        };
+        let at = |offset: usize| {
+            if vertical {
+                position + UVec2::new(offset, 0)
+            } else {
+                position + UVec2::new(0, offset)
+            }
+        };
+        // The first windows take what does not divide evenly
+        let space = total.saturating_sub(children.len() - 1);
+        let mut offset = 0;
+        for (i, child) in children.iter().enumerate() {
+            let length = space / children.len() + usize::from(i < space % children.len());
+            let child_size = if vertical {
+                UVec2::new(length, across)
+            } else {
+                UVec2::new(across, length)
+            };
+            child.arrange_into(at(offset), child_size, placements, borders);
+            offset += length;
+
+            if i + 1 < children.len() {
+                borders.push(Border {
+                    position: at(offset),
+                    length: across,
+                    vertical,
+                });
+                offset += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sizes(layout: &Layout, size: UVec2) -> Vec<(usize, UVec2, UVec2)> {
+        let (placements, _) = layout.arrange(UVec2::default(), size);
+        placements
+            .into_iter()
+            .map(|placement| (placement.id.0, placement.position, placement.size))
+            .collect()
+    }
+
+    #[test]
+    fn test_split_and_remove() {
+        let mut layout = Layout::Window(WindowId(0));
+        assert!(layout.split(WindowId(0), WindowId(1), SplitDirection::Horizontal));
+        assert!(layout.split(WindowId(0), WindowId(2), SplitDirection::Horizontal));
+        assert!(layout.split(WindowId(2), WindowId(3), SplitDirection::Vertical));
+        assert!(!layout.split(WindowId(9), WindowId(4), SplitDirection::Vertical));
+        assert_eq!(layout.get_window_ids(), [0, 2, 3, 1].map(WindowId));
+
+        // 23 rows, two of them borders, and 80 columns with one border
+        let (placements, borders) = layout.arrange(UVec2::default(), UVec2::new(80, 23));
+        assert_eq!(
+            sizes(&layout, UVec2::new(80, 23)),
+            vec![
+                (0, UVec2::new(0, 0), UVec2::new(80, 7)),
+                (2, UVec2::new(0, 8), UVec2::new(40, 7)),
+                (3, UVec2::new(41, 8), UVec2::new(39, 7)),
+                (1, UVec2::new(0, 16), UVec2::new(80, 7)),
+            ]
+        );
+        assert_eq!(placements.len(), 4);
+        assert_eq!(
+            borders,
+            vec![
+                Border {
+                    position: UVec2::new(0, 7),
+                    length: 80,
+                    vertical: false,
+                },
+                Border {
+                    position: UVec2::new(40, 8),
+                    length: 7,
+                    vertical: true,
+                },
+                Border {
+                    position: UVec2::new(0, 15),
+                    length: 80,
+                    vertical: false,
+                },
+            ]
+        );
+
+        // The space of a removed window goes to the others
+        assert!(layout.remove(WindowId(2)));
+        assert_eq!(layout.get_window_ids(), [0, 3, 1].map(WindowId));
+        assert!(layout.replace(WindowId(3), WindowId(5)));
+        assert!(layout.remove(WindowId(0)));
+        assert!(layout.remove(WindowId(1)));
+        assert_eq!(layout, Layout::Window(WindowId(5)));
+        assert!(!layout.remove(WindowId(5)));
+    }
+}
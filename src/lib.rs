@@ -1,466 +1,1823 @@
 mod action;
 mod buffer;
+mod completion;
+mod config;
+mod event_source;
+mod filetype;
+mod help;
+mod highlight;
+mod layout;
 mod managers;
+mod messages;
+mod options;
+mod registers;
+mod script;
+mod search;
+mod shell;
+mod sort;
 mod state;
+mod syntax;
+#[cfg(test)]
+mod test_editor;
 mod ui;
+pub mod version;
 mod window;
 
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::stdout,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
 
+use anyhow::{anyhow, bail};
 use api::{
-    BufferId, CuprumApiRequestKind, CuprumApiResponse, CuprumApiResponseKind, Mode, Position,
-    WindowId,
+    BufferId, BufferInfo, Capability, CuprumApiError, CuprumApiRequestKind, CuprumApiResponse,
+    CuprumApiResponseKind, CuprumApiResult, CuprumNotification, EditorEvent, EventKind,
+    HistoryMessage, Mode, PROTOCOL_VERSION, PluginRequestKind, PluginResponseKind, Position,
+    SearchDirection, SplitDirection, WindowId,
 };
-use builtin::{Builtin, BuiltinApiProvider};
-use crossterm::event::{self, Event};
-use plugin_manager::PluginManager;
+use builtin::{Builtin, BuiltinAction, BuiltinApiProvider, Messages};
+use crossterm::event::{self, Event, KeyCode as TermKeyCode, KeyModifiers};
+use plugin_manager::{PluginChannel, PluginManager};
 use tokio::{
-    sync::{Mutex, MutexGuard},
-    time::sleep,
+    sync::{Mutex, Notify, oneshot, watch},
+    task::JoinSet,
+    time::{sleep, timeout},
+};
+use utils::{
+    grapheme,
+    term::get_terminal_size_or_default,
+    vec2::{IVec2, UVec2},
 };
-use utils::vec2::{IVec2, UVec2};
 
 use crate::{
-    action::Action,
-    buffer::Buffer,
-    state::EditorState,
+    action::{Action, PluginAction},
+    buffer::{Buffer, char_to_byte},
+    completion::PluginSource,
+    config::{Config, get_config_path},
+    event_source::{CommandSource, EventSource, Input, Recorder, ReplaySource, TerminalSource},
+    highlight::{Highlight, HighlightSource},
+    messages::MessageLevel,
+    registers::Register,
+    search::{find_all, search},
+    shell::{SHELL_TIMEOUT, ShellOutput, run_shell},
+    state::{BUFFER_CHANGE_DEBOUNCE, CURSOR_MOVED_DEBOUNCE, EditorState, READONLY_ERROR},
     ui::{
-        input::{InputManager, KeyCode},
-        render::Renderer,
+        input::{InputManager, Key, KeyCode, MapMode, parse_keys},
+        render::{Frame, Renderer, restore_on_panic, restore_terminal},
+        theme::Theme,
     },
     window::Window,
 };
 
+/// Get the buffer a request modifies, `Some(None)` meaning the active buffer
+fn edit_target(request: &CuprumApiRequestKind) -> Option<Option<BufferId>> {
+    match request {
+        CuprumApiRequestKind::InsertChar { buf, .. }
+        | CuprumApiRequestKind::InsertLine { buf, .. }
+        | CuprumApiRequestKind::ReplaceChar { buf, .. }
+        | CuprumApiRequestKind::ReplaceLine { buf, .. }
+        | CuprumApiRequestKind::ReplaceAllLines { buf, .. }
+        | CuprumApiRequestKind::ReplaceContent { buf, .. }
+        | CuprumApiRequestKind::ApplyEdits { buf, .. }
+        | CuprumApiRequestKind::ApplyTextEdits { buf, .. }
+        | CuprumApiRequestKind::RemoveChar { buf, .. }
+        | CuprumApiRequestKind::RemoveLine { buf, .. }
+        | CuprumApiRequestKind::SplitLine { buf, .. }
+        | CuprumApiRequestKind::JoinLines { buf, .. } => Some(*buf),
+        _ => None,
+    }
+}
+
+/// Get a window by id or the active one
+fn get_window(
+    state: &EditorState,
+    win: Option<WindowId>,
+) -> Result<Arc<Mutex<Window>>, CuprumApiError> {
+    if let Some(win) = win {
+        state.window_manager.get_window(win)
+    } else {
+        state.get_active_window()
+    }
+    .ok_or_else(|| CuprumApiError::NotFound("window".to_string()))
+}
+
+/// Get a buffer by id or the one of the active window
+async fn get_buffer(
+    state: &EditorState,
+    buf: Option<BufferId>,
+) -> Result<Arc<Mutex<Buffer>>, CuprumApiError> {
+    if let Some(buf) = buf {
+        state.buffer_manager.get_buffer(buf)
+    } else if let Some(active) = state.get_active_window() {
+        let win = active.lock().await;
+        Some(win.get_buffer())
+    } else {
+        None
+    }
+    .ok_or_else(|| CuprumApiError::NotFound("buffer".to_string()))
+}
+
+/// Fail unless line `y` exists
+fn check_line(buf: &Buffer, y: usize) -> Result<(), CuprumApiError> {
+    if y < buf.get_line_count() {
+        Ok(())
+    } else {
+        Err(CuprumApiError::OutOfRange)
+    }
+}
+
+/// Fail if the buffer an edit is for is read-only, telling the user
+async fn check_writable(
+    state: &mut EditorState,
+    buf: Option<BufferId>,
+) -> Result<(), CuprumApiError> {
+    if let Ok(buf) = get_buffer(state, buf).await
+        && buf.lock().await.is_readonly()
+    {
+        state.messages.error(READONLY_ERROR);
+        return Err(CuprumApiError::ReadOnly);
+    }
+    Ok(())
+}
+
+/// The id and the buffer `SaveBuffer` writes
+async fn get_save_target(
+    state: &EditorState,
+    buf: Option<BufferId>,
+) -> Result<(BufferId, Arc<Mutex<Buffer>>), CuprumApiError> {
+    let id = match buf {
+        Some(id) => id,
+        None => get_window(state, None)?.lock().await.get_buffer_id(),
+    };
+    let buf = state
+        .buffer_manager
+        .get_buffer(id)
+        .ok_or_else(|| CuprumApiError::NotFound("buffer".to_string()))?;
+    Ok((id, buf))
+}
+
+fn no_file_name(state: &mut EditorState) -> CuprumApiError {
+    state.messages.error("E32: No file name");
+    CuprumApiError::NoFileName
+}
+
+/// How long a save waits for each plugin subscribed to `BufWritePre`
+const BUF_WRITE_PRE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Let the plugins subscribed to `BufWritePre` edit a buffer before it is written, one
+/// after another. Returns the errors to show, as the buffer is written anyway.
+async fn run_buf_write_pre(
+    plugins: Vec<PluginChannel>,
+    id: BufferId,
+    buf: &Mutex<Buffer>,
+) -> Vec<String> {
+    let mut errors = Vec::new();
+    for plugin in plugins {
+        let content = buf.lock().await.get_content();
+        let request = PluginRequestKind::BufWritePre {
+            buf: id,
+            content: content.clone(),
+        };
+        let response = timeout(BUF_WRITE_PRE_TIMEOUT, plugin.request(request))
+            .await
+            .unwrap_or(Err(CuprumApiError::Timeout));
+        let result = match response {
+            Ok(PluginResponseKind::Edits(edits)) if edits.is_empty() => Ok(()),
+            Ok(PluginResponseKind::Edits(_)) if !plugin.is_allowed(Capability::WriteBuffers) => {
+                Err(CuprumApiError::PermissionDenied(Capability::WriteBuffers))
+            }
+            Ok(PluginResponseKind::Edits(edits)) => {
+                let mut buf = buf.lock().await;
+                // The edits are of `content`, and must not overwrite what was typed since
+                if buf.get_content() == content {
+                    buf.apply_range_edits(edits)
+                        .map_err(|err| CuprumApiError::InvalidArgument(err.to_string()))
+                } else {
+                    Err(CuprumApiError::InvalidArgument(
+                        "the buffer changed while it was edited".to_string(),
+                    ))
+                }
+            }
+            Ok(response) => Err(CuprumApiError::InvalidArgument(format!(
+                "unexpected {:?}",
+                response
+            ))),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            errors.push(format!("{}: BufWritePre: {}", plugin.get_name(), err));
+        }
+    }
+    errors
+}
+
+/// Write a buffer to its file on a blocking thread, returning the message to show
+async fn write_buffer(buf: Arc<Mutex<Buffer>>) -> Result<anyhow::Result<String>, CuprumApiError> {
+    tokio::task::spawn_blocking(move || {
+        let mut buf = buf.blocking_lock();
+        buf.save()?;
+        let content = buf.get_content();
+        anyhow::Ok(format!(
+            "\"{}\" {}L, {}B written",
+            buf.get_name(),
+            buf.get_line_count(),
+            content.len()
+        ))
+    })
+    .await
+    .map_err(|err| CuprumApiError::Io(err.to_string()))
+}
+
+/// Show the errors of `run_buf_write_pre` and keep the cursors on the edited buffer
+async fn finish_buf_write_pre(state: &mut EditorState, id: BufferId, errors: Vec<String>) {
+    for err in errors {
+        state.messages.error(err);
+    }
+    state.clamp_cursors(Some(id)).await;
+}
+
+/// Show how saving went and tell plugins about it
+async fn finish_save(
+    state: &mut EditorState,
+    id: BufferId,
+    saved: anyhow::Result<String>,
+) -> CuprumApiResult {
+    match saved {
+        Ok(message) => state.messages.info(message),
+        Err(err) => {
+            state
+                .messages
+                .error(format!("E212: Can't open file for writing: {}", err));
+            return Err(CuprumApiError::Io(err.to_string()));
+        }
+    }
+    state.emit(EditorEvent::BufWritePost { buf: id }).await;
+    Ok(None)
+}
+
 pub struct EditorApiHandler {
     state: Arc<Mutex<EditorState>>,
+    plugin: Option<PluginChannel>,
 }
 
 impl EditorApiHandler {
     /// Create a new editor API handler
     pub fn new(state: Arc<Mutex<EditorState>>) -> Self {
-        Self { state }
+        Self {
+            state,
+            plugin: None,
+        }
     }
 
-    /// Process a Cuprum API request
-    async fn process(&mut self, request: CuprumApiRequestKind) -> Option<CuprumApiResponseKind> {
+    /// Create a new editor API handler for requests from a plugin
+    pub fn with_plugin(state: Arc<Mutex<EditorState>>, plugin: PluginChannel) -> Self {
+        Self {
+            state,
+            plugin: Some(plugin),
+        }
+    }
+
+    /// Wait for the user to answer a prompt, without holding the state lock
+    async fn prompt_input(&mut self, prompt: &str) -> CuprumApiResult {
+        let answer = {
+            let mut state = self.state.lock().await;
+            let answer = state
+                .start_input_prompt(prompt)
+                .await
+                .map_err(|err| CuprumApiError::NotAllowed(err.to_string()))?;
+            // Shown while the answer is awaited
+            state.touch();
+            answer
+        };
+
+        let answer = match &self.plugin {
+            Some(plugin) => tokio::select! {
+                answer = answer => answer.ok().flatten(),
+                _ = plugin.closed() => {
+                    self.state.lock().await.cancel_input_prompt().await;
+                    None
+                }
+            },
+            None => answer.await.ok().flatten(),
+        };
+        Ok(Some(CuprumApiResponseKind::PromptInput(answer)))
+    }
+
+    /// Process the requests of a batch in order under one lock of the state, so requests
+    /// from elsewhere and rendering never see it half done. A failed request does not stop
+    /// the ones after it.
+    async fn process_batch(&mut self, requests: Vec<CuprumApiRequestKind>) -> CuprumApiResult {
         let mut state = self.state.lock().await;
+        let mut results = Vec::with_capacity(requests.len());
+        for request in requests {
+            let result = match self.check_permission(&mut state, &request) {
+                Err(err) => Err(err),
+                Ok(()) => match request {
+                    CuprumApiRequestKind::Batch { .. } => Err(CuprumApiError::InvalidArgument(
+                        "batches cannot be nested".to_string(),
+                    )),
+                    // The answer needs the state, which the batch holds
+                    CuprumApiRequestKind::PromptInput { .. } => Err(CuprumApiError::NotAllowed(
+                        "prompts cannot be batched".to_string(),
+                    )),
+                    CuprumApiRequestKind::SaveBuffer { buf, .. } => {
+                        self.save_buffer_locked(&mut state, buf).await
+                    }
+                    request => {
+                        match edit_target(&request) {
+                            Some(buf) => check_writable(&mut state, buf).await,
+                            None => Ok(()),
+                        }?;
+                        self.process_locked(&mut state, request).await
+                    }
+                },
+            };
+            results.push(result);
+        }
+        Ok(Some(CuprumApiResponseKind::Batch(results)))
+    }
 
-        async fn get_window(
-            state: MutexGuard<'_, EditorState>,
-            win: Option<WindowId>,
-        ) -> Option<Arc<Mutex<Window>>> {
-            if let Some(win) = win {
-                state.window_manager.get_window(win)
-            } else {
-                state.get_active_window()
-            }
+    /// Reject requests the plugin has no capability for, telling the user
+    fn check_permission(
+        &self,
+        state: &mut EditorState,
+        request: &CuprumApiRequestKind,
+    ) -> Result<(), CuprumApiError> {
+        let Some(plugin) = &self.plugin else {
+            return Ok(());
+        };
+        let denied = match request {
+            CuprumApiRequestKind::Subscribe { events } => events
+                .iter()
+                .filter_map(EventKind::capability)
+                .find(|capability| !plugin.is_allowed(*capability)),
+            _ => request
+                .capability()
+                .filter(|capability| !plugin.is_allowed(*capability)),
+        };
+        if let Some(capability) = denied {
+            let err = CuprumApiError::PermissionDenied(capability);
+            state.messages.error(format!(
+                "{}: {}: {}",
+                plugin.get_name(),
+                request.method_name(),
+                err
+            ));
+            return Err(err);
         }
+        Ok(())
+    }
+
+    /// Process a Cuprum API request
+    async fn process(&mut self, request: CuprumApiRequestKind) -> CuprumApiResult {
+        let result = self.process_request(request).await;
+        self.state.lock().await.touch();
+        result
+    }
 
-        async fn get_buffer(
-            state: MutexGuard<'_, EditorState>,
-            buf: Option<BufferId>,
-        ) -> Option<Arc<Mutex<Buffer>>> {
-            if let Some(buf) = buf {
-                state.buffer_manager.get_buffer(buf)
-            } else if let Some(active) = state.get_active_window() {
-                let win = active.lock().await;
-                Some(win.get_buffer())
-            } else {
-                None
+    async fn process_request(&mut self, request: CuprumApiRequestKind) -> CuprumApiResult {
+        self.check_permission(&mut *self.state.lock().await, &request)?;
+        match request {
+            CuprumApiRequestKind::PromptInput { prompt } => self.prompt_input(&prompt).await,
+            CuprumApiRequestKind::Batch { requests } => self.process_batch(requests).await,
+            CuprumApiRequestKind::SaveBuffer { buf, .. } => self.save_buffer(buf).await,
+            request => {
+                if let Some(buf) = edit_target(&request) {
+                    // The buffer may be busy being saved, so wait for it without the state
+                    let buf = get_buffer(&*self.state.lock().await, buf).await.ok();
+                    let readonly = match buf {
+                        Some(buf) => buf.lock().await.is_readonly(),
+                        None => false,
+                    };
+                    if readonly {
+                        self.state.lock().await.messages.error(READONLY_ERROR);
+                        return Err(CuprumApiError::ReadOnly);
+                    }
+                }
+                let mut state = self.state.lock().await;
+                self.process_locked(&mut state, request).await
             }
         }
+    }
+
+    /// Save a buffer. Other requests and rendering go on while the file is written.
+    async fn save_buffer(&self, buf: Option<BufferId>) -> CuprumApiResult {
+        let (id, buf) = get_save_target(&*self.state.lock().await, buf).await?;
+        if buf.lock().await.is_scratch() {
+            return Err(no_file_name(&mut *self.state.lock().await));
+        }
+        let plugins = self
+            .state
+            .lock()
+            .await
+            .get_subscribers(EventKind::BufWritePre);
+        let errors = run_buf_write_pre(plugins, id, &buf).await;
+        let saved = write_buffer(buf).await?;
+        let mut state = self.state.lock().await;
+        finish_buf_write_pre(&mut state, id, errors).await;
+        finish_save(&mut state, id, saved).await
+    }
+
+    /// Save a buffer while holding the state, as part of a batch
+    async fn save_buffer_locked(
+        &self,
+        state: &mut EditorState,
+        buf: Option<BufferId>,
+    ) -> CuprumApiResult {
+        let (id, buf) = get_save_target(state, buf).await?;
+        if buf.lock().await.is_scratch() {
+            return Err(no_file_name(state));
+        }
+        let errors =
+            run_buf_write_pre(state.get_subscribers(EventKind::BufWritePre), id, &buf).await;
+        finish_buf_write_pre(state, id, errors).await;
+        let saved = write_buffer(buf).await?;
+        finish_save(state, id, saved).await
+    }
+
+    /// Process a request on the locked state
+    async fn process_locked(
+        &self,
+        state: &mut EditorState,
+        request: CuprumApiRequestKind,
+    ) -> CuprumApiResult {
+        let target = edit_target(&request);
+        let result = self.process_with_state(state, request).await;
+        // Edits can leave the cursors on the buffer past its end
+        if let Some(buf) = target
+            && result.is_ok()
+        {
+            state.clamp_cursors(buf).await;
+        }
+        result
+    }
 
+    /// Process a request that works on the state
+    async fn process_with_state(
+        &self,
+        state: &mut EditorState,
+        request: CuprumApiRequestKind,
+    ) -> CuprumApiResult {
         match request {
-            CuprumApiRequestKind::ChangeMode(mode) => {
+            CuprumApiRequestKind::ChangeMode { mode } => {
                 state.set_mode(mode).await;
-                None
+                Ok(None)
+            }
+            CuprumApiRequestKind::GetMode {} => {
+                let mode = state.mode.lock().await.clone();
+                Ok(Some(CuprumApiResponseKind::GetMode(mode)))
             }
-            CuprumApiRequestKind::OpenFile(_path) => {
+            CuprumApiRequestKind::OpenFile { .. } => {
                 todo!()
             }
-            // TODO: Pathを使った処理の実装
-            CuprumApiRequestKind::SaveBuffer(buf, _path) => {
-                if let Some(buf) = get_buffer(state, buf).await {
-                    let mut buf = buf.lock().await;
-                    buf.save().ok();
-                }
-
-                None
+            CuprumApiRequestKind::GetLineCount { buf } => {
+                let buf = get_buffer(state, buf).await?;
+                let count = buf.lock().await.get_line_count();
+                Ok(Some(CuprumApiResponseKind::GetLineCount(count)))
             }
-            CuprumApiRequestKind::GetLineCount(buf) => {
-                if let Some(buf) = get_buffer(state, buf).await {
-                    let buf = buf.lock().await;
-                    let count = buf.get_line_count();
-                    Some(CuprumApiResponseKind::GetLineCount(count))
-                } else {
-                    None
+            CuprumApiRequestKind::GetLineLength { buf, y } => {
+                let buf = get_buffer(state, buf).await?;
+                let length = buf
+                    .lock()
+                    .await
+                    .get_line_length(y)
+                    .ok_or(CuprumApiError::OutOfRange)?;
+                Ok(Some(CuprumApiResponseKind::GetLineLength(length)))
+            }
+            CuprumApiRequestKind::GetChar { buf, pos } => {
+                let buf = get_buffer(state, buf).await?;
+                let ch = buf
+                    .lock()
+                    .await
+                    .get_char(pos)
+                    .ok_or(CuprumApiError::OutOfRange)?;
+                Ok(Some(CuprumApiResponseKind::GetChar(ch)))
+            }
+            CuprumApiRequestKind::GetLine { buf, y } => {
+                let buf = get_buffer(state, buf).await?;
+                let line = buf
+                    .lock()
+                    .await
+                    .get_line(y)
+                    .ok_or(CuprumApiError::OutOfRange)?;
+                Ok(Some(CuprumApiResponseKind::GetLine(line)))
+            }
+            CuprumApiRequestKind::GetAllLines { buf } => {
+                let buf = get_buffer(state, buf).await?;
+                let lines = buf.lock().await.get_all_lines();
+                Ok(Some(CuprumApiResponseKind::GetAllLines(lines)))
+            }
+            CuprumApiRequestKind::GetLines {
+                buf,
+                start_line,
+                count,
+            } => {
+                let buf = get_buffer(state, buf).await?;
+                let buf = buf.lock().await;
+                if start_line >= buf.get_line_count() {
+                    return Err(CuprumApiError::OutOfRange);
                 }
+                let lines = buf.get_lines_range(start_line, count).to_vec();
+                Ok(Some(CuprumApiResponseKind::GetLines(lines)))
+            }
+            CuprumApiRequestKind::GetText { buf, start, end } => {
+                let buf = get_buffer(state, buf).await?;
+                let text = buf
+                    .lock()
+                    .await
+                    .get_text(start, end)
+                    .ok_or(CuprumApiError::OutOfRange)?;
+                Ok(Some(CuprumApiResponseKind::GetText(text)))
+            }
+            CuprumApiRequestKind::GetContent { buf } => {
+                let buf = get_buffer(state, buf).await?;
+                let content = buf.lock().await.get_content();
+                Ok(Some(CuprumApiResponseKind::GetContent(content)))
             }
-            CuprumApiRequestKind::GetLineLength(buf, y) => {
-                if let Some(buf) = get_buffer(state, buf).await {
-                    let buf = buf.lock().await;
-                    if let Some(length) = buf.get_line_length(y) {
-                        return Some(CuprumApiResponseKind::GetLineLength(length));
+            CuprumApiRequestKind::GetBufferInfo { buf } => {
+                let (id, buf) = match buf {
+                    Some(id) => (id, get_buffer(state, Some(id)).await?),
+                    None => {
+                        let win = get_window(state, None)?;
+                        let win = win.lock().await;
+                        (win.get_buffer_id(), win.get_buffer())
                     }
-                }
-
-                None
+                };
+                let buf = buf.lock().await;
+                Ok(Some(CuprumApiResponseKind::GetBufferInfo(BufferInfo {
+                    id,
+                    path: buf
+                        .get_path()
+                        .map(|path| path.to_string_lossy().to_string()),
+                    name: buf.get_name(),
+                    dirty: buf.is_dirty(),
+                    line_count: buf.get_line_count(),
+                    read_only: buf.is_readonly(),
+                    filetype: buf.get_filetype().map(str::to_string),
+                })))
+            }
+            CuprumApiRequestKind::Search {
+                buf,
+                pattern,
+                from,
+                direction,
+            } => {
+                let buf = get_buffer(state, buf).await?;
+                let lines = buf.lock().await.get_all_lines();
+                let found = search(
+                    &lines,
+                    &pattern,
+                    from,
+                    direction == SearchDirection::Forward,
+                )
+                .map_err(|err| CuprumApiError::InvalidArgument(err.to_string()))?;
+                Ok(Some(CuprumApiResponseKind::Search(found)))
             }
-            CuprumApiRequestKind::GetChar(buf, pos) => {
-                if let Some(buf) = get_buffer(state, buf).await {
-                    let buf = buf.lock().await;
-                    if let Some(ch) = buf.get_char(pos) {
-                        return Some(CuprumApiResponseKind::GetChar(ch));
+            CuprumApiRequestKind::FindAll { buf, pattern } => {
+                let buf = get_buffer(state, buf).await?;
+                let lines = buf.lock().await.get_all_lines();
+                let ranges = find_all(&lines, &pattern)
+                    .map_err(|err| CuprumApiError::InvalidArgument(err.to_string()))?;
+                Ok(Some(CuprumApiResponseKind::FindAll(ranges)))
+            }
+            CuprumApiRequestKind::GetEditorInfo {} => Ok(Some(
+                CuprumApiResponseKind::GetEditorInfo(version::get_editor_info()),
+            )),
+            CuprumApiRequestKind::ListBuffers {} => Ok(Some(CuprumApiResponseKind::ListBuffers(
+                state.buffer_manager.get_buffer_ids(),
+            ))),
+            CuprumApiRequestKind::ListWindows {} => {
+                let mut windows = Vec::new();
+                for id in state.window_manager.get_window_ids() {
+                    if let Some(win) = state.window_manager.get_window(id) {
+                        windows.push((id, win.lock().await.get_buffer_id()));
                     }
                 }
-
-                None
+                Ok(Some(CuprumApiResponseKind::ListWindows(windows)))
             }
-            CuprumApiRequestKind::GetLine(buf, y) => {
-                if let Some(buf) = get_buffer(state, buf).await {
-                    let buf = buf.lock().await;
-                    if let Some(line) = buf.get_line(y) {
-                        return Some(CuprumApiResponseKind::GetLine(line));
-                    }
+            CuprumApiRequestKind::GetActiveWindow {} => Ok(Some(
+                CuprumApiResponseKind::GetActiveWindow(state.get_active_window_id()),
+            )),
+            CuprumApiRequestKind::OpenWindow { buf, split } => {
+                let buf = match buf {
+                    Some(buf) => buf,
+                    None => match state.get_active_window() {
+                        Some(win) => win.lock().await.get_buffer_id(),
+                        None => return Err(CuprumApiError::NotFound("window".to_string())),
+                    },
+                };
+                let id = state
+                    .open_window(buf, split)
+                    .await
+                    .ok_or_else(|| CuprumApiError::NotFound("buffer".to_string()))?;
+                Ok(Some(CuprumApiResponseKind::OpenWindow(id)))
+            }
+            CuprumApiRequestKind::CloseWindow { win } => {
+                let id = win.unwrap_or(state.get_active_window_id());
+                if state.window_manager.get_window(id).is_none() {
+                    return Err(CuprumApiError::NotFound("window".to_string()));
                 }
-
-                None
+                if !state.close_window_by_id(id).await {
+                    return Err(CuprumApiError::NotAllowed(
+                        "cannot close the last window".to_string(),
+                    ));
+                }
+                Ok(None)
             }
-            CuprumApiRequestKind::GetAllLines(buf) => {
-                if let Some(buf) = get_buffer(state, buf).await {
-                    let buf = buf.lock().await;
-                    let lines = buf.get_all_lines();
-                    Some(CuprumApiResponseKind::GetAllLines(lines))
-                } else {
-                    None
+            CuprumApiRequestKind::InsertChar { buf, pos, ch } => {
+                let buf = get_buffer(state, buf).await?;
+                let mut buf = buf.lock().await;
+                match buf.get_line_length(pos.y) {
+                    Some(len) if pos.x <= len => {
+                        buf.insert_char(pos, ch);
+                        Ok(None)
+                    }
+                    _ => Err(CuprumApiError::OutOfRange),
                 }
             }
-            CuprumApiRequestKind::GetContent(buf) => {
-                if let Some(buf) = get_buffer(state, buf).await {
-                    let buf = buf.lock().await;
-                    let content = buf.get_content();
-                    Some(CuprumApiResponseKind::GetContent(content))
-                } else {
-                    None
+            CuprumApiRequestKind::InsertLine { buf, y, line } => {
+                let buf = get_buffer(state, buf).await?;
+                let mut buf = buf.lock().await;
+                if y > buf.get_line_count() {
+                    return Err(CuprumApiError::OutOfRange);
                 }
+                buf.insert_line(y, line);
+                Ok(None)
             }
-            CuprumApiRequestKind::InsertChar(buf, pos, ch) => {
-                if let Some(buf) = get_buffer(state, buf).await {
-                    let mut buf = buf.lock().await;
-                    buf.insert_char(pos, ch);
+            CuprumApiRequestKind::ReplaceChar { buf, pos, ch } => {
+                let buf = get_buffer(state, buf).await?;
+                let mut buf = buf.lock().await;
+                if buf.get_char(pos).is_none() {
+                    return Err(CuprumApiError::OutOfRange);
                 }
-
-                None
+                let ch = buf
+                    .replace_char(pos, ch)
+                    .ok_or(CuprumApiError::OutOfRange)?;
+                Ok(Some(CuprumApiResponseKind::ReplaceChar(ch)))
             }
-            CuprumApiRequestKind::InsertLine(buf, y, line) => {
-                if let Some(buf) = get_buffer(state, buf).await {
-                    let mut buf = buf.lock().await;
-                    buf.insert_line(y, line);
+            CuprumApiRequestKind::ReplaceLine { buf, y, line } => {
+                let buf = get_buffer(state, buf).await?;
+                let line = buf
+                    .lock()
+                    .await
+                    .replace_line(y, line)
+                    .ok_or(CuprumApiError::OutOfRange)?;
+                Ok(Some(CuprumApiResponseKind::ReplaceLine(line)))
+            }
+            CuprumApiRequestKind::ReplaceAllLines { buf, lines } => {
+                let buf = get_buffer(state, buf).await?;
+                let lines = buf.lock().await.replace_all_lines(lines);
+                Ok(Some(CuprumApiResponseKind::ReplaceAllLines(lines)))
+            }
+            CuprumApiRequestKind::ReplaceContent { buf, content } => {
+                let buf = get_buffer(state, buf).await?;
+                let content = buf.lock().await.replace_content(content);
+                Ok(Some(CuprumApiResponseKind::ReplaceContent(content)))
+            }
+            CuprumApiRequestKind::ApplyEdits { buf, edits } => {
+                let buf = get_buffer(state, buf).await?;
+                buf.lock()
+                    .await
+                    .apply_edits(edits)
+                    .map_err(|err| CuprumApiError::InvalidArgument(err.to_string()))?;
+                Ok(None)
+            }
+            CuprumApiRequestKind::ApplyTextEdits { buf, edits } => {
+                let buf = get_buffer(state, buf).await?;
+                buf.lock()
+                    .await
+                    .apply_range_edits(edits)
+                    .map_err(|err| CuprumApiError::InvalidArgument(err.to_string()))?;
+                Ok(None)
+            }
+            CuprumApiRequestKind::RemoveChar { buf, pos } => {
+                let buf = get_buffer(state, buf).await?;
+                let mut buf = buf.lock().await;
+                // Lines are joined with `join_lines`, not by removing the line break
+                let line_len = buf
+                    .get_line_length(pos.y)
+                    .ok_or(CuprumApiError::OutOfRange)?;
+                if pos.x >= line_len {
+                    return Err(CuprumApiError::OutOfRange);
                 }
-
-                None
+                let ch = buf.remove_char(pos).ok_or(CuprumApiError::OutOfRange)?;
+                Ok(Some(CuprumApiResponseKind::RemoveChar(ch)))
             }
-            CuprumApiRequestKind::ReplaceChar(buf, pos, ch) => {
-                if let Some(buf) = get_buffer(state, buf).await {
-                    let mut buf = buf.lock().await;
-                    if let Some(ch) = buf.replace_char(pos, ch) {
-                        return Some(CuprumApiResponseKind::ReplaceChar(ch));
+            CuprumApiRequestKind::RemoveLine { buf, y } => {
+                let buf = get_buffer(state, buf).await?;
+                let mut buf = buf.lock().await;
+                let line = buf.remove_line(y).ok_or(CuprumApiError::OutOfRange)?;
+                Ok(Some(CuprumApiResponseKind::RemoveLine(line)))
+            }
+            CuprumApiRequestKind::SplitLine { buf, pos } => {
+                let buf = get_buffer(state, buf).await?;
+                let mut buf = buf.lock().await;
+                match buf.get_line_length(pos.y) {
+                    Some(len) if pos.x <= len => {
+                        buf.split_line(pos);
+                        Ok(None)
                     }
+                    _ => Err(CuprumApiError::OutOfRange),
                 }
-
-                None
             }
-            CuprumApiRequestKind::ReplaceLine(buf, y, line) => {
-                if let Some(buf) = get_buffer(state, buf).await {
-                    let mut buf = buf.lock().await;
-                    if let Some(line) = buf.replace_line(y, line) {
-                        return Some(CuprumApiResponseKind::ReplaceLine(line));
-                    }
+            CuprumApiRequestKind::JoinLines { buf, y } => {
+                let buf = get_buffer(state, buf).await?;
+                let mut buf = buf.lock().await;
+                check_line(&buf, y + 1)?;
+                buf.join_lines(y);
+                Ok(None)
+            }
+            CuprumApiRequestKind::GetCursor { win } => {
+                let win = get_window(state, win)?;
+                let cursor = win.lock().await.get_render_cursor().await;
+                Ok(Some(CuprumApiResponseKind::GetCursor(cursor)))
+            }
+            CuprumApiRequestKind::GetVisualStart { win } => {
+                let win = get_window(state, win)?;
+                let cursor = win.lock().await.get_visual_start().await;
+                Ok(Some(CuprumApiResponseKind::GetVisualStart(cursor)))
+            }
+            CuprumApiRequestKind::GetSelection { win } => {
+                let win = get_window(state, win)?;
+                let selection = win.lock().await.get_selection().await;
+                Ok(Some(CuprumApiResponseKind::GetSelection(selection)))
+            }
+            CuprumApiRequestKind::MoveBy { win, offset } => {
+                let win = get_window(state, win)?;
+                win.lock().await.move_by(offset).await;
+                Ok(None)
+            }
+            CuprumApiRequestKind::MoveToX { win, pos } => {
+                let win = get_window(state, win)?;
+                let mut win = win.lock().await;
+                match pos {
+                    Position::Number(x) => win.move_to_x(x).await,
+                    Position::Start => win.move_to_line_start(),
+                    Position::End => win.move_to_line_end().await,
                 }
-
-                None
+                Ok(None)
             }
-            CuprumApiRequestKind::ReplaceAllLines(buf, lines) => {
-                if let Some(buf) = get_buffer(state, buf).await {
-                    let mut buf = buf.lock().await;
-                    let lines = buf.replace_all_lines(lines);
-                    Some(CuprumApiResponseKind::ReplaceAllLines(lines))
-                } else {
-                    None
+            CuprumApiRequestKind::MoveToY { win, pos } => {
+                let win = get_window(state, win)?;
+                let mut win = win.lock().await;
+                match pos {
+                    Position::Number(y) => win.move_to_y(y).await,
+                    Position::Start => win.move_to_buffer_start(),
+                    Position::End => win.move_to_buffer_end().await,
                 }
+                Ok(None)
             }
-            CuprumApiRequestKind::ReplaceContent(buf, content) => {
-                if let Some(buf) = get_buffer(state, buf).await {
-                    let mut buf = buf.lock().await;
-                    let content = buf.replace_content(content);
-                    Some(CuprumApiResponseKind::ReplaceContent(content))
-                } else {
-                    None
+            CuprumApiRequestKind::Subscribe { events } => {
+                let Some(plugin) = &self.plugin else {
+                    return Err(CuprumApiError::NotAllowed(
+                        "only plugins can subscribe to events".to_string(),
+                    ));
+                };
+                state.subscribe(plugin, events);
+                Ok(None)
+            }
+            CuprumApiRequestKind::RegisterCommand { name } => {
+                let Some(plugin) = &self.plugin else {
+                    return Err(CuprumApiError::NotAllowed(
+                        "only plugins can register commands".to_string(),
+                    ));
+                };
+                state.plugin_commands.insert(name, plugin.clone());
+                Ok(None)
+            }
+            CuprumApiRequestKind::RegisterCompletionSource {
+                name,
+                trigger_chars,
+            } => {
+                let Some(plugin) = &self.plugin else {
+                    return Err(CuprumApiError::NotAllowed(
+                        "only plugins can register completion sources".to_string(),
+                    ));
+                };
+                state.completion.add_plugin_source(PluginSource {
+                    name,
+                    trigger_chars,
+                    plugin: plugin.clone(),
+                });
+                Ok(None)
+            }
+            CuprumApiRequestKind::GetMessages {} => Ok(Some(CuprumApiResponseKind::GetMessages(
+                state
+                    .messages
+                    .get_history()
+                    .map(|entry| HistoryMessage {
+                        time: entry.time.to_rfc3339(),
+                        is_error: entry.message.level == MessageLevel::Error,
+                        text: entry.message.text.clone(),
+                    })
+                    .collect(),
+            ))),
+            CuprumApiRequestKind::GetRegister { name } => {
+                if !registers::is_valid_name(name) {
+                    return Err(CuprumApiError::InvalidArgument(format!(
+                        "register {}",
+                        name
+                    )));
                 }
+                let text = state
+                    .registers
+                    .get(name)
+                    .map(|register| register.text.clone())
+                    .unwrap_or_default();
+                Ok(Some(CuprumApiResponseKind::GetRegister(text)))
             }
-            CuprumApiRequestKind::RemoveChar(buf, pos) => {
-                if let Some(buf) = get_buffer(state, buf).await {
-                    let mut buf = buf.lock().await;
-                    if let Some(ch) = buf.remove_char(pos) {
-                        return Some(CuprumApiResponseKind::RemoveChar(ch));
-                    }
+            CuprumApiRequestKind::SetRegister {
+                name,
+                text,
+                linewise,
+            } => {
+                if !registers::is_valid_name(name) {
+                    return Err(CuprumApiError::InvalidArgument(format!(
+                        "register {}",
+                        name
+                    )));
                 }
-
-                None
+                state.registers.set(name, Register { text, linewise });
+                Ok(None)
+            }
+            CuprumApiRequestKind::GetOption { name } => {
+                let value = state
+                    .options
+                    .get(&name)
+                    .map_err(|err| CuprumApiError::NotFound(err.to_string()))?;
+                Ok(Some(CuprumApiResponseKind::GetOption(value)))
             }
-            CuprumApiRequestKind::RemoveLine(buf, y) => {
-                if let Some(buf) = get_buffer(state, buf).await {
-                    let mut buf = buf.lock().await;
-                    if let Some(line) = buf.remove_line(y) {
-                        return Some(CuprumApiResponseKind::RemoveLine(line));
+            CuprumApiRequestKind::SetOption { name, value } => {
+                let old = state.options.clone();
+                state.options.set(&name, &value).map_err(|err| {
+                    if state.options.get(&name).is_err() {
+                        CuprumApiError::NotFound(err.to_string())
+                    } else {
+                        CuprumApiError::InvalidArgument(err.to_string())
                     }
+                })?;
+                state.keep_option_changes(&old);
+                state.emit_option_changes(&old).await;
+                Ok(None)
+            }
+            CuprumApiRequestKind::OpenPopup { popup } => {
+                let id = state.popup_manager.open_popup(popup);
+                Ok(Some(CuprumApiResponseKind::OpenPopup(id)))
+            }
+            CuprumApiRequestKind::UpdatePopup { id, popup } => {
+                if !state.popup_manager.update_popup(id, popup) {
+                    return Err(CuprumApiError::NotFound("popup".to_string()));
                 }
-
-                None
+                Ok(None)
             }
-            CuprumApiRequestKind::SplitLine(buf, pos) => {
-                if let Some(buf) = get_buffer(state, buf).await {
-                    let mut buf = buf.lock().await;
-                    buf.split_line(pos);
+            CuprumApiRequestKind::ClosePopup { id } => {
+                if !state.popup_manager.close_popup(id) {
+                    return Err(CuprumApiError::NotFound("popup".to_string()));
                 }
-
-                None
+                Ok(None)
             }
-            CuprumApiRequestKind::JoinLines(buf, y) => {
-                if let Some(buf) = get_buffer(state, buf).await {
-                    let mut buf = buf.lock().await;
-                    buf.join_lines(y);
+            CuprumApiRequestKind::PublishDiagnostics { buf, diagnostics } => {
+                let buf = get_buffer(state, buf).await?;
+                buf.lock().await.set_diagnostics(diagnostics);
+                Ok(None)
+            }
+            CuprumApiRequestKind::AddHighlight { y, start, end } => {
+                let buf = get_buffer(state, None).await?;
+                let line = buf
+                    .lock()
+                    .await
+                    .get_line(y)
+                    .ok_or(CuprumApiError::OutOfRange)?;
+                if start > end || end > line.chars().count() {
+                    return Err(CuprumApiError::OutOfRange);
                 }
-
-                None
+                state.highlights.add(Highlight {
+                    y,
+                    range: char_to_byte(&line, start)..char_to_byte(&line, end),
+                    source: HighlightSource::Plugin,
+                });
+                Ok(None)
             }
-            CuprumApiRequestKind::GetCursor(win) => {
-                if let Some(win) = get_window(state, win).await {
-                    let win = win.lock().await;
-                    let cursor = win.get_render_cursor().await;
-                    Some(CuprumApiResponseKind::GetCursor(cursor))
-                } else {
-                    None
+            CuprumApiRequestKind::ClearHighlights {} => {
+                state.highlights.clear(HighlightSource::Plugin);
+                Ok(None)
+            }
+            CuprumApiRequestKind::ShowHover { win, text } => {
+                let win = match win {
+                    Some(id) => state.window_manager.get_window(id),
+                    None => state.get_active_window(),
                 }
+                .ok_or_else(|| CuprumApiError::NotFound("window".to_string()))?;
+                let id = state.show_hover(&win, &text).await;
+                Ok(Some(CuprumApiResponseKind::ShowHover(id)))
             }
-            CuprumApiRequestKind::GetVisualStart(win) => {
-                if let Some(win) = get_window(state, win).await {
-                    let win = win.lock().await;
-                    let cursor = win.get_visual_start().await;
-                    Some(CuprumApiResponseKind::GetVisualStart(cursor))
-                } else {
-                    None
+            CuprumApiRequestKind::ShowMessage { level, text } => {
+                match level {
+                    api::MessageLevel::Info => state.messages.info(text),
+                    api::MessageLevel::Error => state.messages.error(text),
                 }
+                Ok(None)
+            }
+            CuprumApiRequestKind::PromptInput { .. }
+            | CuprumApiRequestKind::Batch { .. }
+            | CuprumApiRequestKind::SaveBuffer { .. } => {
+                unreachable!("handled before locking")
+            }
+            CuprumApiRequestKind::SetStatuslineSegment { id, text, align } => {
+                state.statusline.set(&id, &text, align);
+                Ok(None)
             }
-            CuprumApiRequestKind::MoveBy(win, offset) => {
-                if let Some(win) = get_window(state, win).await {
-                    let mut win = win.lock().await;
-                    win.move_by(offset).await;
+            CuprumApiRequestKind::RemoveStatuslineSegment { id } => {
+                if !state.statusline.remove(&id) {
+                    return Err(CuprumApiError::NotFound("statusline segment".to_string()));
                 }
+                Ok(None)
+            }
+            CuprumApiRequestKind::SetKeymap {
+                mode,
+                lhs,
+                rhs,
+                noremap,
+            } => {
+                MapMode::parse(&mode)
+                    .and_then(|modes| {
+                        let lhs = parse_keys(&lhs)?;
+                        state.map_keys(&modes, lhs, &rhs, noremap)
+                    })
+                    .map_err(|err| CuprumApiError::InvalidArgument(err.to_string()))?;
+                Ok(None)
+            }
+            CuprumApiRequestKind::RegisterKeymap { mode, keys, name } => {
+                let Some(plugin) = &self.plugin else {
+                    return Err(CuprumApiError::NotAllowed(
+                        "only plugins can register keymaps".to_string(),
+                    ));
+                };
+                let mode = MapMode::from_mode(&mode).ok_or_else(|| {
+                    CuprumApiError::InvalidArgument("no mappings in command mode".to_string())
+                })?;
+                let keys = parse_keys(&keys)
+                    .map_err(|err| CuprumApiError::InvalidArgument(err.to_string()))?;
+                state.map_plugin_keys(mode, keys, plugin.clone(), &name);
+                Ok(None)
+            }
+            CuprumApiRequestKind::DelKeymap { mode, lhs } => {
+                MapMode::parse(&mode)
+                    .and_then(|modes| {
+                        let lhs = parse_keys(&lhs)?;
+                        state.unmap_keys(&modes, &lhs)
+                    })
+                    .map_err(|err| CuprumApiError::NotFound(err.to_string()))?;
+                Ok(None)
+            }
+        }
+    }
+}
 
-                None
+/// Wait until the user presses Ctrl-C, discarding other input
+async fn wait_for_interrupt() {
+    loop {
+        while let Ok(true) = event::poll(Duration::ZERO) {
+            if let Ok(Event::Key(key)) = event::read()
+                && key.code == TermKeyCode::Char('c')
+                && key.modifiers.contains(KeyModifiers::CONTROL)
+            {
+                return;
             }
-            CuprumApiRequestKind::MoveToX(win, pos) => {
-                if let Some(win) = get_window(state, win).await {
-                    let mut win = win.lock().await;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+}
 
-                    match pos {
-                        Position::Number(x) => win.move_to_x(x).await,
-                        Position::Start => win.move_to_line_start(),
-                        Position::End => win.move_to_line_end().await,
-                    }
-                }
+/// Run a shell command that can be cancelled with Ctrl-C
+async fn run_shell_interruptible(
+    command: &str,
+    input: Option<String>,
+) -> anyhow::Result<ShellOutput> {
+    tokio::select! {
+        result = run_shell(command, input, SHELL_TIMEOUT) => result,
+        _ = wait_for_interrupt() => Err(anyhow!("Interrupted: {}", command)),
+    }
+}
+
+/// Answer requests queued on a builtin provider's `messages` each time `notify` fires
+async fn serve_builtin(
+    state: Arc<Mutex<EditorState>>,
+    messages: Arc<Mutex<Messages>>,
+    notify: Arc<Notify>,
+) {
+    let mut handler = EditorApiHandler::new(state);
 
-                None
+    loop {
+        notify.notified().await;
+        let messages = BuiltinApiProvider::get_messages(&messages).await;
+        for (notify, response, request) in messages {
+            let res = handler.process(request).await;
+            if let Err(err) = &res {
+                log::error!("{}", err);
             }
-            CuprumApiRequestKind::MoveToY(win, pos) => {
-                if let Some(win) = get_window(state, win).await {
-                    let mut win = win.lock().await;
 
-                    match pos {
-                        Position::Number(y) => win.move_to_y(y).await,
-                        Position::Start => win.move_to_buffer_start(),
-                        Position::End => win.move_to_buffer_end().await,
-                    }
-                }
+            let mut responses = response.lock().await;
+            *responses = res;
 
-                None
-            }
+            notify.notify_one();
+        }
+    }
+}
+
+/// Wait for a signal that ends the process: an interrupt, or on Unix a hangup or `kill`
+async fn termination_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = hangup.recv() => Ok(()),
+            _ = terminate.recv() => Ok(()),
         }
     }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
 }
 
+/// Run `task` until `shutdown` is set, dropping it at the await it is waiting at
+async fn until_shutdown(mut shutdown: watch::Receiver<bool>, task: impl Future<Output = ()>) {
+    tokio::select! {
+        _ = task => {}
+        _ = shutdown.wait_for(|shutdown| *shutdown) => {}
+    }
+}
+
+/// What the editor is started with, from the command line
+#[derive(Debug, Default)]
+pub struct StartupArgs {
+    pub files: Vec<String>,
+    /// Config file to read instead of the usual one
+    pub config: Option<PathBuf>,
+    /// Start with built-in defaults only, without the config file, plugins and scripts
+    pub clean: bool,
+    /// Read the config file, but load no plugins or scripts
+    pub noplugin: bool,
+    pub layout: WindowLayout,
+    /// Command lines from `-c`, run after the `startup` ones of the config file
+    pub commands: Vec<String>,
+    /// Run the commands without a terminal, then quit
+    pub headless: bool,
+    /// File to append the events handled to
+    pub record: Option<PathBuf>,
+    /// File of recorded events to handle instead of those of the terminal
+    pub replay: Option<PathBuf>,
+    /// Replay the events without waiting between them as when they were recorded
+    pub replay_fast: bool,
+}
+
+/// The size windows are laid out for when there is no terminal
+pub const HEADLESS_TERM_SIZE: UVec2 = UVec2 { x: 80, y: 24 };
+
+/// How the windows of the files given on the command line are laid out
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WindowLayout {
+    /// `-p`: a window filling the screen for each file, the first one shown
+    #[default]
+    Tabs,
+    /// `-o`: windows split above each other
+    Horizontal,
+    /// `-O`: windows split side by side
+    Vertical,
+}
+
+/// Limit for mappings that expand to other mappings
+const MAX_FEED_DEPTH: usize = 100;
+
 #[derive(Debug)]
 pub struct EditorApplication {
     state: Arc<Mutex<EditorState>>,
     input_manager: InputManager,
     builtin: Arc<Mutex<Builtin>>,
+    plugin_manager: Arc<Mutex<PluginManager>>,
     is_quit: bool,
+    /// Keys to process with their remap flag and mapping depth
+    pending_keys: VecDeque<(KeyCode, bool, usize)>,
+    /// How many mappings the key being processed was expanded from
+    feed_depth: usize,
 }
 
 impl EditorApplication {
     /// Create a new editor application
     pub fn new(files: Vec<String>) -> anyhow::Result<Self> {
+        Self::with_term_size(files, get_terminal_size_or_default())
+    }
+
+    /// An editor without a terminal, laid out for one of `HEADLESS_TERM_SIZE`. Both
+    /// `--headless` and tests drive it with commands and keys rather than terminal events.
+    pub fn headless(files: Vec<String>) -> anyhow::Result<Self> {
+        Self::with_term_size(files, HEADLESS_TERM_SIZE)
+    }
+
+    /// An editor laid out for a terminal of `term_size`, such as one that is not attached
+    pub fn with_term_size(files: Vec<String>, term_size: UVec2) -> anyhow::Result<Self> {
         Ok(Self {
-            state: Arc::new(Mutex::new(EditorState::new(files)?)),
+            state: Arc::new(Mutex::new(EditorState::with_term_size(files, term_size)?)),
             input_manager: InputManager::default(),
             builtin: Arc::new(Mutex::new(Builtin::default())),
+            plugin_manager: Arc::new(Mutex::new(PluginManager::default())),
             is_quit: false,
+            pending_keys: VecDeque::new(),
+            feed_depth: 0,
         })
     }
 
-    /// Quit the application
-    fn quit(&mut self) {
-        self.is_quit = true;
-    }
-
-    /// Get the quit state
-    fn get_quit(&self) -> bool {
-        self.is_quit
-    }
-
-    /// Run an action
-    async fn run_action(&mut self, action: Action) -> anyhow::Result<()> {
-        match action {
-            Action::Quit => self.quit(),
-            Action::Builtin(action) => {
-                let mut builtin = self.builtin.lock().await;
-                builtin.on_action(action).await?;
+    /// Apply a config file, returning the theme it chooses and the commands to run once
+    /// plugins are loaded. Mistakes in it are shown as messages rather than keeping the
+    /// editor from starting.
+    async fn load_config(&self, path: &Path) -> (Theme, Vec<String>) {
+        let mut state = self.state.lock().await;
+        let config = match Config::read(path) {
+            Ok(Some(config)) => config,
+            Ok(None) => return (Theme::default(), Vec::new()),
+            Err(err) => {
+                state.messages.error(err.to_string());
+                return (Theme::default(), Vec::new());
             }
-        }
-        Ok(())
+        };
+        state.apply_config(&config);
+        state.update_filetype_options().await;
+        let theme = config.get_theme().unwrap_or_else(|err| {
+            state.messages.error(format!("config.toml: {}", err));
+            Theme::default()
+        });
+        (theme, config.startup)
     }
 
-    /// Process a single terminal when in normal mode
-    async fn process_normal(&mut self, evt: Event) -> anyhow::Result<()> {
-        if let Some(action) = self.input_manager.read_event_normal(evt)? {
-            self.run_action(action).await?;
+    /// Run command lines as if typed after `:`, until one quits. A failing command is shown
+    /// as a message, and the rest still run. Returns how many showed an error.
+    async fn run_commands(&mut self, commands: &[String]) -> usize {
+        let mut failed = 0;
+        for command in commands {
+            if self.is_quit {
+                break;
+            }
+            let errors = self.state.lock().await.messages.get_error_count();
+            let input = command.strip_prefix(':').unwrap_or(command);
+            let action = self.state.lock().await.run_command(input).await;
+            let result = match action {
+                Ok(Some(action)) => match self.run_action(action).await {
+                    // Commands that replay keys leave them queued
+                    Ok(()) => self.process_pending_keys().await,
+                    Err(err) => Err(err),
+                },
+                Ok(None) => Ok(()),
+                Err(err) => Err(err),
+            };
+            let mut state = self.state.lock().await;
+            if let Err(err) = result {
+                state.messages.error(err.to_string());
+            }
+            // Commands such as `:wq` show errors without failing
+            if state.messages.get_error_count() > errors {
+                failed += 1;
+            }
         }
-        Ok(())
+        let mut state = self.state.lock().await;
+        state.update_filetype_options().await;
+        state.touch();
+        failed
     }
 
-    /// Process a single terminal when in visual mode
-    async fn process_visual(&mut self, evt: Event) -> anyhow::Result<()> {
-        if let Some(action) = self.input_manager.read_event_visual(evt)? {
-            self.run_action(action).await?;
-        }
-        Ok(())
+    /// Quit the application
+    fn quit(&mut self) {
+        self.is_quit = true;
     }
 
-    /// Process a single terminal when in insert mode
-    async fn process_insert(&mut self, evt: Event, is_append: bool) -> anyhow::Result<()> {
-        if let Some(key_code) = self.input_manager.event_to_key(evt)? {
+    /// Quit unless a buffer has unsaved changes, which `force` discards
+    async fn quit_unless_modified(&mut self, force: bool) {
+        if !force {
             let mut state = self.state.lock().await;
-            if let Some(active_window) = state.get_active_window() {
-                let mut active_window = active_window.lock().await;
-                let cursor = active_window.get_render_cursor().await;
-                match key_code {
-                    KeyCode::Char(ch) => {
-                        {
-                            let active_buffer = active_window.get_buffer();
-                            let mut active_buffer = active_buffer.lock().await;
-
-                            if ch == '\n' {
-                                active_buffer.split_line(cursor);
-                            } else {
-                                active_buffer.insert_char(cursor, ch);
-                            }
-                        }
-
-                        if ch == '\n' {
-                            active_window.move_by(IVec2::new(0, 1)).await;
-                            active_window.move_to_x(0).await;
-                        } else {
-                            active_window.move_by(IVec2::right()).await;
-                        }
-                    }
-                    KeyCode::Backspace => {
-                        let x = cursor.x;
-
-                        let line_len = {
-                            let active_buffer = active_window.get_buffer();
-                            let mut active_buffer = active_buffer.lock().await;
-
-                            let line_len = if cursor.x == 0 && cursor.y != 0 {
-                                active_buffer.get_line_length(cursor.y - 1)
-                            } else {
-                                None
-                            };
-
-                            if cursor.x == 0 {
-                                active_buffer.join_lines(cursor.y - 1);
-                            } else {
-                                active_buffer.remove_char(UVec2::new(cursor.x - 1, cursor.y));
-                            }
-                            line_len
-                        };
-
-                        if cursor.x != 0 {
-                            active_window.move_to_x(x - 1).await;
-                        } else if let Some(line_len) = line_len
-                            && cursor.y != 0
-                        {
-                            // Go to the beginning of the previous line
-                            active_window.move_by(IVec2::new(0, -1)).await;
-                            active_window.move_to_x(line_len).await;
-                        }
-                    }
-                    KeyCode::Delete => {
-                        let active_buffer = active_window.get_buffer();
-                        let mut active_buffer = active_buffer.lock().await;
-                        active_buffer.remove_char(cursor);
-                    }
-                    KeyCode::Esc => {
-                        if is_append {
-                            active_window.move_by(IVec2::left()).await;
-                        }
-
-                        state.set_mode(Mode::Normal).await;
-                    }
-                    _ => {}
-                }
+            if let Some(name) = state.get_modified_buffer().await {
+                state.messages.error(format!(
+                    "E162: No write since last change for buffer \"{}\" (add ! to override)",
+                    name
+                ));
+                return;
             }
         }
-        Ok(())
+        self.quit();
     }
 
-    /// Process a single terminal when in command mode
-    async fn process_command(&mut self, evt: Event) -> anyhow::Result<()> {
-        if let Some(key_code) = self.input_manager.event_to_key(evt)? {
-            let action = {
+    /// Run an action
+    async fn run_action(&mut self, action: Action) -> anyhow::Result<()> {
+        match action {
+            Action::Quit(force) => {
+                let closed = self.state.lock().await.close_window().await;
+                if !closed {
+                    self.quit_unless_modified(force).await;
+                }
+            }
+            Action::QuitAll(force) => self.quit_unless_modified(force).await,
+            Action::WriteQuit => {
+                // A failed save is shown in the message area, and the editor stays open
+                let saved = self
+                    .builtin
+                    .lock()
+                    .await
+                    .on_action(BuiltinAction::Save)
+                    .await;
+                if saved.is_ok() {
+                    self.quit_unless_modified(false).await;
+                }
+            }
+            Action::DeleteLines(start, end) => {
                 let mut state = self.state.lock().await;
-                state.process_command(key_code).await?
-            };
-
-            if let Some(action) = action {
-                self.run_action(action).await?;
+                state.delete_lines(start, end).await;
             }
-        }
-        Ok(())
+            Action::YankLines(start, end) => {
+                let mut state = self.state.lock().await;
+                state.yank_lines(start, end).await;
+            }
+            Action::Paste(after) => {
+                let mut state = self.state.lock().await;
+                state.paste(after).await;
+            }
+            Action::SetOptions(args) => {
+                let mut state = self.state.lock().await;
+                state.set_options(&args).await;
+            }
+            Action::Shell(command) => {
+                let result = run_shell_interruptible(&command, None).await;
+                let mut state = self.state.lock().await;
+                match result {
+                    Ok(output) => state.show_shell_output(&command, output),
+                    Err(err) => state.messages.error(err.to_string()),
+                }
+            }
+            Action::ReadFile(at, path) => {
+                let mut state = self.state.lock().await;
+                state.read_file(at, &path).await;
+            }
+            Action::ReadShell(at, command) => {
+                let result = run_shell_interruptible(&command, None).await;
+                let mut state = self.state.lock().await;
+                state.read_shell_output(at, result).await;
+            }
+            Action::Filter(start, end, command) => {
+                let mut input = {
+                    let state = self.state.lock().await;
+                    state.get_line_range(start, end).await.join("\n")
+                };
+                input.push('\n');
+
+                let result = run_shell_interruptible(&command, Some(input)).await;
+                let mut state = self.state.lock().await;
+                state.filter_lines(start, end, result).await;
+            }
+            Action::Help(topic) => {
+                let mut state = self.state.lock().await;
+                let keymaps = self.input_manager.get_keymaps();
+                if let Err(err) = state.open_help(topic.as_deref(), &keymaps).await {
+                    state.messages.error(err.to_string());
+                }
+            }
+            Action::TimeTravel(amount, forward) => {
+                let mut state = self.state.lock().await;
+                state.time_travel(amount, forward).await;
+            }
+            Action::Messages => self.state.lock().await.open_messages().await,
+            Action::Sort(start, end, options) => {
+                let mut state = self.state.lock().await;
+                state.sort_lines(start, end, options).await;
+            }
+            Action::Substitute {
+                start,
+                end,
+                pattern,
+                replacement,
+                all,
+            } => {
+                let mut state = self.state.lock().await;
+                if let Err(err) = state
+                    .substitute(start, end, &pattern, &replacement, all)
+                    .await
+                {
+                    state.messages.error(err.to_string());
+                }
+            }
+            Action::Global {
+                start,
+                end,
+                pattern,
+                invert,
+                command,
+            } => {
+                let mut state = self.state.lock().await;
+                if let Err(err) = state.global(start, end, &pattern, invert, &command).await {
+                    state.messages.error(err.to_string());
+                }
+            }
+            Action::StartSearch => {
+                let mut state = self.state.lock().await;
+                state.start_search().await;
+            }
+            Action::SearchNext(forward) => {
+                let mut state = self.state.lock().await;
+                if let Err(err) = state.search_next(forward).await {
+                    state.messages.error(err.to_string());
+                }
+            }
+            Action::NextBuffer(forward) => {
+                let mut state = self.state.lock().await;
+                state.next_buffer(forward).await;
+            }
+            Action::NoHighlight => {
+                let mut state = self.state.lock().await;
+                state.no_highlight();
+            }
+            Action::Complete(offset) => self.complete(offset, None).await,
+            Action::DeleteWordBack | Action::DeleteToLineStart => {
+                let mut state = self.state.lock().await;
+                state.cancel_completion();
+                let to_line_start = matches!(action, Action::DeleteToLineStart);
+                state.delete_before_cursor(to_line_start).await;
+            }
+            Action::Page(forward) => {
+                let state = self.state.lock().await;
+                if let Some(win) = state.get_active_window() {
+                    win.lock().await.move_page(forward).await;
+                }
+            }
+            Action::LocationList => {
+                let mut state = self.state.lock().await;
+                state.show_location_list().await;
+            }
+            Action::JumpToLocation(jump) => {
+                let mut state = self.state.lock().await;
+                if let Err(err) = state.jump_to_location(jump).await {
+                    state.messages.error(err.to_string());
+                }
+            }
+            Action::Map {
+                modes,
+                lhs,
+                rhs,
+                noremap,
+            } => {
+                let mut state = self.state.lock().await;
+                if let Err(err) = state.map_keys(&modes, lhs, &rhs, noremap) {
+                    state.messages.error(err.to_string());
+                }
+            }
+            Action::Unmap { modes, lhs } => {
+                let mut state = self.state.lock().await;
+                if let Err(err) = state.unmap_keys(&modes, &lhs) {
+                    state.messages.error(err.to_string());
+                }
+            }
+            Action::PluginKeymap(plugin, name) => {
+                plugin.send(CuprumNotification::Keymap { name }).await;
+            }
+            Action::Plugin(PluginAction::List) => {
+                let plugins = self.plugin_manager.lock().await.list().await;
+                self.state.lock().await.show_plugin_list(plugins);
+            }
+            Action::Plugin(PluginAction::Enable(name)) => {
+                let result = self.plugin_manager.lock().await.enable(&name).await;
+                let mut state = self.state.lock().await;
+                match result {
+                    Ok(()) => state.messages.info(format!("Plugin {} enabled", name)),
+                    Err(err) => state.messages.error(err.to_string()),
+                }
+            }
+            Action::Plugin(PluginAction::Disable(name)) => {
+                let result = self.plugin_manager.lock().await.disable(&name).await;
+                let mut state = self.state.lock().await;
+                match result {
+                    Ok(plugin) => {
+                        state.forget_plugin(&plugin);
+                        state.messages.info(format!("Plugin {} disabled", name));
+                    }
+                    Err(err) => state.messages.error(err.to_string()),
+                }
+            }
+            Action::Feed(keys, remap) => {
+                if self.feed_depth >= MAX_FEED_DEPTH {
+                    self.pending_keys.clear();
+                    self.state
+                        .lock()
+                        .await
+                        .messages
+                        .error("E223: Recursive mapping");
+                    return Ok(());
+                }
+
+                // Replay before any keys still queued, as if typed now
+                for key in keys.into_iter().rev() {
+                    self.pending_keys
+                        .push_front((key, remap, self.feed_depth + 1));
+                }
+            }
+            Action::Builtin(action) => {
+                let mut builtin = self.builtin.lock().await;
+                builtin.on_action(action).await?;
+            }
+        }
+        Ok(())
     }
 
-    /// Process a single terminal event
-    async fn process(&mut self, evt: Event) -> anyhow::Result<()> {
-        let mode = {
+    /// Process a single key when in normal mode
+    async fn process_normal(&mut self, key: KeyCode, remap: bool) -> anyhow::Result<()> {
+        let action = {
             let state = self.state.lock().await;
-            let mode = state.mode.lock().await;
-            mode.clone()
+            let keymap = remap.then_some(&state.user_keymaps.normal);
+            self.input_manager.set_timeout(state.options.timeoutlen);
+            self.input_manager.read_key_normal(key, keymap)
+        };
+        if let Some(action) = action {
+            self.run_action(action).await?;
+        }
+        Ok(())
+    }
+
+    /// Process a single key when in visual mode
+    async fn process_visual(&mut self, key: KeyCode, remap: bool) -> anyhow::Result<()> {
+        let action = {
+            let state = self.state.lock().await;
+            let keymap = remap.then_some(&state.user_keymaps.visual);
+            self.input_manager.set_timeout(state.options.timeoutlen);
+            self.input_manager.read_key_visual(key, keymap)
+        };
+        if let Some(action) = action {
+            self.run_action(action).await?;
+        }
+        Ok(())
+    }
+
+    /// Process a single key when in insert mode, after insert mode mappings
+    async fn process_insert(
+        &mut self,
+        key: KeyCode,
+        is_append: bool,
+        remap: bool,
+    ) -> anyhow::Result<()> {
+        if !remap {
+            return self.insert_key(key, is_append).await;
+        }
+
+        let result = {
+            let state = self.state.lock().await;
+            self.input_manager
+                .read_key_insert(key, &state.user_keymaps.insert)
+        };
+        match result {
+            Ok(action) => self.run_action(action).await?,
+            Err(keys) => {
+                for key in keys {
+                    self.insert_key(key, is_append).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Start insert-mode completion, or move the selection if it is already active.
+    /// The editor is not locked while the plugin sources are asked.
+    async fn complete(&mut self, offset: isize, trigger: Option<char>) {
+        let request = {
+            let mut state = self.state.lock().await;
+            if trigger.is_none() && state.completion.is_active() {
+                state.select_completion(offset);
+                return;
+            }
+            state.prepare_completion(trigger).await
+        };
+        let Some(mut request) = request else {
+            return;
+        };
+
+        request.query_plugins().await;
+        self.state.lock().await.open_completion(request, offset);
+    }
+
+    /// Type a key into the buffer
+    async fn insert_key(&mut self, key_code: KeyCode, is_append: bool) -> anyhow::Result<()> {
+        if let Some(action) = self.input_manager.get_insert_action(key_code) {
+            return self.run_action(action).await;
+        }
+
+        let mut state = self.state.lock().await;
+        let mut trigger = None;
+        match key_code {
+            KeyCode::Char('\n') | KeyCode::Char('\t') if state.completion.is_active() => {
+                state.accept_completion().await;
+                return Ok(());
+            }
+            _ => state.cancel_completion(),
+        }
+
+        if let Some(active_window) = state.get_active_window() {
+            let mut active_window = active_window.lock().await;
+            match key_code {
+                // Control characters would end up in the file as they are
+                KeyCode::Char(ch) if ch.is_control() && ch != '\n' && ch != '\t' => {}
+                KeyCode::Char('\t') if state.options.expandtab => {
+                    let tabstop = state.options.tabstop.max(1);
+                    let cursor = active_window.get_render_cursor().await;
+                    let line = active_window
+                        .get_buffer()
+                        .lock()
+                        .await
+                        .get_line(cursor.y)
+                        .unwrap_or_default();
+                    let col = grapheme::display_col(&line, cursor.x, tabstop);
+                    let buf = active_window.get_buffer_id();
+                    for _ in 0..tabstop - col % tabstop {
+                        let pos = active_window.type_char(' ').await;
+                        state
+                            .emit(EditorEvent::InsertChar { buf, pos, ch: ' ' })
+                            .await;
+                    }
+                }
+                KeyCode::Char(ch) => {
+                    let pos = active_window.type_char(ch).await;
+                    let buf = active_window.get_buffer_id();
+                    state.emit(EditorEvent::InsertChar { buf, pos, ch }).await;
+                    if state.completion.is_trigger(ch) {
+                        trigger = Some(ch);
+                    }
+                }
+                KeyCode::Backspace => {
+                    let cursor = active_window.get_render_cursor().await;
+                    let mut x = cursor.x;
+
+                    let line_len = {
+                        let active_buffer = active_window.get_buffer();
+                        let mut active_buffer = active_buffer.lock().await;
+
+                        let line_len = if cursor.x == 0 && cursor.y != 0 {
+                            active_buffer.get_line_length(cursor.y - 1)
+                        } else {
+                            None
+                        };
+
+                        if cursor.x == 0 {
+                            // Nothing comes before the start of the buffer
+                            if cursor.y != 0 {
+                                active_buffer.join_lines(cursor.y - 1);
+                            }
+                        } else {
+                            let line = active_buffer.get_line(cursor.y).unwrap_or_default();
+                            x = grapheme::prev(&line, cursor.x);
+                            active_buffer.remove_grapheme(UVec2::new(x, cursor.y));
+                        }
+                        line_len
+                    };
+
+                    if cursor.x != 0 {
+                        active_window.move_to_x(x).await;
+                    } else if let Some(line_len) = line_len
+                        && cursor.y != 0
+                    {
+                        // Go to the beginning of the previous line
+                        active_window.move_by(IVec2::new(0, -1)).await;
+                        active_window.move_to_x(line_len).await;
+                    }
+                }
+                KeyCode::Left => active_window.move_by(IVec2::left()).await,
+                KeyCode::Right => active_window.move_by(IVec2::right()).await,
+                KeyCode::Up => active_window.move_by(IVec2::up()).await,
+                KeyCode::Down => active_window.move_by(IVec2::down()).await,
+                KeyCode::Home => active_window.move_to_line_start(),
+                KeyCode::End => active_window.move_to_line_end().await,
+                KeyCode::PageUp => active_window.move_page(false).await,
+                KeyCode::PageDown => active_window.move_page(true).await,
+                KeyCode::Delete => {
+                    // Joins the next line at the end of a line, and does nothing at the
+                    // end of the buffer
+                    let cursor = active_window.get_render_cursor().await;
+                    let active_buffer = active_window.get_buffer();
+                    let mut active_buffer = active_buffer.lock().await;
+                    active_buffer.remove_grapheme(cursor);
+                }
+                KeyCode::Esc => {
+                    // Back onto the character appended after, and never past the last
+                    // character once in normal mode
+                    let cursor = active_window.get_render_cursor().await;
+                    let x = if is_append {
+                        cursor.x.saturating_sub(1)
+                    } else {
+                        cursor.x
+                    };
+                    state.set_mode(Mode::Normal).await;
+                    active_window.move_to_x(x).await;
+                }
+                // Control keys without an insert mode action are ignored, and the leader
+                // was replaced with its key
+                KeyCode::Ctrl(_) | KeyCode::Leader => {}
+            }
+        }
+        drop(state);
+
+        if trigger.is_some() {
+            self.complete(0, trigger).await;
+        }
+        Ok(())
+    }
+
+    /// Process a single key when in command mode
+    async fn process_command(&mut self, key: KeyCode) -> anyhow::Result<()> {
+        let action = {
+            let mut state = self.state.lock().await;
+            state.process_command(key).await?
         };
 
+        if let Some(action) = action {
+            self.run_action(action).await?;
+        }
+        Ok(())
+    }
+
+    /// Process a key in the current mode
+    async fn process_key(&mut self, key: KeyCode, remap: bool) -> anyhow::Result<()> {
+        let (mode, leader) = {
+            let mut state = self.state.lock().await;
+            // Mappings with `<leader>` follow `mapleader`, however it was changed
+            let leader = state.options.mapleader;
+            state.user_keymaps.set_leader(leader);
+            let mode = state.mode.lock().await.clone();
+            (mode, leader)
+        };
+        self.input_manager.set_leader(leader);
+        // Replayed keys may contain the leader
+        let key = match key {
+            KeyCode::Leader => KeyCode::Char(leader),
+            key => key,
+        };
         match mode {
-            Mode::Normal => self.process_normal(evt).await,
-            Mode::Visual => self.process_visual(evt).await,
-            Mode::Insert(is_append) => self.process_insert(evt, is_append).await,
-            Mode::Command => self.process_command(evt).await,
+            Mode::Normal => self.process_normal(key, remap).await,
+            Mode::Visual => self.process_visual(key, remap).await,
+            Mode::Insert(is_append) => self.process_insert(key, is_append, remap).await,
+            Mode::Command => self.process_command(key).await,
         }
     }
 
+    async fn process(&mut self, evt: Event) -> anyhow::Result<()> {
+        let result = self.process_event(evt).await;
+        self.state.lock().await.touch();
+        result
+    }
+
+    async fn process_event(&mut self, evt: Event) -> anyhow::Result<()> {
+        if let Event::Resize(w, h) = evt {
+            let mut state = self.state.lock().await;
+            state.resize(UVec2::new(w.into(), h.into())).await;
+            return Ok(());
+        }
+        let Some(key) = self.input_manager.event_to_key(evt)? else {
+            return Ok(());
+        };
+
+        {
+            let mut state = self.state.lock().await;
+
+            // Messages and hovers stay until the next key press
+            state.messages.clear_current();
+            let closed_hover = state.close_hover();
+            // The key continues the sequence the hint was for, or Esc cancels it
+            let closed_hint = state.close_key_hint();
+
+            // Outside insert mode, Esc closes the topmost dismissable popup before
+            // anything else sees it. In insert mode it always leaves the mode.
+            let inserting = matches!(*state.mode.lock().await, Mode::Insert(_));
+            if key == KeyCode::Esc
+                && !closed_hint
+                && !inserting
+                && (closed_hover || state.close_top_popup())
+            {
+                return Ok(());
+            }
+        }
+
+        let result = self.feed_key(key, true).await;
+        // The key may have entered a buffer of another filetype
+        self.state.lock().await.update_filetype_options().await;
+        result
+    }
+
+    /// Called while no keys come in. Once the leader has waited for the next key for
+    /// `timeoutlen`, the keys that can follow it are shown, and it waits until one comes.
+    pub async fn tick(&mut self) {
+        let mut state = self.state.lock().await;
+        let Some((pending, waited)) = self.input_manager.get_pending() else {
+            return;
+        };
+        let leader = state.options.mapleader;
+        let timeout = chrono::Duration::milliseconds(state.options.timeoutlen as i64);
+        if pending[..] != [KeyCode::Char(leader)] || waited < timeout {
+            return;
+        }
+        let Some(mode) = MapMode::from_mode(&state.mode.lock().await.clone()) else {
+            return;
+        };
+
+        // User mappings hide built-in ones with the same keys
+        let mut continuations: BTreeMap<String, (Key, String)> = BTreeMap::new();
+        let keymaps = [
+            self.input_manager.get_keymap(mode),
+            state.user_keymaps.get(mode),
+        ];
+        for keymap in keymaps {
+            for (keys, entry) in keymap.continuations(pending) {
+                let notation = keys.iter().map(|code| code.to_string()).collect();
+                continuations.insert(notation, (keys, entry.description.clone()));
+            }
+        }
+        if continuations.is_empty() {
+            return;
+        }
+        let pending = pending.clone();
+        state.show_key_hint(&pending, continuations.into_values().collect());
+        state.touch();
+        self.input_manager.hold();
+    }
+
+    /// Process a key and any keys that mappings expand it to
+    async fn feed_key(&mut self, key: KeyCode, remap: bool) -> anyhow::Result<()> {
+        self.pending_keys.push_back((key, remap, 0));
+        self.process_pending_keys().await
+    }
+
+    /// Process the queued keys, then note where they left the cursor, which plugins are
+    /// told once it stays there
+    async fn process_pending_keys(&mut self) -> anyhow::Result<()> {
+        while let Some((key, remap, depth)) = self.pending_keys.pop_front() {
+            self.feed_depth = depth;
+            let result = self.process_key(key, remap).await;
+            if result.is_err() {
+                self.pending_keys.clear();
+                self.feed_depth = 0;
+                return result;
+            }
+        }
+        self.feed_depth = 0;
+
+        let mut state = self.state.lock().await;
+        state.checkpoint().await;
+        state.emit_buf_enter().await;
+        state.emit_cursor_moved(CURSOR_MOVED_DEBOUNCE).await;
+        Ok(())
+    }
+
     /// Editor Application main entry point
-    pub async fn main(files: Vec<String>) -> anyhow::Result<()> {
-        let editor = Arc::new(Mutex::new(EditorApplication::new(files)?));
+    pub async fn main(args: StartupArgs) -> anyhow::Result<()> {
+        // A file that cannot be written is found before the screen is taken over
+        let recorder = match &args.record {
+            Some(path) => Some(Recorder::create(path, get_terminal_size_or_default())?),
+            None => None,
+        };
+        let file_count = args.files.len();
+        let editor = if args.headless {
+            EditorApplication::headless(args.files)?
+        } else {
+            EditorApplication::new(args.files)?
+        };
+        // Every file has a window, shown one at a time unless they are split
+        if file_count > 1 {
+            let mut state = editor.state.lock().await;
+            match args.layout {
+                WindowLayout::Tabs => state.messages.info(format!("{} files to edit", file_count)),
+                WindowLayout::Horizontal => state.split_windows(SplitDirection::Horizontal).await,
+                WindowLayout::Vertical => state.split_windows(SplitDirection::Vertical).await,
+            }
+        }
+
+        // Users should know why their setup is missing
+        let (theme, mut startup) = if args.clean {
+            let mut state = editor.state.lock().await;
+            state
+                .messages
+                .info("Started with --clean: no config, plugins or scripts are loaded");
+            (Theme::default(), Vec::new())
+        } else {
+            if args.noplugin {
+                let mut state = editor.state.lock().await;
+                state
+                    .messages
+                    .info("Started with --noplugin: no plugins or scripts are loaded");
+            }
+            editor.load_config(&get_config_path(args.config)).await
+        };
+        let load_plugins = !args.clean && !args.noplugin;
+        let editor = Arc::new(Mutex::new(editor));
 
         // Run builtin features
-        let (messages, notify, builtin_state, plugin_state) = {
+        let (messages, notify, builtin_state, plugin_state, plugin_manager) = {
             let editor = editor.lock().await;
             let builtin = editor.builtin.lock().await;
             (
@@ -468,102 +1825,2063 @@ impl EditorApplication {
                 builtin.get_notify(),
                 editor.state.clone(),
                 editor.state.clone(),
+                editor.plugin_manager.clone(),
             )
         };
-        tokio::spawn(async move {
-            let mut handler = EditorApiHandler::new(builtin_state);
+        // Tasks that stop when the editor quits, awaited before it returns
+        let (shutdown, _) = watch::channel(false);
+        let mut tasks = JoinSet::new();
+        tasks.spawn(until_shutdown(
+            shutdown.subscribe(),
+            serve_builtin(builtin_state, messages, notify),
+        ));
 
+        // Send buffer changes and cursor moves once typing pauses
+        let changes_state = editor.lock().await.state.clone();
+        tasks.spawn(until_shutdown(shutdown.subscribe(), async move {
             loop {
-                notify.notified().await;
-                let messages = BuiltinApiProvider::get_messages(&messages).await;
-                for (notify, response, request) in messages {
-                    let res = handler.process(request).await;
+                sleep(BUFFER_CHANGE_DEBOUNCE).await;
+                let mut state = changes_state.lock().await;
+                state.emit_buffer_changes(BUFFER_CHANGE_DEBOUNCE).await;
+                state.emit_cursor_moved(CURSOR_MOVED_DEBOUNCE).await;
+            }
+        }));
 
-                    let mut responses = response.lock().await;
-                    *responses = res;
+        // Run plugin manager, then the startup commands once plugins are loaded
+        let plugin_shutdown = shutdown.subscribe();
+        let (plugins_loaded, on_plugins_loaded) = oneshot::channel();
+        if load_plugins {
+            tasks.spawn(async move {
+                let mut handlers = JoinSet::new();
+                let mut plugin_manager = plugin_manager.lock().await;
+                // A panic would end the editor, and it works without plugins
+                let result = match plugin_manager.init().await {
+                    Ok(result) => result,
+                    Err(err) => {
+                        plugin_state
+                            .lock()
+                            .await
+                            .messages
+                            .error(format!("Plugins not loaded: {}", err));
+                        return;
+                    }
+                };
+                for (requests, request_notify, responses, response_notify, channel) in result {
+                    let state = plugin_state.clone();
+                    handlers.spawn(until_shutdown(plugin_shutdown.clone(), async move {
+                        let mut handler = EditorApiHandler::with_plugin(state, channel);
+                        loop {
+                            request_notify.notified().await;
+                            let requests: Vec<_> = requests.lock().await.drain(..).collect();
 
-                    notify.notify_one();
-                }
-            }
-        });
+                            for request in requests {
+                                let result = if request.version != PROTOCOL_VERSION {
+                                    Err(CuprumApiError::VersionMismatch {
+                                        expected: PROTOCOL_VERSION,
+                                        found: request.version,
+                                    })
+                                } else {
+                                    handler.process(request.kind).await
+                                };
+                                if let Err(err) = &result {
+                                    log::error!("{}", err);
+                                }
+                                let mut responses = responses.lock().await;
+                                responses.push(CuprumApiResponse::new(request.id, result));
+                            }
 
-        // Run plugin manager
-        tokio::spawn(async move {
-            let mut plugin_manager = PluginManager::default();
-            let result = plugin_manager.init().await.unwrap();
-            for (requests, request_notify, responses, response_notify) in result {
-                let state = plugin_state.clone();
-                tokio::spawn(async move {
-                    let mut handler = EditorApiHandler::new(state);
-                    loop {
-                        request_notify.notified().await;
-                        let requests = requests.lock().await;
-                        let mut responses = responses.lock().await;
-
-                        for request in requests.clone() {
-                            let response = handler.process(request.kind).await;
-                            responses.push(CuprumApiResponse {
-                                id: request.id,
-                                kind: response,
-                            });
+                            response_notify.notify_one();
                         }
+                    }));
+                }
 
-                        response_notify.notify_one();
-                    }
-                });
+                if let Err(err) = plugin_manager.run().await {
+                    let mut state = plugin_state.lock().await;
+                    state
+                        .messages
+                        .error(format!("Plugins not started: {}", err));
+                }
+                plugins_loaded.send(()).ok();
+                drop(plugin_manager);
+                handlers.join_all().await;
+            });
+        } else {
+            drop(plugins_loaded);
+        }
+
+        // Run scripts
+        if load_plugins {
+            let script_state = editor.lock().await.state.clone();
+            tokio::spawn(script::start(script_state, script::read_scripts()));
+        }
+
+        let replay = match &args.replay {
+            Some(path) => Some(ReplaySource::open(path, args.replay_fast)?),
+            None => None,
+        };
+        let result = if args.headless {
+            // Plugins are loaded first, as they would be with a terminal
+            on_plugins_loaded.await.ok();
+            editor.lock().await.run_commands(&startup).await;
+            let command_count = args.commands.len();
+            let mut sources: Vec<Box<dyn EventSource>> = Vec::new();
+            if let Some(replay) = replay {
+                sources.push(Box::new(replay));
             }
+            sources.push(Box::new(CommandSource::new(args.commands)));
+            Self::run_headless(&editor, sources, recorder, command_count).await
+        } else {
+            startup.extend(args.commands);
+            let startup_editor = editor.clone();
+            tasks.spawn(until_shutdown(shutdown.subscribe(), async move {
+                // Plugins failing to load leave the commands to run without them
+                on_plugins_loaded.await.ok();
+                startup_editor.lock().await.run_commands(&startup).await;
+            }));
+            Self::run_terminal(editor.clone(), theme, replay, recorder).await
+        };
+        // Printed once the screen is put back
+        if result.is_ok() && args.replay.is_some() {
+            let hash = editor
+                .lock()
+                .await
+                .state
+                .lock()
+                .await
+                .get_state_hash()
+                .await;
+            println!("state hash: {:016x}", hash);
+        }
+
+        // Requests stop being handled before the plugins see their input closed
+        shutdown.send_replace(true);
+        while tasks.join_next().await.is_some() {}
+        let plugin_manager = editor.lock().await.plugin_manager.clone();
+        plugin_manager.lock().await.shutdown().await;
+
+        result
+    }
 
-            plugin_manager.run().await.unwrap();
+    /// Draw in the terminal and handle its events, or those of `replay`, until the editor
+    /// quits
+    async fn run_terminal(
+        editor: Arc<Mutex<Self>>,
+        theme: Theme,
+        replay: Option<ReplaySource>,
+        mut recorder: Option<Recorder>,
+    ) -> anyhow::Result<()> {
+        // The terminal is put back before a panic message is printed, so it does not leave
+        // it in raw mode on the alternate screen
+        restore_on_panic(restore_terminal);
+        // A signal quits between two inputs, so the terminal is put back, a save finishes
+        // and the plugins are shut down as they are after `:q!`
+        let signaled = Arc::new(AtomicBool::new(false));
+        let handle_signal = tokio::spawn({
+            let editor = editor.clone();
+            let signaled = signaled.clone();
+            async move {
+                if termination_signal().await.is_ok() {
+                    signaled.store(true, Ordering::Relaxed);
+                    editor.lock().await.quit();
+                }
+            }
         });
 
-        // Render in terminal
-        let editor_render = editor.clone();
+        // Render in terminal. The renderer only takes the state lock to copy a frame, and
+        // the editor lock is left to the input loop, so keys never wait for the terminal.
+        let render_state = editor.lock().await.state.clone();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let render_stopped = stopped.clone();
         let handle_render = tokio::spawn(async move {
-            let renderer = Renderer::default();
+            let mut renderer = Renderer::new(theme);
             renderer.init_screen().ok();
-            loop {
-                let editor = editor_render.lock().await;
-                if editor.get_quit() {
+            let mut drawn_revision = None;
+            while !render_stopped.load(Ordering::Relaxed) {
+                // Nothing changed since the last frame was captured, so it is still on screen
+                let frame = {
+                    let state = render_state.lock().await;
+                    let revision = Some(state.get_revision());
+                    if revision == drawn_revision {
+                        None
+                    } else {
+                        drawn_revision = revision;
+                        Frame::capture(&state).await
+                    }
+                };
+                // The screen cannot be drawn any more, so leave it as it was found
+                if let Some(frame) = frame
+                    && let Err(err) = renderer.render_changed(&mut stdout(), frame)
+                {
+                    log::error!("Render error: {:?}", err);
+                    render_stopped.store(true, Ordering::Relaxed);
                     break;
                 }
 
-                let state = editor.state.lock().await;
-                let active_window = state.get_active_window();
-                if let Some(win) = active_window {
-                    let buf = {
-                        let win = win.lock().await;
-                        win.get_buffer()
-                    };
-
-                    renderer
-                        .render(win, buf, state.mode.clone(), state.command_buf.clone())
-                        .await
-                        .unwrap();
-                }
                 sleep(Duration::from_millis(32)).await;
             }
             renderer.clean_screen().ok();
         });
 
-        // Handle terminal events
+        // Handle terminal events, or those of a recorded session instead
+        let mut source: Box<dyn EventSource> = match replay {
+            Some(replay) => Box::new(replay),
+            None => Box::new(TerminalSource::new(stopped.clone())),
+        };
+        let result = Self::run_source(&editor, source.as_mut(), recorder.as_mut()).await;
+        handle_signal.abort();
+
+        stopped.store(true, Ordering::Relaxed);
+        handle_render.await?;
+
+        if signaled.load(Ordering::Relaxed) {
+            bail!("terminated by a signal");
+        }
+        result.map(|_| ())
+    }
+
+    /// The main loop: handle input from `source` until it ends or the editor quits, writing
+    /// the events to `recorder`. Returns how many of its command lines showed an error.
+    async fn run_source(
+        editor: &Mutex<Self>,
+        source: &mut dyn EventSource,
+        mut recorder: Option<&mut Recorder>,
+    ) -> anyhow::Result<usize> {
+        let mut failed = 0;
         loop {
-            let event = event::read()?;
+            let input = source.next_input().await?;
             let mut editor = editor.lock().await;
-
-            match editor.process(event).await {
-                Ok(_) => {}
-                Err(e) => {
-                    log::error!("Error: {:?}", e);
+            match input {
+                Input::Event(event) => {
+                    if let Some(Err(err)) =
+                        recorder.as_mut().map(|recorder| recorder.record(&event))
+                    {
+                        log::error!("Recording stopped: {}", err);
+                        recorder = None;
+                    }
+                    if let Err(e) = editor.process(event).await {
+                        log::error!("Error: {:?}", e);
+                    }
                 }
+                Input::Command(command) => failed += editor.run_commands(&[command]).await,
+                Input::Idle => editor.tick().await,
+                Input::End => break,
             }
-
             if editor.is_quit {
                 break;
             }
         }
+        Ok(failed)
+    }
 
-        handle_render.await?;
+    /// Without a terminal, replay a recorded session if there is one, then run the `-c`
+    /// commands, and write the errors shown on the way to stderr. Changes the commands do
+    /// not write are lost. Fails if any command did.
+    async fn run_headless(
+        editor: &Mutex<Self>,
+        sources: Vec<Box<dyn EventSource>>,
+        mut recorder: Option<Recorder>,
+        command_count: usize,
+    ) -> anyhow::Result<()> {
+        let mut failed = 0;
+        for mut source in sources {
+            failed += Self::run_source(editor, source.as_mut(), recorder.as_mut()).await?;
+        }
 
+        let editor = editor.lock().await;
+        let state = editor.state.lock().await;
+        for entry in state.messages.get_history() {
+            if entry.message.level == MessageLevel::Error {
+                eprintln!("{}", entry.message.text);
+            }
+        }
+        if failed > 0 {
+            bail!("{} of {} commands failed", failed, command_count);
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use api::{
+        CompletionItem, CuprumApi, CuprumApiProvider, Diagnostic, DiagnosticSeverity,
+        PluginResponse, Popup, RangeEdit, SplitDirection, StatusAlign, TextRange,
+    };
+    use crossterm::event::KeyEvent;
+    use plugin_manager::InProcessPlugin;
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::{
+        buffer::BufferChange,
+        event_source::RecordedEvent,
+        highlight::{Highlight, HighlightSource},
+        test_editor::{TestEditor, key_event, spawn_builtin_handler},
+    };
+
+    #[tokio::test]
+    async fn test_api_errors() {
+        let state = Arc::new(Mutex::new(EditorState::new(Vec::new()).unwrap()));
+        let mut handler = EditorApiHandler::new(state.clone());
+
+        assert!(matches!(
+            handler.process(CuprumApiRequestKind::GetLine { buf: None, y: 0 }).await,
+            Ok(Some(CuprumApiResponseKind::GetLine(line))) if line.is_empty()
+        ));
+        assert_eq!(
+            handler
+                .process(CuprumApiRequestKind::GetLine { buf: None, y: 5 })
+                .await
+                .unwrap_err(),
+            CuprumApiError::OutOfRange
+        );
+        assert_eq!(
+            handler
+                .process(CuprumApiRequestKind::GetLineCount {
+                    buf: Some(BufferId(9))
+                })
+                .await
+                .unwrap_err(),
+            CuprumApiError::NotFound("buffer".to_string())
+        );
+        assert!(matches!(
+            handler
+                .process(CuprumApiRequestKind::RegisterCommand {
+                    name: "x".to_string()
+                })
+                .await,
+            Err(CuprumApiError::NotAllowed(_))
+        ));
+
+        state.lock().await.open_messages().await;
+        assert_eq!(
+            handler
+                .process(CuprumApiRequestKind::RemoveLine { buf: None, y: 0 })
+                .await
+                .unwrap_err(),
+            CuprumApiError::ReadOnly
+        );
+    }
+
+    #[tokio::test]
+    async fn test_buffer_info() {
+        let path = std::env::temp_dir().join("cuprum_test_buffer_info.txt");
+        std::fs::write(&path, "a\nb\n").unwrap();
+        let state = EditorState::new(vec![path.to_string_lossy().to_string()]).unwrap();
+        let mut handler = EditorApiHandler::new(Arc::new(Mutex::new(state)));
+
+        let info = handler
+            .process(CuprumApiRequestKind::GetBufferInfo { buf: None })
+            .await;
+        assert!(matches!(
+            info,
+            Ok(Some(CuprumApiResponseKind::GetBufferInfo(BufferInfo {
+                id: BufferId(0),
+                path: Some(ref file),
+                ref name,
+                dirty: false,
+                line_count: 2,
+                read_only: false,
+                filetype: Some(ref filetype),
+            }))) if *file == path.to_string_lossy()
+                && name == "cuprum_test_buffer_info.txt"
+                && filetype == "text"
+        ));
+        std::fs::remove_file(&path).unwrap();
+
+        let state = Arc::new(Mutex::new(EditorState::new(Vec::new()).unwrap()));
+        let mut handler = EditorApiHandler::new(state);
+        handler
+            .process(CuprumApiRequestKind::InsertLine {
+                buf: None,
+                y: 0,
+                line: "x".to_string(),
+            })
+            .await
+            .unwrap();
+        let info = handler
+            .process(CuprumApiRequestKind::GetBufferInfo {
+                buf: Some(BufferId(0)),
+            })
+            .await;
+        assert!(matches!(
+            info,
+            Ok(Some(CuprumApiResponseKind::GetBufferInfo(BufferInfo {
+                path: None,
+                ref name,
+                dirty: true,
+                line_count: 2,
+                ..
+            }))) if name == "[No Name]"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_slow_save_does_not_block_requests() {
+        let paths: Vec<String> = (0..2)
+            .map(|i| {
+                let path = std::env::temp_dir().join(format!("cuprum_test_slow_save_{}.txt", i));
+                std::fs::write(&path, "a\n").unwrap();
+                path.to_string_lossy().to_string()
+            })
+            .collect();
+        let state = Arc::new(Mutex::new(EditorState::new(paths.clone()).unwrap()));
+
+        // Holding the buffer makes the save wait as if the file system were slow
+        let buf = state
+            .lock()
+            .await
+            .buffer_manager
+            .get_buffer(BufferId(0))
+            .unwrap();
+        let held = buf.lock().await;
+        let mut saver = EditorApiHandler::new(state.clone());
+        let save = tokio::spawn(async move {
+            saver
+                .process(CuprumApiRequestKind::SaveBuffer {
+                    buf: Some(BufferId(0)),
+                    path: None,
+                })
+                .await
+        });
+        sleep(Duration::from_millis(50)).await;
+
+        let mut handler = EditorApiHandler::new(state.clone());
+        let count = timeout(
+            Duration::from_secs(1),
+            handler.process(CuprumApiRequestKind::GetLineCount {
+                buf: Some(BufferId(1)),
+            }),
+        )
+        .await
+        .expect("blocked by the save");
+        assert!(matches!(
+            count,
+            Ok(Some(CuprumApiResponseKind::GetLineCount(1)))
+        ));
+        assert!(!save.is_finished());
+
+        drop(held);
+        assert!(save.await.unwrap().is_ok());
+        for path in paths {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_esc_closes_popup() {
+        let mut editor = TestEditor::new(&[("a.txt", "one")]);
+        let popup = |title: &str, dismissable| Popup {
+            title: Some(title.to_string()),
+            dismissable,
+            ..Default::default()
+        };
+        let titles = |popups: Vec<Popup>| -> Vec<String> {
+            popups.into_iter().filter_map(|popup| popup.title).collect()
+        };
+        {
+            let mut state = editor.app.state.lock().await;
+            state.popup_manager.open_popup(popup("clock", false));
+            state.popup_manager.open_popup(popup("info", true));
+        }
+
+        // Esc in insert mode leaves the mode and keeps the popups
+        editor.keys("i<Esc>").await;
+        assert_eq!(editor.mode().await, Mode::Normal);
+        let popups = editor.app.state.lock().await.popup_manager.get_popups();
+        assert_eq!(titles(popups), ["clock", "info"]);
+
+        // In normal mode it closes only the dismissable one
+        editor.keys("<Esc>").await;
+        let popups = editor.app.state.lock().await.popup_manager.get_popups();
+        assert_eq!(titles(popups), ["clock"]);
+        editor.keys("<Esc>").await;
+        let popups = editor.app.state.lock().await.popup_manager.get_popups();
+        assert_eq!(titles(popups), ["clock"]);
+    }
+
+    #[tokio::test]
+    async fn test_leader_hint() {
+        let mut editor = TestEditor::new(&[("a.txt", "one\ntwo")]);
+        editor
+            .keys(":nnoremap <lt>leader>d x<CR>:set tm=0<CR><Space>")
+            .await;
+        editor.app.tick().await;
+        let popups = editor.app.state.lock().await.popup_manager.get_popups();
+        assert_eq!(popups.len(), 1);
+        assert_eq!(popups[0].title.as_deref(), Some("<Space>"));
+        assert_eq!(
+            popups[0].lines,
+            ["d  x", "q  close the window", "w  save the buffer"]
+        );
+
+        // The leader waits for the next key while the hint is shown
+        editor.keys("d").await;
+        assert_eq!(editor.lines().await, ["ne", "two"]);
+        assert!(editor.app.state.lock().await.popup_manager.is_empty());
+
+        // Mappings follow the leader when it changes
+        editor.keys(":set tm=1000 mapleader=,<CR><Space>d").await;
+        assert_eq!(editor.lines().await, ["ne", "two"]);
+        editor.keys(",d").await;
+        assert_eq!(editor.lines().await, ["e", "two"]);
+    }
+
+    #[tokio::test]
+    async fn test_example_config() {
+        let mut editor = TestEditor::new(&[("a.txt", "one\ntwo")]);
+        let (_, startup) = editor
+            .app
+            .load_config(Path::new("examples/config.toml"))
+            .await;
+        assert_eq!(editor.app.run_commands(&startup).await, 0);
+        {
+            let state = editor.app.state.lock().await;
+            let errors: Vec<String> = state
+                .messages
+                .get_history()
+                .filter(|entry| entry.message.level == MessageLevel::Error)
+                .map(|entry| entry.message.text.clone())
+                .collect();
+            assert!(errors.is_empty(), "{:?}", errors);
+            assert!(!state.options.wrap);
+        }
+
+        editor.keys(":Dup<CR>").await;
+        assert_eq!(editor.lines().await, ["one", "one", "two"]);
+    }
+
+    #[tokio::test]
+    async fn test_startup_commands() {
+        let mut editor = TestEditor::new(&[("a.txt", "one\ntwo")]);
+        editor.keys("j").await;
+        let (_, startup) = editor
+            .app
+            .load_config(Path::new("fixtures/commands.toml"))
+            .await;
+        editor.app.run_commands(&startup).await;
+
+        assert_eq!(editor.lines().await, ["hello", "one", "two"]);
+        assert_eq!(editor.mode().await, Mode::Normal);
+        let state = editor.app.state.lock().await;
+        assert!(state.options.number);
+        assert_eq!(state.options.tabstop, 4);
+        // Failing commands are reported, and the ones after them still run
+        let errors: Vec<String> = state
+            .messages
+            .get_history()
+            .map(|entry| entry.message.text.clone())
+            .filter(|text| !text.starts_with("config.toml"))
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "E488: Trailing characters",
+                "E492: Not an editor command: nosuchcommand"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replay_session() {
+        let keys = "jddihi <Esc>:set nu<CR>";
+        let mut typed = TestEditor::new(&[("a.txt", "one\ntwo\nthree")]);
+        typed.keys(keys).await;
+        assert_eq!(typed.lines().await, ["one", "hi three"]);
+
+        let session = typed.path("session.jsonl");
+        let term_size = typed.app.state.lock().await.term_size;
+        let mut recorder = Recorder::create(&session, term_size).unwrap();
+        for key in parse_keys(keys).unwrap() {
+            recorder.record(&key_event(key)).unwrap();
+        }
+
+        // Replaying the session in a terminal of another size, and recording it again
+        let replayed = TestEditor::new(&[("a.txt", "one\ntwo\nthree")]);
+        replayed
+            .app
+            .state
+            .lock()
+            .await
+            .resize(UVec2::new(40, 10))
+            .await;
+        let replayed = Mutex::new(replayed.app);
+        let mut source = ReplaySource::open(&session, true).unwrap();
+        let again = typed.path("again.jsonl");
+        let mut recorder = Recorder::create(&again, term_size).unwrap();
+        let failed = EditorApplication::run_source(&replayed, &mut source, Some(&mut recorder))
+            .await
+            .unwrap();
+        assert_eq!(failed, 0);
+
+        let replayed = replayed.lock().await;
+        let state = replayed.state.lock().await;
+        let win = state.get_active_window().unwrap();
+        let buf = win.lock().await.get_buffer();
+        assert_eq!(buf.lock().await.get_all_lines(), typed.lines().await);
+        assert!(state.options.number);
+        assert_eq!(state.term_size, term_size);
+        assert_eq!(
+            state.get_state_hash().await,
+            typed.app.state.lock().await.get_state_hash().await
+        );
+
+        let events = |path: &Path| {
+            let source = std::fs::read_to_string(path).unwrap();
+            source
+                .lines()
+                .map(|line| serde_json::from_str::<RecordedEvent>(line).unwrap().event)
+                .collect::<Vec<Event>>()
+        };
+        // After the size it started with
+        assert_eq!(events(&again)[1..], events(&session));
+    }
+
+    #[tokio::test]
+    async fn test_time_travel_limits() {
+        let mut editor = TestEditor::new(&[("a.txt", "one")]);
+        editor.keys("xx").await;
+        editor.keys(":earlier 99999999999h<CR>").await;
+        assert_eq!(editor.lines().await, ["one"]);
+        editor.keys(":later 99999999999h<CR>").await;
+        assert_eq!(editor.lines().await, ["e"]);
+
+        editor.keys(":earlier 9999999999999999h<CR>").await;
+        assert_eq!(editor.lines().await, ["e"]);
+        let state = editor.app.state.lock().await;
+        assert_eq!(
+            state.messages.get_current().unwrap().text,
+            "E475: Invalid argument: 9999999999999999h"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_expandtab() {
+        let mut editor = TestEditor::new(&[("a.txt", "")]);
+        editor.keys("i<Tab>x<Esc>:set et ts=4<CR>").await;
+        editor.keys("A<Tab>y<Tab><Esc>").await;
+        // Spaces reach the same columns a tab would
+        assert_eq!(editor.lines().await, vec!["\tx   y   "]);
+
+        let mut editor = TestEditor::new(&[("a.txt", "日本")]);
+        editor.keys(":set et ts=4<CR>A<Tab>x<Esc>").await;
+        // Two wide characters already take a tab stop
+        assert_eq!(editor.lines().await, vec!["日本    x"]);
+    }
+
+    #[tokio::test]
+    async fn test_quit_with_unsaved_changes() {
+        let mut editor = TestEditor::new(&[("a.txt", "one")]);
+        editor.keys("x:q<CR>").await;
+        assert!(!editor.app.is_quit);
+        let state = editor.app.state.lock().await;
+        let message = state.messages.get_current().unwrap();
+        assert!(message.text.starts_with("E162: "), "{}", message.text);
+        drop(state);
+
+        editor.keys(":qa<CR>").await;
+        assert!(!editor.app.is_quit);
+        editor.keys(":q!<CR>").await;
+        assert!(editor.app.is_quit);
+
+        let mut editor = TestEditor::new(&[("a.txt", "one")]);
+        editor.keys("x:wq<CR>").await;
+        assert!(editor.app.is_quit);
+    }
+
+    #[tokio::test]
+    async fn test_revision() {
+        let mut editor = TestEditor::new(&[("a.txt", "one\ntwo")]);
+        let revision = |editor: &TestEditor| editor.app.state.try_lock().unwrap().get_revision();
+
+        // Waiting for keys changes nothing to draw
+        let idle = revision(&editor);
+        editor.app.tick().await;
+        assert_eq!(revision(&editor), idle);
+
+        editor.keys("j").await;
+        let moved = revision(&editor);
+        assert!(moved > idle);
+
+        editor
+            .api
+            .set_statusline_segment("x".to_string(), "x".to_string(), StatusAlign::Left)
+            .await
+            .unwrap();
+        let requested = revision(&editor);
+        assert!(requested > moved);
+
+        // Messages can come from anywhere
+        editor.app.state.lock().await.messages.info("hello");
+        assert!(revision(&editor) > requested);
+    }
+
+    #[tokio::test]
+    async fn test_save_reports_result() {
+        let message = |editor: &TestEditor| {
+            let state = editor.app.state.try_lock().unwrap();
+            state.messages.get_current().unwrap().text
+        };
+
+        let mut editor = TestEditor::new(&[("a.txt", "one\ntwo\n")]);
+        editor.keys(":w<CR>").await;
+        assert_eq!(message(&editor), "\"a.txt\" 2L, 8B written");
+
+        let mut editor = TestEditor::new(&[]);
+        assert!(matches!(
+            editor.api.save_buffer(None, None).await,
+            Err(CuprumApiError::NoFileName)
+        ));
+        assert_eq!(message(&editor), "E32: No file name");
+    }
+
+    #[tokio::test]
+    async fn test_buf_write_pre() {
+        let mut editor = TestEditor::new(&[("a.txt", "one  \ntwo")]);
+        let formatter = Arc::new(InProcessPlugin::new());
+        let reader = InProcessPlugin::new().with_capabilities("reader", &[Capability::ReadBuffers]);
+        for plugin in [&*formatter, &reader] {
+            EditorApiHandler::with_plugin(editor.app.state.clone(), plugin.channel.clone())
+                .process(CuprumApiRequestKind::Subscribe {
+                    events: vec![EventKind::BufWritePre],
+                })
+                .await
+                .unwrap();
+        }
+
+        // Both remove the trailing spaces of the first line of what they are sent
+        for plugin in [formatter.clone(), Arc::new(reader)] {
+            tokio::spawn(async move {
+                loop {
+                    plugin.notify.notified().await;
+                    for request in plugin.take_requests().await {
+                        let PluginRequestKind::BufWritePre { content, .. } = request.request else {
+                            continue;
+                        };
+                        let line = content.lines().next().unwrap();
+                        let edit = RangeEdit {
+                            range: TextRange {
+                                start: UVec2::new(line.trim_end().len(), 0),
+                                end: UVec2::new(line.len(), 0),
+                            },
+                            text: String::new(),
+                        };
+                        plugin
+                            .respond(PluginResponse {
+                                request_id: request.request_id,
+                                result: Ok(PluginResponseKind::Edits(vec![edit])),
+                            })
+                            .await;
+                    }
+                }
+            });
+        }
+
+        // The formatted buffer is written, once
+        editor.keys("A  <Esc>:w<CR>").await;
+        assert_eq!(editor.lines().await, vec!["one", "two"]);
+        assert_eq!(editor.cursor().await, UVec2::new(2, 0));
+        assert_eq!(
+            std::fs::read_to_string(editor.path("a.txt")).unwrap(),
+            "one\ntwo"
+        );
+        let state = editor.app.state.lock().await;
+        let history: Vec<String> = state
+            .messages
+            .get_history()
+            .map(|entry| entry.message.text.clone())
+            .collect();
+        assert_eq!(
+            history[history.len() - 2..],
+            [
+                "reader: BufWritePre: permission denied: needs the write_buffers capability",
+                "\"a.txt\" 2L, 7B written",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_buffers_and_windows() {
+        let paths: Vec<String> = (0..3)
+            .map(|i| {
+                let path = std::env::temp_dir().join(format!("cuprum_test_list_{}.txt", i));
+                std::fs::write(&path, "").unwrap();
+                path.to_string_lossy().to_string()
+            })
+            .collect();
+        let state = Arc::new(Mutex::new(EditorState::new(paths.clone()).unwrap()));
+        let mut handler = EditorApiHandler::new(state.clone());
+
+        assert!(matches!(
+            handler.process(CuprumApiRequestKind::ListBuffers {}).await,
+            Ok(Some(CuprumApiResponseKind::ListBuffers(ids)))
+                if ids == vec![BufferId(0), BufferId(1), BufferId(2)]
+        ));
+        assert!(matches!(
+            handler.process(CuprumApiRequestKind::ListWindows {}).await,
+            Ok(Some(CuprumApiResponseKind::ListWindows(windows))) if windows == vec![
+                (WindowId(0), BufferId(0)),
+                (WindowId(1), BufferId(1)),
+                (WindowId(2), BufferId(2)),
+            ]
+        ));
+        assert!(matches!(
+            handler
+                .process(CuprumApiRequestKind::GetActiveWindow {})
+                .await,
+            Ok(Some(CuprumApiResponseKind::GetActiveWindow(WindowId(0))))
+        ));
+
+        for path in paths {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_open_and_close_window() {
+        let state = Arc::new(Mutex::new(EditorState::new(Vec::new()).unwrap()));
+        let mut handler = EditorApiHandler::new(state.clone());
+
+        let request = CuprumApiRequestKind::OpenWindow {
+            buf: Some(BufferId(0)),
+            split: SplitDirection::Vertical,
+        };
+        assert!(matches!(
+            handler.process(request).await,
+            Ok(Some(CuprumApiResponseKind::OpenWindow(WindowId(1))))
+        ));
+        assert_eq!(state.lock().await.get_active_window_id(), WindowId(1));
+        // Side by side on a terminal of 80 columns, with a border between them
+        let geometry = async |id: WindowId| {
+            let state = state.lock().await;
+            let win = state.window_manager.get_window(id).unwrap();
+            let win = win.lock().await;
+            (win.get_position(), win.get_size())
+        };
+        assert_eq!(
+            geometry(WindowId(0)).await,
+            (UVec2::new(0, 0), UVec2::new(40, 23))
+        );
+        assert_eq!(
+            geometry(WindowId(1)).await,
+            (UVec2::new(41, 0), UVec2::new(39, 23))
+        );
+        assert!(matches!(
+            handler.process(CuprumApiRequestKind::ListWindows {}).await,
+            Ok(Some(CuprumApiResponseKind::ListWindows(windows)))
+                if windows == vec![(WindowId(0), BufferId(0)), (WindowId(1), BufferId(0))]
+        ));
+
+        handler
+            .process(CuprumApiRequestKind::CloseWindow { win: None })
+            .await
+            .unwrap();
+        assert_eq!(state.lock().await.get_active_window_id(), WindowId(0));
+        assert_eq!(
+            geometry(WindowId(0)).await,
+            (UVec2::new(0, 0), UVec2::new(80, 23))
+        );
+        assert!(matches!(
+            handler
+                .process(CuprumApiRequestKind::CloseWindow { win: None })
+                .await,
+            Err(CuprumApiError::NotAllowed(_))
+        ));
+        assert_eq!(
+            handler
+                .process(CuprumApiRequestKind::CloseWindow {
+                    win: Some(WindowId(1))
+                })
+                .await
+                .unwrap_err(),
+            CuprumApiError::NotFound("window".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ranged_text() {
+        let mut editor = TestEditor::new(&[("a.txt", "one\ntwo\nthree\n")]);
+        assert_eq!(
+            editor.api.get_lines(None, 1, 5).await.unwrap(),
+            vec!["two", "three"]
+        );
+        assert_eq!(editor.api.get_text(None, 2, 9).await.unwrap(), "e\ntwo\nt");
+        assert_eq!(editor.api.get_text(None, 8, 14).await.unwrap(), "three\n");
+        assert_eq!(
+            editor.api.get_lines(None, 3, 1).await.unwrap_err(),
+            CuprumApiError::OutOfRange
+        );
+        assert_eq!(
+            editor.api.get_text(None, 5, 15).await.unwrap_err(),
+            CuprumApiError::OutOfRange
+        );
+    }
+
+    #[tokio::test]
+    async fn test_batch() {
+        let state = Arc::new(Mutex::new(EditorState::new(Vec::new()).unwrap()));
+        let mut api = CuprumApi::new(spawn_builtin_handler(state));
+        let results = api
+            .batch()
+            .insert_line(None, 0, "first".to_string())
+            .get_line(None, 0)
+            .get_line(None, 9)
+            .change_mode(Mode::Visual)
+            .get_mode()
+            .send()
+            .await
+            .unwrap();
+
+        // The failed request does not stop the ones after it
+        assert!(matches!(
+            &results[..],
+            [
+                Ok(None),
+                Ok(Some(CuprumApiResponseKind::GetLine(line))),
+                Err(CuprumApiError::OutOfRange),
+                Ok(None),
+                Ok(Some(CuprumApiResponseKind::GetMode(Mode::Visual))),
+            ] if line == "first"
+        ));
+
+        let mut provider = api.provider;
+        let nested = provider
+            .send_message(CuprumApiRequestKind::Batch {
+                requests: vec![CuprumApiRequestKind::Batch {
+                    requests: Vec::new(),
+                }],
+            })
+            .await;
+        assert!(matches!(
+            nested,
+            Ok(Some(CuprumApiResponseKind::Batch(results)))
+                if matches!(results[..], [Err(CuprumApiError::InvalidArgument(_))])
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_batch_is_atomic() {
+        let state = Arc::new(Mutex::new(EditorState::new(Vec::new()).unwrap()));
+        let done = Arc::new(AtomicBool::new(false));
+        // Takes the state as often as it can while the batch runs
+        let observer = tokio::spawn({
+            let state = state.clone();
+            let done = done.clone();
+            async move {
+                let mut counts = std::collections::BTreeSet::new();
+                while !done.load(Ordering::Relaxed) {
+                    let state = state.lock().await;
+                    let buf = get_buffer(&state, None).await.unwrap();
+                    counts.insert(buf.lock().await.get_line_count());
+                }
+                counts
+            }
+        });
+
+        let requests = (0..200)
+            .map(|y| CuprumApiRequestKind::InsertLine {
+                buf: None,
+                y,
+                line: y.to_string(),
+            })
+            .collect();
+        let mut handler = EditorApiHandler::new(state.clone());
+        handler
+            .process(CuprumApiRequestKind::Batch { requests })
+            .await
+            .unwrap();
+        done.store(true, Ordering::Relaxed);
+
+        let counts = observer.await.unwrap();
+        assert!(
+            counts.iter().all(|count| [1, 201].contains(count)),
+            "{:?}",
+            counts
+        );
+    }
+
+    /// Apply changes sent to plugins to a copy of the content
+    fn apply_changes(content: &mut String, changes: Vec<BufferChange>) {
+        for change in changes {
+            let offset = |end: usize| {
+                content
+                    .char_indices()
+                    .nth(end)
+                    .map_or(content.len(), |(i, _)| i)
+            };
+            let range = offset(change.start)..offset(change.old_end);
+            content.replace_range(range, &change.new_text);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_insert_line_through_api() {
+        let mut editor = TestEditor::new(&[("a.txt", "a\nb\n")]);
+        let buf = editor
+            .app
+            .state
+            .lock()
+            .await
+            .buffer_manager
+            .get_buffer(BufferId(0))
+            .unwrap();
+        buf.lock().await.take_changes(Duration::ZERO);
+
+        let mut content = buf.lock().await.get_content();
+        for (y, line, lines) in [
+            (0, "top", vec!["top", "a", "b"]),
+            (2, "middle", vec!["top", "a", "middle", "b"]),
+            (4, "end", vec!["top", "a", "middle", "b", "end"]),
+        ] {
+            editor
+                .api
+                .insert_line(None, y, line.to_string())
+                .await
+                .unwrap();
+            assert_eq!(editor.lines().await, lines);
+
+            // Plugins following the changes end up with the same content
+            apply_changes(&mut content, buf.lock().await.take_changes(Duration::ZERO));
+            assert_eq!(content, buf.lock().await.get_content());
+        }
+        assert_eq!(content, "top\na\nmiddle\nb\nend\n");
+
+        assert_eq!(
+            editor.api.insert_line(None, 6, "x".to_string()).await,
+            Err(CuprumApiError::OutOfRange)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_remove_line_through_api() {
+        let mut editor = TestEditor::new(&[("a.txt", "a\nb\nc\nd\n")]);
+        let buf = editor
+            .app
+            .state
+            .lock()
+            .await
+            .buffer_manager
+            .get_buffer(BufferId(0))
+            .unwrap();
+        buf.lock().await.take_changes(Duration::ZERO);
+
+        let mut content = buf.lock().await.get_content();
+        for (y, removed, lines) in [
+            (0, "a", vec!["b", "c", "d"]),
+            (1, "c", vec!["b", "d"]),
+            (1, "d", vec!["b"]),
+            (0, "b", vec![""]),
+        ] {
+            assert_eq!(editor.api.remove_line(None, y).await.unwrap(), removed);
+            assert_eq!(editor.lines().await, lines);
+            apply_changes(&mut content, buf.lock().await.take_changes(Duration::ZERO));
+            assert_eq!(content, buf.lock().await.get_content());
+        }
+        assert_eq!(content, "\n");
+
+        assert_eq!(
+            editor.api.remove_line(None, 1).await,
+            Err(CuprumApiError::OutOfRange)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_arrow_keys() {
+        let mut editor = TestEditor::new(&[("a.txt", "abc\nd\nefgh")]);
+        editor.keys("<Right><Right><Down>").await;
+        assert_eq!(editor.cursor().await, UVec2::new(2, 1));
+        editor.keys("<End><Up><Home>").await;
+        assert_eq!(editor.cursor().await, UVec2::new(0, 0));
+
+        // In insert mode the cursor can go past the last character
+        editor.keys("i<End>x<Down><Down><Left>y<Home>z<Esc>").await;
+        assert_eq!(editor.lines().await, vec!["abcx", "d", "zefgyh"]);
+
+        let win = editor.app.state.lock().await.get_active_window().unwrap();
+        win.lock().await.set_size(UVec2::new(80, 2));
+        editor.keys("gg<PageDown>").await;
+        assert_eq!(editor.cursor().await.y, 1);
+        editor.keys("<PageDown><PageDown>").await;
+        assert_eq!(editor.cursor().await.y, 2);
+        editor.keys("i<PageUp><PageUp><Esc>").await;
+        assert_eq!(editor.cursor().await.y, 0);
+    }
+
+    #[tokio::test]
+    async fn test_control_keys_in_insert_mode() {
+        let mut editor = TestEditor::new(&[("a.txt", "")]);
+        editor.keys("ia").await;
+        for (code, modifiers) in [
+            (TermKeyCode::Char('s'), KeyModifiers::CONTROL),
+            (TermKeyCode::Char('\u{13}'), KeyModifiers::NONE),
+            (TermKeyCode::Char('\u{0}'), KeyModifiers::NONE),
+            (TermKeyCode::Char('\u{7f}'), KeyModifiers::NONE),
+        ] {
+            editor
+                .event(Event::Key(KeyEvent::new(code, modifiers)))
+                .await;
+        }
+        editor.keys("b<Esc>").await;
+        assert_eq!(editor.lines().await, vec!["ab"]);
+    }
+
+    #[tokio::test]
+    async fn test_delete_before_cursor() {
+        let mut editor = TestEditor::new(&[("a.txt", "one\nfoo.bar(baz  ")]);
+        editor.keys("jA<C-w>").await;
+        assert_eq!(editor.lines().await, vec!["one", "foo.bar("]);
+        editor.keys("<C-w>").await;
+        assert_eq!(editor.lines().await, vec!["one", "foo.bar"]);
+        // Sent as a control character by some terminals
+        editor
+            .event(Event::Key(KeyEvent::new(
+                TermKeyCode::Char('\u{17}'),
+                KeyModifiers::NONE,
+            )))
+            .await;
+        assert_eq!(editor.lines().await, vec!["one", "foo."]);
+
+        editor.keys("x<Left><Left><C-u>").await;
+        assert_eq!(editor.lines().await, vec!["one", ".x"]);
+        assert_eq!(editor.cursor().await, UVec2::new(0, 1));
+        editor.keys("<C-u>").await;
+        assert_eq!(editor.lines().await, vec!["one.x"]);
+        assert_eq!(editor.cursor().await, UVec2::new(3, 0));
+        editor.keys("<C-w><C-w><Esc>").await;
+        assert_eq!(editor.lines().await, vec![".x"]);
+    }
+
+    #[tokio::test]
+    async fn test_append() {
+        // Start, middle and end of a line
+        for (start, x) in [("0", 0), ("l", 1), ("$", 2)] {
+            let mut editor = TestEditor::new(&[("a.txt", "abc")]);
+            editor.keys(start).await;
+            editor.keys("a").await;
+            assert_eq!(editor.cursor().await.x, x + 1, "{}a", start);
+            editor.keys("<Esc>").await;
+            assert_eq!(editor.cursor().await.x, x, "{}a<Esc>", start);
+
+            editor.keys("aX<Esc>").await;
+            assert_eq!(editor.cursor().await.x, x + 1, "{}aX<Esc>", start);
+
+            editor.keys("A").await;
+            assert_eq!(editor.cursor().await.x, 4, "{}A", start);
+            editor.keys("Y<Esc>").await;
+            assert_eq!(editor.cursor().await.x, 4, "{}AY<Esc>", start);
+            editor.keys("A<Esc>").await;
+            assert_eq!(editor.cursor().await.x, 4, "{}A<Esc>", start);
+        }
+
+        let mut editor = TestEditor::new(&[("a.txt", "")]);
+        editor.keys("a").await;
+        assert_eq!(editor.cursor().await.x, 0);
+        editor.keys("ab<Esc>").await;
+        assert_eq!(editor.lines().await, vec!["ab"]);
+        assert_eq!(editor.cursor().await.x, 1);
+    }
+
+    #[tokio::test]
+    async fn test_multibyte_lines() {
+        let lines = ["abcdefgh", "日本語", "ab", "äöü ß", "", "こんにちは世界"];
+        let mut editor = TestEditor::new(&[("a.txt", &lines.join("\n"))]);
+        editor.keys("$").await;
+        for _ in 0..2 {
+            for (y, line) in lines.iter().enumerate() {
+                let cursor = editor.app.state.lock().await.get_active_window().unwrap();
+                let cursor = cursor.lock().await.get_render_cursor().await;
+                assert_eq!(cursor.y, y);
+                assert!(
+                    cursor.x < line.chars().count().max(1),
+                    "{:?} on {}",
+                    cursor,
+                    line
+                );
+                editor.keys("j").await;
+            }
+            editor.keys("gg").await;
+        }
+
+        // Columns count characters, so edits land where the cursor is
+        editor.keys(":6<CR>lllix<Esc>").await;
+        assert_eq!(editor.lines().await[5], "こんにxちは世界");
+        editor.keys(":2<CR>A!<CR><Esc>klx").await;
+        assert_eq!(editor.lines().await[1..3], ["日語!", ""]);
+        editor.keys("G").await;
+        assert_eq!(editor.cursor().await, UVec2::new(7, 6));
+    }
+
+    #[tokio::test]
+    async fn test_grapheme_clusters() {
+        // A decomposed が, a ZWJ family and an accent, starting at chars 0, 2 and 7
+        let line = "か\u{3099}👨\u{200d}👩\u{200d}👧e\u{301}";
+        let mut editor = TestEditor::new(&[("a.txt", &format!("abc\n{}\nが", line))]);
+        let render_cursor = |editor: &TestEditor| {
+            let win = editor.app.state.try_lock().unwrap().get_active_window();
+            let win = win.unwrap();
+            async move { win.lock().await.get_render_cursor().await }
+        };
+
+        editor.keys("jl").await;
+        assert_eq!(editor.cursor().await, UVec2::new(2, 1));
+        editor.keys("l").await;
+        assert_eq!(editor.cursor().await, UVec2::new(7, 1));
+        editor.keys("lh").await;
+        assert_eq!(editor.cursor().await, UVec2::new(2, 1));
+        // Vertical moves keep the column, drawn at the start of its cluster
+        editor.keys("kllj").await;
+        assert_eq!(render_cursor(&editor).await, UVec2::new(2, 1));
+
+        editor.keys("x").await;
+        assert_eq!(editor.lines().await[1], "か\u{3099}e\u{301}");
+        editor.keys("0a<BS><Esc>").await;
+        assert_eq!(editor.lines().await[1], "e\u{301}");
+        editor.keys("i<Del><Esc>").await;
+        assert_eq!(editor.lines().await[1], "");
+
+        // The precomposed form is one char
+        editor.keys("jaa<Esc>").await;
+        assert_eq!(editor.lines().await[2], "がa");
+    }
+
+    #[tokio::test]
+    async fn test_visual_selection() {
+        let content = "zero\none two\nthree\n\nfour";
+        for (keys, selection, lines, cursor) in [
+            // Forwards on one line, up to the last character
+            (
+                "lvll",
+                ((1, 0), (4, 0)),
+                vec!["z", "one two", "three", "", "four"],
+                (0, 0),
+            ),
+            // Backwards over lines
+            (
+                "jjllvkh",
+                ((1, 1), (3, 2)),
+                vec!["zero", "oee", "", "four"],
+                (1, 1),
+            ),
+            // Backwards on one line
+            (
+                "jllllvhh",
+                ((2, 1), (5, 1)),
+                vec!["zero", "onwo", "three", "", "four"],
+                (2, 1),
+            ),
+            // Forwards over lines
+            (
+                "jlllvj",
+                ((3, 1), (4, 2)),
+                vec!["zero", "onee", "", "four"],
+                (3, 1),
+            ),
+            // Up to an empty line takes its line break
+            (
+                "jjlvj",
+                ((1, 2), (0, 4)),
+                vec!["zero", "one two", "tfour"],
+                (1, 2),
+            ),
+        ] {
+            let mut editor = TestEditor::new(&[("a.txt", content)]);
+            editor.keys(keys).await;
+            let ((start_x, start_y), (end_x, end_y)) = selection;
+            assert_eq!(
+                editor.api.get_selection(None).await.unwrap(),
+                TextRange {
+                    start: UVec2::new(start_x, start_y),
+                    end: UVec2::new(end_x, end_y),
+                },
+                "{}",
+                keys
+            );
+
+            editor.keys("d").await;
+            assert_eq!(editor.lines().await, lines, "{}", keys);
+            assert_eq!(editor.cursor().await, UVec2::new(cursor.0, cursor.1));
+            assert_eq!(editor.mode().await, Mode::Normal);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backspace_at_buffer_start() {
+        let mut editor = TestEditor::new(&[("a.txt", "")]);
+        editor.keys("i<BS><BS>ab<BS><BS><BS><Esc>").await;
+        assert_eq!(editor.lines().await, vec![""]);
+        assert_eq!(editor.cursor().await, UVec2::new(0, 0));
+
+        // Column 0 of a later line joins it to the previous one, until the first line
+        let mut editor = TestEditor::new(&[("a.txt", "ab\ncd")]);
+        editor.keys("ji<BS>").await;
+        assert_eq!(editor.lines().await, vec!["abcd"]);
+        assert_eq!(editor.cursor().await, UVec2::new(2, 0));
+        editor.keys("<BS><BS><BS><BS>").await;
+        assert_eq!(editor.lines().await, vec!["cd"]);
+        assert_eq!(editor.cursor().await, UVec2::new(0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_delete_at_buffer_end() {
+        for content in ["ab\ncd", "ab\ncd\n"] {
+            let mut editor = TestEditor::new(&[("a.txt", content)]);
+            editor.keys("A<Del>").await;
+            assert_eq!(editor.lines().await, vec!["abcd"], "{:?}", content);
+            // The joined line is deleted up to the end of the buffer, then nothing
+            editor.keys("<Del><Del><Del><Del><Esc>").await;
+            assert_eq!(editor.lines().await, vec!["ab"], "{:?}", content);
+        }
+
+        let mut editor = TestEditor::new(&[("a.txt", "")]);
+        editor.keys("i<Del><Esc>").await;
+        assert_eq!(editor.lines().await, vec![""]);
+    }
+
+    #[tokio::test]
+    async fn test_remove_char_at_line_end() {
+        let mut editor = TestEditor::new(&[("a.txt", "abc\nxyz")]);
+        editor.keys("$x").await;
+        assert_eq!(editor.cursor().await, UVec2::new(1, 0));
+        editor.keys("xx").await;
+        assert_eq!(editor.lines().await, vec!["", "xyz"]);
+        assert_eq!(editor.cursor().await, UVec2::new(0, 0));
+        editor.keys("x").await;
+        assert_eq!(editor.lines().await, vec!["", "xyz"]);
+        editor.keys("jl").await;
+        assert_eq!(editor.cursor().await, UVec2::new(1, 1));
+
+        // Empty lines are never joined
+        let mut editor = TestEditor::new(&[("a.txt", "one\n\ntwo")]);
+        editor.keys("jxx").await;
+        assert_eq!(editor.lines().await, vec!["one", "", "two"]);
+        assert!(matches!(
+            editor.api.remove_char(None, UVec2::new(3, 0)).await,
+            Err(CuprumApiError::OutOfRange)
+        ));
+        assert_eq!(editor.lines().await, vec!["one", "", "two"]);
+    }
+
+    #[tokio::test]
+    async fn test_delete_last_line() {
+        let mut editor = TestEditor::new(&[("a.txt", "one\ntwo\nthree")]);
+        editor.keys("G$dd").await;
+        assert_eq!(editor.lines().await, vec!["one", "two"]);
+        assert_eq!(editor.cursor().await, UVec2::new(2, 1));
+        editor.keys("k").await;
+        assert_eq!(editor.cursor().await, UVec2::new(2, 0));
+        editor.keys("jx").await;
+        assert_eq!(editor.lines().await, vec!["one", "tw"]);
+
+        // Other windows on the buffer are clamped too
+        let win = editor.api.get_active_window().await.unwrap();
+        editor
+            .api
+            .open_window(None, SplitDirection::Horizontal)
+            .await
+            .unwrap();
+        editor.keys("Gdddd").await;
+        assert_eq!(editor.lines().await, vec![""]);
+        assert_eq!(
+            editor.api.get_cursor(Some(win)).await.unwrap(),
+            UVec2::new(0, 0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resize() {
+        let lines: Vec<String> = (0..30).map(|i| i.to_string()).collect();
+        let mut editor = TestEditor::new(&[("a.txt", &lines.join("\n"))]);
+        editor.keys("G").await;
+        let win = editor.app.state.lock().await.get_active_window().unwrap();
+        assert_eq!(win.lock().await.get_scroll(), 7);
+
+        // The window follows the terminal before anything is drawn
+        editor.event(Event::Resize(40, 11)).await;
+        assert_eq!(editor.app.state.lock().await.term_size, UVec2::new(40, 11));
+        let win = win.lock().await;
+        assert_eq!(win.get_size(), UVec2::new(40, 10));
+        assert_eq!(win.get_scroll(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_empty_buffer() {
+        // Each step leaves the lines, the line count and the reachable positions agreeing
+        let mut editor = TestEditor::new(&[]);
+        for (keys, lines, end) in [
+            ("0", vec![""], UVec2::new(0, 0)),
+            ("ia<Esc>", vec!["a"], UVec2::new(0, 0)),
+            ("x", vec![""], UVec2::new(0, 0)),
+            ("ia<CR><Esc>", vec!["a", ""], UVec2::new(0, 1)),
+            ("i<BS><Esc>", vec!["a"], UVec2::new(0, 0)),
+            ("dd", vec![""], UVec2::new(0, 0)),
+            ("dd", vec![""], UVec2::new(0, 0)),
+        ] {
+            editor.keys(keys).await;
+            assert_eq!(editor.lines().await, lines, "{}", keys);
+            assert_eq!(
+                editor.api.get_line_count(None).await.unwrap(),
+                lines.len(),
+                "{}",
+                keys
+            );
+            editor.keys("Gjjll").await;
+            assert_eq!(editor.cursor().await, end, "{}", keys);
+        }
+        assert_eq!(editor.api.get_content(None).await.unwrap(), "");
+    }
+
+    #[tokio::test]
+    async fn test_motions_with_trailing_line_break() {
+        for (content, end) in [
+            ("one\ntwo", UVec2::new(2, 1)),
+            ("one\ntwo\n", UVec2::new(2, 1)),
+            ("one\ntwo\n\n", UVec2::new(0, 2)),
+            ("\n", UVec2::new(0, 0)),
+        ] {
+            let mut editor = TestEditor::new(&[("a.txt", content)]);
+            editor.keys("G").await;
+            assert_eq!(editor.cursor().await, end, "{:?}", content);
+            editor.keys("jjj").await;
+            assert_eq!(editor.cursor().await.y, end.y, "{:?}", content);
+
+            editor.keys(":w<CR>").await;
+            assert_eq!(
+                std::fs::read_to_string(editor.path("a.txt")).unwrap(),
+                content
+            );
+        }
+
+        // `:N` past the end stops on the last line, and never past its last character
+        let mut editor = TestEditor::new(&[("a.txt", "abc\nd\n")]);
+        editor.keys("ll:9<CR>").await;
+        assert_eq!(editor.cursor().await, UVec2::new(0, 1));
+    }
+
+    #[tokio::test]
+    async fn test_plugin_completion_source() {
+        let mut editor = TestEditor::new(&[("a.txt", "apple\n\n")]);
+        let dict = Arc::new(InProcessPlugin::new());
+        let slow = InProcessPlugin::new();
+        for (plugin, name) in [(&*dict, "dict"), (&slow, "slow")] {
+            EditorApiHandler::with_plugin(editor.app.state.clone(), plugin.channel.clone())
+                .process(CuprumApiRequestKind::RegisterCompletionSource {
+                    name: name.to_string(),
+                    trigger_chars: vec!['.'],
+                })
+                .await
+                .unwrap();
+        }
+
+        // `dict` answers with the fruits starting with the prefix, `slow` never answers
+        let source = dict.clone();
+        tokio::spawn(async move {
+            loop {
+                source.notify.notified().await;
+                for request in source.take_requests().await {
+                    let PluginRequestKind::Complete { prefix, .. } = request.request else {
+                        continue;
+                    };
+                    let items = ["apricot", "avocado"]
+                        .into_iter()
+                        .filter(|word| word.starts_with(&prefix))
+                        .map(|word| CompletionItem {
+                            label: word.to_string(),
+                            insert_text: word.to_string(),
+                            detail: Some("fruit".to_string()),
+                        })
+                        .collect();
+                    source
+                        .respond(PluginResponse {
+                            request_id: request.request_id,
+                            result: Ok(PluginResponseKind::Completions(items)),
+                        })
+                        .await;
+                }
+            }
+        });
+        let labels = |state: &EditorState| {
+            let completion = state.completion.active.as_ref().unwrap();
+            completion
+                .candidates
+                .iter()
+                .map(|candidate| format!("{}/{}", candidate.label, candidate.source))
+                .collect::<Vec<_>>()
+        };
+
+        let start = Instant::now();
+        editor.keys("jiap<C-n>").await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+        {
+            let state = editor.app.state.lock().await;
+            assert_eq!(labels(&state), vec!["apple/buffer", "apricot/dict"]);
+            let lines = state.completion.active.as_ref().unwrap().get_lines();
+            assert_eq!(
+                lines,
+                vec!["> apple           buffer", "  apricot  fruit  dict"]
+            );
+        }
+
+        editor.keys("<C-n><CR>").await;
+        assert_eq!(editor.lines().await, vec!["apple", "apricot"]);
+        assert_eq!(editor.cursor().await, UVec2::new(7, 1));
+
+        // A trigger character asks the plugin sources without a prefix
+        editor.keys(".").await;
+        let state = editor.app.state.lock().await;
+        assert_eq!(labels(&state), vec!["apricot/dict", "avocado/dict"]);
+    }
+
+    /// A language server with one check: `var` should be `let`
+    async fn lint(mut api: CuprumApi<BuiltinApiProvider>) {
+        let lines = api.get_all_lines(None).await.unwrap();
+        let diagnostics = lines
+            .iter()
+            .enumerate()
+            .filter_map(|(y, line)| {
+                let x = line[..line.find("var")?].chars().count();
+                Some(Diagnostic {
+                    range: TextRange {
+                        start: UVec2::new(x, y),
+                        end: UVec2::new(x + 3, y),
+                    },
+                    severity: DiagnosticSeverity::Warning,
+                    message: "use `let`".to_string(),
+                })
+            })
+            .collect();
+        api.publish_diagnostics(None, diagnostics).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_signs_and_location_list() {
+        let mut editor = TestEditor::new(&[("a.js", "var a;\nlet b;\n  var c;\nvar d;")]);
+        lint(CuprumApi::new(spawn_builtin_handler(
+            editor.app.state.clone(),
+        )))
+        .await;
+
+        let frame = Frame::capture(&*editor.app.state.lock().await)
+            .await
+            .unwrap();
+        let warning = Some(DiagnosticSeverity::Warning);
+        assert_eq!(frame.window.signs, vec![warning, None, warning, warning]);
+        assert_eq!((frame.window.position.x, frame.window.size.x), (2, 78));
+
+        let message = async |editor: &TestEditor| {
+            let state = editor.app.state.lock().await;
+            state.messages.get_current().unwrap().text
+        };
+        editor.keys(":lne<CR>").await;
+        assert_eq!(editor.cursor().await, UVec2::new(2, 2));
+        assert_eq!(message(&editor).await, "(2 of 3) warning: use `let`");
+        editor.keys(":lne<CR>").await;
+        assert_eq!(editor.cursor().await, UVec2::new(0, 3));
+        editor.keys(":lne<CR>").await;
+        assert_eq!(editor.cursor().await, UVec2::new(0, 3));
+        assert_eq!(message(&editor).await, "E553: No more items");
+        editor.keys(":lp<CR>").await;
+        assert_eq!(editor.cursor().await, UVec2::new(2, 2));
+        editor.keys(":ll<CR>").await;
+        assert_eq!(editor.cursor().await, UVec2::new(0, 0));
+        editor.keys(":ll 3<CR>").await;
+        assert_eq!(editor.cursor().await, UVec2::new(0, 3));
+    }
+
+    #[tokio::test]
+    async fn test_plugin_highlights() {
+        let mut editor = TestEditor::new(&[("a.txt", "añb foo\nfoo")]);
+        editor.api.add_highlight(0, 1, 3).await.unwrap();
+        assert_eq!(
+            editor.api.add_highlight(0, 2, 8).await,
+            Err(CuprumApiError::OutOfRange)
+        );
+        assert_eq!(
+            editor.api.add_highlight(2, 0, 0).await,
+            Err(CuprumApiError::OutOfRange)
+        );
+        let plugin = async |editor: &TestEditor| {
+            let highlights = editor.app.state.lock().await.get_highlights().await;
+            highlights
+                .into_iter()
+                .filter(|highlight| highlight.source == HighlightSource::Plugin)
+                .map(|highlight| (highlight.y, highlight.range))
+                .collect::<Vec<_>>()
+        };
+        // Byte ranges, kept when search matches are hidden
+        assert_eq!(plugin(&editor).await, vec![(0, 1..4)]);
+        editor.keys("/foo<CR>:noh<CR>").await;
+        assert_eq!(plugin(&editor).await, vec![(0, 1..4)]);
+
+        editor.api.clear_highlights().await.unwrap();
+        assert_eq!(plugin(&editor).await, vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_diagnostics_and_hover() {
+        let mut editor = TestEditor::new(&[("a.js", "// ä\n  var a = 1;")]);
+        lint(CuprumApi::new(spawn_builtin_handler(
+            editor.app.state.clone(),
+        )))
+        .await;
+
+        let highlights = editor.app.state.lock().await.get_highlights().await;
+        assert_eq!(
+            highlights,
+            vec![Highlight {
+                y: 1,
+                range: 2..5,
+                source: HighlightSource::Diagnostic(DiagnosticSeverity::Warning),
+            }]
+        );
+
+        editor.keys(":llist<CR>").await;
+        let popups = editor.app.state.lock().await.popup_manager.get_popups();
+        assert_eq!(popups[0].lines, vec!["2:3 warning: use `let`"]);
+        editor.keys("<Esc>").await;
+
+        // The fix is applied with the range of the diagnostic
+        editor
+            .api
+            .apply_text_edits(
+                None,
+                vec![RangeEdit {
+                    range: TextRange {
+                        start: UVec2::new(2, 1),
+                        end: UVec2::new(5, 1),
+                    },
+                    text: "let".to_string(),
+                }],
+            )
+            .await
+            .unwrap();
+        lint(CuprumApi::new(spawn_builtin_handler(
+            editor.app.state.clone(),
+        )))
+        .await;
+        assert_eq!(editor.lines().await, vec!["// ä", "  let a = 1;"]);
+        assert!(
+            editor
+                .app
+                .state
+                .lock()
+                .await
+                .get_highlights()
+                .await
+                .is_empty()
+        );
+
+        editor.keys("jllllll").await;
+        editor
+            .api
+            .show_hover(None, "a: number".to_string())
+            .await
+            .unwrap();
+        let popups = editor.app.state.lock().await.popup_manager.get_popups();
+        assert_eq!(popups[0].lines, vec!["a: number"]);
+        assert_eq!(popups[0].position, UVec2::new(6, 2));
+
+        // Any key closes the hover, and still does what it does
+        editor.keys("l").await;
+        assert!(
+            editor
+                .app
+                .state
+                .lock()
+                .await
+                .popup_manager
+                .get_popups()
+                .is_empty()
+        );
+        assert_eq!(editor.cursor().await, UVec2::new(7, 1));
+    }
+
+    #[tokio::test]
+    async fn test_options_through_api() {
+        let mut editor = TestEditor::new(&[("a.txt", "")]);
+        let plugin = InProcessPlugin::new();
+        EditorApiHandler::with_plugin(editor.app.state.clone(), plugin.channel.clone())
+            .process(CuprumApiRequestKind::Subscribe {
+                events: vec![EventKind::OptionChanged],
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(editor.api.get_option_as::<usize>("ts").await.unwrap(), 8);
+        assert!(!editor.api.get_option_as::<bool>("number").await.unwrap());
+        assert!(matches!(
+            editor.api.get_option_as::<bool>("tabstop").await,
+            Err(CuprumApiError::InvalidArgument(_))
+        ));
+
+        editor
+            .api
+            .set_option("ts".to_string(), "4".to_string())
+            .await
+            .unwrap();
+        assert_eq!(editor.app.state.lock().await.options.tabstop, 4);
+        assert!(matches!(
+            editor
+                .api
+                .set_option("ts".to_string(), "x".to_string())
+                .await,
+            Err(CuprumApiError::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            editor
+                .api
+                .set_option("foo".to_string(), "1".to_string())
+                .await,
+            Err(CuprumApiError::NotFound(_))
+        ));
+
+        // Setting an option to its value is not a change
+        editor.keys(":set number ts=4<CR>").await;
+        let changed = |name: &str, value: &str| {
+            CuprumNotification::Event(EditorEvent::OptionChanged {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+        };
+        assert_eq!(
+            plugin.channel.drain().await,
+            vec![changed("tabstop", "4"), changed("number", "true")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_plugin_capabilities() {
+        let editor = TestEditor::new(&[("a.txt", "one")]);
+        let plugin = InProcessPlugin::new().with_capabilities("reader", &[Capability::ReadBuffers]);
+        let mut handler =
+            EditorApiHandler::with_plugin(editor.app.state.clone(), plugin.channel.clone());
+
+        assert!(
+            handler
+                .process(CuprumApiRequestKind::GetLine { buf: None, y: 0 })
+                .await
+                .is_ok()
+        );
+        let result = handler
+            .process(CuprumApiRequestKind::Batch {
+                requests: vec![
+                    CuprumApiRequestKind::GetMode {},
+                    CuprumApiRequestKind::RemoveLine { buf: None, y: 0 },
+                ],
+            })
+            .await
+            .unwrap();
+        let Some(CuprumApiResponseKind::Batch(results)) = result else {
+            panic!("{:?}", result);
+        };
+        assert!(results[0].is_ok());
+        assert!(matches!(
+            results[1],
+            Err(CuprumApiError::PermissionDenied(Capability::WriteBuffers))
+        ));
+        assert_eq!(editor.lines().await, vec!["one"]);
+        assert!(matches!(
+            handler
+                .process(CuprumApiRequestKind::SetOption {
+                    name: "number".to_string(),
+                    value: "true".to_string(),
+                })
+                .await,
+            Err(CuprumApiError::PermissionDenied(Capability::ChangeOptions))
+        ));
+
+        let state = editor.app.state.lock().await;
+        assert!(!state.options.number);
+        assert_eq!(
+            state.messages.get_current().unwrap().text,
+            "reader: set_option: permission denied: needs the change_options capability"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_capabilities() {
+        let mut editor = TestEditor::new(&[("a.txt", "one")]);
+        let plugin = InProcessPlugin::new().with_capabilities("blind", &[]);
+        let mut handler =
+            EditorApiHandler::with_plugin(editor.app.state.clone(), plugin.channel.clone());
+
+        for events in [
+            vec![EventKind::ModeChanged, EventKind::BufferChanged],
+            vec![EventKind::InsertChar],
+        ] {
+            assert!(matches!(
+                handler
+                    .process(CuprumApiRequestKind::Subscribe { events })
+                    .await,
+                Err(CuprumApiError::PermissionDenied(Capability::ReadBuffers))
+            ));
+        }
+        handler
+            .process(CuprumApiRequestKind::Subscribe {
+                events: vec![EventKind::ModeChanged],
+            })
+            .await
+            .unwrap();
+
+        editor.keys("ix<Esc>").await;
+        editor
+            .app
+            .state
+            .lock()
+            .await
+            .emit_buffer_changes(Duration::ZERO)
+            .await;
+        assert_eq!(editor.lines().await, vec!["xone"]);
+        let events = plugin.channel.drain().await;
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|event| matches!(
+            event,
+            CuprumNotification::Event(EditorEvent::ModeChanged { .. })
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_get_mode_through_builtin_provider() {
+        let state = Arc::new(Mutex::new(EditorState::new(Vec::new()).unwrap()));
+        let mut api = CuprumApi::new(spawn_builtin_handler(state));
+        assert_eq!(api.get_mode().await.unwrap(), Mode::Normal);
+        api.change_mode(Mode::Insert(true)).await.unwrap();
+        assert_eq!(api.get_mode().await.unwrap(), Mode::Insert(true));
+    }
+
+    #[tokio::test]
+    async fn test_register_keymap() {
+        let state = Arc::new(Mutex::new(EditorState::new(Vec::new()).unwrap()));
+        let (.., channel) = plugin_manager::Plugin::new("plugin".into()).get();
+        let mut handler = EditorApiHandler::with_plugin(state.clone(), channel);
+
+        handler
+            .process(CuprumApiRequestKind::RegisterKeymap {
+                mode: Mode::Normal,
+                keys: "<leader>d".to_string(),
+                name: "date".to_string(),
+            })
+            .await
+            .unwrap();
+        {
+            let state = state.lock().await;
+            let keys = vec![KeyCode::Char(' '), KeyCode::Char('d')];
+            assert!(matches!(
+                state.user_keymaps.normal.get(&keys),
+                Some(Action::PluginKeymap(_, name)) if name == "date"
+            ));
+            assert!(state.messages.get_current().is_none());
+        }
+
+        handler
+            .process(CuprumApiRequestKind::RegisterKeymap {
+                mode: Mode::Normal,
+                keys: "dd".to_string(),
+                name: "delete".to_string(),
+            })
+            .await
+            .unwrap();
+        let message = state.lock().await.messages.get_current().unwrap();
+        assert_eq!(message.text, "Plugin mapping dd shadows a built-in mapping");
+
+        assert!(matches!(
+            EditorApiHandler::new(state)
+                .process(CuprumApiRequestKind::RegisterKeymap {
+                    mode: Mode::Normal,
+                    keys: "x".to_string(),
+                    name: "x".to_string()
+                })
+                .await,
+            Err(CuprumApiError::NotAllowed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_prompt_input() {
+        let state = Arc::new(Mutex::new(EditorState::new(Vec::new()).unwrap()));
+        let prompt = |state: Arc<Mutex<EditorState>>| {
+            tokio::spawn(async move {
+                EditorApiHandler::new(state)
+                    .process(CuprumApiRequestKind::PromptInput {
+                        prompt: "Name: ".to_string(),
+                    })
+                    .await
+            })
+        };
+        let wait_for_prompt = async |state: &Arc<Mutex<EditorState>>| {
+            while state.lock().await.get_prompt_text() != "Name: " {
+                tokio::task::yield_now().await;
+            }
+        };
+
+        let answer = prompt(state.clone());
+        wait_for_prompt(&state).await;
+        for key in "Ann\n".chars() {
+            state
+                .lock()
+                .await
+                .process_command(KeyCode::Char(key))
+                .await
+                .unwrap();
+        }
+        assert!(matches!(
+            answer.await.unwrap(),
+            Ok(Some(CuprumApiResponseKind::PromptInput(Some(name)))) if name == "Ann"
+        ));
+        assert!(state.lock().await.messages.get_current().is_none());
+
+        let answer = prompt(state.clone());
+        wait_for_prompt(&state).await;
+        state
+            .lock()
+            .await
+            .process_command(KeyCode::Esc)
+            .await
+            .unwrap();
+        assert!(matches!(
+            answer.await.unwrap(),
+            Ok(Some(CuprumApiResponseKind::PromptInput(None)))
+        ));
+        assert_eq!(*state.lock().await.mode.lock().await, Mode::Normal);
+    }
+
+    #[tokio::test]
+    async fn test_prompt_input_plugin_exit() {
+        let state = Arc::new(Mutex::new(EditorState::new(Vec::new()).unwrap()));
+        let plugin = plugin_manager::Plugin::new("plugin".into());
+        let (.., channel) = plugin.get();
+        let mut handler = EditorApiHandler::with_plugin(state.clone(), channel);
+
+        let answer = tokio::spawn(async move {
+            handler
+                .process(CuprumApiRequestKind::PromptInput {
+                    prompt: "Name: ".to_string(),
+                })
+                .await
+        });
+        while state.lock().await.get_prompt_text() != "Name: " {
+            tokio::task::yield_now().await;
+        }
+        drop(plugin);
+
+        assert!(matches!(
+            answer.await.unwrap(),
+            Ok(Some(CuprumApiResponseKind::PromptInput(None)))
+        ));
+        assert_eq!(state.lock().await.get_prompt_text(), ":");
+        assert_eq!(*state.lock().await.mode.lock().await, Mode::Normal);
+    }
+
+    #[tokio::test]
+    async fn test_registers() {
+        let mut app = EditorApplication::new(Vec::new()).unwrap();
+        let mut handler = EditorApiHandler::new(app.state.clone());
+        handler
+            .process(CuprumApiRequestKind::SetRegister {
+                name: 'a',
+                text: "one\ntwo".to_string(),
+                linewise: true,
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            handler.process(CuprumApiRequestKind::GetRegister { name: '"' }).await,
+            Ok(Some(CuprumApiResponseKind::GetRegister(text))) if text == "one\ntwo"
+        ));
+
+        app.feed_key(KeyCode::Char('p'), true).await.unwrap();
+        let lines = handler
+            .process(CuprumApiRequestKind::GetAllLines { buf: None })
+            .await
+            .unwrap();
+        assert!(matches!(
+            lines,
+            Some(CuprumApiResponseKind::GetAllLines(lines)) if lines == ["", "one", "two"]
+        ));
+
+        handler
+            .process(CuprumApiRequestKind::SetRegister {
+                name: '+',
+                text: "x".to_string(),
+                linewise: false,
+            })
+            .await
+            .unwrap();
+        app.feed_key(KeyCode::Char('P'), true).await.unwrap();
+        assert_eq!(
+            app.state.lock().await.get_line_range(1, 1).await,
+            vec!["xone".to_string()]
+        );
+
+        assert!(matches!(
+            handler
+                .process(CuprumApiRequestKind::GetRegister { name: '!' })
+                .await,
+            Err(CuprumApiError::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            handler
+                .process(CuprumApiRequestKind::SetRegister {
+                    name: '%',
+                    text: String::new(),
+                    linewise: false
+                })
+                .await,
+            Err(CuprumApiError::InvalidArgument(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_recursive_mapping() {
+        let mut app = EditorApplication::new(Vec::new()).unwrap();
+        app.run_action(Action::Map {
+            modes: vec![MapMode::Normal],
+            lhs: vec![KeyCode::Char('a')],
+            rhs: "b".to_string(),
+            noremap: false,
+        })
+        .await
+        .unwrap();
+        app.run_action(Action::Map {
+            modes: vec![MapMode::Normal],
+            lhs: vec![KeyCode::Char('b')],
+            rhs: "a".to_string(),
+            noremap: false,
+        })
+        .await
+        .unwrap();
+
+        app.feed_key(KeyCode::Char('a'), true).await.unwrap();
+        let message = app.state.lock().await.messages.get_current().unwrap();
+        assert_eq!(message.text, "E223: Recursive mapping");
+    }
+
+    #[tokio::test]
+    async fn test_noremap() {
+        let mut app = EditorApplication::new(Vec::new()).unwrap();
+        app.run_action(Action::Map {
+            modes: vec![MapMode::Normal],
+            lhs: vec![KeyCode::Char('/')],
+            rhs: "x".to_string(),
+            noremap: false,
+        })
+        .await
+        .unwrap();
+        // Without remapping, `/` in the mapping is the built-in search key
+        app.run_action(Action::Map {
+            modes: vec![MapMode::Normal],
+            lhs: vec![KeyCode::Char('x')],
+            rhs: "/".to_string(),
+            noremap: true,
+        })
+        .await
+        .unwrap();
+
+        app.feed_key(KeyCode::Char('x'), true).await.unwrap();
+        let state = app.state.lock().await;
+        assert!(matches!(*state.mode.lock().await, Mode::Command));
+        assert_eq!(state.command_prompt, '/');
+        assert!(state.messages.get_current().is_none());
+    }
+}
@@ -1,20 +1,233 @@
+use std::path::PathBuf;
+
 use chrono::Local;
+use clap::ValueEnum;
 use fern::Dispatch;
+use log::LevelFilter;
+
+/// Levels `--log-level` takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+/// Where log messages are written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum LogOutput {
+    File(PathBuf),
+    /// For running without a terminal UI, which would otherwise draw over them
+    Stderr,
+}
+
+/// What to log and where, from the command line and `$CUPRUM_LOG`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LogConfig {
+    pub level: LevelFilter,
+    /// Levels of modules and crates, such as `plugin_manager=debug`
+    pub modules: Vec<(String, LevelFilter)>,
+    pub output: LogOutput,
+    /// Parts of `$CUPRUM_LOG` that could not be read, logged once the logger is up
+    pub invalid: Vec<String>,
+}
+
+impl LogConfig {
+    /// `level` from `--log-level` wins over the default level of `filter`, and each `-v`
+    /// (`verbose`) makes it one level more detailed
+    pub fn new(
+        level: Option<LogLevel>,
+        verbose: u8,
+        filter: Option<&str>,
+        output: LogOutput,
+    ) -> Self {
+        let mut config = Self {
+            level: LevelFilter::Info,
+            modules: Vec::new(),
+            output,
+            invalid: Vec::new(),
+        };
+        if let Some(filter) = filter {
+            config.parse_filter(filter);
+        }
+        if let Some(level) = level {
+            config.level = level.into();
+        }
+        config.level = raise(config.level, verbose);
+        config
+    }
+
+    /// Read directives in env_logger's syntax, such as `warn,plugin_manager=debug`. A
+    /// module without a level logs everything, like in env_logger.
+    fn parse_filter(&mut self, filter: &str) {
+        for directive in filter.split(',').map(str::trim) {
+            if directive.is_empty() {
+                continue;
+            }
+            match directive.split_once('=') {
+                Some((module, level)) => match level.parse() {
+                    Ok(level) if !module.is_empty() => {
+                        self.modules.push((module.to_string(), level))
+                    }
+                    _ => self.invalid.push(directive.to_string()),
+                },
+                None => match directive.parse() {
+                    Ok(level) => self.level = level,
+                    Err(_) => self
+                        .modules
+                        .push((directive.to_string(), LevelFilter::Trace)),
+                },
+            }
+        }
+    }
+
+    fn dispatch(&self) -> Dispatch {
+        let mut dispatch = Dispatch::new()
+            .format(|out, message, record| {
+                out.finish(format_args!(
+                    "[{}] [{}] [{}] {}",
+                    Local::now().format("%Y-%m-%d %H:%M:%S"),
+                    record.level(),
+                    record.target(),
+                    message
+                ))
+            })
+            .level(self.level);
+        for (module, level) in &self.modules {
+            dispatch = dispatch.level_for(module.clone(), *level);
+        }
+        dispatch
+    }
+}
+
+/// A level `steps` more detailed than `level`, at most `trace`
+fn raise(level: LevelFilter, steps: u8) -> LevelFilter {
+    LevelFilter::iter()
+        .nth(level as usize + steps as usize)
+        .unwrap_or(LevelFilter::Trace)
+}
+
+/// `~/.cuprum/output.log`, where the log is written without `--log-file`
+pub(crate) fn get_default_log_file() -> PathBuf {
+    home::home_dir().unwrap().join(".cuprum/output.log")
+}
 
 /// Initialize the logger.
-pub(crate) fn init_logger() -> Result<(), fern::InitError> {
-    Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "[{}] [{}] [{}] {}",
-                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                record.level(),
-                record.target(),
-                message
-            ))
-        })
-        .level(log::LevelFilter::Info)
-        .chain(fern::log_file("output.log")?)
-        .apply()?;
+pub(crate) fn init_logger(config: &LogConfig) -> Result<(), fern::InitError> {
+    let dispatch = config.dispatch();
+    let dispatch = match &config.output {
+        LogOutput::File(path) => {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            dispatch.chain(fern::log_file(path)?)
+        }
+        LogOutput::Stderr => dispatch.chain(std::io::stderr()),
+    };
+    dispatch.apply()?;
+
+    for directive in &config.invalid {
+        log::warn!("CUPRUM_LOG: Invalid directive: {}", directive);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use log::{Level, Log, Record};
+
+    use super::*;
+
+    fn config(level: Option<LogLevel>, verbose: u8, filter: Option<&str>) -> LogConfig {
+        LogConfig::new(level, verbose, filter, LogOutput::Stderr)
+    }
+
+    #[test]
+    fn test_log_level() {
+        assert_eq!(config(None, 0, None).level, LevelFilter::Info);
+        assert_eq!(config(None, 1, None).level, LevelFilter::Debug);
+        assert_eq!(config(None, 5, None).level, LevelFilter::Trace);
+        assert_eq!(
+            config(Some(LogLevel::Error), 0, None).level,
+            LevelFilter::Error
+        );
+        assert_eq!(
+            config(Some(LogLevel::Warn), 2, None).level,
+            LevelFilter::Debug
+        );
+        // `--log-level` wins over the default level of `$CUPRUM_LOG`
+        assert_eq!(config(None, 0, Some("warn")).level, LevelFilter::Warn);
+        assert_eq!(
+            config(Some(LogLevel::Debug), 0, Some("warn")).level,
+            LevelFilter::Debug
+        );
+    }
+
+    #[test]
+    fn test_parse_filter() {
+        let config = config(
+            None,
+            0,
+            Some("error, plugin_manager=debug,cuprum::ui,cuprum::render=loud,=info"),
+        );
+        assert_eq!(config.level, LevelFilter::Error);
+        assert_eq!(
+            config.modules,
+            [
+                ("plugin_manager".to_string(), LevelFilter::Debug),
+                ("cuprum::ui".to_string(), LevelFilter::Trace),
+            ]
+        );
+        assert_eq!(config.invalid, ["cuprum::render=loud", "=info"]);
+    }
+
+    #[test]
+    fn test_level_filtering() {
+        let config = config(None, 0, Some("warn,plugin_manager=debug"));
+        let (sender, receiver) = mpsc::channel();
+        let (_, logger) = config.dispatch().chain(sender).into_log();
+
+        let log = |level: Level, target: &str| {
+            logger.log(
+                &Record::builder()
+                    .level(level)
+                    .target(target)
+                    .args(format_args!("{}", target))
+                    .build(),
+            )
+        };
+        log(Level::Info, "cuprum");
+        log(Level::Warn, "cuprum");
+        log(Level::Debug, "plugin_manager::wasm");
+        log(Level::Trace, "plugin_manager");
+        drop(logger);
+
+        let lines: Vec<String> = receiver
+            .iter()
+            .map(|line| line.trim_end().to_string())
+            .collect();
+        assert_eq!(lines.len(), 2, "{:?}", lines);
+        assert!(lines[0].ends_with("[WARN] [cuprum] cuprum"), "{:?}", lines);
+        assert!(
+            lines[1].ends_with("[DEBUG] [plugin_manager::wasm] plugin_manager::wasm"),
+            "{:?}",
+            lines
+        );
+    }
+}
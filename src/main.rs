@@ -1,21 +1,245 @@
 mod log;
 
-use clap::Parser;
-use cuprum::EditorApplication;
+use std::path::PathBuf;
 
-use crate::log::init_logger;
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser, Subcommand};
+use cuprum::{
+    EditorApplication, StartupArgs, WindowLayout,
+    version::{get_build_info, get_version_text},
+};
+use plugin_manager::{find_plugins, get_plugin_dir, install};
+
+use crate::log::{LogConfig, LogLevel, LogOutput, get_default_log_file, init_logger};
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, version)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+    /// Read this config file instead of $CUPRUM_CONFIG or ~/.cuprum/config.toml
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Print the version, commit, protocol, features and plugin directory of this build
+    #[arg(long, exclusive = true)]
+    build_info: bool,
+    /// Start with built-in defaults only, without the config file, plugins and scripts
+    #[arg(long, conflicts_with = "config")]
+    clean: bool,
+    /// Read the config file, but load no plugins or scripts
+    #[arg(long)]
+    noplugin: bool,
+    /// Open a window for each file, split above each other
+    #[arg(short = 'o', group = "layout")]
+    horizontal: bool,
+    /// Open a window for each file, split side by side
+    #[arg(short = 'O', group = "layout")]
+    vertical: bool,
+    /// Open a window filling the screen for each file, as without a flag
+    #[arg(short = 'p', group = "layout")]
+    tabs: bool,
+    /// Run a command line after loading the files, as if typed after `:`
+    #[arg(short = 'c', value_name = "COMMAND")]
+    commands: Vec<String>,
+    /// Run the -c commands without a terminal and quit, failing if any of them fails
+    #[arg(long)]
+    headless: bool,
+    /// Append the events the editor handles to this file, to replay them later
+    #[arg(long, value_name = "PATH")]
+    record: Option<PathBuf>,
+    /// Handle the events of a --record file instead of the terminal, then print a hash of
+    /// the buffers and the cursor
+    #[arg(long, value_name = "PATH")]
+    replay: Option<PathBuf>,
+    /// Replay the events one after another instead of at the pace they were recorded
+    #[arg(long, requires = "replay")]
+    replay_fast: bool,
+    /// Log messages of this level and above, `info` by default
+    #[arg(long, value_enum, value_name = "LEVEL")]
+    log_level: Option<LogLevel>,
+    /// Write the log to this file instead of ~/.cuprum/output.log
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+    /// Write the log to stderr instead of a file
+    #[arg(long, conflicts_with = "log_file")]
+    log_stderr: bool,
+    /// Log in more detail, once more for each -v
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
     files: Vec<String>,
 }
 
+impl Cli {
+    fn layout(&self) -> WindowLayout {
+        if self.horizontal {
+            WindowLayout::Horizontal
+        } else if self.vertical {
+            WindowLayout::Vertical
+        } else {
+            WindowLayout::Tabs
+        }
+    }
+
+    /// How to log, `filter` being `$CUPRUM_LOG`
+    fn log_config(&self, filter: Option<&str>) -> LogConfig {
+        let output = if self.log_stderr {
+            LogOutput::Stderr
+        } else {
+            LogOutput::File(self.log_file.clone().unwrap_or_else(get_default_log_file))
+        };
+        LogConfig::new(self.log_level, self.verbose, filter, output)
+    }
+}
+
+#[derive(Subcommand)]
+enum CliCommand {
+    /// Manage the installed plugins
+    Plugin {
+        #[command(subcommand)]
+        command: PluginCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum PluginCommand {
+    /// Install a plugin from a local directory or a git URL
+    Install { source: String },
+    /// Remove a plugin with its manifest and source
+    Remove { name: String },
+    /// List the plugins with their capabilities and commands
+    List,
+}
+
+fn run_plugin_command(command: PluginCommand) -> anyhow::Result<()> {
+    let plugin_dir = get_plugin_dir();
+    match command {
+        PluginCommand::Install { source } => {
+            let plugin = install::install(&plugin_dir, &source)?;
+            println!("Installed {}", plugin.name);
+        }
+        PluginCommand::Remove { name } => {
+            install::remove(&plugin_dir, &name)?;
+            println!("Removed {}", name);
+        }
+        PluginCommand::List => {
+            if !plugin_dir.exists() {
+                return Ok(());
+            }
+            for plugin in find_plugins(&plugin_dir)? {
+                let capabilities: Vec<String> = plugin
+                    .manifest
+                    .capabilities
+                    .iter()
+                    .map(|capability| capability.to_string())
+                    .collect();
+                println!(
+                    "{}\t{}\t{}",
+                    plugin.name,
+                    capabilities.join(","),
+                    plugin.command.display()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    init_logger()?;
+    // `--version` also shows the commit and the protocol, which are not a literal
+    let command = Cli::command().version(get_version_text().leak() as &str);
+    let cli = Cli::from_arg_matches(&command.get_matches())?;
+    if cli.build_info {
+        println!("{}", get_build_info());
+        return Ok(());
+    }
+    let filter = std::env::var("CUPRUM_LOG").ok();
+    init_logger(&cli.log_config(filter.as_deref()))?;
 
-    let cli = Cli::parse();
-    EditorApplication::main(cli.files).await?;
+    match cli.command {
+        Some(CliCommand::Plugin { command }) => run_plugin_command(command)?,
+        None => {
+            let args = StartupArgs {
+                layout: cli.layout(),
+                commands: cli.commands,
+                headless: cli.headless,
+                record: cli.record,
+                replay: cli.replay,
+                replay_fast: cli.replay_fast,
+                files: cli.files,
+                config: cli.config,
+                clean: cli.clean,
+                noplugin: cli.noplugin,
+            };
+            EditorApplication::main(args).await?
+        }
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use ::log::LevelFilter;
+
+    use super::*;
+
+    #[test]
+    fn test_log_flags() {
+        let cli = Cli::try_parse_from(["cuprum", "a.txt"]).unwrap();
+        let config = cli.log_config(None);
+        assert_eq!(config.level, LevelFilter::Info);
+        assert_eq!(config.output, LogOutput::File(get_default_log_file()));
+        assert_eq!(cli.files, ["a.txt"]);
+
+        let cli = Cli::try_parse_from([
+            "cuprum",
+            "--log-level",
+            "warn",
+            "-vv",
+            "--log-file",
+            "/tmp/cuprum.log",
+        ])
+        .unwrap();
+        let config = cli.log_config(Some("plugin_manager=trace"));
+        assert_eq!(config.level, LevelFilter::Debug);
+        assert_eq!(config.output, LogOutput::File("/tmp/cuprum.log".into()));
+        assert_eq!(config.modules.len(), 1);
+
+        let cli = Cli::try_parse_from(["cuprum", "--verbose", "--log-stderr"]).unwrap();
+        let config = cli.log_config(None);
+        assert_eq!(config.level, LevelFilter::Debug);
+        assert_eq!(config.output, LogOutput::Stderr);
+
+        assert!(Cli::try_parse_from(["cuprum", "--log-level", "loud"]).is_err());
+        assert!(Cli::try_parse_from(["cuprum", "--log-stderr", "--log-file", "a.log"]).is_err());
+    }
+
+    #[test]
+    fn test_startup_modes() {
+        let cli = Cli::try_parse_from(["cuprum", "--noplugin", "a.txt"]).unwrap();
+        assert!(cli.noplugin && !cli.clean);
+        let cli = Cli::try_parse_from(["cuprum", "--clean"]).unwrap();
+        assert!(cli.clean);
+        // Nothing would read the config file
+        assert!(Cli::try_parse_from(["cuprum", "--clean", "--config", "a.toml"]).is_err());
+
+        let cli = Cli::try_parse_from(["cuprum", "--headless", "-c", "%s/a/b/g", "-c", "wq", "a"])
+            .unwrap();
+        assert!(cli.headless);
+        assert_eq!(cli.commands, ["%s/a/b/g", "wq"]);
+        assert_eq!(cli.files, ["a"]);
+    }
+
+    #[test]
+    fn test_window_layout() {
+        let layout = |args: &[&str]| {
+            let args = ["cuprum"].iter().chain(args);
+            Cli::try_parse_from(args).map(|cli| cli.layout())
+        };
+        assert_eq!(layout(&["a", "b"]).unwrap(), WindowLayout::Tabs);
+        assert_eq!(layout(&["-o", "a", "b"]).unwrap(), WindowLayout::Horizontal);
+        assert_eq!(layout(&["-O", "a", "b"]).unwrap(), WindowLayout::Vertical);
+        assert_eq!(layout(&["-p", "a"]).unwrap(), WindowLayout::Tabs);
+        assert!(layout(&["-o", "-O", "a"]).is_err());
+    }
+}
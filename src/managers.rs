@@ -1,6 +1,6 @@
 use std::{collections::HashMap, sync::Arc};
 
-use api::{BufferId, WindowId};
+use api::{BufferId, Popup, PopupId, WindowId};
 use tokio::sync::Mutex;
 
 use crate::{buffer::Buffer, window::Window};
@@ -27,6 +27,13 @@ impl BufferManager {
     pub fn get_buffer(&self, id: BufferId) -> Option<Arc<Mutex<Buffer>>> {
         self.buffers.get(&id).cloned()
     }
+
+    /// Get the ids of all open buffers, sorted
+    pub fn get_buffer_ids(&self) -> Vec<BufferId> {
+        let mut ids: Vec<BufferId> = self.buffers.keys().copied().collect();
+        ids.sort_by_key(|id| id.0);
+        ids
+    }
 }
 
 #[derive(Debug, Default)]
@@ -44,11 +51,108 @@ impl WindowManager {
         (id, win)
     }
 
-    pub fn close_buffer(&mut self, id: WindowId) {
+    pub fn close_window(&mut self, id: WindowId) {
         self.windows.remove(&id);
     }
 
+    /// Get the ids of all open windows, sorted
+    pub fn get_window_ids(&self) -> Vec<WindowId> {
+        let mut ids: Vec<WindowId> = self.windows.keys().copied().collect();
+        ids.sort_by_key(|id| id.0);
+        ids
+    }
+
     pub fn get_window(&self, id: WindowId) -> Option<Arc<Mutex<Window>>> {
         self.windows.get(&id).cloned()
     }
 }
+
+#[derive(Debug, Default)]
+pub struct PopupManager {
+    /// Open popups, from bottom to top
+    popups: Vec<(PopupId, Popup)>,
+    next_index: usize,
+}
+
+impl PopupManager {
+    pub fn open_popup(&mut self, popup: Popup) -> PopupId {
+        let id = PopupId(self.next_index);
+        self.popups.push((id, popup));
+        self.next_index += 1;
+        id
+    }
+
+    /// Returns false if there is no such popup
+    pub fn update_popup(&mut self, id: PopupId, popup: Popup) -> bool {
+        if let Some((_, old)) = self.popups.iter_mut().find(|(popup_id, _)| *popup_id == id) {
+            *old = popup;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns false if there is no such popup
+    pub fn close_popup(&mut self, id: PopupId) -> bool {
+        let count = self.popups.len();
+        self.popups.retain(|(popup_id, _)| *popup_id != id);
+        self.popups.len() != count
+    }
+
+    /// Close the topmost dismissable popup, returning whether one was open
+    pub fn close_top(&mut self) -> bool {
+        match self.popups.iter().rposition(|(_, popup)| popup.dismissable) {
+            Some(index) => {
+                self.popups.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.popups.is_empty()
+    }
+
+    pub fn get_popups(&self) -> Vec<Popup> {
+        self.popups.iter().map(|(_, popup)| popup.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_popup_manager() {
+        let mut popups = PopupManager::default();
+        let a = popups.open_popup(Popup {
+            title: Some("a".to_string()),
+            ..Default::default()
+        });
+        let b = popups.open_popup(Popup {
+            dismissable: true,
+            ..Default::default()
+        });
+        assert_ne!(a, b);
+
+        popups.update_popup(
+            b,
+            Popup {
+                lines: vec!["hello".to_string()],
+                dismissable: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(popups.get_popups()[1].lines, vec!["hello".to_string()]);
+
+        assert!(popups.close_top());
+        assert_eq!(popups.get_popups().len(), 1);
+        // Popups that are not dismissable stay
+        assert!(!popups.close_top());
+
+        popups.close_popup(a);
+        assert!(popups.is_empty());
+        assert!(!popups.close_top());
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Local};
+
+/// How many messages are kept for `:messages`
+pub const MESSAGE_HISTORY_LIMIT: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageLevel {
+    Info,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub level: MessageLevel,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub time: DateTime<Local>,
+    pub message: Message,
+}
+
+impl HistoryEntry {
+    /// A line for the `:messages` buffer
+    pub fn format(&self) -> String {
+        let level = match self.message.level {
+            MessageLevel::Info => "",
+            MessageLevel::Error => " [error]",
+        };
+        format!(
+            "{}{} {}",
+            self.time.format("%H:%M:%S"),
+            level,
+            self.message.text
+        )
+    }
+}
+
+/// Messages shown to the user at the bottom of the screen
+#[derive(Debug, Default)]
+pub struct MessageQueue {
+    current: Option<Message>,
+    history: VecDeque<HistoryEntry>,
+    /// Errors shown so far, including those the history no longer keeps
+    error_count: usize,
+    /// Bumped whenever the message shown changes, which happens outside of keys and
+    /// requests too
+    revision: u64,
+}
+
+impl MessageQueue {
+    pub fn push(&mut self, level: MessageLevel, text: impl Into<String>) {
+        let message = Message {
+            level,
+            text: text.into(),
+        };
+
+        if level == MessageLevel::Error {
+            log::error!("{}", message.text);
+            self.error_count += 1;
+        }
+
+        if self.history.len() == MESSAGE_HISTORY_LIMIT {
+            self.history.pop_front();
+        }
+        self.history.push_back(HistoryEntry {
+            time: Local::now(),
+            message: message.clone(),
+        });
+        self.current = Some(message);
+        self.revision += 1;
+    }
+
+    pub fn info(&mut self, text: impl Into<String>) {
+        self.push(MessageLevel::Info, text);
+    }
+
+    pub fn error(&mut self, text: impl Into<String>) {
+        self.push(MessageLevel::Error, text);
+    }
+
+    /// Get the message currently shown
+    pub fn get_current(&self) -> Option<Message> {
+        self.current.clone()
+    }
+
+    /// Hide the current message, keeping it in the history
+    pub fn clear_current(&mut self) {
+        if self.current.take().is_some() {
+            self.revision += 1;
+        }
+    }
+
+    pub fn get_revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn get_error_count(&self) -> usize {
+        self.error_count
+    }
+
+    /// Past messages, oldest first
+    pub fn get_history(&self) -> impl Iterator<Item = &HistoryEntry> {
+        self.history.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_limit() {
+        let mut messages = MessageQueue::default();
+        for i in 0..MESSAGE_HISTORY_LIMIT + 5 {
+            messages.info(i.to_string());
+        }
+        let history: Vec<_> = messages.get_history().collect();
+        assert_eq!(history.len(), MESSAGE_HISTORY_LIMIT);
+        assert_eq!(history[0].message.text, "5");
+        assert_eq!(
+            history.last().unwrap().message.text,
+            (MESSAGE_HISTORY_LIMIT + 4).to_string()
+        );
+    }
+}
@@ -0,0 +1,291 @@
+use anyhow::{anyhow, bail};
+
+use crate::ui::input::{DEFAULT_LEADER, KeyCode, parse_keys};
+
+/// Runtime options changed with `:set`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    pub number: bool,
+    pub wrap: bool,
+    pub tabstop: usize,
+    /// Type spaces up to the next tab stop instead of a tab
+    pub expandtab: bool,
+    pub scrolloff: usize,
+    /// Columns kept visible left and right of the cursor when lines scroll sideways
+    pub sidescrolloff: usize,
+    pub ignorecase: bool,
+    /// Milliseconds to wait for the next key of a mapping
+    pub timeoutlen: usize,
+    /// The key `<leader>` stands for in mappings
+    pub mapleader: char,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            number: false,
+            wrap: true,
+            tabstop: 8,
+            expandtab: false,
+            scrolloff: 0,
+            sidescrolloff: 0,
+            ignorecase: false,
+            timeoutlen: 500,
+            mapleader: DEFAULT_LEADER,
+        }
+    }
+}
+
+enum OptionField {
+    Bool(fn(&Options) -> bool, fn(&mut Options, bool)),
+    Number(fn(&Options) -> usize, fn(&mut Options, usize)),
+    /// A single key, given in key notation such as `<Space>`
+    Key(fn(&Options) -> char, fn(&mut Options, char)),
+}
+
+struct OptionDef {
+    name: &'static str,
+    alias: &'static str,
+    field: OptionField,
+}
+
+const OPTIONS: &[OptionDef] = &[
+    OptionDef {
+        name: "number",
+        alias: "nu",
+        field: OptionField::Bool(|o| o.number, |o, v| o.number = v),
+    },
+    OptionDef {
+        name: "wrap",
+        alias: "wrap",
+        field: OptionField::Bool(|o| o.wrap, |o, v| o.wrap = v),
+    },
+    OptionDef {
+        name: "tabstop",
+        alias: "ts",
+        field: OptionField::Number(|o| o.tabstop, |o, v| o.tabstop = v),
+    },
+    OptionDef {
+        name: "expandtab",
+        alias: "et",
+        field: OptionField::Bool(|o| o.expandtab, |o, v| o.expandtab = v),
+    },
+    OptionDef {
+        name: "scrolloff",
+        alias: "so",
+        field: OptionField::Number(|o| o.scrolloff, |o, v| o.scrolloff = v),
+    },
+    OptionDef {
+        name: "sidescrolloff",
+        alias: "siso",
+        field: OptionField::Number(|o| o.sidescrolloff, |o, v| o.sidescrolloff = v),
+    },
+    OptionDef {
+        name: "ignorecase",
+        alias: "ic",
+        field: OptionField::Bool(|o| o.ignorecase, |o, v| o.ignorecase = v),
+    },
+    OptionDef {
+        name: "timeoutlen",
+        alias: "tm",
+        field: OptionField::Number(|o| o.timeoutlen, |o, v| o.timeoutlen = v),
+    },
+    OptionDef {
+        name: "mapleader",
+        alias: "leader",
+        field: OptionField::Key(|o| o.mapleader, |o, v| o.mapleader = v),
+    },
+];
+
+fn find_option(name: &str) -> Option<&'static OptionDef> {
+    OPTIONS
+        .iter()
+        .find(|def| def.name == name || def.alias == name)
+}
+
+impl Options {
+    /// Get an option value as a string
+    pub fn get(&self, name: &str) -> anyhow::Result<String> {
+        let def = find_option(name).ok_or(anyhow!("E518: Unknown option: {}", name))?;
+        Ok(match def.field {
+            OptionField::Bool(get, _) => get(self).to_string(),
+            OptionField::Number(get, _) => get(self).to_string(),
+            OptionField::Key(get, _) => KeyCode::Char(get(self)).to_string(),
+        })
+    }
+
+    /// Set an option from a string value
+    pub fn set(&mut self, name: &str, value: &str) -> anyhow::Result<()> {
+        let def = find_option(name).ok_or(anyhow!("E518: Unknown option: {}", name))?;
+        match def.field {
+            OptionField::Bool(_, set) => match value {
+                "true" | "1" => set(self, true),
+                "false" | "0" => set(self, false),
+                _ => bail!("E474: Invalid argument: {}={}", name, value),
+            },
+            OptionField::Number(_, set) => match value.parse() {
+                Ok(value) => set(self, value),
+                Err(_) => bail!("E521: Number required after =: {}={}", name, value),
+            },
+            OptionField::Key(_, set) => match parse_keys(value).as_deref() {
+                Ok([KeyCode::Char(ch)]) => set(self, *ch),
+                _ => bail!("E474: Invalid argument: {}={}", name, value),
+            },
+        }
+        Ok(())
+    }
+
+    /// Full names and new values of the options that differ from `old`
+    pub fn changes(&self, old: &Options) -> Vec<(String, String)> {
+        OPTIONS
+            .iter()
+            .filter_map(|def| {
+                let value = self.get(def.name).ok()?;
+                (old.get(def.name).ok()? != value).then(|| (def.name.to_string(), value))
+            })
+            .collect()
+    }
+
+    /// Format an option the way `:set {option}?` shows it
+    fn show(&self, def: &OptionDef) -> String {
+        match def.field {
+            OptionField::Bool(get, _) if get(self) => format!("  {}", def.name),
+            OptionField::Bool(..) => format!("no{}", def.name),
+            OptionField::Number(get, _) => format!("  {}={}", def.name, get(self)),
+            OptionField::Key(get, _) => format!("  {}={}", def.name, KeyCode::Char(get(self))),
+        }
+    }
+
+    /// Format all options
+    pub fn show_all(&self) -> String {
+        OPTIONS
+            .iter()
+            .map(|def| self.show(def).trim().to_string())
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    /// Apply a single `:set` argument such as `number`, `nonumber`, `number!`,
+    /// `tabstop=4` or `tabstop?`. Returns the text to show for queries.
+    pub fn apply(&mut self, arg: &str) -> anyhow::Result<Option<String>> {
+        if let Some((name, value)) = arg.split_once('=') {
+            let def = find_option(name).ok_or(anyhow!("E518: Unknown option: {}", name))?;
+            if let OptionField::Bool(..) = def.field {
+                bail!("E474: Invalid argument: {}", arg);
+            }
+            self.set(name, value)?;
+            return Ok(None);
+        }
+
+        if let Some(name) = arg.strip_suffix('?') {
+            let def = find_option(name).ok_or(anyhow!("E518: Unknown option: {}", name))?;
+            return Ok(Some(self.show(def)));
+        }
+
+        if let Some(name) = arg.strip_suffix('!') {
+            let def = find_option(name).ok_or(anyhow!("E518: Unknown option: {}", name))?;
+            let OptionField::Bool(get, set) = def.field else {
+                bail!("E474: Invalid argument: {}", arg);
+            };
+            set(self, !get(self));
+            return Ok(None);
+        }
+
+        if let Some(def) = find_option(arg) {
+            return match def.field {
+                OptionField::Bool(_, set) => {
+                    set(self, true);
+                    Ok(None)
+                }
+                // Like vim, `:set tabstop` shows the value
+                OptionField::Number(..) | OptionField::Key(..) => Ok(Some(self.show(def))),
+            };
+        }
+
+        if let Some(name) = arg.strip_prefix("no")
+            && let Some(def) = find_option(name)
+            && let OptionField::Bool(_, set) = def.field
+        {
+            set(self, false);
+            return Ok(None);
+        }
+
+        bail!("E518: Unknown option: {}", arg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_bool() {
+        let mut options = Options::default();
+        assert_eq!(options.apply("number").unwrap(), None);
+        assert!(options.number);
+        assert_eq!(options.apply("nonu").unwrap(), None);
+        assert!(!options.number);
+        assert_eq!(options.apply("number!").unwrap(), None);
+        assert!(options.number);
+        assert_eq!(
+            options.apply("number?").unwrap(),
+            Some("  number".to_string())
+        );
+        assert_eq!(options.apply("nowrap").unwrap(), None);
+        assert_eq!(options.apply("wrap?").unwrap(), Some("nowrap".to_string()));
+    }
+
+    #[test]
+    fn test_apply_number() {
+        let mut options = Options::default();
+        assert_eq!(options.apply("tabstop=4").unwrap(), None);
+        assert_eq!(options.tabstop, 4);
+        assert_eq!(options.apply("ts=2").unwrap(), None);
+        assert_eq!(
+            options.apply("tabstop?").unwrap(),
+            Some("  tabstop=2".to_string())
+        );
+        assert_eq!(
+            options.apply("so").unwrap(),
+            Some("  scrolloff=0".to_string())
+        );
+    }
+
+    #[test]
+    fn test_apply_key() {
+        let mut options = Options::default();
+        assert_eq!(
+            options.apply("mapleader").unwrap(),
+            Some("  mapleader=<Space>".to_string())
+        );
+        assert_eq!(options.apply("mapleader=,").unwrap(), None);
+        assert_eq!(options.mapleader, ',');
+        options.set("leader", "<Space>").unwrap();
+        assert_eq!(options.mapleader, ' ');
+        assert!(options.apply("mapleader=ab").is_err());
+        assert!(options.apply("mapleader=<C-x>").is_err());
+    }
+
+    #[test]
+    fn test_apply_invalid() {
+        let mut options = Options::default();
+        assert!(options.apply("foo").is_err());
+        assert!(options.apply("nofoo").is_err());
+        assert!(options.apply("tabstop=abc").is_err());
+        assert!(options.apply("number=3").is_err());
+        assert!(options.apply("notabstop").is_err());
+        assert!(options.apply("tabstop!").is_err());
+        assert_eq!(options, Options::default());
+    }
+
+    #[test]
+    fn test_get_set() {
+        let mut options = Options::default();
+        options.set("ic", "true").unwrap();
+        assert_eq!(options.get("ignorecase").unwrap(), "true");
+        options.set("tabstop", "3").unwrap();
+        assert_eq!(options.get("ts").unwrap(), "3");
+        assert!(options.set("tabstop", "x").is_err());
+        assert!(options.get("foo").is_err());
+    }
+}
@@ -0,0 +1,38 @@
+use std::collections::HashMap;
+
+/// The register used when no name is given
+pub const UNNAMED_REGISTER: char = '"';
+/// The system clipboard
+// TODO: Read and write the clipboard instead of storing the text
+pub const CLIPBOARD_REGISTER: char = '+';
+
+/// Whether `name` is a register that can be read and written
+pub fn is_valid_name(name: char) -> bool {
+    name == UNNAMED_REGISTER || name == CLIPBOARD_REGISTER || name.is_ascii_alphanumeric()
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Register {
+    pub text: String,
+    /// Whether the text is a list of whole lines
+    pub linewise: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Registers {
+    map: HashMap<char, Register>,
+}
+
+impl Registers {
+    pub fn get(&self, name: char) -> Option<&Register> {
+        self.map.get(&name)
+    }
+
+    /// Store a register, also updating the unnamed register like vim does
+    pub fn set(&mut self, name: char, register: Register) {
+        if name != UNNAMED_REGISTER {
+            self.map.insert(UNNAMED_REGISTER, register.clone());
+        }
+        self.map.insert(name, register);
+    }
+}
@@ -0,0 +1,310 @@
+//! Rhai scripts extending the editor without building a plugin.
+//!
+//! Each function of the API is available as `cuprum::name(args...)`, handled by an
+//! `EditorApiHandler` directly. Scripts register commands, keymaps and events like
+//! plugins do, and get them by defining `on_command(name, bang, range, args)`,
+//! `on_keymap(name)` and `on_event(event)`. A script subscribed to `BufWritePre` defines
+//! `on_buf_write_pre(buf, content)`, returning edits of `content` like
+//! `apply_text_edits` takes them, or nothing.
+
+use std::{cell::RefCell, path::PathBuf, rc::Rc, sync::Arc};
+
+use api::{
+    API_METHODS, CuprumApiError, CuprumApiRequestKind, CuprumNotification, PluginRequestKind,
+    PluginResponse, PluginResponseKind, RangeEdit,
+};
+use plugin_manager::InProcessPlugin;
+use rhai::{
+    AST, CallFnOptions, Dynamic, Engine, EvalAltResult, Map, Module, Scope,
+    serde::{from_dynamic, to_dynamic},
+};
+use tokio::{
+    runtime::Handle,
+    sync::{Mutex, oneshot},
+};
+
+use crate::{EditorApiHandler, state::EditorState};
+
+fn get_script_dir() -> PathBuf {
+    let home_dir = home::home_dir().unwrap();
+    home_dir.join(".cuprum/scripts")
+}
+
+/// Read the `.rhai` files of the script directory with their names, in name order
+pub fn read_scripts() -> Vec<(String, String)> {
+    let Ok(entries) = std::fs::read_dir(get_script_dir()) else {
+        return Vec::new();
+    };
+
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rhai"))
+        .collect();
+    paths.sort();
+
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let name = path.file_name()?.to_string_lossy().to_string();
+            match std::fs::read_to_string(&path) {
+                Ok(source) => Some((name, source)),
+                Err(err) => {
+                    log::error!("{}: {}", name, err);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// A script that ran, kept to call its handlers
+struct Script {
+    name: String,
+    ast: AST,
+    scope: Scope<'static>,
+}
+
+impl Script {
+    /// Call `handler` if the script defines it with as many parameters as `args`
+    fn call(
+        &mut self,
+        engine: &Engine,
+        handler: &str,
+        args: &[Dynamic],
+    ) -> Option<Result<Dynamic, Box<EvalAltResult>>> {
+        let defined = self
+            .ast
+            .iter_functions()
+            .any(|f| f.name == handler && f.params.len() == args.len());
+        defined.then(|| {
+            engine.call_fn_with_options::<Dynamic>(
+                CallFnOptions::new().eval_ast(false),
+                &mut self.scope,
+                &self.ast,
+                handler,
+                args.to_vec(),
+            )
+        })
+    }
+}
+
+/// Run the scripts on their own thread, returning once each has run its top level.
+/// The thread is not a blocking task, which would keep the runtime from shutting down.
+pub async fn start(state: Arc<Mutex<EditorState>>, scripts: Vec<(String, String)>) {
+    let (loaded, on_loaded) = oneshot::channel();
+    let runtime = Handle::current();
+    std::thread::spawn(move || {
+        let plugin = InProcessPlugin::new();
+        let notify = plugin.notify.clone();
+        let channel = plugin.channel.clone();
+        let handler = EditorApiHandler::with_plugin(state.clone(), channel.clone());
+        let engine = new_engine(Rc::new(RefCell::new(handler)), runtime.clone());
+        let report = |name: &str, err: Box<EvalAltResult>| {
+            log::error!("{}: {}", name, err);
+            let mut state = runtime.block_on(state.lock());
+            state.messages.error(format!("{}: {}", name, err));
+        };
+
+        let mut loaded_scripts = Vec::new();
+        for (name, source) in scripts {
+            let mut scope = Scope::new();
+            let result = engine
+                .compile(&source)
+                .map_err(Box::<EvalAltResult>::from)
+                .and_then(|ast| engine.run_ast_with_scope(&mut scope, &ast).map(|_| ast));
+            match result {
+                Ok(ast) => loaded_scripts.push(Script { name, ast, scope }),
+                Err(err) => report(&name, err),
+            }
+        }
+        loaded.send(()).ok();
+
+        loop {
+            runtime.block_on(notify.notified());
+            for request in runtime.block_on(plugin.take_requests()) {
+                let result = match request.request {
+                    PluginRequestKind::BufWritePre { buf, content } => {
+                        // The edits of all scripts are applied together, so must not overlap
+                        let args = [to_dynamic(buf).unwrap_or_default(), content.into()];
+                        let mut edits = Vec::new();
+                        for script in &mut loaded_scripts {
+                            let result = match script.call(&engine, "on_buf_write_pre", &args) {
+                                Some(Ok(result)) if result.is_unit() => continue,
+                                Some(Ok(result)) => from_dynamic::<Vec<RangeEdit>>(&result),
+                                Some(Err(err)) => Err(err),
+                                None => continue,
+                            };
+                            match result {
+                                Ok(script_edits) => edits.extend(script_edits),
+                                Err(err) => report(&script.name, err),
+                            }
+                        }
+                        Ok(PluginResponseKind::Edits(edits))
+                    }
+                    request => Err(CuprumApiError::InvalidArgument(format!(
+                        "unexpected request {:?}",
+                        request
+                    ))),
+                };
+                runtime.block_on(plugin.respond(PluginResponse {
+                    request_id: request.request_id,
+                    result,
+                }));
+            }
+            for notification in runtime.block_on(channel.drain()) {
+                let (handler, args) = match notification {
+                    CuprumNotification::RunCommand {
+                        name,
+                        bang,
+                        range,
+                        args,
+                    } => (
+                        "on_command",
+                        vec![
+                            name.into(),
+                            bang.into(),
+                            to_dynamic(range).unwrap_or_default(),
+                            args.into(),
+                        ],
+                    ),
+                    CuprumNotification::Keymap { name } => ("on_keymap", vec![name.into()]),
+                    CuprumNotification::Event(event) => {
+                        ("on_event", vec![to_dynamic(event).unwrap_or_default()])
+                    }
+                };
+
+                for script in &mut loaded_scripts {
+                    if let Some(Err(err)) = script.call(&engine, handler, &args) {
+                        report(&script.name, err);
+                    }
+                }
+            }
+        }
+    });
+
+    on_loaded.await.ok();
+}
+
+/// An engine with the `cuprum` module, whose functions call `handler`
+fn new_engine(handler: Rc<RefCell<EditorApiHandler>>, runtime: Handle) -> Engine {
+    let mut engine = Engine::new();
+    engine.register_fn(
+        "__cuprum_call",
+        move |method: &str, args: Map| -> Result<Dynamic, Box<EvalAltResult>> {
+            let mut request = Map::new();
+            request.insert(method.into(), args.into());
+            let request: CuprumApiRequestKind =
+                from_dynamic(&request.into()).map_err(|err| format!("{}: {}", method, err))?;
+
+            let response = runtime
+                .block_on(handler.borrow_mut().process(request))
+                .map_err(|err| format!("{}: {}", method, err))?;
+            // Responses are tagged with the method name, like requests
+            Ok(match to_dynamic(response)?.try_cast::<Map>() {
+                Some(response) => response.into_values().next().unwrap_or_default(),
+                None => Dynamic::UNIT,
+            })
+        },
+    );
+
+    let source: String = API_METHODS
+        .iter()
+        .map(|method| {
+            let args = method
+                .args
+                .iter()
+                .map(|arg| format!("{}: {}", arg, arg))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!(
+                "fn {}({}) {{ __cuprum_call(\"{}\", #{{{}}}) }}\n",
+                method.name,
+                method.args.join(", "),
+                method.name,
+                args
+            )
+        })
+        .collect();
+    let ast = engine.compile(source).expect("the cuprum module compiles");
+    let module = Module::eval_ast_as_new(Scope::new(), &ast, &engine).unwrap();
+    engine.register_static_module("cuprum", module.into());
+    engine
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::{sleep, timeout};
+
+    use super::*;
+    use crate::test_editor::TestEditor;
+
+    #[tokio::test]
+    async fn test_trim_whitespace_on_save() {
+        let mut editor = TestEditor::new(&[("a.txt", "one  \ntwo\t\nthree")]);
+        let script = include_str!("../examples/trim-whitespace.rhai");
+        start(
+            editor.app.state.clone(),
+            vec![("trim-whitespace.rhai".to_string(), script.to_string())],
+        )
+        .await;
+
+        // The save waits for the script, so the trimmed lines are written at once
+        editor.keys(":w<CR>").await;
+        assert_eq!(
+            std::fs::read_to_string(editor.path("a.txt")).unwrap(),
+            "one\ntwo\nthree"
+        );
+
+        // Commands are not waited for
+        editor.keys("A \t<Esc>:trim<CR>").await;
+        timeout(Duration::from_secs(5), async {
+            while editor.lines().await != vec!["one", "two", "three"] {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the buffer was not trimmed");
+    }
+
+    #[tokio::test]
+    async fn test_script_errors_are_reported() {
+        let editor = TestEditor::new(&[("a.txt", "one")]);
+        start(
+            editor.app.state.clone(),
+            vec![
+                (
+                    "broken.rhai".to_string(),
+                    "cuprum::get_line((), 9);".to_string(),
+                ),
+                (
+                    "works.rhai".to_string(),
+                    "cuprum::insert_line((), 0, \"zero\");".to_string(),
+                ),
+            ],
+        )
+        .await;
+
+        let message = editor
+            .app
+            .state
+            .lock()
+            .await
+            .messages
+            .get_current()
+            .unwrap();
+        assert!(
+            message.text.starts_with("broken.rhai: "),
+            "{}",
+            message.text
+        );
+        assert!(
+            message.text.contains("position out of range"),
+            "{}",
+            message.text
+        );
+        // The other scripts still run
+        assert_eq!(editor.lines().await, vec!["zero", "one"]);
+    }
+}
@@ -0,0 +1,134 @@
+use anyhow::bail;
+use utils::vec2::UVec2;
+
+/// A match of a search pattern. `end` is exclusive, and `x` is a byte offset in the line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Match {
+    pub start: UVec2,
+    pub end: UVec2,
+}
+
+/// Find every occurrence of `pattern`, which may contain `\n` to match across lines
+pub fn find_byte_matches(lines: &[String], pattern: &str) -> Vec<Match> {
+    if pattern.is_empty() {
+        return Vec::new();
+    }
+
+    let content = lines.join("\n");
+    let mut line_starts = Vec::with_capacity(lines.len());
+    let mut offset = 0;
+    for line in lines {
+        line_starts.push(offset);
+        offset += line.len() + 1;
+    }
+    let to_pos = |offset: usize| {
+        let y = line_starts.partition_point(|start| *start <= offset) - 1;
+        UVec2::new(offset - line_starts[y], y)
+    };
+
+    content
+        .match_indices(pattern)
+        .map(|(start, _)| Match {
+            start: to_pos(start),
+            end: to_pos(start + pattern.len()),
+        })
+        .collect()
+}
+
+/// Character ranges of every match in the content, with lines joined by `\n`
+pub fn find_all(lines: &[String], pattern: &str) -> anyhow::Result<Vec<(usize, usize)>> {
+    if pattern.is_empty() {
+        bail!("empty search pattern");
+    }
+
+    let content = lines.join("\n");
+    let pattern_chars = pattern.chars().count();
+    let mut chars = 0;
+    let mut bytes = 0;
+    Ok(content
+        .match_indices(pattern)
+        .map(|(start, _)| {
+            // Matches are in order, so count the characters since the previous one
+            chars += content[bytes..start].chars().count();
+            bytes = start;
+            (chars, chars + pattern_chars)
+        })
+        .collect())
+}
+
+/// Character index of the first match starting at or after `from`, or the last one
+/// starting before it when searching backward, wrapping around the content
+pub fn search(
+    lines: &[String],
+    pattern: &str,
+    from: usize,
+    forward: bool,
+) -> anyhow::Result<Option<usize>> {
+    let starts = find_all(lines, pattern)?
+        .into_iter()
+        .map(|(start, _)| start);
+    Ok(if forward {
+        starts
+            .clone()
+            .find(|start| *start >= from)
+            .or_else(|| starts.clone().next())
+    } else {
+        starts
+            .clone()
+            .rfind(|start| *start < from)
+            .or_else(|| starts.clone().next_back())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn test_match_across_lines() {
+        let lines = lines(&["foo", "bar foo", "bar"]);
+        assert_eq!(
+            find_byte_matches(&lines, "foo\nbar"),
+            vec![
+                Match {
+                    start: UVec2::new(0, 0),
+                    end: UVec2::new(3, 1),
+                },
+                Match {
+                    start: UVec2::new(4, 1),
+                    end: UVec2::new(3, 2),
+                },
+            ]
+        );
+        assert_eq!(find_all(&lines, "foo\nbar").unwrap(), vec![(0, 7), (8, 15)]);
+    }
+
+    #[test]
+    fn test_char_offsets() {
+        let lines = lines(&["äb ab", "ab"]);
+        assert_eq!(find_all(&lines, "ab").unwrap(), vec![(3, 5), (6, 8)]);
+        assert_eq!(find_all(&lines, "äb").unwrap(), vec![(0, 2)]);
+    }
+
+    #[test]
+    fn test_search_wraps() {
+        let lines = lines(&["ab ab", "ab"]);
+        assert_eq!(search(&lines, "ab", 1, true).unwrap(), Some(3));
+        assert_eq!(search(&lines, "ab", 7, true).unwrap(), Some(0));
+        assert_eq!(search(&lines, "ab", 3, false).unwrap(), Some(0));
+        assert_eq!(search(&lines, "ab", 0, false).unwrap(), Some(6));
+        assert_eq!(search(&lines, "x", 0, true).unwrap(), None);
+    }
+
+    #[test]
+    fn test_empty_pattern() {
+        let lines = lines(&["a"]);
+        assert!(find_all(&lines, "").is_err());
+        assert!(search(&lines, "", 0, true).is_err());
+        assert!(find_byte_matches(&lines, "").is_empty());
+    }
+}
@@ -0,0 +1,101 @@
+use std::{process::Stdio, time::Duration};
+
+use anyhow::anyhow;
+use tokio::{io::AsyncWriteExt, process::Command, time::timeout};
+
+/// How long a shell command may run before it is killed
+pub const SHELL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The shell commands run in, and the flag that passes it a command
+#[cfg(not(windows))]
+const SHELL: [&str; 2] = ["sh", "-c"];
+#[cfg(windows)]
+const SHELL: [&str; 2] = ["cmd", "/C"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShellOutput {
+    pub stdout: String,
+    pub stderr: String,
+    /// Exit code, `None` if the command was killed by a signal
+    pub code: Option<i32>,
+}
+
+impl ShellOutput {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+/// Run a command with `sh -c`, or `cmd /C` on Windows, optionally writing `input` to its stdin.
+/// The command is killed if it runs longer than `limit` or the future is dropped.
+pub async fn run_shell(
+    command: &str,
+    input: Option<String>,
+    limit: Duration,
+) -> anyhow::Result<ShellOutput> {
+    let [shell, flag] = SHELL;
+    let mut child = Command::new(shell)
+        .arg(flag)
+        .arg(command)
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    if let Some(input) = input
+        && let Some(mut stdin) = child.stdin.take()
+    {
+        // Write in the background so a command that does not read stdin cannot block us
+        tokio::spawn(async move {
+            stdin.write_all(input.as_bytes()).await.ok();
+        });
+    }
+
+    let output = timeout(limit, child.wait_with_output())
+        .await
+        .map_err(|_| anyhow!("Command timed out: {}", command))??;
+
+    Ok(ShellOutput {
+        stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+        stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        code: output.status.code(),
+    })
+}
+
+// The commands are written for `sh`
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run_shell() {
+        let output = run_shell("echo hello; echo error >&2", None, SHELL_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(output.stdout, "hello\n");
+        assert_eq!(output.stderr, "error\n");
+        assert!(output.success());
+
+        let output = run_shell("exit 3", None, SHELL_TIMEOUT).await.unwrap();
+        assert_eq!(output.code, Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_run_shell_input() {
+        let output = run_shell("tr a-z A-Z", Some("abc\n".to_string()), SHELL_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(output.stdout, "ABC\n");
+    }
+
+    #[tokio::test]
+    async fn test_run_shell_timeout() {
+        let result = run_shell("sleep 5", None, Duration::from_millis(100)).await;
+        assert!(result.is_err());
+    }
+}
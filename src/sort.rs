@@ -0,0 +1,110 @@
+use std::cmp::Ordering;
+
+use anyhow::bail;
+
+/// Flags of `:sort`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SortOptions {
+    /// `:sort!`
+    pub reverse: bool,
+    /// `u`: keep only the first of equal lines
+    pub unique: bool,
+    /// `n`: compare the first number on each line
+    pub numeric: bool,
+}
+
+impl SortOptions {
+    /// Parse the arguments of `:sort`, e.g. `u` or `n u`
+    pub fn parse(reverse: bool, args: &str) -> anyhow::Result<Self> {
+        let mut options = Self {
+            reverse,
+            ..Default::default()
+        };
+        for ch in args.chars().filter(|ch| !ch.is_whitespace()) {
+            match ch {
+                'u' => options.unique = true,
+                'n' => options.numeric = true,
+                _ => bail!("E474: Invalid argument"),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// The first integer on a line, if any
+fn first_number(line: &str) -> Option<i64> {
+    let start = line.find(|ch: char| ch.is_ascii_digit())?;
+    let digits: String = line[start..]
+        .chars()
+        .take_while(|ch| ch.is_ascii_digit())
+        .collect();
+    let number: i64 = digits.parse().ok()?;
+
+    if line[..start].ends_with('-') {
+        Some(-number)
+    } else {
+        Some(number)
+    }
+}
+
+/// Sort lines stably. In numeric mode, lines without a number come first.
+pub fn sort_lines(mut lines: Vec<String>, options: SortOptions) -> Vec<String> {
+    let compare = |a: &String, b: &String| -> Ordering {
+        if options.numeric {
+            first_number(a).cmp(&first_number(b))
+        } else {
+            a.cmp(b)
+        }
+    };
+
+    if options.reverse {
+        lines.sort_by(|a, b| compare(b, a));
+    } else {
+        lines.sort_by(compare);
+    }
+
+    if options.unique {
+        lines.dedup_by(|a, b| compare(a, b) == Ordering::Equal);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sort(lines: &[&str], reverse: bool, args: &str) -> Vec<String> {
+        let lines = lines.iter().map(|line| line.to_string()).collect();
+        sort_lines(lines, SortOptions::parse(reverse, args).unwrap())
+    }
+
+    #[test]
+    fn test_sort() {
+        assert_eq!(sort(&["c", "a", "b"], false, ""), vec!["a", "b", "c"]);
+        assert_eq!(sort(&["c", "a", "b"], true, ""), vec!["c", "b", "a"]);
+        assert_eq!(sort(&["b", "a", "b", "a"], false, "u"), vec!["a", "b"]);
+        assert_eq!(sort(&["b", "a", "b", "a"], true, "u"), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn test_sort_numeric() {
+        let lines = ["x10", "x9", "none", "-3 y", "x9 again"];
+        assert_eq!(
+            sort(&lines, false, "n"),
+            vec!["none", "-3 y", "x9", "x9 again", "x10"]
+        );
+        assert_eq!(
+            sort(&lines, true, "n"),
+            vec!["x10", "x9", "x9 again", "-3 y", "none"]
+        );
+        assert_eq!(
+            sort(&lines, false, "n u"),
+            vec!["none", "-3 y", "x9", "x10"]
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(SortOptions::parse(false, "z").is_err());
+    }
+}
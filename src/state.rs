@@ -1,109 +1,2628 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hasher,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use api::{Mode, WindowId};
-use tokio::sync::Mutex;
-use utils::vec2::IVec2;
+use anyhow::{anyhow, bail};
+use api::{
+    BufferId, CuprumNotification, Diagnostic, EditorEvent, EventKind, Mode, Popup, PopupId,
+    SplitDirection, TextEdit, WindowId,
+};
+use plugin_manager::{PluginChannel, PluginInfo};
+use tokio::sync::{Mutex, oneshot};
+use utils::{
+    grapheme,
+    hash::Fnv1a,
+    term::get_terminal_size_or_default,
+    vec2::{IVec2, UVec2},
+};
 
 use crate::{
-    action::Action,
-    buffer::Buffer,
-    managers::{BufferManager, WindowManager},
-    ui::{commands::CommandMap, input::KeyCode},
-    window::Window,
+    action::{Action, LocationJump, find_action, looks_like_action_name},
+    buffer::{Buffer, undo::UndoAmount},
+    completion::{Completion, CompletionManager, CompletionRequest, get_prefix, is_word_char},
+    config::{Config, KeyBinding, option_value},
+    help::{find_topic, help_lines},
+    highlight::{
+        Highlight, HighlightSource, Highlights, diagnostic_highlights, find_matches, find_next,
+    },
+    layout::{Border, Layout, Placement},
+    managers::{BufferManager, PopupManager, WindowManager},
+    messages::MessageQueue,
+    options::Options,
+    registers::{Register, Registers, UNNAMED_REGISTER},
+    shell::ShellOutput,
+    sort::{SortOptions, sort_lines},
+    ui::{
+        commands::{CommandCompletion, CommandContext, CommandMap, parse_command},
+        input::{Key, KeyCode, Keymap, MapMode, UserKeymaps, parse_keys},
+        statusline::StatusSegments,
+    },
+    window::{Window, get_window_size},
 };
 
+/// How long the cursor must stay put before `CursorMoved` is sent
+pub const CURSOR_MOVED_DEBOUNCE: Duration = Duration::from_millis(50);
+/// How long a buffer must be left unchanged before its changes are sent
+pub const BUFFER_CHANGE_DEBOUNCE: Duration = Duration::from_millis(50);
+
+pub const READONLY_ERROR: &str = "E21: Cannot make changes, the buffer is read-only";
+
 #[derive(Debug)]
 pub struct EditorState {
     pub buffer_manager: BufferManager,
     pub window_manager: WindowManager,
+    pub popup_manager: PopupManager,
+    pub completion: CompletionManager,
+    pub registers: Registers,
+    pub options: Options,
+    /// Options as set by the config file and `:set`, without those of a filetype
+    global_options: Options,
+    /// The filetype whose options are layered over `global_options`
+    options_filetype: Option<String>,
+    /// Options of each filetype from the config file, as `:set` takes them
+    filetype_options: HashMap<String, Vec<(String, String)>>,
+    pub messages: MessageQueue,
     active_window: WindowId,
+    /// The windows on the screen, always including the active one
+    layout: Layout,
     pub mode: Arc<Mutex<Mode>>,
     pub command_buf: String,
+    /// `:` for commands, `/` for searches
+    pub command_prompt: char,
     command_map: CommandMap,
+    /// Commands registered by plugins, run after the built-in ones
+    pub plugin_commands: HashMap<String, PluginChannel>,
+    /// Plugins and the events they subscribed to
+    subscriptions: Vec<(PluginChannel, HashSet<EventKind>)>,
+    /// Last cursor position sent in a `CursorMoved` event
+    last_cursor_event: Option<(WindowId, UVec2)>,
+    /// Where the cursor was last seen, and since when it has been there
+    cursor_moved_at: Option<(WindowId, UVec2, Instant)>,
+    /// Buffer of the last `BufEnter` event
+    last_entered_buffer: Option<BufferId>,
+    /// Status line text set by plugins
+    pub statusline: StatusSegments,
+    command_completion: Option<CommandCompletion>,
+    /// Prompt shown by `prompt_input` and where to send the answer
+    input_prompt: Option<(String, oneshot::Sender<Option<String>>)>,
+    /// Whether `Ctrl-r` was typed and a register name is expected next
+    register_pending: bool,
+    pub highlights: Highlights,
+    /// Last search pattern, kept after `:noh` so `n` still works
+    last_search: Option<String>,
+    /// Whether matches of the last search are highlighted
+    hlsearch: bool,
+    /// Mappings added with `:map` or by plugins
+    pub user_keymaps: UserKeymaps,
+    help_window: Option<WindowId>,
+    messages_window: Option<WindowId>,
+    /// The window to go back to when the active one is closed
+    previous_window: Option<WindowId>,
+    /// Popup opened by `show_hover`, closed by the next key
+    hover: Option<PopupId>,
+    /// Popup listing the keys that can follow a pending sequence, closed by the next key
+    key_hint: Option<PopupId>,
+    /// Size of the terminal, updated when it is resized
+    pub term_size: UVec2,
+    /// Bumped by `touch` once keys, commands or requests may have changed what is drawn
+    revision: u64,
 }
 
 impl EditorState {
     pub fn new(files: Vec<String>) -> anyhow::Result<Self> {
+        Self::with_term_size(files, get_terminal_size_or_default())
+    }
+
+    /// Lay windows out for a terminal of `term_size`, instead of asking the terminal
+    pub fn with_term_size(files: Vec<String>, term_size: UVec2) -> anyhow::Result<Self> {
         let mode = Arc::new(Mutex::new(Mode::Normal));
+        let win_size = get_window_size(term_size);
         let mut buffer_manager = BufferManager::default();
         let mut window_manager = WindowManager::default();
         if files.is_empty() {
             let (id, buf) = buffer_manager.open_buffer(Buffer::default());
-            window_manager.open_window(Window::new(id, buf, mode.clone()));
+            window_manager.open_window(Window::new(id, buf, mode.clone(), win_size));
         } else {
             for file in files {
                 let buf = Buffer::open(PathBuf::from(file))?;
                 let (id, buf) = buffer_manager.open_buffer(buf);
-                window_manager.open_window(Window::new(id, buf, mode.clone()));
+                window_manager.open_window(Window::new(id, buf, mode.clone(), win_size));
             }
         }
 
         Ok(Self {
             buffer_manager,
             window_manager,
+            popup_manager: PopupManager::default(),
+            completion: CompletionManager::default(),
+            registers: Registers::default(),
+            options: Options::default(),
+            global_options: Options::default(),
+            options_filetype: None,
+            filetype_options: HashMap::new(),
+            messages: MessageQueue::default(),
             active_window: WindowId(0),
+            layout: Layout::Window(WindowId(0)),
             mode,
             command_buf: String::new(),
+            command_prompt: ':',
             command_map: CommandMap::default(),
+            plugin_commands: HashMap::new(),
+            subscriptions: Vec::new(),
+            last_cursor_event: None,
+            cursor_moved_at: None,
+            last_entered_buffer: None,
+            statusline: StatusSegments::default(),
+            command_completion: None,
+            register_pending: false,
+            input_prompt: None,
+            highlights: Highlights::default(),
+            last_search: None,
+            hlsearch: false,
+            user_keymaps: UserKeymaps::default(),
+            help_window: None,
+            messages_window: None,
+            previous_window: None,
+            hover: None,
+            key_hint: None,
+            term_size,
+            revision: 0,
         })
     }
 
+    /// Note that what is drawn may have changed
+    pub fn touch(&mut self) {
+        self.revision += 1;
+    }
+
+    /// Changes whenever what is drawn may have, so an unchanged revision means the last
+    /// frame is still the one to show
+    pub fn get_revision(&self) -> u64 {
+        self.revision + self.messages.get_revision()
+    }
+
+    pub fn get_active_window_id(&self) -> WindowId {
+        self.active_window
+    }
+
     pub fn get_active_window(&self) -> Option<Arc<Mutex<Window>>> {
         self.window_manager.get_window(self.active_window)
     }
 
     pub async fn set_mode(&mut self, mode: Mode) {
-        match mode {
-            Mode::Insert(true) => {
-                if let Some(win) = self.get_active_window() {
-                    let mut win = win.lock().await;
-                    win.move_by(IVec2::right()).await;
-                }
+        if let Mode::Insert(_) = mode
+            && !self.check_modifiable().await
+        {
+            return;
+        }
+
+        let old_mode = self.mode.lock().await.clone();
+        if let Mode::Visual = old_mode
+            && !matches!(mode, Mode::Visual)
+            && let Some(win) = self.get_active_window()
+        {
+            let mut win = win.lock().await;
+            win.end_visual().await;
+
+            // Commands typed from visual mode apply to the selection
+            if let Mode::Command = mode {
+                self.command_buf = "'<,'>".to_string();
             }
-            Mode::Visual => {
-                if let Some(win) = self.get_active_window() {
-                    let mut win = win.lock().await;
-                    win.start_visual().await;
-                }
+        }
+
+        if let Mode::Visual = mode
+            && let Some(win) = self.get_active_window()
+        {
+            win.lock().await.start_visual().await;
+        }
+
+        *self.mode.lock().await = mode.clone();
+
+        // Appending goes after the character under the cursor, which can be past the end of
+        // the line only now that the mode is insert
+        if let Mode::Insert(true) = mode
+            && let Some(win) = self.get_active_window()
+        {
+            let mut win = win.lock().await;
+            let cursor = win.get_render_cursor().await;
+            let line = win.get_buffer().lock().await.get_line(cursor.y);
+            win.move_to_x(grapheme::next(&line.unwrap_or_default(), cursor.x))
+                .await;
+        }
+        if old_mode != mode {
+            self.emit(EditorEvent::ModeChanged { mode }).await;
+        }
+    }
+
+    /// Add events to those a plugin receives
+    pub fn subscribe(&mut self, plugin: &PluginChannel, events: Vec<EventKind>) {
+        match self
+            .subscriptions
+            .iter_mut()
+            .find(|(subscriber, _)| subscriber == plugin)
+        {
+            Some((_, subscribed)) => subscribed.extend(events),
+            None => self
+                .subscriptions
+                .push((plugin.clone(), events.into_iter().collect())),
+        }
+    }
+
+    /// The plugins subscribed to `kind`
+    pub fn get_subscribers(&self, kind: EventKind) -> Vec<PluginChannel> {
+        self.subscriptions
+            .iter()
+            .filter(|(_, events)| events.contains(&kind))
+            .map(|(plugin, _)| plugin.clone())
+            .collect()
+    }
+
+    /// Drop the commands, mappings and subscriptions of a plugin that was stopped
+    pub fn forget_plugin(&mut self, plugin: &PluginChannel) {
+        self.plugin_commands.retain(|_, owner| owner != plugin);
+        self.completion.forget_plugin(plugin);
+        self.subscriptions
+            .retain(|(subscriber, _)| subscriber != plugin);
+        for mode in [MapMode::Normal, MapMode::Visual, MapMode::Insert] {
+            self.user_keymaps.get_mut(mode).retain(
+                |action| !matches!(action, Action::PluginKeymap(owner, _) if owner == plugin),
+            );
+        }
+    }
+
+    /// Send an event to the plugins subscribed to it
+    pub async fn emit(&self, event: EditorEvent) {
+        for (plugin, events) in &self.subscriptions {
+            if events.contains(&event.kind()) {
+                plugin.send(CuprumNotification::Event(event.clone())).await;
             }
-            _ => {}
         }
+    }
+
+    /// Send `BufEnter` if the active buffer changed since the last one
+    pub async fn emit_buf_enter(&mut self) {
+        let Some(win) = self.get_active_window() else {
+            return;
+        };
+        let buf = win.lock().await.get_buffer_id();
+        if self.last_entered_buffer != Some(buf) {
+            self.last_entered_buffer = Some(buf);
+            self.emit(EditorEvent::BufEnter { buf }).await;
+        }
+    }
+
+    /// Send `CursorMoved` once the cursor has stayed where it is for `debounce`, unless it
+    /// was sent there already. Quick moves are sent as one, with where they stopped.
+    pub async fn emit_cursor_moved(&mut self, debounce: Duration) {
+        let Some(win) = self.get_active_window() else {
+            return;
+        };
+        let id = self.active_window;
+        let pos = win.lock().await.get_cursor();
+        let since = match self.cursor_moved_at {
+            Some((last_id, last_pos, since)) if (last_id, last_pos) == (id, pos) => since,
+            _ => Instant::now(),
+        };
+        self.cursor_moved_at = Some((id, pos, since));
+        if since.elapsed() < debounce || self.last_cursor_event == Some((id, pos)) {
+            return;
+        }
+
+        self.last_cursor_event = Some((id, pos));
+        self.emit(EditorEvent::CursorMoved { win: id, pos }).await;
+    }
+
+    /// Send `BufferChanged` for the buffers that were not changed within `debounce`
+    pub async fn emit_buffer_changes(&self, debounce: Duration) {
+        for id in self.buffer_manager.get_buffer_ids() {
+            let Some(buf) = self.buffer_manager.get_buffer(id) else {
+                continue;
+            };
+            let changes = buf.lock().await.take_changes(debounce);
+            for change in changes {
+                self.emit(EditorEvent::BufferChanged {
+                    buf: id,
+                    revision: change.revision,
+                    start: change.start,
+                    old_end: change.old_end,
+                    new_text: change.new_text,
+                })
+                .await;
+            }
+        }
+    }
+
+    /// Close the completion popup if any, otherwise the topmost dismissable popup
+    pub fn close_top_popup(&mut self) -> bool {
+        if self.completion.is_active() {
+            self.cancel_completion();
+            true
+        } else {
+            self.popup_manager.close_top()
+        }
+    }
+
+    /// Move the selection of the active completion
+    pub fn select_completion(&mut self, offset: isize) {
+        if let Some(completion) = &mut self.completion.active {
+            completion.select_by(offset);
+            self.popup_manager
+                .update_popup(completion.popup, completion.to_popup());
+        }
+    }
+
+    /// Get the prefix before the cursor with the built-in candidates for it.
+    /// With a `trigger` character only the plugin sources it starts are asked.
+    pub async fn prepare_completion(&self, trigger: Option<char>) -> Option<CompletionRequest> {
+        let win = self.get_active_window()?;
+        let win = win.lock().await;
+        let cursor = win.get_render_cursor().await;
+
+        let buf = win.get_buffer();
+        let buf = buf.lock().await;
+        let line = buf.get_line(cursor.y).unwrap_or_default();
+        let prefix = get_prefix(&line, cursor.x);
+        let candidates = match trigger {
+            None if prefix.is_empty() => return None,
+            None => self.completion.collect(&buf, &prefix),
+            Some(_) => Vec::new(),
+        };
+
+        let start = UVec2::new(cursor.x - prefix.chars().count(), cursor.y);
+        let anchor =
+            win.get_position() + UVec2::new(start.x, cursor.y.saturating_sub(win.get_scroll()) + 1);
+        Some(CompletionRequest {
+            buf: win.get_buffer_id(),
+            start,
+            cursor,
+            prefix,
+            anchor,
+            candidates,
+            plugin_sources: self.completion.get_plugin_sources(trigger),
+        })
+    }
+
+    /// Show the completion popup, selecting the last candidate if `offset` is negative
+    pub fn open_completion(&mut self, request: CompletionRequest, offset: isize) {
+        if request.candidates.is_empty() {
+            return;
+        }
+        self.cancel_completion();
+
+        let selected = if offset < 0 {
+            request.candidates.len() - 1
+        } else {
+            0
+        };
+        let mut completion = Completion {
+            start: request.start,
+            prefix: request.prefix,
+            candidates: request.candidates,
+            selected,
+            anchor: request.anchor,
+            popup: PopupId(0),
+        };
+        completion.popup = self.popup_manager.open_popup(completion.to_popup());
+        self.completion.active = Some(completion);
+    }
+
+    /// Replace the typed prefix with the selected candidate
+    pub async fn accept_completion(&mut self) {
+        let Some(completion) = self.completion.active.take() else {
+            return;
+        };
+        self.popup_manager.close_popup(completion.popup);
+
+        let Some(win) = self.get_active_window() else {
+            return;
+        };
+        let mut win = win.lock().await;
+        let cursor = win.get_render_cursor().await;
+        let text = &completion.get_selected().insert_text;
+
+        {
+            let buf = win.get_buffer();
+            let mut buf = buf.lock().await;
+            let Some(line_length) = buf.get_line_length(completion.start.y) else {
+                return;
+            };
+
+            let end = cursor.x.min(line_length);
+            let start = completion.start.x.min(end);
+            let edit = TextEdit {
+                start: buf.get_char_offset(UVec2::new(start, completion.start.y)),
+                end: buf.get_char_offset(UVec2::new(end, completion.start.y)),
+                text: text.clone(),
+            };
+            if let Err(err) = buf.apply_edits(vec![edit]) {
+                log::error!("{}", err);
+                return;
+            }
+        }
+
+        // The cursor goes to the end of the inserted text, which may span lines
+        let lines: Vec<&str> = text.split('\n').collect();
+        let last_length = lines.last().map_or(0, |line| line.chars().count());
+        if lines.len() > 1 {
+            win.move_by(IVec2::new(0, lines.len() as isize - 1)).await;
+            win.move_to_x(last_length).await;
+        } else {
+            win.move_to_x(completion.start.x + last_length).await;
+        }
+    }
 
-        let mut mutex_mode = self.mode.lock().await;
-        *mutex_mode = mode;
+    pub fn cancel_completion(&mut self) {
+        if let Some(completion) = self.completion.active.take() {
+            self.popup_manager.close_popup(completion.popup);
+        }
     }
 
     async fn set_command_to_normal_mode(&mut self) {
+        if let Some((_, answer)) = self.input_prompt.take() {
+            answer.send(None).ok();
+        }
         self.command_buf = String::new();
+        self.command_prompt = ':';
         self.set_mode(Mode::Normal).await;
     }
 
-    pub async fn process_command(&mut self, key_code: KeyCode) -> anyhow::Result<Option<Action>> {
-        match key_code {
-            KeyCode::Esc => {
-                self.command_buf = String::new();
-                self.set_command_to_normal_mode().await;
+    /// Ask the user for a line of text in the command line.
+    /// The answer is None if the prompt is cancelled.
+    pub async fn start_input_prompt(
+        &mut self,
+        prompt: &str,
+    ) -> anyhow::Result<oneshot::Receiver<Option<String>>> {
+        if matches!(*self.mode.lock().await, Mode::Command) {
+            bail!("the command line is in use");
+        }
+
+        let (answer, receiver) = oneshot::channel();
+        self.set_mode(Mode::Command).await;
+        self.command_buf = String::new();
+        self.input_prompt = Some((prompt.to_string(), answer));
+        Ok(receiver)
+    }
+
+    /// Close the prompt of `prompt_input`, if it is open
+    pub async fn cancel_input_prompt(&mut self) {
+        if self.input_prompt.is_some() {
+            self.set_command_to_normal_mode().await;
+        }
+    }
+
+    /// The text before the command line input
+    pub fn get_prompt_text(&self) -> String {
+        match &self.input_prompt {
+            Some((prompt, _)) => prompt.clone(),
+            None => self.command_prompt.to_string(),
+        }
+    }
+
+    async fn get_command_context(&self) -> CommandContext {
+        let Some(win) = self.get_active_window() else {
+            return CommandContext::default();
+        };
+        let win = win.lock().await;
+        let current_line = win.get_cursor().y;
+        let buf = win.get_buffer();
+        let line_count = buf.lock().await.get_line_count();
+        let visual_lines = win.get_last_visual().map(|(start, end)| (start.y, end.y));
+
+        CommandContext {
+            current_line,
+            line_count,
+            visual_lines,
+        }
+    }
+
+    /// Apply the arguments of `:set`, showing queried values and errors as messages
+    pub async fn set_options(&mut self, args: &str) {
+        if args.trim().is_empty() {
+            let all = self.options.show_all();
+            self.messages.info(all);
+            return;
+        }
+
+        let old = self.options.clone();
+        for arg in args.split_whitespace() {
+            // The filetype belongs to the buffer rather than being an option of its own
+            let result = match arg.split_once('=') {
+                Some(("filetype" | "ft", filetype)) => {
+                    self.set_filetype(filetype).await;
+                    Ok(None)
+                }
+                None if matches!(arg, "filetype" | "ft" | "filetype?" | "ft?") => {
+                    let filetype = self.get_filetype().await.unwrap_or_default();
+                    Ok(Some(format!("  filetype={}", filetype)))
+                }
+                _ => self.options.apply(arg),
+            };
+            match result {
+                Ok(Some(text)) => self.messages.info(text),
+                Ok(None) => {}
+                Err(err) => {
+                    self.messages.error(err.to_string());
+                    break;
+                }
             }
-            KeyCode::Backspace => {
-                if self.command_buf.is_empty() {
-                    self.set_command_to_normal_mode().await;
-                } else {
-                    self.command_buf.pop();
+        }
+        self.keep_option_changes(&old);
+        self.emit_option_changes(&old).await;
+        self.update_filetype_options().await;
+    }
+
+    /// Keep the options changed since `old` when another filetype's are applied, as
+    /// `:set` sets them for every buffer
+    pub fn keep_option_changes(&mut self, old: &Options) {
+        for (name, value) in self.options.changes(old) {
+            self.global_options.set(&name, &value).ok();
+        }
+    }
+
+    /// The filetype of the active buffer
+    pub async fn get_filetype(&self) -> Option<String> {
+        let buf = self.get_active_window()?.lock().await.get_buffer();
+        buf.lock().await.get_filetype().map(str::to_string)
+    }
+
+    /// Set the filetype of the active buffer, or clear it if empty
+    async fn set_filetype(&mut self, filetype: &str) {
+        let Some(win) = self.get_active_window() else {
+            return;
+        };
+        let buf = win.lock().await.get_buffer();
+        let filetype = (!filetype.is_empty()).then(|| filetype.to_string());
+        buf.lock().await.set_filetype(filetype);
+    }
+
+    /// Layer the options of the active buffer's filetype over the others, unless they
+    /// already are
+    pub async fn update_filetype_options(&mut self) {
+        let filetype = self.get_filetype().await;
+        if filetype == self.options_filetype {
+            return;
+        }
+
+        let old = std::mem::replace(&mut self.options, self.global_options.clone());
+        if let Some(options) = filetype
+            .as_ref()
+            .and_then(|filetype| self.filetype_options.get(filetype))
+        {
+            for (name, value) in options {
+                // Checked when the config was read
+                self.options.set(name, value).ok();
+            }
+        }
+        self.options_filetype = filetype;
+        self.emit_option_changes(&old).await;
+    }
+
+    /// Send `OptionChanged` for each option that differs from `old`
+    pub async fn emit_option_changes(&self, old: &Options) {
+        for (name, value) in self.options.changes(old) {
+            self.emit(EditorEvent::OptionChanged { name, value }).await;
+        }
+    }
+
+    /// Map `lhs` to replay `rhs` in each of the modes
+    pub fn map_keys(
+        &mut self,
+        modes: &[MapMode],
+        lhs: Key,
+        rhs: &str,
+        noremap: bool,
+    ) -> anyhow::Result<()> {
+        let keys = parse_keys(rhs)?;
+        for mode in modes {
+            self.user_keymaps.get_mut(*mode).reg(
+                lhs.clone(),
+                Action::Feed(keys.clone(), !noremap),
+                rhs,
+            );
+        }
+        Ok(())
+    }
+
+    /// Set the options and mappings of a config file, reporting the ones that are wrong
+    pub fn apply_config(&mut self, config: &Config) {
+        for (name, value) in &config.options {
+            if let Err(err) = self.options.set(name, &option_value(value)) {
+                self.messages.error(format!("config.toml: {}", err));
+            }
+        }
+        self.global_options = self.options.clone();
+        for (filetype, options) in &config.filetype {
+            let mut valid = Vec::new();
+            for (name, value) in options {
+                let value = option_value(value);
+                // Checked now rather than each time a buffer of the type is entered
+                match Options::default().set(name, &value) {
+                    Ok(()) => valid.push((name.clone(), value)),
+                    Err(err) => self
+                        .messages
+                        .error(format!("config.toml: filetype.{}: {}", filetype, err)),
                 }
             }
-            KeyCode::Char('\n') => {
-                if let Some(action) = self.command_map.get(&self.command_buf) {
-                    let action = action.clone();
-                    self.set_command_to_normal_mode().await;
-                    return Ok(Some(action));
-                } else {
-                    self.set_command_to_normal_mode().await;
+            self.filetype_options.insert(filetype.clone(), valid);
+        }
+
+        let keymaps = &config.keymaps;
+        for (mode, maps) in [
+            (MapMode::Normal, &keymaps.normal),
+            (MapMode::Visual, &keymaps.visual),
+            (MapMode::Insert, &keymaps.insert),
+        ] {
+            // Notations of the keys mapped so far, as different ones can be the same keys
+            let mut mapped = HashMap::new();
+            for (notation, binding) in maps {
+                let result = parse_keys(notation).and_then(|lhs| {
+                    if let Some(other) = mapped.insert(lhs.clone(), notation) {
+                        bail!("Same keys as {}", other);
+                    }
+                    self.map_binding(mode, lhs, binding)
+                });
+                if let Err(err) = result {
+                    self.messages
+                        .error(format!("config.toml: mapping {}: {}", notation, err));
                 }
             }
-            KeyCode::Char(ch) => self.command_buf.push(ch),
-            _ => {}
         }
 
-        Ok(None)
+        for (name, rhs) in &config.commands {
+            if let Err(err) = self.define_command(name, rhs) {
+                self.messages
+                    .error(format!("config.toml: command {}: {}", name, err));
+            }
+        }
+    }
+
+    /// Define a user command running an action name, or replaying keys without remapping
+    fn define_command(&mut self, name: &str, rhs: &str) -> anyhow::Result<()> {
+        if !name.starts_with(|ch: char| ch.is_ascii_uppercase()) {
+            bail!("E183: User defined commands must start with an uppercase letter");
+        }
+        if !name.chars().all(|ch| ch.is_ascii_alphanumeric()) {
+            bail!("E182: Invalid command name");
+        }
+        let action = match find_action(rhs) {
+            Some(action) => action,
+            None if looks_like_action_name(rhs) => bail!("Unknown action: {}", rhs),
+            None => Action::Feed(parse_keys(rhs)?, false),
+        };
+        self.command_map.reg_action(name, rhs, action);
+        Ok(())
+    }
+
+    /// Map `lhs` as a config file says
+    fn map_binding(&mut self, mode: MapMode, lhs: Key, binding: &KeyBinding) -> anyhow::Result<()> {
+        match binding {
+            KeyBinding::Keys(rhs) if find_action(rhs).is_some() || looks_like_action_name(rhs) => {
+                self.map_action(&[mode], lhs, rhs)
+            }
+            // Anything else is keys, such as `dd`
+            KeyBinding::Keys(rhs) => self.map_keys(&[mode], lhs, rhs, true),
+            KeyBinding::Enabled(false) => {
+                // Found before the built-in mapping, and does nothing
+                self.user_keymaps
+                    .get_mut(mode)
+                    .reg(lhs, Action::Feed(Vec::new(), false), "<Nop>");
+                Ok(())
+            }
+            KeyBinding::Enabled(true) => bail!("Only false can be given instead of keys"),
+        }
+    }
+
+    /// Map `lhs` to a named action in each of the modes
+    pub fn map_action(&mut self, modes: &[MapMode], lhs: Key, name: &str) -> anyhow::Result<()> {
+        let action = find_action(name).ok_or(anyhow!("Unknown action: {}", name))?;
+        for mode in modes {
+            self.user_keymaps
+                .get_mut(*mode)
+                .reg(lhs.clone(), action.clone(), name);
+        }
+        Ok(())
+    }
+
+    /// Map `lhs` to notify a plugin, warning if it hides a built-in mapping
+    pub fn map_plugin_keys(&mut self, mode: MapMode, lhs: Key, plugin: PluginChannel, name: &str) {
+        if let Some(keymap) = mode.default_keymap()
+            && (keymap.get(&lhs).is_some() || keymap.has_prefix(&lhs))
+        {
+            let notation: String = lhs.iter().map(|code| code.to_string()).collect();
+            let warning = format!("Plugin mapping {} shadows a built-in mapping", notation);
+            log::warn!("{}", warning);
+            self.messages.info(warning);
+        }
+
+        self.user_keymaps.get_mut(mode).reg(
+            lhs,
+            Action::PluginKeymap(plugin, name.to_string()),
+            &format!("plugin: {}", name),
+        );
+    }
+
+    /// Remove a mapping from each of the modes
+    pub fn unmap_keys(&mut self, modes: &[MapMode], lhs: &Key) -> anyhow::Result<()> {
+        let mut removed = false;
+        for mode in modes {
+            removed |= self.user_keymaps.get_mut(*mode).remove(lhs);
+        }
+        if !removed {
+            bail!("E31: No such mapping");
+        }
+        Ok(())
+    }
+
+    /// Whether the active buffer can be edited, showing an error if not
+    pub async fn check_modifiable(&mut self) -> bool {
+        let Some(win) = self.get_active_window() else {
+            return true;
+        };
+        let buf = win.lock().await.get_buffer();
+        if buf.lock().await.is_readonly() {
+            self.messages.error(READONLY_ERROR);
+            return false;
+        }
+        true
+    }
+
+    /// `<C-w>` and `<C-u>` in insert mode: delete the word before the cursor, or everything
+    /// before it on the line. At the start of a line, join it to the previous one.
+    pub async fn delete_before_cursor(&mut self, to_line_start: bool) {
+        if !self.check_modifiable().await {
+            return;
+        }
+        let Some(win) = self.get_active_window() else {
+            return;
+        };
+        let mut win = win.lock().await;
+        let cursor = win.get_render_cursor().await;
+        let buf = win.get_buffer();
+        let mut buf = buf.lock().await;
+
+        if cursor.x == 0 {
+            let Some(len) = cursor.y.checked_sub(1).and_then(|y| buf.get_line_length(y)) else {
+                return;
+            };
+            buf.join_lines(cursor.y - 1);
+            drop(buf);
+            win.move_by(IVec2::up()).await;
+            win.move_to_x(len).await;
+            return;
+        }
+
+        let line: Vec<char> = buf.get_line(cursor.y).unwrap_or_default().chars().collect();
+        let before = &line[..cursor.x];
+        let start = if to_line_start {
+            0
+        } else {
+            // Spaces, then a word or a run of other characters
+            let end = before
+                .iter()
+                .rposition(|ch| !ch.is_whitespace())
+                .map_or(0, |x| x + 1);
+            let is_word = is_word_char(before.get(end.wrapping_sub(1)).copied().unwrap_or(' '));
+            before[..end]
+                .iter()
+                .rposition(|&ch| ch.is_whitespace() || is_word_char(ch) != is_word)
+                .map_or(0, |x| x + 1)
+        };
+        buf.replace_line(
+            cursor.y,
+            line[..start].iter().chain(&line[cursor.x..]).collect(),
+        );
+        drop(buf);
+        win.move_to_x(start).await;
+    }
+
+    /// Open the help in its own window, jumping to `topic` if given
+    pub async fn open_help(
+        &mut self,
+        topic: Option<&str>,
+        keymaps: &[(&str, &Keymap)],
+    ) -> anyhow::Result<()> {
+        let lines = help_lines(keymaps, &self.command_map);
+        let y = match topic {
+            Some(topic) => Some(
+                find_topic(&lines, topic, &self.command_map)
+                    .ok_or_else(|| anyhow!("E149: Sorry, no help for {}", topic))?,
+            ),
+            None => None,
+        };
+
+        let (id, win) = self.open_read_only_window(self.help_window, lines).await;
+        self.help_window = Some(id);
+
+        let mut win = win.lock().await;
+        win.move_to_y(y.unwrap_or(0)).await;
+        win.move_to_line_start();
+        win.sync_scroll();
+        Ok(())
+    }
+
+    /// Record an undo state for the active buffer, unless a change is still being typed
+    pub async fn checkpoint(&self) {
+        if matches!(*self.mode.lock().await, Mode::Insert(_)) {
+            return;
+        }
+        if let Some(win) = self.get_active_window() {
+            let buf = win.lock().await.get_buffer();
+            buf.lock().await.checkpoint();
+        }
+    }
+
+    /// `:earlier` and `:later` on the active buffer
+    pub async fn time_travel(&mut self, amount: UndoAmount, forward: bool) {
+        let Some(win) = self.get_active_window() else {
+            return;
+        };
+        let mut win = win.lock().await;
+        let moved = win.get_buffer().lock().await.time_travel(amount, forward);
+        if !moved {
+            self.messages.info(if forward {
+                "Already at newest change"
+            } else {
+                "Already at oldest change"
+            });
+            return;
+        }
+
+        let y = win.get_cursor().y;
+        win.move_to_y(y).await;
+    }
+
+    /// `:messages`: show the message history, newest at the bottom
+    pub async fn open_messages(&mut self) {
+        let lines: Vec<String> = self
+            .messages
+            .get_history()
+            .map(|entry| entry.format())
+            .collect();
+        let lines = if lines.is_empty() {
+            vec![String::new()]
+        } else {
+            lines
+        };
+
+        let (id, win) = self
+            .open_read_only_window(self.messages_window, lines)
+            .await;
+        self.messages_window = Some(id);
+
+        let mut win = win.lock().await;
+        win.move_to_buffer_end().await;
+        win.move_to_line_start();
+        win.sync_scroll();
+    }
+
+    /// Switch to a read-only window, refreshing its lines, or open a new one
+    async fn open_read_only_window(
+        &mut self,
+        existing: Option<WindowId>,
+        lines: Vec<String>,
+    ) -> (WindowId, Arc<Mutex<Window>>) {
+        if let Some(id) = existing
+            && let Some(win) = self.window_manager.get_window(id)
+        {
+            win.lock()
+                .await
+                .get_buffer()
+                .lock()
+                .await
+                .replace_all_lines(lines);
+            self.focus_window(id).await;
+            return (id, win);
+        }
+
+        let (buf_id, buf) = self.buffer_manager.open_buffer(Buffer::read_only(lines));
+        let (id, win) = self.window_manager.open_window(Window::new(
+            buf_id,
+            buf,
+            self.mode.clone(),
+            get_window_size(self.term_size),
+        ));
+        self.focus_window(id).await;
+        (id, win)
+    }
+
+    /// Split the active window to show a buffer in another one, and focus it
+    pub async fn open_window(
+        &mut self,
+        buf_id: BufferId,
+        direction: SplitDirection,
+    ) -> Option<WindowId> {
+        let buf = self.buffer_manager.get_buffer(buf_id)?;
+        let (id, _) = self.window_manager.open_window(Window::new(
+            buf_id,
+            buf,
+            self.mode.clone(),
+            get_window_size(self.term_size),
+        ));
+        self.layout.split(self.active_window, id, direction);
+        self.focus_window(id).await;
+        Some(id)
+    }
+
+    /// Show every window, split in `direction`, such as for the files given with `-o`
+    pub async fn split_windows(&mut self, direction: SplitDirection) {
+        let windows = self.window_manager.get_window_ids();
+        self.layout = match windows.as_slice() {
+            [id] => Layout::Window(*id),
+            _ => Layout::Split(direction, windows.into_iter().map(Layout::Window).collect()),
+        };
+        self.arrange_windows().await;
+    }
+
+    /// Make a window active, showing it in place of the active one if it is hidden
+    async fn focus_window(&mut self, id: WindowId) {
+        if id == self.active_window {
+            return;
+        }
+        if !self.layout.contains(id) && !self.layout.replace(self.active_window, id) {
+            self.layout = Layout::Window(id);
+        }
+        self.previous_window = Some(self.active_window);
+        self.active_window = id;
+        self.arrange_windows().await;
+    }
+
+    /// The windows on the screen, with where they are, and the borders between them
+    pub fn get_layout(&self) -> (Vec<Placement>, Vec<Border>) {
+        self.layout
+            .arrange(UVec2::default(), get_window_size(self.term_size))
+    }
+
+    /// Fit the windows on the screen into their part of it
+    async fn arrange_windows(&mut self) {
+        let (placements, _) = self.get_layout();
+        for placement in placements {
+            if let Some(win) = self.window_manager.get_window(placement.id) {
+                let mut win = win.lock().await;
+                win.set_position(placement.position);
+                win.set_size(placement.size);
+                win.sync_scroll();
+            }
+        }
+    }
+
+    /// `:bnext` and `:bprevious`: make the window of the next or previous file active,
+    /// wrapping around. Each file given on the command line has a window of its own.
+    pub async fn next_buffer(&mut self, forward: bool) {
+        let ids: Vec<WindowId> = self
+            .window_manager
+            .get_window_ids()
+            .into_iter()
+            .filter(|id| Some(*id) != self.help_window && Some(*id) != self.messages_window)
+            .collect();
+        let next = match ids.iter().position(|id| *id == self.active_window) {
+            Some(index) if forward => ids.get(index + 1).or(ids.first()),
+            Some(index) => index.checked_sub(1).and_then(|i| ids.get(i)).or(ids.last()),
+            // From the help or the messages
+            None if forward => ids.first(),
+            None => ids.last(),
+        };
+        if let Some(&next) = next {
+            self.focus_window(next).await;
+        }
+    }
+
+    /// Lay the windows out for a terminal of the new size. Hidden windows fill it, until
+    /// they are shown in place of another.
+    pub async fn resize(&mut self, term_size: UVec2) {
+        self.term_size = term_size;
+        for id in self.window_manager.get_window_ids() {
+            if let Some(win) = self.window_manager.get_window(id) {
+                let mut win = win.lock().await;
+                win.set_size(get_window_size(term_size));
+                win.sync_scroll();
+            }
+        }
+        self.arrange_windows().await;
+    }
+
+    /// Clamp the cursors of the windows on a buffer, `None` meaning the active one
+    pub async fn clamp_cursors(&self, buf: Option<BufferId>) {
+        let buf = match buf {
+            Some(buf) => buf,
+            None => match self.get_active_window() {
+                Some(win) => win.lock().await.get_buffer_id(),
+                None => return,
+            },
+        };
+        for id in self.window_manager.get_window_ids() {
+            let Some(win) = self.window_manager.get_window(id) else {
+                continue;
+            };
+            let mut win = win.lock().await;
+            if win.get_buffer_id() == buf {
+                win.clamp_to_buffer().await;
+            }
+        }
+    }
+
+    /// A hash of the lines of every buffer, the active window and its cursor, for checking
+    /// that a replayed session ends where it did when it was recorded. It does not change
+    /// between runs or builds, so it can be compared with one printed elsewhere.
+    pub async fn get_state_hash(&self) -> u64 {
+        let mut hasher = Fnv1a::default();
+        for id in self.buffer_manager.get_buffer_ids() {
+            if let Some(buf) = self.buffer_manager.get_buffer(id) {
+                let lines = buf.lock().await.get_all_lines();
+                hasher.write_usize(lines.len());
+                for line in lines {
+                    hasher.write_usize(line.len());
+                    hasher.write(line.as_bytes());
+                }
+            }
+        }
+        hasher.write_usize(self.active_window.0);
+        if let Some(win) = self.get_active_window() {
+            let cursor = win.lock().await.get_cursor();
+            hasher.write_usize(cursor.x);
+            hasher.write_usize(cursor.y);
+        }
+        hasher.finish()
+    }
+
+    /// Name of a buffer with unsaved changes, if there is one
+    pub async fn get_modified_buffer(&self) -> Option<String> {
+        for id in self.buffer_manager.get_buffer_ids() {
+            let buf = self.buffer_manager.get_buffer(id)?;
+            let buf = buf.lock().await;
+            if buf.is_dirty() {
+                return Some(buf.get_name());
+            }
+        }
+        None
+    }
+
+    /// Close the active window. Returns false if it is the last one.
+    pub async fn close_window(&mut self) -> bool {
+        self.close_window_by_id(self.active_window).await
+    }
+
+    /// Close a window, moving the focus away if it is the active one.
+    /// Returns false if it is the last one.
+    pub async fn close_window_by_id(&mut self, closing: WindowId) -> bool {
+        let remaining: Vec<WindowId> = self
+            .window_manager
+            .get_window_ids()
+            .into_iter()
+            .filter(|id| *id != closing)
+            .collect();
+        let Some(first) = remaining.first() else {
+            return false;
+        };
+
+        if self.help_window == Some(closing) || self.messages_window == Some(closing) {
+            if let Some(win) = self.window_manager.get_window(closing) {
+                let buf_id = win.lock().await.get_buffer_id();
+                self.buffer_manager.close_buffer(buf_id);
+            }
+            if self.help_window == Some(closing) {
+                self.help_window = None;
+            } else {
+                self.messages_window = None;
+            }
+        }
+        self.window_manager.close_window(closing);
+
+        // The others in its split take its space, or a hidden window does if it was alone
+        let split = self.layout.remove(closing);
+        if self.active_window == closing {
+            let next = match self.previous_window.take() {
+                Some(id) if remaining.contains(&id) && (!split || self.layout.contains(id)) => id,
+                _ if split => self.layout.get_window_ids()[0],
+                _ => *first,
+            };
+            if !split {
+                self.layout = Layout::Window(next);
+            }
+            self.active_window = next;
+        } else if self.previous_window == Some(closing) {
+            self.previous_window = None;
+        }
+        self.arrange_windows().await;
+        true
+    }
+
+    /// Open the command line to type a search pattern
+    pub async fn start_search(&mut self) {
+        self.set_mode(Mode::Command).await;
+        self.command_buf = String::new();
+        self.command_prompt = '/';
+    }
+
+    /// Search for a pattern and jump to the next match. An empty pattern repeats the last search.
+    pub async fn search(&mut self, pattern: &str) -> anyhow::Result<()> {
+        if !pattern.is_empty() {
+            self.last_search = Some(pattern.to_string());
+        }
+        self.search_next(true).await
+    }
+
+    /// `n` and `N`: jump to the next or previous match of the last search
+    pub async fn search_next(&mut self, forward: bool) -> anyhow::Result<()> {
+        let Some(pattern) = self.last_search.clone() else {
+            bail!("E35: No previous regular expression");
+        };
+        self.hlsearch = true;
+
+        let Some(win) = self.get_active_window() else {
+            return Ok(());
+        };
+        let mut win = win.lock().await;
+        let lines = win.get_buffer().lock().await.get_all_lines();
+        let Some(pos) = find_next(&lines, &pattern, win.get_cursor(), forward) else {
+            bail!("E486: Pattern not found: {}", pattern);
+        };
+
+        win.move_to_y(pos.y).await;
+        win.move_to_x(pos.x).await;
+        win.sync_scroll();
+        Ok(())
+    }
+
+    /// `:noh`: hide search highlights without forgetting the pattern
+    pub fn no_highlight(&mut self) {
+        self.hlsearch = false;
+        self.highlights.clear(HighlightSource::Search);
+    }
+
+    /// Get the highlights to draw, with search matches and diagnostics computed for the lines
+    /// the active window shows, so a frame costs the same however long the buffer is
+    pub async fn get_highlights(&self) -> Vec<Highlight> {
+        let mut highlights = self.highlights.get_all().to_vec();
+        if let Some(win) = self.get_active_window() {
+            highlights.extend(self.get_window_highlights(&*win.lock().await).await);
+        }
+        highlights
+    }
+
+    /// The search matches and diagnostics on the visible lines of a window
+    pub async fn get_window_highlights(&self, win: &Window) -> Vec<Highlight> {
+        let mut highlights = Vec::new();
+        let (scroll, height) = (win.get_scroll(), win.get_size().y);
+        let buf = win.get_buffer();
+        let buf = buf.lock().await;
+
+        if self.hlsearch
+            && let Some(pattern) = &self.last_search
+        {
+            // A match shown at the top may start on the lines above
+            let first = scroll.saturating_sub(pattern.matches('\n').count());
+            let lines = buf.get_lines_range(first, scroll + height - first);
+            highlights.extend(find_matches(lines, first, pattern));
+        }
+
+        highlights.extend(diagnostic_highlights(
+            buf.get_lines_range(scroll, height),
+            scroll,
+            buf.get_diagnostics(),
+        ));
+
+        highlights
+    }
+
+    /// `:llist`: show the diagnostics of the active buffer in a popup, in position order
+    pub async fn show_location_list(&mut self) {
+        let Some(win) = self.get_active_window() else {
+            return;
+        };
+        let buf = win.lock().await.get_buffer();
+        let buf = buf.lock().await;
+        let diagnostics = buf.get_location_list();
+        if diagnostics.is_empty() {
+            self.messages.info("No diagnostics".to_string());
+            return;
+        }
+
+        let lines = diagnostics
+            .into_iter()
+            .map(|diagnostic| {
+                format!(
+                    "{}:{} {}: {}",
+                    diagnostic.range.start.y + 1,
+                    diagnostic.range.start.x + 1,
+                    diagnostic.severity,
+                    diagnostic.message.replace('\n', " ")
+                )
+            })
+            .collect();
+        self.open_text_popup(format!("diagnostics: {}", buf.get_name()), lines);
+    }
+
+    /// `:ll`, `:lnext` and `:lprevious`: move the cursor to a diagnostic of the active
+    /// buffer and show its message
+    pub async fn jump_to_location(&mut self, jump: LocationJump) -> anyhow::Result<()> {
+        let Some(win) = self.get_active_window() else {
+            return Ok(());
+        };
+        let mut win = win.lock().await;
+        let diagnostics = win.get_buffer().lock().await.get_location_list();
+        if diagnostics.is_empty() {
+            bail!("E42: No Errors");
+        }
+
+        let cursor = win.get_cursor();
+        let cursor = (cursor.y, cursor.x);
+        let start = |diagnostic: &Diagnostic| (diagnostic.range.start.y, diagnostic.range.start.x);
+        let index = match jump {
+            LocationJump::Nth(index) => Some(index.min(diagnostics.len() - 1)),
+            LocationJump::Next(true) => diagnostics.iter().position(|d| start(d) > cursor),
+            LocationJump::Next(false) => diagnostics.iter().rposition(|d| start(d) < cursor),
+        };
+        let Some(index) = index else {
+            bail!("E553: No more items");
+        };
+
+        let diagnostic = &diagnostics[index];
+        win.move_to_y(diagnostic.range.start.y).await;
+        win.move_to_x(diagnostic.range.start.x).await;
+        win.sync_scroll();
+        self.messages.info(format!(
+            "({} of {}) {}: {}",
+            index + 1,
+            diagnostics.len(),
+            diagnostic.severity,
+            diagnostic.message.replace('\n', " ")
+        ));
+        Ok(())
+    }
+
+    /// Show text in a popup below the cursor of `win`, replacing the previous one
+    pub async fn show_hover(&mut self, win: &Mutex<Window>, text: &str) -> PopupId {
+        self.close_hover();
+
+        let win = win.lock().await;
+        let cursor = win.get_render_cursor().await;
+        let lines: Vec<String> = text
+            .lines()
+            .map(|line| line.replace('\t', "    "))
+            .collect();
+        let width = lines
+            .iter()
+            .map(|line| line.chars().count())
+            .max()
+            .unwrap_or(0);
+        let id = self.popup_manager.open_popup(Popup {
+            size: UVec2::new(width + 2, lines.len() + 2),
+            lines,
+            position: win.get_position()
+                + UVec2::new(cursor.x, cursor.y.saturating_sub(win.get_scroll()) + 1),
+            border: true,
+            title: None,
+            dismissable: true,
+        });
+        self.hover = Some(id);
+        id
+    }
+
+    /// Close the hover popup, returning false if there was none
+    pub fn close_hover(&mut self) -> bool {
+        match self.hover.take() {
+            Some(id) => self.popup_manager.close_popup(id),
+            None => false,
+        }
+    }
+
+    /// List the keys that can follow a pending sequence above the status line, with
+    /// what they do
+    pub fn show_key_hint(&mut self, prefix: &Key, continuations: Vec<(Key, String)>) {
+        self.close_key_hint();
+
+        let keys: Vec<String> = continuations
+            .iter()
+            .map(|(keys, _)| keys.iter().map(|code| code.to_string()).collect())
+            .collect();
+        let key_width = keys.iter().map(|keys| keys.chars().count()).max();
+        let lines: Vec<String> = keys
+            .iter()
+            .zip(&continuations)
+            .map(|(keys, (_, description))| {
+                format!(
+                    "{:width$}  {}",
+                    keys,
+                    description,
+                    width = key_width.unwrap_or(0)
+                )
+            })
+            .collect();
+        let width = lines
+            .iter()
+            .map(|line| line.chars().count())
+            .max()
+            .unwrap_or(0);
+        let size = UVec2::new(
+            (width + 2).min(self.term_size.x),
+            (lines.len() + 2).min(self.term_size.y.saturating_sub(1)),
+        );
+        let title = prefix.iter().map(|code| code.to_string()).collect();
+        self.key_hint = Some(self.popup_manager.open_popup(Popup {
+            lines,
+            position: UVec2::new(
+                self.term_size.x - size.x,
+                self.term_size.y.saturating_sub(1) - size.y,
+            ),
+            size,
+            border: true,
+            title: Some(title),
+            dismissable: false,
+        }));
+    }
+
+    /// Close the popup of `show_key_hint`, returning false if there was none
+    pub fn close_key_hint(&mut self) -> bool {
+        match self.key_hint.take() {
+            Some(id) => self.popup_manager.close_popup(id),
+            None => false,
+        }
+    }
+
+    /// Show the output of `:!{cmd}` in a popup, closed with Esc
+    pub fn show_shell_output(&mut self, command: &str, output: ShellOutput) {
+        let status = match output.code {
+            Some(code) => format!("exit {}", code),
+            None => "killed".to_string(),
+        };
+
+        let lines: Vec<String> = output
+            .stdout
+            .lines()
+            .chain(output.stderr.lines())
+            .map(|line| line.replace('\t', "    "))
+            .collect();
+
+        if lines.is_empty() {
+            self.messages.info(format!("!{} ({})", command, status));
+            return;
+        }
+
+        self.open_text_popup(format!("!{} ({})", command, status), lines);
+        if output.success() {
+            self.messages.info(format!("!{} ({})", command, status));
+        } else {
+            self.messages.error(format!("!{} ({})", command, status));
+        }
+    }
+
+    /// Show the state of each plugin for `:plugin list`
+    pub fn show_plugin_list(&mut self, plugins: Vec<(String, PluginInfo)>) {
+        if plugins.is_empty() {
+            self.messages.info("No plugins loaded".to_string());
+            return;
+        }
+
+        let width = plugins
+            .iter()
+            .map(|(name, _)| name.len())
+            .max()
+            .unwrap_or(0);
+        let lines = plugins
+            .into_iter()
+            .map(|(name, info)| {
+                let version = match info.protocol_version {
+                    Some(version) => format!("protocol {}", version),
+                    None => "protocol -".to_string(),
+                };
+                format!(
+                    "{:width$}  {:10}  {:8}  {} requests",
+                    name, version, info.status, info.request_count
+                )
+            })
+            .collect();
+        self.open_text_popup("plugins".to_string(), lines);
+    }
+
+    /// Open a bordered popup sized to its lines
+    fn open_text_popup(&mut self, title: String, lines: Vec<String>) {
+        let term_size = self.term_size;
+        let width = lines
+            .iter()
+            .map(|line| line.chars().count())
+            .max()
+            .unwrap_or(0);
+        self.popup_manager.open_popup(Popup {
+            size: UVec2::new(
+                (width + 2).min(term_size.x.saturating_sub(2)),
+                (lines.len() + 2).min(term_size.y.saturating_sub(3)),
+            ),
+            lines,
+            position: UVec2::new(1, 1),
+            border: true,
+            title: Some(title),
+            dismissable: true,
+        });
+    }
+
+    /// Insert lines before line `at`, moving the cursor to the first inserted line
+    pub async fn insert_lines(&mut self, at: usize, lines: Vec<String>) {
+        if !self.check_modifiable().await {
+            return;
+        }
+
+        let Some(win) = self.get_active_window() else {
+            return;
+        };
+        let mut win = win.lock().await;
+        {
+            let buf = win.get_buffer();
+            let mut buf = buf.lock().await;
+            let at = at.min(buf.get_line_count());
+            for (i, line) in lines.into_iter().enumerate() {
+                buf.insert_line(at + i, line);
+            }
+        }
+
+        win.move_to_y(at).await;
+    }
+
+    /// `:r {file}`
+    pub async fn read_file(&mut self, at: usize, path: &str) {
+        match std::fs::read_to_string(path) {
+            Ok(content) => {
+                let lines = content.lines().map(|line| line.to_string()).collect();
+                self.insert_lines(at, lines).await;
+            }
+            Err(err) => self
+                .messages
+                .error(format!("E484: Can't open file {}: {}", path, err)),
+        }
+    }
+
+    /// `:r !{cmd}`, leaving the buffer untouched if the command fails
+    pub async fn read_shell_output(&mut self, at: usize, output: anyhow::Result<ShellOutput>) {
+        match output {
+            Ok(output) if output.success() => {
+                let lines = output.stdout.lines().map(|line| line.to_string()).collect();
+                self.insert_lines(at, lines).await;
+            }
+            Ok(output) => self.messages.error(format!(
+                "shell returned {}: {}",
+                output.code.unwrap_or(-1),
+                output.stderr.trim()
+            )),
+            Err(err) => self.messages.error(err.to_string()),
+        }
+    }
+
+    /// Copy an inclusive range of lines into the unnamed register
+    pub async fn yank_lines(&mut self, start: usize, end: usize) {
+        let lines = self.get_line_range(start, end).await;
+        if !lines.is_empty() {
+            self.registers.set(
+                UNNAMED_REGISTER,
+                Register {
+                    text: lines.join("\n"),
+                    linewise: true,
+                },
+            );
+        }
+    }
+
+    /// `p` and `P`: put the unnamed register after or before the cursor
+    pub async fn paste(&mut self, after: bool) {
+        let Some(register) = self.registers.get(UNNAMED_REGISTER).cloned() else {
+            return;
+        };
+        if !self.check_modifiable().await {
+            return;
+        }
+
+        let Some(win) = self.get_active_window() else {
+            return;
+        };
+        let mut win = win.lock().await;
+        let cursor = win.get_render_cursor().await;
+        let lines: Vec<String> = register.text.split('\n').map(str::to_string).collect();
+
+        if register.linewise {
+            let at = if after { cursor.y + 1 } else { cursor.y };
+            {
+                let buf = win.get_buffer();
+                let mut buf = buf.lock().await;
+                let at = at.min(buf.get_line_count());
+                for (i, line) in lines.into_iter().enumerate() {
+                    buf.insert_line(at + i, line);
+                }
+            }
+            win.move_to_y(at).await;
+            win.move_to_x(0).await;
+            return;
+        }
+
+        let end = {
+            let buf = win.get_buffer();
+            let mut buf = buf.lock().await;
+            let Some(line) = buf.get_line(cursor.y) else {
+                return;
+            };
+            let chars: Vec<char> = line.chars().collect();
+            let x = if after && !chars.is_empty() {
+                cursor.x + 1
+            } else {
+                cursor.x
+            }
+            .min(chars.len());
+            let head: String = chars[..x].iter().collect();
+            let tail: String = chars[x..].iter().collect();
+
+            let last = lines.len() - 1;
+            let mut new_lines = lines;
+            let end_x = new_lines[last].chars().count() + if last == 0 { x } else { 0 };
+            new_lines[0] = head + new_lines[0].as_str();
+            new_lines[last].push_str(&tail);
+
+            let mut new_lines = new_lines.into_iter();
+            buf.replace_line(cursor.y, new_lines.next().unwrap_or_default());
+            for (i, line) in new_lines.enumerate() {
+                buf.insert_line(cursor.y + 1 + i, line);
+            }
+            UVec2::new(end_x.saturating_sub(1), cursor.y + last)
+        };
+        win.move_to_y(end.y).await;
+        win.move_to_x(end.x).await;
+    }
+
+    /// Get an inclusive range of lines of the active buffer
+    pub async fn get_line_range(&self, start: usize, end: usize) -> Vec<String> {
+        let Some(win) = self.get_active_window() else {
+            return Vec::new();
+        };
+        let buf = win.lock().await.get_buffer();
+        let buf = buf.lock().await;
+
+        let lines = buf.get_all_lines();
+        let end = end.min(lines.len().saturating_sub(1));
+        lines
+            .get(start..=end)
+            .map(|lines| lines.to_vec())
+            .unwrap_or_default()
+    }
+
+    /// Replace an inclusive range of lines with the output of a filter command.
+    /// The buffer is left untouched if the command fails.
+    pub async fn filter_lines(
+        &mut self,
+        start: usize,
+        end: usize,
+        output: anyhow::Result<ShellOutput>,
+    ) {
+        if !self.check_modifiable().await {
+            return;
+        }
+
+        let output = match output {
+            Ok(output) if output.success() => output,
+            Ok(output) => {
+                self.messages.error(format!(
+                    "shell returned {}: {}",
+                    output.code.unwrap_or(-1),
+                    output.stderr.trim()
+                ));
+                return;
+            }
+            Err(err) => {
+                self.messages.error(err.to_string());
+                return;
+            }
+        };
+
+        let lines = output.stdout.lines().map(|line| line.to_string()).collect();
+        self.replace_line_range(start, end, lines).await;
+    }
+
+    /// `:[range]sort`
+    pub async fn sort_lines(&mut self, start: usize, end: usize, options: SortOptions) {
+        if !self.check_modifiable().await {
+            return;
+        }
+
+        let lines = self.get_line_range(start, end).await;
+        self.replace_line_range(start, end, sort_lines(lines, options))
+            .await;
+    }
+
+    /// `:s`: replace a pattern in a range of lines, moving the cursor to the last changed line
+    pub async fn substitute(
+        &mut self,
+        start: usize,
+        end: usize,
+        pattern: &str,
+        replacement: &str,
+        all: bool,
+    ) -> anyhow::Result<()> {
+        let pattern = if pattern.is_empty() {
+            self.last_search
+                .clone()
+                .ok_or_else(|| anyhow!("E35: No previous regular expression"))?
+        } else {
+            pattern.to_string()
+        };
+        if !self.check_modifiable().await {
+            return Ok(());
+        }
+
+        let Some(win) = self.get_active_window() else {
+            return Ok(());
+        };
+        let mut win = win.lock().await;
+        let mut last_changed = None;
+        {
+            let buf = win.get_buffer();
+            let mut buf = buf.lock().await;
+            for y in start..=end {
+                let Some(line) = buf.get_line(y) else {
+                    break;
+                };
+                if !line.contains(&pattern) {
+                    continue;
+                }
+
+                let line = if all {
+                    line.replace(&pattern, replacement)
+                } else {
+                    line.replacen(&pattern, replacement, 1)
+                };
+                buf.replace_line(y, line);
+                last_changed = Some(y);
+            }
+        }
+        self.last_search = Some(pattern.clone());
+
+        let Some(y) = last_changed else {
+            bail!("E486: Pattern not found: {}", pattern);
+        };
+        win.move_to_y(y).await;
+        win.move_to_line_start();
+        Ok(())
+    }
+
+    /// `:g` and `:v`. Matching lines are marked first, then the command runs on each,
+    /// following them as earlier commands add or remove lines.
+    pub async fn global(
+        &mut self,
+        start: usize,
+        end: usize,
+        pattern: &str,
+        invert: bool,
+        command: &str,
+    ) -> anyhow::Result<()> {
+        let pattern = if pattern.is_empty() {
+            self.last_search
+                .clone()
+                .ok_or_else(|| anyhow!("E35: No previous regular expression"))?
+        } else {
+            pattern.to_string()
+        };
+        let marked: Vec<usize> = self
+            .get_line_range(start, end)
+            .await
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| line.contains(&pattern) != invert)
+            .map(|(i, _)| start + i)
+            .collect();
+        self.last_search = Some(pattern);
+
+        if command.is_empty() {
+            self.messages
+                .info(format!("{} matching lines", marked.len()));
+            return Ok(());
+        }
+
+        let mut delta: isize = 0;
+        for y in marked {
+            let ctx = self.get_command_context().await;
+            let y = y as isize + delta;
+            if y < 0 || y as usize >= ctx.line_count {
+                continue;
+            }
+
+            let ctx = CommandContext {
+                current_line: y as usize,
+                ..ctx
+            };
+            if let Some(action) = self.command_map.dispatch(command, &ctx)? {
+                self.run_line_action(action).await?;
+            }
+            delta += self.get_command_context().await.line_count as isize - ctx.line_count as isize;
+        }
+        Ok(())
+    }
+
+    /// Run an action produced by a command inside `:g`
+    async fn run_line_action(&mut self, action: Action) -> anyhow::Result<()> {
+        match action {
+            Action::DeleteLines(start, end) => self.delete_lines(start, end).await,
+            Action::YankLines(start, end) => self.yank_lines(start, end).await,
+            Action::Sort(start, end, options) => self.sort_lines(start, end, options).await,
+            Action::Substitute {
+                start,
+                end,
+                pattern,
+                replacement,
+                all,
+            } => {
+                // Lines without a match are skipped silently inside `:g`
+                self.substitute(start, end, &pattern, &replacement, all)
+                    .await
+                    .ok();
+            }
+            _ => bail!("E478: Command not supported in :global"),
+        }
+        Ok(())
+    }
+
+    /// Replace an inclusive range of lines, moving the cursor to the first new line
+    async fn replace_line_range(&mut self, start: usize, end: usize, lines: Vec<String>) {
+        let Some(win) = self.get_active_window() else {
+            return;
+        };
+        let mut win = win.lock().await;
+        {
+            let buf = win.get_buffer();
+            let mut buf = buf.lock().await;
+            // The new lines go in first, so removing the old ones never empties the buffer
+            let count = lines.len();
+            for (i, line) in lines.into_iter().enumerate() {
+                buf.insert_line(start + i, line);
+            }
+            for _ in start..=end {
+                if buf.remove_line(start + count).is_none() {
+                    break;
+                }
+            }
+        }
+
+        win.move_to_y(start).await;
+    }
+
+    /// Delete an inclusive range of lines, keeping them in the unnamed register
+    pub async fn delete_lines(&mut self, start: usize, end: usize) {
+        if !self.check_modifiable().await {
+            return;
+        }
+
+        self.yank_lines(start, end).await;
+
+        let Some(win) = self.get_active_window() else {
+            return;
+        };
+        let mut win = win.lock().await;
+        {
+            let buf = win.get_buffer();
+            let mut buf = buf.lock().await;
+            for _ in start..=end {
+                if buf.remove_line(start).is_none() {
+                    break;
+                }
+            }
+        }
+
+        win.move_to_y(start).await;
+    }
+
+    /// Complete the command name, cycling through the matches on repeated Tab
+    fn complete_command(&mut self) {
+        if let Some(completion) = &mut self.command_completion {
+            completion.next();
+        } else {
+            let mut names = self.command_map.names();
+            names.extend(self.plugin_commands.keys().cloned());
+            names.sort();
+            self.command_completion = CommandCompletion::new(&self.command_buf, &names);
+        }
+
+        if let Some(completion) = &self.command_completion {
+            self.command_buf = completion.get_text();
+        }
+    }
+
+    /// Run a command line, first against the built-in commands and then plugin commands
+    pub async fn run_command(&mut self, input: &str) -> anyhow::Result<Option<Action>> {
+        let ctx = self.get_command_context().await;
+        let command = parse_command(input)?;
+
+        if self.command_map.resolve(&command.name)?.is_some() {
+            return self.command_map.dispatch(input, &ctx);
+        }
+
+        if let Some(plugin) = self.plugin_commands.get(&command.name) {
+            let range = match command.range {
+                Some(_) => Some(ctx.resolve_range(command.range)?),
+                None => None,
+            };
+            plugin
+                .send(CuprumNotification::RunCommand {
+                    name: command.name,
+                    bang: command.bang,
+                    range,
+                    args: command.args,
+                })
+                .await;
+            return Ok(None);
+        }
+
+        bail!("E492: Not an editor command: {}", command.name)
+    }
+
+    /// Text inserted by `Ctrl-r {key}` in the command line, cut at the first newline
+    async fn get_command_register(&self, key_code: KeyCode) -> Option<String> {
+        let text = match key_code {
+            KeyCode::Char('%') => {
+                let win = self.get_active_window()?;
+                let buf = win.lock().await.get_buffer();
+                let buf = buf.lock().await;
+                buf.get_path()?.to_string_lossy().to_string()
+            }
+            KeyCode::Ctrl('w') => self.get_cursor_word().await?,
+            KeyCode::Char(name) => self.registers.get(name)?.text.clone(),
+            _ => return None,
+        };
+        Some(text.split('\n').next().unwrap_or_default().to_string())
+    }
+
+    /// The keyword under or after the cursor on the current line
+    async fn get_cursor_word(&self) -> Option<String> {
+        let win = self.get_active_window()?;
+        let win = win.lock().await;
+        let cursor = win.get_cursor();
+        let line = win.get_buffer().lock().await.get_line(cursor.y)?;
+
+        let is_word = |ch: &char| ch.is_alphanumeric() || *ch == '_';
+        let chars: Vec<char> = line.chars().collect();
+        let start = (cursor.x.min(chars.len())..chars.len()).find(|&x| is_word(&chars[x]))?;
+        let start = (0..start)
+            .rev()
+            .find(|&x| !is_word(&chars[x]))
+            .map_or(0, |x| x + 1);
+        Some(chars[start..].iter().take_while(|ch| is_word(ch)).collect())
+    }
+
+    pub async fn process_command(&mut self, key_code: KeyCode) -> anyhow::Result<Option<Action>> {
+        if self.register_pending {
+            self.register_pending = false;
+            if let Some(text) = self.get_command_register(key_code).await {
+                self.command_buf.push_str(&text);
+            }
+            return Ok(None);
+        }
+
+        if key_code == KeyCode::Char('\t')
+            && self.command_prompt == ':'
+            && self.input_prompt.is_none()
+        {
+            self.complete_command();
+            return Ok(None);
+        }
+        self.command_completion = None;
+
+        match key_code {
+            KeyCode::Esc => {
+                self.command_buf = String::new();
+                self.set_command_to_normal_mode().await;
+            }
+            KeyCode::Backspace => {
+                if self.command_buf.is_empty() {
+                    self.set_command_to_normal_mode().await;
+                } else {
+                    self.command_buf.pop();
+                }
+            }
+            KeyCode::Char('\n') => {
+                let input = self.command_buf.clone();
+                if let Some((_, answer)) = self.input_prompt.take() {
+                    answer.send(Some(input)).ok();
+                    self.set_command_to_normal_mode().await;
+                    return Ok(None);
+                }
+
+                let prompt = self.command_prompt;
+                self.set_command_to_normal_mode().await;
+                let result = if prompt == '/' {
+                    self.search(&input).await.map(|_| None)
+                } else {
+                    self.run_command(&input).await
+                };
+                match result {
+                    Ok(action) => return Ok(action),
+                    Err(err) => self.messages.error(err.to_string()),
+                }
+            }
+            KeyCode::Ctrl('r') => self.register_pending = true,
+            KeyCode::Char(ch) => self.command_buf.push(ch),
+            _ => {}
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use api::{BufferId, Position};
+    use builtin::BuiltinAction;
+
+    use super::*;
+    use crate::messages::{Message, MessageLevel};
+    #[cfg(unix)]
+    use crate::shell::{SHELL_TIMEOUT, run_shell};
+
+    async fn state_with_lines(lines: &[&str]) -> EditorState {
+        let state = EditorState::new(Vec::new()).unwrap();
+        let buf = state.buffer_manager.get_buffer(BufferId(0)).unwrap();
+        buf.lock()
+            .await
+            .replace_all_lines(lines.iter().map(|line| line.to_string()).collect());
+        state
+    }
+
+    async fn get_lines(state: &EditorState) -> Vec<String> {
+        let buf = state.buffer_manager.get_buffer(BufferId(0)).unwrap();
+        buf.lock().await.get_all_lines()
+    }
+
+    #[tokio::test]
+    async fn test_delete_lines() {
+        let mut state = state_with_lines(&["a", "b", "c", "d"]).await;
+        state.delete_lines(1, 2).await;
+        assert_eq!(get_lines(&state).await, vec!["a", "d"]);
+        assert_eq!(
+            state.registers.get(UNNAMED_REGISTER),
+            Some(&Register {
+                text: "b\nc".to_string(),
+                linewise: true
+            })
+        );
+
+        state.delete_lines(0, 1).await;
+        assert_eq!(get_lines(&state).await, vec![""]);
+    }
+
+    #[tokio::test]
+    async fn test_read_file() {
+        let path = std::env::temp_dir().join(format!("cuprum-read-{}.txt", std::process::id()));
+        std::fs::write(&path, "x\ny\n").unwrap();
+
+        let mut state = state_with_lines(&["a", "b"]).await;
+        state.read_file(1, path.to_str().unwrap()).await;
+        assert_eq!(get_lines(&state).await, vec!["a", "x", "y", "b"]);
+        std::fs::remove_file(&path).unwrap();
+
+        state.read_file(0, path.to_str().unwrap()).await;
+        assert_eq!(get_lines(&state).await, vec!["a", "x", "y", "b"]);
+        assert_eq!(
+            state.messages.get_current().unwrap().level,
+            MessageLevel::Error
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_read_shell_output() {
+        let mut state = state_with_lines(&["a", "b"]).await;
+        let output = run_shell("echo hello", None, SHELL_TIMEOUT).await;
+        state.read_shell_output(0, output).await;
+        assert_eq!(get_lines(&state).await, vec!["hello", "a", "b"]);
+
+        let output = run_shell("echo oops >&2; exit 1", None, SHELL_TIMEOUT).await;
+        state.read_shell_output(0, output).await;
+        assert_eq!(get_lines(&state).await, vec!["hello", "a", "b"]);
+        assert_eq!(
+            state.messages.get_current().unwrap().text,
+            "shell returned 1: oops"
+        );
+    }
+
+    #[cfg(unix)]
+    async fn filter(state: &mut EditorState, start: usize, end: usize, command: &str) {
+        let mut input = state.get_line_range(start, end).await.join("\n");
+        input.push('\n');
+        let output = run_shell(command, Some(input), SHELL_TIMEOUT).await;
+        state.filter_lines(start, end, output).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_filter_lines() {
+        let mut state = state_with_lines(&["x", "c", "b", "a", "y"]).await;
+        filter(&mut state, 1, 3, "sort").await;
+        assert_eq!(get_lines(&state).await, vec!["x", "a", "b", "c", "y"]);
+
+        filter(&mut state, 0, 4, "tr a-z A-Z").await;
+        assert_eq!(get_lines(&state).await, vec!["X", "A", "B", "C", "Y"]);
+
+        filter(&mut state, 0, 4, "echo failed >&2; exit 2").await;
+        assert_eq!(get_lines(&state).await, vec!["X", "A", "B", "C", "Y"]);
+        assert_eq!(
+            state.messages.get_current().unwrap().text,
+            "shell returned 2: failed"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_command() {
+        let mut state = state_with_lines(&[""]).await;
+        state.set_mode(Mode::Command).await;
+        for ch in "qw\n".chars() {
+            state.process_command(KeyCode::Char(ch)).await.unwrap();
+        }
+        assert!(matches!(*state.mode.lock().await, Mode::Normal));
+        assert_eq!(
+            state.messages.get_current(),
+            Some(Message {
+                level: MessageLevel::Error,
+                text: "E492: Not an editor command: qw".to_string()
+            })
+        );
+
+        state.set_mode(Mode::Command).await;
+        for ch in "1,x\n".chars() {
+            state.process_command(KeyCode::Char(ch)).await.unwrap();
+        }
+        assert_eq!(
+            state.messages.get_current().unwrap().level,
+            MessageLevel::Error
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_and_no_highlight() {
+        let mut state = state_with_lines(&["foo", "bar foo"]).await;
+        state.highlights.add(Highlight {
+            y: 0,
+            range: 0..1,
+            source: HighlightSource::Plugin,
+        });
+
+        state.start_search().await;
+        for ch in "foo\n".chars() {
+            state.process_command(KeyCode::Char(ch)).await.unwrap();
+        }
+        let cursor = state.get_active_window().unwrap().lock().await.get_cursor();
+        assert_eq!(cursor, UVec2::new(4, 1));
+        let search_count = |highlights: &[Highlight]| {
+            highlights
+                .iter()
+                .filter(|highlight| highlight.source == HighlightSource::Search)
+                .count()
+        };
+        assert_eq!(search_count(&state.get_highlights().await), 2);
+
+        state.set_mode(Mode::Command).await;
+        for ch in "noh".chars() {
+            state.process_command(KeyCode::Char(ch)).await.unwrap();
+        }
+        let action = state.process_command(KeyCode::Char('\n')).await.unwrap();
+        assert!(matches!(action, Some(Action::NoHighlight)));
+        state.no_highlight();
+        let highlights = state.get_highlights().await;
+        assert_eq!(search_count(&highlights), 0);
+        assert_eq!(highlights.len(), 1);
+
+        // The pattern is kept for `n`, which shows the highlights again
+        state.search_next(true).await.unwrap();
+        let cursor = state.get_active_window().unwrap().lock().await.get_cursor();
+        assert_eq!(cursor, UVec2::new(0, 0));
+        assert_eq!(search_count(&state.get_highlights().await), 2);
+    }
+
+    #[tokio::test]
+    async fn test_help_window() {
+        let mut state = state_with_lines(&["a"]).await;
+        let normal = Keymap::normal_default();
+        state
+            .open_help(Some("dd"), &[("Normal", &normal)])
+            .await
+            .unwrap();
+        assert_ne!(state.active_window, WindowId(0));
+
+        let win = state.get_active_window().unwrap();
+        let (cursor, buf) = {
+            let win = win.lock().await;
+            (win.get_cursor(), win.get_buffer())
+        };
+        let line = buf.lock().await.get_line(cursor.y).unwrap();
+        assert!(line.contains("delete the line"));
+
+        state.set_mode(Mode::Insert(false)).await;
+        assert!(matches!(*state.mode.lock().await, Mode::Normal));
+        assert_eq!(state.messages.get_current().unwrap().text, READONLY_ERROR);
+
+        assert!(state.close_window().await);
+        assert_eq!(state.active_window, WindowId(0));
+        assert!(!state.close_window().await);
+    }
+
+    #[tokio::test]
+    async fn test_messages_window() {
+        let mut state = state_with_lines(&["a"]).await;
+        state.messages.info("first");
+        state.messages.error("second");
+        state.open_messages().await;
+
+        let lines = {
+            let win = state.get_active_window().unwrap();
+            let buf = win.lock().await.get_buffer();
+            buf.lock().await.get_all_lines()
+        };
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" first"));
+        assert!(lines[1].ends_with(" [error] second"));
+
+        state.delete_lines(0, 0).await;
+        assert_eq!(state.messages.get_current().unwrap().text, READONLY_ERROR);
+
+        // Rerunning refreshes the same window
+        let id = state.active_window;
+        state.open_messages().await;
+        assert_eq!(state.active_window, id);
+        let win = state.get_active_window().unwrap();
+        let buf = win.lock().await.get_buffer();
+        assert_eq!(buf.lock().await.get_line_count(), 3);
+
+        assert!(state.close_window().await);
+        assert_eq!(state.active_window, WindowId(0));
+    }
+
+    #[tokio::test]
+    async fn test_time_travel() {
+        let mut state = state_with_lines(&["a", "b", "c"]).await;
+        state.checkpoint().await;
+        state.delete_lines(2, 2).await;
+        state.checkpoint().await;
+        state.delete_lines(1, 1).await;
+
+        state.time_travel(UndoAmount::Steps(1), false).await;
+        assert_eq!(get_lines(&state).await, vec!["a", "b"]);
+        state.time_travel(UndoAmount::Steps(1), false).await;
+        assert_eq!(get_lines(&state).await, vec!["a", "b", "c"]);
+        // Back to the empty buffer the test started from
+        state
+            .time_travel(UndoAmount::Time(Duration::from_secs(60)), false)
+            .await;
+        assert_eq!(get_lines(&state).await, vec![""]);
+        state.time_travel(UndoAmount::Steps(1), false).await;
+        assert_eq!(
+            state.messages.get_current().unwrap().text,
+            "Already at oldest change"
+        );
+
+        state
+            .time_travel(UndoAmount::Time(Duration::from_secs(60)), true)
+            .await;
+        assert_eq!(get_lines(&state).await, vec!["a"]);
+    }
+
+    #[tokio::test]
+    async fn test_forget_plugin() {
+        let mut state = state_with_lines(&["abc"]).await;
+        let (.., plugin) = plugin_manager::Plugin::new("plugin".into()).get();
+        let (.., other) = plugin_manager::Plugin::new("other".into()).get();
+        state
+            .plugin_commands
+            .insert("Greet".to_string(), plugin.clone());
+        state
+            .plugin_commands
+            .insert("Other".to_string(), other.clone());
+        state.map_plugin_keys(
+            MapMode::Normal,
+            vec![KeyCode::Char('Q')],
+            plugin.clone(),
+            "q",
+        );
+        state.subscribe(&plugin, vec![EventKind::ModeChanged]);
+
+        state.forget_plugin(&plugin);
+        assert_eq!(
+            state.plugin_commands.keys().collect::<Vec<_>>(),
+            vec!["Other"]
+        );
+        assert!(
+            state
+                .user_keymaps
+                .get_mut(MapMode::Normal)
+                .get(&vec![KeyCode::Char('Q')])
+                .is_none()
+        );
+        state.set_mode(Mode::Visual).await;
+        assert!(plugin.drain().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_event_subscriptions() {
+        let mut state = state_with_lines(&["abc"]).await;
+        let (.., plugin) = plugin_manager::Plugin::new("plugin".into()).get();
+        state.subscribe(&plugin, vec![EventKind::ModeChanged]);
+        state.subscribe(&plugin, vec![EventKind::CursorMoved]);
+
+        state.set_mode(Mode::Visual).await;
+        state
+            .emit(EditorEvent::BufWritePost { buf: BufferId(0) })
+            .await;
+        assert_eq!(
+            plugin.drain().await,
+            vec![CuprumNotification::Event(EditorEvent::ModeChanged {
+                mode: Mode::Visual
+            })]
+        );
+
+        // Unchanged positions are not sent again
+        state.emit_cursor_moved(Duration::ZERO).await;
+        state.emit_cursor_moved(Duration::ZERO).await;
+        let moved = |x| {
+            vec![CuprumNotification::Event(EditorEvent::CursorMoved {
+                win: WindowId(0),
+                pos: UVec2::new(x, 0),
+            })]
+        };
+        assert_eq!(plugin.drain().await, moved(0));
+
+        // Moves in quick succession are sent once the cursor stops, with where it stopped
+        let win = state.get_active_window().unwrap();
+        for x in [1, 2] {
+            win.lock().await.move_to_x(x).await;
+            state.emit_cursor_moved(Duration::from_secs(60)).await;
+        }
+        assert!(plugin.drain().await.is_empty());
+        state.emit_cursor_moved(Duration::ZERO).await;
+        state.emit_cursor_moved(Duration::ZERO).await;
+        assert_eq!(plugin.drain().await, moved(2));
+    }
+
+    #[tokio::test]
+    async fn test_buffer_changed_events() {
+        let mut state = state_with_lines(&["ab", "cd"]).await;
+        let (.., plugin) = plugin_manager::Plugin::new("plugin".into()).get();
+        state.subscribe(&plugin, vec![EventKind::BufferChanged]);
+        let buf = state.get_active_window().unwrap().lock().await.get_buffer();
+        let changed = |revision, start, old_end, new_text: &str| {
+            CuprumNotification::Event(EditorEvent::BufferChanged {
+                buf: BufferId(0),
+                revision,
+                start,
+                old_end,
+                new_text: new_text.to_string(),
+            })
+        };
+
+        // Typing is coalesced
+        {
+            let mut buf = buf.lock().await;
+            buf.take_changes(Duration::ZERO);
+            buf.insert_char(UVec2::new(1, 0), 'x');
+            buf.insert_char(UVec2::new(2, 0), 'y');
+            buf.split_line(UVec2::new(3, 0));
+        }
+        state.emit_buffer_changes(Duration::ZERO).await;
+        assert_eq!(plugin.drain().await, vec![changed(2, 1, 1, "xy\n")]);
+
+        // Lines are "axy", "b", "cd"
+        {
+            let mut buf = buf.lock().await;
+            buf.remove_char(UVec2::new(0, 0));
+            buf.replace_line(1, "bb".to_string());
+            buf.remove_line(2);
+            buf.insert_line(0, "top".to_string());
+        }
+        state.emit_buffer_changes(Duration::ZERO).await;
+        assert_eq!(
+            plugin.drain().await,
+            vec![
+                changed(3, 0, 1, ""),
+                changed(4, 3, 4, "bb"),
+                changed(5, 5, 8, ""),
+                changed(6, 0, 0, "top\n"),
+            ]
+        );
+        assert_eq!(buf.lock().await.get_content(), "top\nxy\nbb");
+
+        // Changes are held back while the buffer keeps changing
+        buf.lock().await.replace_content("new".to_string());
+        state.emit_buffer_changes(Duration::from_secs(60)).await;
+        assert!(plugin.drain().await.is_empty());
+        state.emit_buffer_changes(Duration::ZERO).await;
+        assert_eq!(plugin.drain().await, vec![changed(7, 0, 9, "new")]);
+    }
+
+    #[tokio::test]
+    async fn test_sort_lines() {
+        let mut state = state_with_lines(&["x", "c", "a", "c", "y"]).await;
+        state
+            .sort_lines(1, 3, SortOptions::parse(false, "u").unwrap())
+            .await;
+        assert_eq!(get_lines(&state).await, vec!["x", "a", "c", "y"]);
+
+        state
+            .sort_lines(0, 3, SortOptions::parse(true, "").unwrap())
+            .await;
+        assert_eq!(get_lines(&state).await, vec!["y", "x", "c", "a"]);
+    }
+
+    #[tokio::test]
+    async fn test_substitute() {
+        let mut state = state_with_lines(&["a a", "b", "a"]).await;
+        state.substitute(0, 2, "a", "x", false).await.unwrap();
+        assert_eq!(get_lines(&state).await, vec!["x a", "b", "x"]);
+        state.substitute(0, 0, "a", "y", true).await.unwrap();
+        assert_eq!(get_lines(&state).await, vec!["x y", "b", "x"]);
+        assert!(state.substitute(0, 2, "z", "y", true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_global_delete() {
+        let mut state = state_with_lines(&["foo 1", "bar", "foo 2", "foo 3", "baz"]).await;
+        state.global(0, 4, "foo", false, "d").await.unwrap();
+        assert_eq!(get_lines(&state).await, vec!["bar", "baz"]);
+    }
+
+    #[tokio::test]
+    async fn test_global_substitute() {
+        let mut state = state_with_lines(&["foo a", "bar a", "foo a"]).await;
+        state.global(0, 2, "foo", false, "s/a/b/").await.unwrap();
+        assert_eq!(get_lines(&state).await, vec!["foo b", "bar a", "foo b"]);
+    }
+
+    #[tokio::test]
+    async fn test_global_inverted() {
+        let mut state = state_with_lines(&["foo", "bar", "baz", "foo"]).await;
+        state.global(0, 3, "foo", true, "d").await.unwrap();
+        assert_eq!(get_lines(&state).await, vec!["foo", "foo"]);
+    }
+
+    #[tokio::test]
+    async fn test_global_last_search() {
+        let mut state = state_with_lines(&["foo", "bar", "foo"]).await;
+        assert!(state.global(0, 2, "", false, "d").await.is_err());
+        assert_eq!(get_lines(&state).await, vec!["foo", "bar", "foo"]);
+
+        state.search("foo").await.unwrap();
+        state.global(0, 2, "", false, "d").await.unwrap();
+        assert_eq!(get_lines(&state).await, vec!["bar"]);
+    }
+
+    #[tokio::test]
+    async fn test_command_line_registers() {
+        let mut state = state_with_lines(&["let foo_bar = 1;"]).await;
+        state.registers.set(
+            'a',
+            Register {
+                text: "first\nsecond".to_string(),
+                linewise: true,
+            },
+        );
+        for key in [KeyCode::Ctrl('r'), KeyCode::Char('"')] {
+            state.process_command(key).await.unwrap();
+        }
+        assert_eq!(state.command_buf, "first");
+
+        state.command_buf.clear();
+        for key in [KeyCode::Ctrl('r'), KeyCode::Char('a')] {
+            state.process_command(key).await.unwrap();
+        }
+        assert_eq!(state.command_buf, "first");
+
+        state.command_buf.clear();
+        let win = state.get_active_window().unwrap();
+        win.lock().await.move_to_x(6).await;
+        for key in [KeyCode::Ctrl('r'), KeyCode::Ctrl('w')] {
+            state.process_command(key).await.unwrap();
+        }
+        assert_eq!(state.command_buf, "foo_bar");
+
+        // Unknown registers and a scratch buffer's filename insert nothing
+        for key in [KeyCode::Ctrl('r'), KeyCode::Char('z')] {
+            state.process_command(key).await.unwrap();
+        }
+        for key in [KeyCode::Ctrl('r'), KeyCode::Char('%')] {
+            state.process_command(key).await.unwrap();
+        }
+        assert_eq!(state.command_buf, "foo_bar");
+    }
+
+    #[tokio::test]
+    async fn test_command_line_filename() {
+        let path = std::env::temp_dir().join("cuprum_test_command_line_filename.txt");
+        std::fs::write(&path, "a\n").unwrap();
+        let mut state = EditorState::new(vec![path.to_string_lossy().to_string()]).unwrap();
+        for key in [KeyCode::Ctrl('r'), KeyCode::Char('%')] {
+            state.process_command(key).await.unwrap();
+        }
+        assert_eq!(state.command_buf, path.to_string_lossy());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_yank_lines() {
+        let mut state = state_with_lines(&["a", "b", "c"]).await;
+        state.yank_lines(0, 5).await;
+        assert_eq!(get_lines(&state).await, vec!["a", "b", "c"]);
+        assert_eq!(
+            state.registers.get(UNNAMED_REGISTER).unwrap().text,
+            "a\nb\nc"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_complete_command() {
+        let mut state = state_with_lines(&[""]).await;
+        state.set_mode(Mode::Command).await;
+        state.process_command(KeyCode::Char('w')).await.unwrap();
+        state.process_command(KeyCode::Char('r')).await.unwrap();
+        state.process_command(KeyCode::Char('\t')).await.unwrap();
+        assert_eq!(state.command_buf, "write");
+
+        state.command_buf.clear();
+        state.process_command(KeyCode::Char('x')).await.unwrap();
+        state.process_command(KeyCode::Char('\t')).await.unwrap();
+        assert_eq!(state.command_buf, "x");
+    }
+
+    #[tokio::test]
+    async fn test_set_options() {
+        let mut state = state_with_lines(&[""]).await;
+        state.set_options("number ts=4").await;
+        assert!(state.options.number);
+        assert_eq!(state.options.tabstop, 4);
+
+        state.set_options("tabstop?").await;
+        assert_eq!(
+            state.messages.get_current().unwrap().text,
+            "  tabstop=4".to_string()
+        );
+
+        state.set_options("foo").await;
+        let message = state.messages.get_current().unwrap();
+        assert_eq!(message.level, MessageLevel::Error);
+        assert_eq!(message.text, "E518: Unknown option: foo");
+    }
+
+    #[tokio::test]
+    async fn test_filetype_options() {
+        let dir = std::env::temp_dir();
+        let files: Vec<String> = ["cuprum_test_filetype.rs", "cuprum_test_filetype.txt"]
+            .iter()
+            .map(|name| {
+                let path = dir.join(name);
+                std::fs::write(&path, "").unwrap();
+                path.to_string_lossy().to_string()
+            })
+            .collect();
+        let mut state = EditorState::new(files.clone()).unwrap();
+        for file in files {
+            std::fs::remove_file(file).unwrap();
+        }
+        let config: Config = toml::from_str(
+            "[options]\ntabstop = 2\n[filetype.rust]\ntabstop = 4\nexpandtab = true\n",
+        )
+        .unwrap();
+        state.apply_config(&config);
+
+        // The first window shows the Rust file
+        state.update_filetype_options().await;
+        assert_eq!((state.options.tabstop, state.options.expandtab), (4, true));
+        state.active_window = WindowId(1);
+        state.update_filetype_options().await;
+        assert_eq!((state.options.tabstop, state.options.expandtab), (2, false));
+
+        // `:set` applies to every buffer, under the options of the filetype
+        state.set_options("ts=3 nu").await;
+        state.active_window = WindowId(0);
+        state.update_filetype_options().await;
+        assert_eq!((state.options.tabstop, state.options.number), (4, true));
+        state.active_window = WindowId(1);
+        state.update_filetype_options().await;
+        assert_eq!(state.options.tabstop, 3);
+
+        // Setting the filetype applies its options
+        state.set_options("ft?").await;
+        assert_eq!(
+            state.messages.get_current().unwrap().text,
+            "  filetype=text"
+        );
+        state.set_options("filetype=rust").await;
+        assert_eq!(state.get_filetype().await.as_deref(), Some("rust"));
+        assert_eq!(state.options.tabstop, 4);
+    }
+
+    #[tokio::test]
+    async fn test_next_buffer() {
+        let dir = std::env::temp_dir();
+        let files: Vec<String> = (0..3)
+            .map(|i| {
+                let path = dir.join(format!("cuprum_test_next_buffer_{}.txt", i));
+                std::fs::write(&path, i.to_string()).unwrap();
+                path.to_string_lossy().to_string()
+            })
+            .collect();
+        let mut state = EditorState::with_term_size(files.clone(), UVec2::new(80, 24)).unwrap();
+        for file in files {
+            std::fs::remove_file(file).unwrap();
+        }
+
+        // Each file has a window of its own filling the screen
+        for (i, id) in state
+            .window_manager
+            .get_window_ids()
+            .into_iter()
+            .enumerate()
+        {
+            let win = state.window_manager.get_window(id).unwrap();
+            let win = win.lock().await;
+            assert_eq!(win.get_buffer_id(), BufferId(i));
+            assert_eq!(win.get_position(), UVec2::new(0, 0));
+            assert_eq!(win.get_size(), UVec2::new(80, 23));
+        }
+
+        let mut visited = Vec::new();
+        for forward in [true, true, true, false] {
+            let command = if forward { "bn" } else { "bp" };
+            assert!(matches!(
+                state.run_command(command).await.unwrap(),
+                Some(Action::NextBuffer(f)) if f == forward
+            ));
+            state.next_buffer(forward).await;
+            visited.push(state.active_window.0);
+        }
+        assert_eq!(visited, [1, 2, 0, 2]);
+
+        // Going on from the help starts over at the first file
+        state.open_help(None, &[]).await.unwrap();
+        assert_eq!(state.help_window, Some(state.active_window));
+        state.next_buffer(true).await;
+        assert_eq!(state.active_window, WindowId(0));
+    }
+
+    #[tokio::test]
+    async fn test_config_commands() {
+        let config = Config::read(std::path::Path::new("fixtures/commands.toml"))
+            .unwrap()
+            .unwrap();
+        let mut state = EditorState::new(Vec::new()).unwrap();
+        state.apply_config(&config);
+
+        let entry = state.command_map.get_entry("W").unwrap();
+        assert_eq!(
+            (entry.spec.as_str(), entry.description.as_str()),
+            ("W", "save")
+        );
+        assert_eq!(
+            state.command_map.get_entry("Greet").unwrap().description,
+            "ggOhello<Esc>"
+        );
+        for name in ["lower", "Bad-name", "Nope"] {
+            assert!(state.command_map.get_entry(name).is_none(), "{}", name);
+        }
+        let errors: Vec<String> = state
+            .messages
+            .get_history()
+            .map(|entry| entry.message.text.clone())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "config.toml: command Bad-name: E182: Invalid command name",
+                "config.toml: command Nope: Unknown action: no_such_action",
+                "config.toml: command lower: \
+                 E183: User defined commands must start with an uppercase letter",
+            ]
+        );
+
+        // Commands run like built-in ones, abbreviations included
+        assert!(matches!(
+            state.run_command("To").await.unwrap(),
+            Some(Action::Builtin(BuiltinAction::MoveToY(Position::Start)))
+        ));
+        assert!(matches!(
+            state.run_command("Greet").await.unwrap(),
+            Some(Action::Feed(keys, false)) if keys.len() == 9
+        ));
+        assert!(state.run_command("W file").await.is_err());
+        assert!(state.run_command("1,2W").await.is_err());
     }
 }
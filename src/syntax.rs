@@ -0,0 +1,574 @@
+//! Incremental syntax highlighting.
+//!
+//! A highlighter goes through the lines in order, carrying a small state from each line
+//! to the next, such as being inside a block comment. The spans and states of each line
+//! are cached, so after an edit only the edited lines are highlighted again, followed by
+//! the lines after them until they start in the same state as before.
+
+use std::ops::Range;
+
+/// What a highlighter carries from a line to the next, such as how deep in nested block
+/// comments the line ends
+pub type LineState = u32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyntaxKind {
+    Comment,
+    String,
+    Keyword,
+}
+
+/// A highlighted span of a line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    /// Byte range within the line
+    pub range: Range<usize>,
+    pub kind: SyntaxKind,
+}
+
+pub trait Highlighter {
+    /// Highlight a line starting in `state`, which the line before left, and leave the
+    /// state the next line starts in
+    fn highlight_line(&self, line: &str, state: &mut LineState) -> Vec<Span>;
+}
+
+/// Comments, strings and keywords of a language with C-like or `#` comments. Line states
+/// count how deep in block comments a line ends.
+#[derive(Debug)]
+pub struct Language {
+    keywords: &'static [&'static str],
+    line_comment: &'static str,
+    block_comments: bool,
+    /// Whether `/*` inside a block comment opens another one, as in Rust
+    nested_comments: bool,
+    /// Whether `'` quotes strings and characters. Rust uses it for lifetimes too.
+    single_quotes: bool,
+}
+
+const RUST: Language = Language {
+    keywords: &[
+        "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+        "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move",
+        "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait",
+        "true", "type", "unsafe", "use", "where", "while",
+    ],
+    line_comment: "//",
+    block_comments: true,
+    nested_comments: true,
+    single_quotes: false,
+};
+
+const C: Language = Language {
+    keywords: &[
+        "auto",
+        "break",
+        "case",
+        "char",
+        "const",
+        "continue",
+        "default",
+        "do",
+        "double",
+        "else",
+        "enum",
+        "extern",
+        "float",
+        "for",
+        "goto",
+        "if",
+        "int",
+        "long",
+        "return",
+        "short",
+        "signed",
+        "sizeof",
+        "static",
+        "struct",
+        "switch",
+        "typedef",
+        "union",
+        "unsigned",
+        "void",
+        "while",
+        "class",
+        "namespace",
+        "template",
+        "typename",
+        "public",
+        "private",
+        "protected",
+        "virtual",
+        "new",
+        "delete",
+        "true",
+        "false",
+        "nullptr",
+    ],
+    line_comment: "//",
+    block_comments: true,
+    nested_comments: false,
+    single_quotes: true,
+};
+
+const GO: Language = Language {
+    keywords: &[
+        "break",
+        "case",
+        "chan",
+        "const",
+        "continue",
+        "default",
+        "defer",
+        "else",
+        "false",
+        "for",
+        "func",
+        "go",
+        "goto",
+        "if",
+        "import",
+        "interface",
+        "map",
+        "nil",
+        "package",
+        "range",
+        "return",
+        "select",
+        "struct",
+        "switch",
+        "true",
+        "type",
+        "var",
+    ],
+    line_comment: "//",
+    block_comments: true,
+    nested_comments: false,
+    single_quotes: true,
+};
+
+const JAVASCRIPT: Language = Language {
+    keywords: &[
+        "async",
+        "await",
+        "break",
+        "case",
+        "catch",
+        "class",
+        "const",
+        "continue",
+        "default",
+        "delete",
+        "do",
+        "else",
+        "export",
+        "extends",
+        "false",
+        "finally",
+        "for",
+        "from",
+        "function",
+        "if",
+        "import",
+        "in",
+        "instanceof",
+        "interface",
+        "let",
+        "new",
+        "null",
+        "of",
+        "return",
+        "switch",
+        "this",
+        "throw",
+        "true",
+        "try",
+        "type",
+        "typeof",
+        "undefined",
+        "var",
+        "void",
+        "while",
+        "yield",
+    ],
+    line_comment: "//",
+    block_comments: true,
+    nested_comments: false,
+    single_quotes: true,
+};
+
+const PYTHON: Language = Language {
+    keywords: &[
+        "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+        "elif", "else", "except", "False", "finally", "for", "from", "global", "if", "import",
+        "in", "is", "lambda", "None", "nonlocal", "not", "or", "pass", "raise", "return", "True",
+        "try", "while", "with", "yield",
+    ],
+    line_comment: "#",
+    block_comments: false,
+    nested_comments: false,
+    single_quotes: true,
+};
+
+const SH: Language = Language {
+    keywords: &[
+        "case", "do", "done", "elif", "else", "esac", "export", "fi", "for", "function", "if",
+        "in", "local", "return", "then", "until", "while",
+    ],
+    line_comment: "#",
+    block_comments: false,
+    nested_comments: false,
+    single_quotes: true,
+};
+
+const TOML: Language = Language {
+    keywords: &["true", "false"],
+    line_comment: "#",
+    block_comments: false,
+    nested_comments: false,
+    single_quotes: true,
+};
+
+/// The highlighter of a filetype, if there is one
+pub fn get_highlighter(filetype: &str) -> Option<&'static dyn Highlighter> {
+    let language = match filetype {
+        "rust" => &RUST,
+        "c" | "cpp" => &C,
+        "go" => &GO,
+        "javascript" | "typescript" => &JAVASCRIPT,
+        "python" => &PYTHON,
+        "sh" => &SH,
+        "toml" => &TOML,
+        _ => return None,
+    };
+    Some(language)
+}
+
+fn is_word_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_'
+}
+
+impl Highlighter for Language {
+    fn highlight_line(&self, line: &str, state: &mut LineState) -> Vec<Span> {
+        // Scanned by bytes, stopping only on ASCII ones, so every span is on char boundaries
+        let bytes = line.as_bytes();
+        let mut spans = Vec::new();
+        let mut comment = (*state > 0).then_some(0);
+        let mut i = 0;
+        while i < bytes.len() {
+            let rest = &bytes[i..];
+            if let Some(start) = comment {
+                if self.nested_comments && rest.starts_with(b"/*") {
+                    *state += 1;
+                    i += 2;
+                } else if rest.starts_with(b"*/") {
+                    *state -= 1;
+                    i += 2;
+                    if *state == 0 {
+                        spans.push(Span {
+                            range: start..i,
+                            kind: SyntaxKind::Comment,
+                        });
+                        comment = None;
+                    }
+                } else {
+                    i += 1;
+                }
+            } else if rest.starts_with(self.line_comment.as_bytes()) {
+                spans.push(Span {
+                    range: i..bytes.len(),
+                    kind: SyntaxKind::Comment,
+                });
+                break;
+            } else if self.block_comments && rest.starts_with(b"/*") {
+                comment = Some(i);
+                *state = 1;
+                i += 2;
+            } else if rest[0] == b'"' || (self.single_quotes && rest[0] == b'\'') {
+                // Unclosed strings end with the line
+                let quote = rest[0];
+                let mut end = i + 1;
+                while end < bytes.len() && bytes[end] != quote {
+                    end += if bytes[end] == b'\\' { 2 } else { 1 };
+                }
+                let end = (end + 1).min(bytes.len());
+                spans.push(Span {
+                    range: i..end,
+                    kind: SyntaxKind::String,
+                });
+                i = end;
+            } else if is_word_byte(rest[0]) {
+                let start = i;
+                while i < bytes.len() && is_word_byte(bytes[i]) {
+                    i += 1;
+                }
+                if self.keywords.contains(&&line[start..i]) {
+                    spans.push(Span {
+                        range: start..i,
+                        kind: SyntaxKind::Keyword,
+                    });
+                }
+            } else {
+                i += 1;
+            }
+        }
+        if let Some(start) = comment {
+            spans.push(Span {
+                range: start..bytes.len(),
+                kind: SyntaxKind::Comment,
+            });
+        }
+        spans
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct CachedLine {
+    /// `None` if the line was never highlighted
+    start_state: Option<LineState>,
+    end_state: LineState,
+    spans: Vec<Span>,
+}
+
+/// The spans of the lines of a buffer, kept up to date with `edit` and `highlight`
+#[derive(Debug, Default)]
+pub struct SyntaxCache {
+    lines: Vec<CachedLine>,
+    /// Lines before this one are up to date
+    valid: usize,
+    /// Lines from this one on are unchanged, and only out of date if the state they start
+    /// in changed
+    edited_end: usize,
+}
+
+impl SyntaxCache {
+    /// Take edited lines, given as a range of the lines after the edit, with the number of
+    /// lines there are now
+    pub fn edit(&mut self, edited: Range<usize>, line_count: usize) {
+        // Lines were inserted or removed in the edited range, so the ones after line up
+        let at = edited.start.min(self.lines.len());
+        let old_count = self.lines.len();
+        if line_count > old_count {
+            let added = line_count - old_count;
+            self.lines
+                .splice(at..at, std::iter::repeat_n(CachedLine::default(), added));
+        } else {
+            let removed = (old_count - line_count).min(old_count - at);
+            self.lines.drain(at..at + removed);
+        }
+
+        if self.valid < self.lines.len() && self.valid <= edited.start {
+            // Still catching up with an earlier edit, which the lines moved past
+            let shifted = if self.edited_end > edited.start {
+                self.edited_end
+                    .saturating_add_signed(line_count as isize - old_count as isize)
+            } else {
+                self.edited_end
+            };
+            self.edited_end = shifted.max(edited.end);
+        } else {
+            self.edited_end = edited.end;
+        }
+        self.valid = self.valid.min(edited.start);
+    }
+
+    /// Bring lines up to `end` up to date, returning how many lines were highlighted.
+    /// The lines before them are highlighted too, as they decide the state.
+    pub fn highlight(
+        &mut self,
+        highlighter: &dyn Highlighter,
+        lines: &[String],
+        end: usize,
+    ) -> usize {
+        // Changed without saying which lines, so all of them are out of date
+        if self.lines.len() != lines.len() {
+            self.edit(0..lines.len(), lines.len());
+        }
+
+        let end = end.min(lines.len());
+        let mut highlighted = 0;
+        while self.valid < end {
+            let y = self.valid;
+            let state = y.checked_sub(1).map_or(0, |y| self.lines[y].end_state);
+            if y >= self.edited_end && self.lines[y].start_state == Some(state) {
+                // Everything from here on is as it was
+                self.valid = self.lines.len();
+                break;
+            }
+
+            let mut end_state = state;
+            let spans = highlighter.highlight_line(&lines[y], &mut end_state);
+            self.lines[y] = CachedLine {
+                start_state: Some(state),
+                end_state,
+                spans,
+            };
+            self.valid += 1;
+            highlighted += 1;
+        }
+        highlighted
+    }
+
+    /// The spans of line `y`, if it is up to date
+    pub fn get_spans(&self, y: usize) -> Option<&[Span]> {
+        if y < self.valid {
+            self.lines.get(y).map(|line| line.spans.as_slice())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use utils::vec2::UVec2;
+
+    use super::*;
+    use crate::buffer::Buffer;
+
+    /// Highlights `/* */` comments, which span lines
+    struct BlockComments;
+
+    impl Highlighter for BlockComments {
+        fn highlight_line(&self, line: &str, state: &mut LineState) -> Vec<Span> {
+            let mut spans = Vec::new();
+            let mut start = (*state == 1).then_some(0);
+            let mut rest = 0;
+            loop {
+                match start {
+                    Some(from) => match line[rest..].find("*/") {
+                        Some(i) => {
+                            rest += i + 2;
+                            spans.push(Span {
+                                range: from..rest,
+                                kind: SyntaxKind::Comment,
+                            });
+                            start = None;
+                        }
+                        None => break,
+                    },
+                    None => match line[rest..].find("/*") {
+                        Some(i) => {
+                            start = Some(rest + i);
+                            rest += i + 2;
+                        }
+                        None => break,
+                    },
+                }
+            }
+            if let Some(from) = start {
+                spans.push(Span {
+                    range: from..line.len(),
+                    kind: SyntaxKind::Comment,
+                });
+            }
+            *state = u32::from(start.is_some());
+            spans
+        }
+    }
+
+    /// Bring the cache up to date with the edits of `buf`
+    fn highlight(cache: &mut SyntaxCache, buf: &mut Buffer, end: usize) -> usize {
+        if let Some(edited) = buf.take_edited_lines() {
+            cache.edit(edited, buf.get_line_count());
+        }
+        let lines = buf.get_lines_range(0, buf.get_line_count());
+        cache.highlight(&BlockComments, lines, end)
+    }
+
+    fn is_comment(cache: &SyntaxCache, y: usize) -> bool {
+        !cache.get_spans(y).unwrap().is_empty()
+    }
+
+    #[test]
+    fn test_edits_highlight_few_lines() {
+        let mut buf = Buffer::default();
+        let lines: Vec<String> = (0..50_000).map(|i| format!("line {}", i)).collect();
+        buf.replace_all_lines(lines);
+        let mut cache = SyntaxCache::default();
+        assert_eq!(highlight(&mut cache, &mut buf, 50_000), 50_000);
+
+        // Typing in the middle of the file
+        for x in 0..100 {
+            buf.insert_char(UVec2::new(x, 25_000), 'a');
+            assert_eq!(highlight(&mut cache, &mut buf, 50_000), 1);
+        }
+        buf.split_line(UVec2::new(3, 30_000));
+        assert_eq!(highlight(&mut cache, &mut buf, 50_001), 2);
+        buf.join_lines(30_000);
+        assert_eq!(highlight(&mut cache, &mut buf, 50_000), 1);
+        assert_eq!(cache.get_spans(49_999), Some(&[][..]));
+    }
+
+    #[test]
+    fn test_languages() {
+        use SyntaxKind::*;
+
+        let highlight = |filetype: &str, lines: &[&'static str]| {
+            let highlighter = get_highlighter(filetype).unwrap();
+            let mut state = 0;
+            lines
+                .iter()
+                .map(|line| {
+                    let spans = highlighter.highlight_line(line, &mut state);
+                    spans
+                        .into_iter()
+                        .map(|span| (&line[span.range], span.kind))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            highlight(
+                "rust",
+                &["let s = \"a \\\" ä\"; // fn", "/* a /* b */ fn", "c */ fn"]
+            ),
+            vec![
+                vec![
+                    ("let", Keyword),
+                    ("\"a \\\" ä\"", String),
+                    ("// fn", Comment)
+                ],
+                vec![("/* a /* b */ fn", Comment)],
+                vec![("c */", Comment), ("fn", Keyword)],
+            ]
+        );
+        // Lifetimes are not strings in Rust, and words only match whole
+        assert_eq!(
+            highlight("rust", &["fn f<'a>(x: &'a str) -> format"]),
+            vec![vec![("fn", Keyword)]]
+        );
+        assert_eq!(
+            highlight("python", &["if x == 'ä': # no", "return \"open"]),
+            vec![
+                vec![("if", Keyword), ("'ä'", String), ("# no", Comment)],
+                vec![("return", Keyword), ("\"open", String)],
+            ]
+        );
+        assert!(get_highlighter("text").is_none());
+    }
+
+    #[test]
+    fn test_comments_across_lines() {
+        let mut buf = Buffer::default();
+        let lines: Vec<String> = (0..100).map(|i| format!("line {}", i)).collect();
+        buf.replace_all_lines(lines);
+        let mut cache = SyntaxCache::default();
+        highlight(&mut cache, &mut buf, 100);
+
+        // Opening a comment changes every line after it, up to where it is closed
+        buf.insert_line(10, "/* open".to_string());
+        buf.insert_line(20, "close */".to_string());
+        assert_eq!(highlight(&mut cache, &mut buf, 102), 12);
+        assert!(!is_comment(&cache, 9));
+        assert!((10..=20).all(|y| is_comment(&cache, y)));
+        assert!(!is_comment(&cache, 21));
+
+        // Only the visible lines are brought up to date
+        buf.remove_line(20);
+        assert_eq!(highlight(&mut cache, &mut buf, 50), 30);
+        assert!(is_comment(&cache, 49));
+        assert_eq!(cache.get_spans(50), None);
+        assert_eq!(highlight(&mut cache, &mut buf, 101), 51);
+        assert!(is_comment(&cache, 100));
+    }
+}
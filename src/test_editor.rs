@@ -0,0 +1,201 @@
+//! Drive the editor from tests without a terminal
+
+use std::{
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use api::{CuprumApi, Mode};
+use builtin::BuiltinApiProvider;
+use crossterm::event::{Event, KeyCode as TermKeyCode, KeyEvent, KeyModifiers};
+use tokio::sync::Mutex;
+use utils::vec2::UVec2;
+
+use crate::{
+    EditorApplication, serve_builtin,
+    state::EditorState,
+    ui::input::{KeyCode, parse_keys},
+};
+
+/// A provider whose requests are processed against `state`
+pub fn spawn_builtin_handler(state: Arc<Mutex<EditorState>>) -> BuiltinApiProvider {
+    let provider = BuiltinApiProvider::default();
+    tokio::spawn(serve_builtin(
+        state,
+        provider.messages.clone(),
+        provider.get_notify(),
+    ));
+    provider
+}
+
+/// Gives every test editor its own fixture directory
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// An editor opened on in-memory fixtures, fed with synthetic key events
+pub struct TestEditor {
+    pub app: EditorApplication,
+    /// Requests go to the same state the keys are fed into
+    pub api: CuprumApi<BuiltinApiProvider>,
+    dir: FixtureDir,
+}
+
+/// Removed with the fixtures in it when the editor is dropped, or once the rest of it is
+/// when its `app` has been moved out
+struct FixtureDir(PathBuf);
+
+impl Drop for FixtureDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+impl TestEditor {
+    /// Write each `(name, content)` fixture to a temporary directory and open them all
+    pub fn new(files: &[(&str, &str)]) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "cuprum-test-editor-{}-{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths = files
+            .iter()
+            .map(|(name, content)| {
+                let path = dir.join(name);
+                std::fs::write(&path, content).unwrap();
+                path.to_string_lossy().to_string()
+            })
+            .collect();
+
+        let app = EditorApplication::headless(paths).unwrap();
+        {
+            let builtin = app.builtin.try_lock().unwrap();
+            tokio::spawn(serve_builtin(
+                app.state.clone(),
+                builtin.get_messages(),
+                builtin.get_notify(),
+            ));
+        }
+        let api = CuprumApi::new(spawn_builtin_handler(app.state.clone()));
+        Self {
+            app,
+            api,
+            dir: FixtureDir(dir),
+        }
+    }
+
+    /// Get the path a fixture was written to
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.0.join(name)
+    }
+
+    /// Feed key notation such as `ihello<Esc>` or `:w<CR>` one event at a time
+    pub async fn keys(&mut self, notation: &str) {
+        for key in parse_keys(notation).unwrap() {
+            let key = match key {
+                KeyCode::Leader => KeyCode::Char(self.app.state.lock().await.options.mapleader),
+                key => key,
+            };
+            self.event(key_event(key)).await;
+        }
+    }
+
+    pub async fn event(&mut self, event: Event) {
+        self.app.process(event).await.unwrap();
+    }
+
+    /// Lines of the active buffer
+    pub async fn lines(&self) -> Vec<String> {
+        let win = self.app.state.lock().await.get_active_window().unwrap();
+        let buf = win.lock().await.get_buffer();
+        buf.lock().await.get_all_lines()
+    }
+
+    pub async fn cursor(&self) -> UVec2 {
+        let win = self.app.state.lock().await.get_active_window().unwrap();
+        win.lock().await.get_cursor()
+    }
+
+    pub async fn mode(&self) -> Mode {
+        let mode = self.app.state.lock().await.mode.clone();
+        mode.lock().await.clone()
+    }
+}
+
+/// The terminal event that `InputManager::event_to_key` reads back as `key`
+pub fn key_event(key: KeyCode) -> Event {
+    let (code, modifiers) = match key {
+        KeyCode::Char('\n') => (TermKeyCode::Enter, KeyModifiers::NONE),
+        KeyCode::Char('\t') => (TermKeyCode::Tab, KeyModifiers::NONE),
+        KeyCode::Char(ch) => (TermKeyCode::Char(ch), KeyModifiers::NONE),
+        KeyCode::Ctrl(ch) => (TermKeyCode::Char(ch), KeyModifiers::CONTROL),
+        KeyCode::Backspace => (TermKeyCode::Backspace, KeyModifiers::NONE),
+        KeyCode::Delete => (TermKeyCode::Delete, KeyModifiers::NONE),
+        KeyCode::Left => (TermKeyCode::Left, KeyModifiers::NONE),
+        KeyCode::Right => (TermKeyCode::Right, KeyModifiers::NONE),
+        KeyCode::Up => (TermKeyCode::Up, KeyModifiers::NONE),
+        KeyCode::Down => (TermKeyCode::Down, KeyModifiers::NONE),
+        KeyCode::Home => (TermKeyCode::Home, KeyModifiers::NONE),
+        KeyCode::End => (TermKeyCode::End, KeyModifiers::NONE),
+        KeyCode::PageUp => (TermKeyCode::PageUp, KeyModifiers::NONE),
+        KeyCode::PageDown => (TermKeyCode::PageDown, KeyModifiers::NONE),
+        KeyCode::Esc => (TermKeyCode::Esc, KeyModifiers::NONE),
+        KeyCode::Leader => unreachable!("the leader is replaced with its key"),
+    };
+    Event::Key(KeyEvent::new(code, modifiers))
+}
+
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_insert_text() {
+        let mut editor = TestEditor::new(&[("a.txt", "world")]);
+        editor.keys("ihello <Esc>").await;
+        assert_eq!(editor.lines().await, vec!["hello world"]);
+        assert_eq!(editor.mode().await, Mode::Normal);
+        assert_eq!(editor.cursor().await, UVec2::new(6, 0));
+    }
+
+    #[tokio::test]
+    async fn test_without_terminal() {
+        let mut editor = TestEditor::new(&[]);
+        editor.keys("ihello<Esc>:help<CR>").await;
+        let state = editor.app.state.lock().await;
+        assert_eq!(state.term_size, UVec2::new(80, 24));
+        let ids = state.window_manager.get_window_ids();
+        assert_eq!(ids.len(), 2);
+        for id in ids {
+            let win = state.window_manager.get_window(id).unwrap();
+            assert_eq!(win.lock().await.get_size(), UVec2::new(80, 23));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delete_line() {
+        let mut editor = TestEditor::new(&[("a.txt", "one\ntwo\nthree")]);
+        editor.keys("jdd").await;
+        assert_eq!(editor.lines().await, vec!["one", "three"]);
+        assert_eq!(editor.cursor().await.y, 1);
+        assert_eq!(editor.api.get_line(None, 1).await.unwrap(), "three");
+    }
+
+    #[tokio::test]
+    async fn test_write() {
+        let mut editor = TestEditor::new(&[("a.txt", "old")]);
+        editor
+            .api
+            .replace_line(None, 0, "new".to_string())
+            .await
+            .unwrap();
+        editor.keys(":w<CR>").await;
+        assert_eq!(
+            std::fs::read_to_string(editor.path("a.txt")).unwrap(),
+            "new"
+        );
+        assert_eq!(editor.mode().await, Mode::Normal);
+    }
+}
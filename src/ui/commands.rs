@@ -1,23 +1,688 @@
 use std::collections::HashMap;
 
+use anyhow::bail;
 use builtin::BuiltinAction;
 
-use crate::action::Action;
+use crate::{
+    action::{Action, LocationJump, PluginAction},
+    buffer::undo::UndoAmount,
+    sort::SortOptions,
+    ui::input::{MapMode, parse_keys},
+};
+
+/// The line an address starts from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressBase {
+    /// 1-based line number
+    Number(usize),
+    /// `.`
+    Current,
+    /// `$`
+    Last,
+    /// `'<` or `'>`
+    Mark(char),
+}
+
+/// A line address such as `5`, `.+3` or `$-1`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Address {
+    pub base: AddressBase,
+    pub offset: isize,
+}
+
+impl Address {
+    pub fn new(base: AddressBase) -> Self {
+        Self { base, offset: 0 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandRange {
+    /// `%`
+    All,
+    Line(Address),
+    Lines(Address, Address),
+}
+
+/// Editor state a command is run against
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommandContext {
+    /// 0-based line of the cursor
+    pub current_line: usize,
+    pub line_count: usize,
+    /// First and last line of the last visual selection
+    pub visual_lines: Option<(usize, usize)>,
+}
+
+impl CommandContext {
+    /// Resolve an address to a 0-based line, clamped to the buffer
+    pub fn resolve_address(&self, address: Address) -> anyhow::Result<usize> {
+        let last = self.line_count.saturating_sub(1) as isize;
+        let base = match address.base {
+            AddressBase::Number(number) => number as isize - 1,
+            AddressBase::Current => self.current_line as isize,
+            AddressBase::Last => last,
+            AddressBase::Mark(mark) => match (mark, self.visual_lines) {
+                ('<', Some((start, _))) => start as isize,
+                ('>', Some((_, end))) => end as isize,
+                _ => bail!("E20: Mark not set"),
+            },
+        };
+        Ok((base + address.offset).clamp(0, last) as usize)
+    }
+
+    /// Resolve a range to an inclusive interval of 0-based lines.
+    /// Without a range, the current line is used; reversed ranges are swapped.
+    pub fn resolve_range(&self, range: Option<CommandRange>) -> anyhow::Result<(usize, usize)> {
+        let (start, end) = match range {
+            None => (self.current_line, self.current_line),
+            Some(CommandRange::All) => (0, self.line_count.saturating_sub(1)),
+            Some(CommandRange::Line(address)) => {
+                let line = self.resolve_address(address)?;
+                (line, line)
+            }
+            Some(CommandRange::Lines(start, end)) => {
+                (self.resolve_address(start)?, self.resolve_address(end)?)
+            }
+        };
+
+        if start <= end {
+            Ok((start, end))
+        } else {
+            Ok((end, start))
+        }
+    }
+}
+
+/// A command line split into its parts, e.g. `:1,5w! file`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Command {
+    pub range: Option<CommandRange>,
+    pub name: String,
+    pub bang: bool,
+    pub args: String,
+}
+
+/// Parses a command line into a [`Command`]
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    input: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Self {
+        Self {
+            chars: input.chars().collect(),
+            pos: 0,
+            input,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(ch) = self.peek()
+            && ch.is_whitespace()
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn parse_number(&mut self) -> anyhow::Result<Option<usize>> {
+        let start = self.pos;
+        while let Some(ch) = self.peek()
+            && ch.is_ascii_digit()
+        {
+            self.pos += 1;
+        }
+
+        if start == self.pos {
+            return Ok(None);
+        }
+
+        let number: String = self.chars[start..self.pos].iter().collect();
+        match number.parse() {
+            Ok(number) => Ok(Some(number)),
+            Err(_) => bail!("E16: Invalid range"),
+        }
+    }
+
+    fn parse_address(&mut self) -> anyhow::Result<Option<Address>> {
+        let base = match self.peek() {
+            Some('.') => {
+                self.pos += 1;
+                Some(AddressBase::Current)
+            }
+            Some('$') => {
+                self.pos += 1;
+                Some(AddressBase::Last)
+            }
+            Some('\'') => {
+                self.pos += 1;
+                match self.peek() {
+                    Some(mark @ ('<' | '>')) => {
+                        self.pos += 1;
+                        Some(AddressBase::Mark(mark))
+                    }
+                    _ => bail!("E20: Mark not set"),
+                }
+            }
+            Some(ch) if ch.is_ascii_digit() => self.parse_number()?.map(AddressBase::Number),
+            _ => None,
+        };
+
+        // An offset alone is relative to the current line, e.g. `+3`
+        let mut address = match base {
+            Some(base) => Address::new(base),
+            None if matches!(self.peek(), Some('+' | '-')) => Address::new(AddressBase::Current),
+            None => return Ok(None),
+        };
+
+        while let Some(sign @ ('+' | '-')) = self.peek() {
+            self.pos += 1;
+            let offset = self.parse_number()?.unwrap_or(1) as isize;
+            address.offset += if sign == '+' { offset } else { -offset };
+        }
+
+        Ok(Some(address))
+    }
+
+    fn parse_range(&mut self) -> anyhow::Result<Option<CommandRange>> {
+        if self.peek() == Some('%') {
+            self.pos += 1;
+            return Ok(Some(CommandRange::All));
+        }
+
+        let Some(start) = self.parse_address()? else {
+            if self.peek() == Some(',') {
+                bail!("E16: Invalid range");
+            }
+            return Ok(None);
+        };
+
+        if self.peek() != Some(',') {
+            return Ok(Some(CommandRange::Line(start)));
+        }
+        self.pos += 1;
+
+        match self.parse_address()? {
+            Some(end) => Ok(Some(CommandRange::Lines(start, end))),
+            None => bail!("E16: Invalid range"),
+        }
+    }
+
+    fn parse_name(&mut self) -> String {
+        let start = self.pos;
+        match self.peek() {
+            Some(ch) if ch.is_ascii_alphabetic() => {
+                while let Some(ch) = self.peek()
+                    && ch.is_ascii_alphabetic()
+                {
+                    self.pos += 1;
+                }
+            }
+            // Symbol commands such as `!` and `&` are a single character
+            Some(ch) if !ch.is_whitespace() => self.pos += 1,
+            _ => {}
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn parse(mut self) -> anyhow::Result<Command> {
+        self.skip_whitespace();
+        let range = self.parse_range()?;
+        self.skip_whitespace();
+
+        let name = self.parse_name();
+
+        let bang = name != "!" && self.peek() == Some('!');
+        if bang {
+            self.pos += 1;
+        }
+
+        self.skip_whitespace();
+        let offset = self.chars[..self.pos]
+            .iter()
+            .map(|ch| ch.len_utf8())
+            .sum::<usize>();
+        let args = self.input[offset..].to_string();
+
+        Ok(Command {
+            range,
+            name,
+            bang,
+            args,
+        })
+    }
+}
+
+/// Split a command line into range, name, bang flag and arguments
+pub fn parse_command(input: &str) -> anyhow::Result<Command> {
+    Parser::new(input).parse()
+}
+
+/// State of Tab completion in the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandCompletion {
+    /// Text before the command name, such as a range
+    pub head: String,
+    pub matches: Vec<String>,
+    /// The match currently shown, `None` while showing the common prefix
+    pub index: Option<usize>,
+}
+
+impl CommandCompletion {
+    /// Start completing the command name at the end of `input`
+    pub fn new(input: &str, names: &[String]) -> Option<Self> {
+        let command = parse_command(input).ok()?;
+        if command.bang
+            || !command.args.is_empty()
+            || !command.name.chars().all(|ch| ch.is_ascii_alphabetic())
+            || !input.ends_with(&command.name)
+        {
+            return None;
+        }
+
+        let head = input[..input.len() - command.name.len()].to_string();
+        let matches: Vec<String> = names
+            .iter()
+            .filter(|name| name.starts_with(&command.name))
+            .cloned()
+            .collect();
+        if matches.is_empty() {
+            return None;
+        }
+
+        // Complete to the longest common prefix first if it adds anything
+        let prefix = longest_common_prefix(&matches);
+        let index = if matches.len() > 1 && prefix.len() > command.name.len() {
+            None
+        } else {
+            Some(0)
+        };
+
+        Some(Self {
+            head,
+            matches,
+            index,
+        })
+    }
+
+    /// Move to the next match
+    pub fn next(&mut self) {
+        self.index = Some(match self.index {
+            Some(index) => (index + 1) % self.matches.len(),
+            None => 0,
+        });
+    }
+
+    /// Get the command line with the completion applied
+    pub fn get_text(&self) -> String {
+        match self.index {
+            Some(index) => self.head.clone() + self.matches[index].as_str(),
+            None => self.head.clone() + longest_common_prefix(&self.matches).as_str(),
+        }
+    }
+}
+
+fn longest_common_prefix(words: &[String]) -> String {
+    let Some(first) = words.first() else {
+        return String::new();
+    };
+
+    let mut len = first.len();
+    for word in &words[1..] {
+        len = first
+            .chars()
+            .zip(word.chars())
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a.len_utf8())
+            .sum::<usize>()
+            .min(len);
+    }
+    first[..len].to_string()
+}
+
+/// A command implementation, turning a parsed command into an action
+pub type CommandHandler = fn(&Command, &CommandContext) -> anyhow::Result<Option<Action>>;
+
+/// `:{number}` jumps to a line
+fn goto_line(command: &Command, ctx: &CommandContext) -> anyhow::Result<Option<Action>> {
+    let y = match command.range {
+        Some(CommandRange::Line(address)) => ctx.resolve_address(address)?,
+        Some(CommandRange::Lines(_, address)) => ctx.resolve_address(address)?,
+        Some(CommandRange::All) => ctx.line_count.saturating_sub(1),
+        None => return Ok(None),
+    };
+    Ok(Some(Action::Builtin(BuiltinAction::GotoLine(y))))
+}
+
+/// `:[range]d` deletes lines
+fn delete_lines(command: &Command, ctx: &CommandContext) -> anyhow::Result<Option<Action>> {
+    let (start, end) = ctx.resolve_range(command.range)?;
+    Ok(Some(Action::DeleteLines(start, end)))
+}
+
+/// `:ll [n]` goes to the diagnostic numbered `n` in the location list, the first by default
+fn location(command: &Command, _: &CommandContext) -> anyhow::Result<Option<Action>> {
+    let args = command.args.trim();
+    let n = if args.is_empty() {
+        1
+    } else {
+        let Ok(n) = args.parse::<usize>() else {
+            bail!("E488: Trailing characters: {}", args);
+        };
+        n
+    };
+    Ok(Some(Action::JumpToLocation(LocationJump::Nth(
+        n.saturating_sub(1),
+    ))))
+}
+
+/// `:set {option}` changes options
+fn set_options(command: &Command, _: &CommandContext) -> anyhow::Result<Option<Action>> {
+    Ok(Some(Action::SetOptions(command.args.clone())))
+}
+
+/// `:!{cmd}` runs a shell command, `:{range}!{cmd}` filters lines through it
+fn shell(command: &Command, ctx: &CommandContext) -> anyhow::Result<Option<Action>> {
+    if command.args.trim().is_empty() {
+        bail!("E471: Argument required");
+    }
+    if command.range.is_some() {
+        let (start, end) = ctx.resolve_range(command.range)?;
+        return Ok(Some(Action::Filter(start, end, command.args.clone())));
+    }
+    Ok(Some(Action::Shell(command.args.clone())))
+}
+
+/// `:[line]r {file}` and `:[line]r !{cmd}` insert text below a line
+fn read(command: &Command, ctx: &CommandContext) -> anyhow::Result<Option<Action>> {
+    let at = match command.range {
+        None => ctx.current_line + 1,
+        Some(CommandRange::All) => ctx.line_count,
+        Some(CommandRange::Line(address)) | Some(CommandRange::Lines(_, address)) => {
+            // `:0r` inserts above the first line
+            if address == Address::new(AddressBase::Number(0)) {
+                0
+            } else {
+                ctx.resolve_address(address)? + 1
+            }
+        }
+    };
+
+    let args = command.args.trim();
+    if command.bang {
+        return Ok(Some(Action::ReadShell(at, args.to_string())));
+    }
+    if let Some(shell_command) = args.strip_prefix('!') {
+        return Ok(Some(Action::ReadShell(
+            at,
+            shell_command.trim().to_string(),
+        )));
+    }
+    if args.is_empty() {
+        bail!("E32: No file name");
+    }
+    Ok(Some(Action::ReadFile(at, args.to_string())))
+}
+
+/// `:map {lhs} {rhs}` and its variants, `prefix` selecting the modes
+fn map_keys(command: &Command, prefix: &str, noremap: bool) -> anyhow::Result<Option<Action>> {
+    let modes = MapMode::parse(prefix)?;
+    let Some((lhs, rhs)) = command.args.trim().split_once(char::is_whitespace) else {
+        bail!("E474: Invalid argument");
+    };
+    Ok(Some(Action::Map {
+        modes,
+        lhs: parse_keys(lhs)?,
+        rhs: rhs.trim().to_string(),
+        noremap,
+    }))
+}
+
+/// `:unmap {lhs}` and its variants
+fn unmap_keys(command: &Command, prefix: &str) -> anyhow::Result<Option<Action>> {
+    let modes = MapMode::parse(prefix)?;
+    Ok(Some(Action::Unmap {
+        modes,
+        lhs: parse_keys(command.args.trim())?,
+    }))
+}
+
+/// `:[range]sort[!] [n][u]` sorts lines, the whole buffer without a range
+fn sort(command: &Command, ctx: &CommandContext) -> anyhow::Result<Option<Action>> {
+    let range = command.range.or(Some(CommandRange::All));
+    let (start, end) = ctx.resolve_range(range)?;
+    let options = SortOptions::parse(command.bang, &command.args)?;
+    Ok(Some(Action::Sort(start, end, options)))
+}
+
+/// Split `/pattern/rest` at the first unescaped delimiter, returning the
+/// pattern (with `\/` unescaped) and the text after it
+fn take_delimited(input: &str) -> anyhow::Result<(String, &str)> {
+    let mut chars = input.char_indices();
+    let Some((_, delim)) = chars.next() else {
+        bail!("E476: Invalid command");
+    };
+    if delim.is_alphanumeric() || delim.is_whitespace() || delim == '\\' {
+        bail!("E146: Regular expressions can't be delimited by letters");
+    }
+
+    let mut pattern = String::new();
+    let mut escaped = false;
+    for (i, ch) in chars {
+        if escaped {
+            if ch != delim {
+                pattern.push('\\');
+            }
+            pattern.push(ch);
+            escaped = false;
+        } else if ch == '\\' {
+            escaped = true;
+        } else if ch == delim {
+            return Ok((pattern, &input[i + ch.len_utf8()..]));
+        } else {
+            pattern.push(ch);
+        }
+    }
+
+    // The closing delimiter may be left out
+    Ok((pattern, ""))
+}
+
+/// `:[range]s/{pattern}/{string}/[g]`
+fn substitute(command: &Command, ctx: &CommandContext) -> anyhow::Result<Option<Action>> {
+    let (start, end) = ctx.resolve_range(command.range)?;
+    let (pattern, rest) = take_delimited(&command.args)?;
+    let delim = command.args.chars().next().unwrap_or('/');
+    let rest = format!("{}{}", delim, rest);
+    let (replacement, flags) = take_delimited(&rest)?;
+
+    let mut all = false;
+    for flag in flags.trim().chars() {
+        match flag {
+            'g' => all = true,
+            _ => bail!("E488: Trailing characters: {}", flags),
+        }
+    }
+
+    Ok(Some(Action::Substitute {
+        start,
+        end,
+        pattern,
+        replacement,
+        all,
+    }))
+}
+
+/// `:[range]g/{pattern}/{cmd}` and `:v`, running a command on (non-)matching lines
+fn global(command: &Command, ctx: &CommandContext, invert: bool) -> anyhow::Result<Option<Action>> {
+    let range = command.range.or(Some(CommandRange::All));
+    let (start, end) = ctx.resolve_range(range)?;
+    let (pattern, rest) = take_delimited(&command.args)?;
+
+    Ok(Some(Action::Global {
+        start,
+        end,
+        pattern,
+        invert: invert != command.bang,
+        command: rest.trim().to_string(),
+    }))
+}
+
+/// `:[range]y` yanks lines
+fn yank_lines(command: &Command, ctx: &CommandContext) -> anyhow::Result<Option<Action>> {
+    let (start, end) = ctx.resolve_range(command.range)?;
+    Ok(Some(Action::YankLines(start, end)))
+}
+
+/// `:plugin list`, `:plugin enable {name}` and `:plugin disable {name}`
+fn plugin(command: &Command, _: &CommandContext) -> anyhow::Result<Option<Action>> {
+    let mut args = command.args.split_whitespace();
+    let action = match (args.next(), args.next(), args.next()) {
+        (None | Some("list"), None, None) => PluginAction::List,
+        (Some("enable"), Some(name), None) => PluginAction::Enable(name.to_string()),
+        (Some("disable"), Some(name), None) => PluginAction::Disable(name.to_string()),
+        _ => bail!("E475: Invalid argument: {}", command.args.trim()),
+    };
+    Ok(Some(Action::Plugin(action)))
+}
+
+#[derive(Debug)]
+pub struct CommandEntry {
+    /// The spec the command was registered with, e.g. `w[rite]`
+    pub spec: String,
+    /// Shown in `:help`
+    pub description: String,
+    /// Length of the shortest accepted abbreviation
+    min_len: usize,
+    target: CommandTarget,
+}
+
+/// What a command runs
+#[derive(Debug)]
+enum CommandTarget {
+    Handler(CommandHandler),
+    /// An action that takes no range or arguments, like the commands of `config.toml`
+    Action(Action),
+}
+
+/// Split a command spec in vim's `com[mand]` notation into the name and the
+/// length of its shortest abbreviation
+fn parse_command_spec(spec: &str) -> (String, usize) {
+    match spec.split_once('[') {
+        Some((short, rest)) => (
+            format!("{}{}", short, rest.trim_end_matches(']')),
+            short.len(),
+        ),
+        None => (spec.to_string(), spec.len()),
+    }
+}
 
 #[derive(Debug)]
 pub struct CommandMap {
-    map: HashMap<String, Action>,
+    map: HashMap<String, CommandEntry>,
 }
 
 impl CommandMap {
-    /// Register a command name to an action
-    pub fn reg(&mut self, name: &str, action: Action) {
-        self.map.insert(name.to_string(), action);
+    /// Register a command to a handler. The spec may declare abbreviations
+    /// like `w[rite]`, which accepts `w`, `wr`, ... `write`.
+    pub fn reg(&mut self, spec: &str, description: &str, handler: CommandHandler) {
+        let (name, min_len) = parse_command_spec(spec);
+        self.map.insert(
+            name,
+            CommandEntry {
+                spec: spec.to_string(),
+                description: description.to_string(),
+                min_len,
+                target: CommandTarget::Handler(handler),
+            },
+        );
+    }
+
+    /// Register a command that runs an action. It can be abbreviated to any unique prefix.
+    pub fn reg_action(&mut self, name: &str, description: &str, action: Action) {
+        self.map.insert(
+            name.to_string(),
+            CommandEntry {
+                spec: name.to_string(),
+                description: description.to_string(),
+                min_len: name.len(),
+                target: CommandTarget::Action(action),
+            },
+        );
     }
 
-    pub fn get(&self, name: &str) -> Option<&Action> {
+    pub fn get_entry(&self, name: &str) -> Option<&CommandEntry> {
         self.map.get(name)
     }
+
+    /// Resolve a typed command name to the canonical name of a command.
+    /// Declared abbreviations are tried first, then the shortest unique prefix.
+    pub fn resolve(&self, name: &str) -> anyhow::Result<Option<String>> {
+        if self.map.contains_key(name) {
+            return Ok(Some(name.to_string()));
+        }
+        if name.is_empty() {
+            return Ok(None);
+        }
+
+        let prefixed: Vec<(&String, &CommandEntry)> = self
+            .map
+            .iter()
+            .filter(|(canonical, _)| canonical.starts_with(name))
+            .collect();
+        let declared: Vec<&String> = prefixed
+            .iter()
+            .filter(|(_, entry)| name.len() >= entry.min_len)
+            .map(|(canonical, _)| *canonical)
+            .collect();
+
+        let matches = if declared.is_empty() {
+            prefixed.iter().map(|(canonical, _)| *canonical).collect()
+        } else {
+            declared
+        };
+        match matches.as_slice() {
+            [] => Ok(None),
+            [canonical] => Ok(Some(canonical.to_string())),
+            _ => bail!("E464: Ambiguous command: {}", name),
+        }
+    }
+
+    /// Get all registered command names, sorted
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .map
+            .keys()
+            .filter(|name| !name.is_empty())
+            .cloned()
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Parse a command line and run the matching handler
+    pub fn dispatch(&self, input: &str, ctx: &CommandContext) -> anyhow::Result<Option<Action>> {
+        let command = parse_command(input)?;
+        let Some(name) = self.resolve(&command.name)? else {
+            return Ok(None);
+        };
+        match &self.map[&name].target {
+            CommandTarget::Handler(handler) => handler(&command, ctx),
+            CommandTarget::Action(_) if command.range.is_some() => {
+                bail!("E481: No range allowed")
+            }
+            CommandTarget::Action(_) if command.bang || !command.args.trim().is_empty() => {
+                bail!("E488: Trailing characters")
+            }
+            CommandTarget::Action(action) => Ok(Some(action.clone())),
+        }
+    }
 }
 
 impl Default for CommandMap {
@@ -26,9 +691,619 @@ impl Default for CommandMap {
             map: HashMap::default(),
         };
 
-        s.reg("q", Action::Quit);
-        s.reg("w", Action::Builtin(BuiltinAction::Save));
+        s.reg("", "jump to a line", goto_line);
+        s.reg("d[elete]", "delete lines", delete_lines);
+        s.reg("y[ank]", "yank lines", yank_lines);
+        s.reg("se[t]", "show or change options", set_options);
+        s.reg("!", "run a shell command or filter lines", shell);
+        s.reg("sor[t]", "sort lines", sort);
+        s.reg("s[ubstitute]", "replace text in lines", substitute);
+        s.reg("g[lobal]", "run a command on matching lines", |c, ctx| {
+            global(c, ctx, false)
+        });
+        s.reg(
+            "v[global]",
+            "run a command on lines that do not match",
+            |c, ctx| global(c, ctx, true),
+        );
+        s.reg("r[ead]", "insert a file or command output", read);
+        s.reg("plug[in]", "list, enable or disable plugins", plugin);
+        s.reg("bn[ext]", "go to the next file", |_, _| {
+            Ok(Some(Action::NextBuffer(true)))
+        });
+        s.reg("bp[revious]", "go to the previous file", |_, _| {
+            Ok(Some(Action::NextBuffer(false)))
+        });
+        s.reg("noh[lsearch]", "hide search highlights", |_, _| {
+            Ok(Some(Action::NoHighlight))
+        });
+        s.reg("lli[st]", "list the diagnostics of the buffer", |_, _| {
+            Ok(Some(Action::LocationList))
+        });
+        s.reg("ll", "go to a diagnostic of the buffer", location);
+        s.reg("lne[xt]", "go to the next diagnostic", |_, _| {
+            Ok(Some(Action::JumpToLocation(LocationJump::Next(true))))
+        });
+        s.reg("lp[revious]", "go to the previous diagnostic", |_, _| {
+            Ok(Some(Action::JumpToLocation(LocationJump::Next(false))))
+        });
+        s.reg("map", "map keys in normal and visual mode", |c, _| {
+            map_keys(c, "", false)
+        });
+        s.reg("nm[ap]", "map keys in normal mode", |c, _| {
+            map_keys(c, "n", false)
+        });
+        s.reg("vm[ap]", "map keys in visual mode", |c, _| {
+            map_keys(c, "v", false)
+        });
+        s.reg("im[ap]", "map keys in insert mode", |c, _| {
+            map_keys(c, "i", false)
+        });
+        s.reg("no[remap]", "map keys without remapping", |c, _| {
+            map_keys(c, "", true)
+        });
+        s.reg(
+            "nn[oremap]",
+            "map keys in normal mode without remapping",
+            |c, _| map_keys(c, "n", true),
+        );
+        s.reg(
+            "vn[oremap]",
+            "map keys in visual mode without remapping",
+            |c, _| map_keys(c, "v", true),
+        );
+        s.reg(
+            "ino[remap]",
+            "map keys in insert mode without remapping",
+            |c, _| map_keys(c, "i", true),
+        );
+        s.reg(
+            "unm[ap]",
+            "remove a mapping in normal and visual mode",
+            |c, _| unmap_keys(c, ""),
+        );
+        s.reg("nun[map]", "remove a normal mode mapping", |c, _| {
+            unmap_keys(c, "n")
+        });
+        s.reg("vu[nmap]", "remove a visual mode mapping", |c, _| {
+            unmap_keys(c, "v")
+        });
+        s.reg("iu[nmap]", "remove an insert mode mapping", |c, _| {
+            unmap_keys(c, "i")
+        });
+        s.reg(
+            "ea[rlier]",
+            "go back in time through the undo history",
+            |c, _| Ok(Some(Action::TimeTravel(UndoAmount::parse(&c.args)?, false))),
+        );
+        s.reg(
+            "lat[er]",
+            "go forward in time through the undo history",
+            |c, _| Ok(Some(Action::TimeTravel(UndoAmount::parse(&c.args)?, true))),
+        );
+        s.reg("mes[sages]", "show past messages", |_, _| {
+            Ok(Some(Action::Messages))
+        });
+        s.reg("h[elp]", "open this help", |command, _| {
+            let topic = command.args.trim();
+            Ok(Some(Action::Help(
+                (!topic.is_empty()).then(|| topic.to_string()),
+            )))
+        });
+        s.reg(
+            "q[uit]",
+            "close the window, quit if it is the last",
+            |command, _| Ok(Some(Action::Quit(command.bang))),
+        );
+        s.reg("qa[ll]", "quit", |command, _| {
+            Ok(Some(Action::QuitAll(command.bang)))
+        });
+        s.reg("w[rite]", "save the buffer", |_, _| {
+            Ok(Some(Action::Builtin(BuiltinAction::Save)))
+        });
+        s.reg("wq", "save and quit", |_, _| Ok(Some(Action::WriteQuit)));
+        s.reg("wqa[ll]", "save and quit", |_, _| {
+            Ok(Some(Action::WriteQuit))
+        });
 
         s
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ui::input::KeyCode;
+
+    fn command(range: Option<CommandRange>, name: &str, bang: bool, args: &str) -> Command {
+        Command {
+            range,
+            name: name.to_string(),
+            bang,
+            args: args.to_string(),
+        }
+    }
+
+    fn line(number: usize) -> Address {
+        Address::new(AddressBase::Number(number))
+    }
+
+    #[test]
+    fn test_parse_name() {
+        assert_eq!(parse_command("w").unwrap(), command(None, "w", false, ""));
+        assert_eq!(parse_command("wq").unwrap(), command(None, "wq", false, ""));
+        assert_eq!(parse_command("w ").unwrap(), command(None, "w", false, ""));
+        assert_eq!(parse_command("  q").unwrap(), command(None, "q", false, ""));
+        assert_eq!(parse_command("").unwrap(), command(None, "", false, ""));
+    }
+
+    #[test]
+    fn test_parse_bang_and_args() {
+        assert_eq!(parse_command("q!").unwrap(), command(None, "q", true, ""));
+        assert_eq!(
+            parse_command("w file.txt").unwrap(),
+            command(None, "w", false, "file.txt")
+        );
+        assert_eq!(
+            parse_command("w! dir/ファイル.txt ").unwrap(),
+            command(None, "w", true, "dir/ファイル.txt ")
+        );
+        assert_eq!(
+            parse_command("s/a/b/g").unwrap(),
+            command(None, "s", false, "/a/b/g")
+        );
+        assert_eq!(
+            parse_command("!ls -la").unwrap(),
+            command(None, "!", false, "ls -la")
+        );
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(
+            parse_command("5").unwrap(),
+            command(Some(CommandRange::Line(line(5))), "", false, "")
+        );
+        assert_eq!(
+            parse_command("%d").unwrap(),
+            command(Some(CommandRange::All), "d", false, "")
+        );
+        assert_eq!(
+            parse_command("1,10d").unwrap(),
+            command(Some(CommandRange::Lines(line(1), line(10))), "d", false, "")
+        );
+        assert_eq!(
+            parse_command(".,$y").unwrap(),
+            command(
+                Some(CommandRange::Lines(
+                    Address::new(AddressBase::Current),
+                    Address::new(AddressBase::Last)
+                )),
+                "y",
+                false,
+                ""
+            )
+        );
+        assert_eq!(
+            parse_command("3 w! out").unwrap(),
+            command(Some(CommandRange::Line(line(3))), "w", true, "out")
+        );
+    }
+
+    #[test]
+    fn test_parse_offsets() {
+        assert_eq!(
+            parse_command(".+3").unwrap().range,
+            Some(CommandRange::Line(Address {
+                base: AddressBase::Current,
+                offset: 3
+            }))
+        );
+        assert_eq!(
+            parse_command("$-1,$").unwrap().range,
+            Some(CommandRange::Lines(
+                Address {
+                    base: AddressBase::Last,
+                    offset: -1
+                },
+                Address::new(AddressBase::Last)
+            ))
+        );
+        assert_eq!(
+            parse_command("-").unwrap().range,
+            Some(CommandRange::Line(Address {
+                base: AddressBase::Current,
+                offset: -1
+            }))
+        );
+        assert_eq!(
+            parse_command("5++").unwrap().range,
+            Some(CommandRange::Line(Address {
+                base: AddressBase::Number(5),
+                offset: 2
+            }))
+        );
+    }
+
+    #[test]
+    fn test_parse_marks() {
+        assert_eq!(
+            parse_command("'<,'>d").unwrap(),
+            command(
+                Some(CommandRange::Lines(
+                    Address::new(AddressBase::Mark('<')),
+                    Address::new(AddressBase::Mark('>'))
+                )),
+                "d",
+                false,
+                ""
+            )
+        );
+        assert!(parse_command("'a,'bd").is_err());
+    }
+
+    #[test]
+    fn test_parse_invalid_range() {
+        assert!(parse_command(",5d").is_err());
+        assert!(parse_command("1,d").is_err());
+        assert!(parse_command("99999999999999999999999").is_err());
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let map = CommandMap::default();
+        let ctx = CommandContext::default();
+        assert!(matches!(
+            map.dispatch("q", &ctx),
+            Ok(Some(Action::Quit(false)))
+        ));
+        assert!(matches!(
+            map.dispatch("w ", &ctx),
+            Ok(Some(Action::Builtin(BuiltinAction::Save)))
+        ));
+        assert!(matches!(map.dispatch("nonexistent", &ctx), Ok(None)));
+    }
+
+    fn goto_target(input: &str, ctx: &CommandContext) -> Option<usize> {
+        match CommandMap::default().dispatch(input, ctx) {
+            Ok(Some(Action::Builtin(BuiltinAction::GotoLine(y)))) => Some(y),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_goto_line() {
+        let ctx = CommandContext {
+            current_line: 4,
+            line_count: 10,
+            ..Default::default()
+        };
+        assert_eq!(goto_target("0", &ctx), Some(0));
+        assert_eq!(goto_target("1", &ctx), Some(0));
+        assert_eq!(goto_target("5", &ctx), Some(4));
+        assert_eq!(goto_target("42", &ctx), Some(9));
+        assert_eq!(goto_target("$", &ctx), Some(9));
+        assert_eq!(goto_target(".+2", &ctx), Some(6));
+        assert_eq!(goto_target("", &ctx), None);
+    }
+
+    fn resolve(input: &str, ctx: &CommandContext) -> anyhow::Result<(usize, usize)> {
+        ctx.resolve_range(parse_command(input)?.range)
+    }
+
+    #[test]
+    fn test_resolve_range() {
+        let ctx = CommandContext {
+            current_line: 2,
+            line_count: 10,
+            visual_lines: Some((3, 5)),
+        };
+        assert_eq!(resolve("d", &ctx).unwrap(), (2, 2));
+        assert_eq!(resolve("1,5d", &ctx).unwrap(), (0, 4));
+        assert_eq!(resolve("%d", &ctx).unwrap(), (0, 9));
+        assert_eq!(resolve(".,+3y", &ctx).unwrap(), (2, 5));
+        assert_eq!(resolve(".,$y", &ctx).unwrap(), (2, 9));
+        assert_eq!(resolve("'<,'>d", &ctx).unwrap(), (3, 5));
+    }
+
+    #[test]
+    fn test_resolve_range_reversed_and_clamped() {
+        let ctx = CommandContext {
+            current_line: 2,
+            line_count: 10,
+            visual_lines: None,
+        };
+        assert_eq!(resolve("5,1d", &ctx).unwrap(), (0, 4));
+        assert_eq!(resolve("5,100d", &ctx).unwrap(), (4, 9));
+        assert_eq!(resolve("0,3d", &ctx).unwrap(), (0, 2));
+        assert_eq!(resolve(".-10,.d", &ctx).unwrap(), (0, 2));
+        assert!(resolve("'<,'>d", &ctx).is_err());
+    }
+
+    #[test]
+    fn test_dispatch_range_commands() {
+        let map = CommandMap::default();
+        let ctx = CommandContext {
+            current_line: 0,
+            line_count: 3,
+            visual_lines: None,
+        };
+        assert!(matches!(
+            map.dispatch("%d", &ctx),
+            Ok(Some(Action::DeleteLines(0, 2)))
+        ));
+        assert!(matches!(
+            map.dispatch(".,+1y", &ctx),
+            Ok(Some(Action::YankLines(0, 1)))
+        ));
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_read() {
+        let map = CommandMap::default();
+        let ctx = CommandContext {
+            current_line: 2,
+            line_count: 5,
+            visual_lines: None,
+        };
+        assert!(matches!(
+            map.dispatch("r file.txt", &ctx),
+            Ok(Some(Action::ReadFile(3, path))) if path == "file.txt"
+        ));
+        assert!(matches!(
+            map.dispatch("0r file.txt", &ctx),
+            Ok(Some(Action::ReadFile(0, path))) if path == "file.txt"
+        ));
+        assert!(matches!(
+            map.dispatch("$r !echo hi", &ctx),
+            Ok(Some(Action::ReadShell(5, cmd))) if cmd == "echo hi"
+        ));
+        assert!(matches!(
+            map.dispatch("1r!ls", &ctx),
+            Ok(Some(Action::ReadShell(1, cmd))) if cmd == "ls"
+        ));
+        assert!(map.dispatch("r", &ctx).is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let map = CommandMap::default();
+        let ctx = CommandContext {
+            current_line: 0,
+            line_count: 5,
+            visual_lines: Some((1, 3)),
+        };
+        assert!(matches!(
+            map.dispatch("'<,'>!sort", &ctx),
+            Ok(Some(Action::Filter(1, 3, cmd))) if cmd == "sort"
+        ));
+        assert!(matches!(
+            map.dispatch("!ls", &ctx),
+            Ok(Some(Action::Shell(cmd))) if cmd == "ls"
+        ));
+    }
+
+    #[test]
+    fn test_parse_command_spec() {
+        assert_eq!(parse_command_spec("w[rite]"), ("write".to_string(), 1));
+        assert_eq!(parse_command_spec("wqa[ll]"), ("wqall".to_string(), 3));
+        assert_eq!(parse_command_spec("sort"), ("sort".to_string(), 4));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut map = CommandMap {
+            map: HashMap::default(),
+        };
+        map.reg("s[ubstitute]", "", |_, _| Ok(None));
+        map.reg("sp[lit]", "", |_, _| Ok(None));
+        map.reg("se[t]", "", |_, _| Ok(None));
+        map.reg("sort", "", |_, _| Ok(None));
+        map.reg("sou[rce]", "", |_, _| Ok(None));
+
+        let resolve = |name: &str| map.resolve(name).unwrap();
+        assert_eq!(resolve("s").as_deref(), Some("substitute"));
+        assert_eq!(resolve("su").as_deref(), Some("substitute"));
+        assert_eq!(resolve("sp").as_deref(), Some("split"));
+        assert_eq!(resolve("spl").as_deref(), Some("split"));
+        assert_eq!(resolve("se").as_deref(), Some("set"));
+        assert_eq!(resolve("sor").as_deref(), Some("sort"));
+        assert_eq!(resolve("sou").as_deref(), Some("source"));
+        assert_eq!(resolve("x"), None);
+        assert!(map.resolve("so").is_err());
+    }
+
+    #[test]
+    fn test_dispatch_abbreviations() {
+        let map = CommandMap::default();
+        let ctx = CommandContext {
+            current_line: 0,
+            line_count: 3,
+            visual_lines: None,
+        };
+        assert!(matches!(
+            map.dispatch("wri", &ctx),
+            Ok(Some(Action::Builtin(BuiltinAction::Save)))
+        ));
+        assert!(matches!(
+            map.dispatch("qa", &ctx),
+            Ok(Some(Action::QuitAll(false)))
+        ));
+        assert!(matches!(
+            map.dispatch("wqa", &ctx),
+            Ok(Some(Action::WriteQuit))
+        ));
+        assert!(matches!(
+            map.dispatch("wq", &ctx),
+            Ok(Some(Action::WriteQuit))
+        ));
+        assert!(matches!(
+            map.dispatch("del", &ctx),
+            Ok(Some(Action::DeleteLines(0, 0)))
+        ));
+    }
+
+    #[test]
+    fn test_sort_command() {
+        let map = CommandMap::default();
+        let ctx = CommandContext {
+            current_line: 1,
+            line_count: 5,
+            visual_lines: Some((1, 2)),
+        };
+        assert!(matches!(
+            map.dispatch("sort", &ctx),
+            Ok(Some(Action::Sort(0, 4, options))) if options == SortOptions::default()
+        ));
+        assert!(matches!(
+            map.dispatch("'<,'>sort! nu", &ctx),
+            Ok(Some(Action::Sort(
+                1,
+                2,
+                SortOptions {
+                    reverse: true,
+                    unique: true,
+                    numeric: true
+                }
+            )))
+        ));
+        assert!(map.dispatch("sort x", &ctx).is_err());
+    }
+
+    #[test]
+    fn test_substitute_command() {
+        let map = CommandMap::default();
+        let ctx = CommandContext {
+            current_line: 1,
+            line_count: 5,
+            visual_lines: None,
+        };
+        assert!(matches!(
+            map.dispatch("%s/a\\/b/c/g", &ctx),
+            Ok(Some(Action::Substitute { start: 0, end: 4, pattern, replacement, all: true }))
+                if pattern == "a/b" && replacement == "c"
+        ));
+        assert!(matches!(
+            map.dispatch("s#x#y", &ctx),
+            Ok(Some(Action::Substitute { start: 1, end: 1, pattern, replacement, all: false }))
+                if pattern == "x" && replacement == "y"
+        ));
+        assert!(map.dispatch("s/a/b/z", &ctx).is_err());
+        assert!(map.dispatch("s", &ctx).is_err());
+    }
+
+    #[test]
+    fn test_global_command() {
+        let map = CommandMap::default();
+        let ctx = CommandContext {
+            current_line: 1,
+            line_count: 5,
+            visual_lines: None,
+        };
+        assert!(matches!(
+            map.dispatch("g/foo/s/a/b/", &ctx),
+            Ok(Some(Action::Global { start: 0, end: 4, pattern, invert: false, command }))
+                if pattern == "foo" && command == "s/a/b/"
+        ));
+        assert!(matches!(
+            map.dispatch("v/foo/d", &ctx),
+            Ok(Some(Action::Global { invert: true, .. }))
+        ));
+        assert!(matches!(
+            map.dispatch("g!/foo/d", &ctx),
+            Ok(Some(Action::Global { invert: true, .. }))
+        ));
+    }
+
+    #[test]
+    fn test_map_commands() {
+        let map = CommandMap::default();
+        let ctx = CommandContext::default();
+        assert!(matches!(
+            map.dispatch("nmap <leader>w :w<CR>", &ctx),
+            Ok(Some(Action::Map { modes, lhs, rhs, noremap: false }))
+                if modes == vec![MapMode::Normal]
+                    && lhs == vec![KeyCode::Leader, KeyCode::Char('w')]
+                    && rhs == ":w<CR>"
+        ));
+        assert!(matches!(
+            map.dispatch("ino jk <Esc>", &ctx),
+            Ok(Some(Action::Map { modes, noremap: true, .. })) if modes == vec![MapMode::Insert]
+        ));
+        assert!(matches!(
+            map.dispatch("unmap x", &ctx),
+            Ok(Some(Action::Unmap { modes, .. })) if modes.len() == 2
+        ));
+        assert!(map.dispatch("nmap x", &ctx).is_err());
+    }
+
+    #[test]
+    fn test_plugin_command() {
+        let map = CommandMap::default();
+        let ctx = CommandContext::default();
+        let plugin = |input| match map.dispatch(input, &ctx) {
+            Ok(Some(Action::Plugin(action))) => Some(action),
+            _ => None,
+        };
+        assert_eq!(plugin("plugin"), Some(PluginAction::List));
+        assert_eq!(plugin("plug list"), Some(PluginAction::List));
+        assert_eq!(
+            plugin("plugin disable clock"),
+            Some(PluginAction::Disable("clock".to_string()))
+        );
+        assert_eq!(
+            plugin("plugin enable clock"),
+            Some(PluginAction::Enable("clock".to_string()))
+        );
+        assert_eq!(plugin("plugin enable"), None);
+        assert_eq!(plugin("plugin stop clock"), None);
+    }
+
+    #[test]
+    fn test_complete_single_match() {
+        let names = names(&["quit", "set", "write"]);
+        let mut completion = CommandCompletion::new("wr", &names).unwrap();
+        assert_eq!(completion.get_text(), "write");
+        completion.next();
+        assert_eq!(completion.get_text(), "write");
+
+        let completion = CommandCompletion::new("1,5s", &names).unwrap();
+        assert_eq!(completion.get_text(), "1,5set");
+    }
+
+    #[test]
+    fn test_complete_multiple_matches() {
+        let names = names(&["sort", "split", "splitright", "substitute"]);
+        let mut completion = CommandCompletion::new("sp", &names).unwrap();
+        assert_eq!(completion.get_text(), "split");
+        completion.next();
+        assert_eq!(completion.get_text(), "split");
+        completion.next();
+        assert_eq!(completion.get_text(), "splitright");
+        completion.next();
+        assert_eq!(completion.get_text(), "split");
+
+        let mut completion = CommandCompletion::new("s", &names).unwrap();
+        assert_eq!(completion.get_text(), "sort");
+        completion.next();
+        assert_eq!(completion.get_text(), "split");
+
+        let names = self::names(&["noremap", "nohlsearch"]);
+        let mut completion = CommandCompletion::new("n", &names).unwrap();
+        assert_eq!(completion.get_text(), "no");
+        completion.next();
+        assert_eq!(completion.get_text(), "noremap");
+    }
+
+    #[test]
+    fn test_complete_no_match() {
+        let names = names(&["quit", "write"]);
+        assert_eq!(CommandCompletion::new("x", &names), None);
+        assert_eq!(CommandCompletion::new("w file", &names), None);
+        assert_eq!(CommandCompletion::new("q!", &names), None);
+    }
+}
@@ -1,5 +1,10 @@
-use std::collections::HashMap;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt::{self, Display},
+};
 
+use anyhow::bail;
 use api::{Mode, Position};
 use builtin::BuiltinAction;
 use chrono::{DateTime, Duration, Local};
@@ -8,7 +13,7 @@ use utils::vec2::IVec2;
 
 use crate::action::Action;
 
-#[derive(Debug, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
 pub enum KeyCode {
     Char(char),
     Ctrl(char),
@@ -18,64 +23,355 @@ pub enum KeyCode {
     Right,
     Up,
     Down,
+    Home,
+    End,
+    PageUp,
+    PageDown,
     Esc,
+    /// `<leader>` in a mapping, standing for the key of `mapleader`
+    Leader,
 }
 
-type Key = Vec<KeyCode>;
+impl Display for KeyCode {
+    /// Key notation as used in `:help`, e.g. `x`, `<C-r>`, `<Esc>`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyCode::Char('\n') => write!(f, "<CR>"),
+            KeyCode::Char('\t') => write!(f, "<Tab>"),
+            KeyCode::Char(' ') => write!(f, "<Space>"),
+            KeyCode::Char(ch) => write!(f, "{}", ch),
+            KeyCode::Ctrl(ch) => write!(f, "<C-{}>", ch),
+            KeyCode::Backspace => write!(f, "<BS>"),
+            KeyCode::Delete => write!(f, "<Del>"),
+            KeyCode::Left => write!(f, "<Left>"),
+            KeyCode::Right => write!(f, "<Right>"),
+            KeyCode::Up => write!(f, "<Up>"),
+            KeyCode::Down => write!(f, "<Down>"),
+            KeyCode::Home => write!(f, "<Home>"),
+            KeyCode::End => write!(f, "<End>"),
+            KeyCode::PageUp => write!(f, "<PageUp>"),
+            KeyCode::PageDown => write!(f, "<PageDown>"),
+            KeyCode::Esc => write!(f, "<Esc>"),
+            KeyCode::Leader => write!(f, "<leader>"),
+        }
+    }
+}
+
+pub type Key = Vec<KeyCode>;
+
+/// The default of `mapleader`
+pub const DEFAULT_LEADER: char = ' ';
+
+/// Parse key notation such as `<leader>w`, `:w<CR>` or `<C-x>j` into keys
+pub fn parse_keys(notation: &str) -> anyhow::Result<Key> {
+    let mut keys = Vec::new();
+    let mut rest = notation;
+    while let Some(ch) = rest.chars().next() {
+        if ch == '<'
+            && let Some(end) = rest.find('>')
+        {
+            let name = &rest[1..end];
+            let key = match name.to_ascii_lowercase().as_str() {
+                "cr" | "enter" | "return" => Some(KeyCode::Char('\n')),
+                "esc" => Some(KeyCode::Esc),
+                "tab" => Some(KeyCode::Char('\t')),
+                "space" => Some(KeyCode::Char(' ')),
+                "bs" => Some(KeyCode::Backspace),
+                "del" => Some(KeyCode::Delete),
+                "left" => Some(KeyCode::Left),
+                "right" => Some(KeyCode::Right),
+                "up" => Some(KeyCode::Up),
+                "down" => Some(KeyCode::Down),
+                "home" => Some(KeyCode::Home),
+                "end" => Some(KeyCode::End),
+                "pageup" => Some(KeyCode::PageUp),
+                "pagedown" => Some(KeyCode::PageDown),
+                "lt" => Some(KeyCode::Char('<')),
+                "leader" => Some(KeyCode::Leader),
+                lower => match lower.strip_prefix("c-") {
+                    Some(ctrl) if ctrl.chars().count() == 1 => {
+                        ctrl.chars().next().map(KeyCode::Ctrl)
+                    }
+                    _ => None,
+                },
+            };
+
+            if let Some(key) = key {
+                keys.push(key);
+                rest = &rest[end + 1..];
+                continue;
+            }
+        }
+
+        keys.push(KeyCode::Char(ch));
+        rest = &rest[ch.len_utf8()..];
+    }
+
+    if keys.is_empty() {
+        bail!("E474: Invalid argument");
+    }
+    Ok(keys)
+}
+
+/// Modes a mapping can be defined for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapMode {
+    Normal,
+    Visual,
+    Insert,
+}
+
+impl MapMode {
+    /// Parse the mode prefix of a mapping command (`n`, `v`, `i`, or empty for normal and visual)
+    pub fn parse(prefix: &str) -> anyhow::Result<Vec<Self>> {
+        Ok(match prefix {
+            "" => vec![MapMode::Normal, MapMode::Visual],
+            "n" => vec![MapMode::Normal],
+            "v" => vec![MapMode::Visual],
+            "i" => vec![MapMode::Insert],
+            _ => bail!("E474: Invalid argument"),
+        })
+    }
+
+    /// The mapping mode for an editor mode, if mappings apply to it
+    pub fn from_mode(mode: &Mode) -> Option<Self> {
+        match mode {
+            Mode::Normal => Some(MapMode::Normal),
+            Mode::Visual => Some(MapMode::Visual),
+            Mode::Insert(_) => Some(MapMode::Insert),
+            Mode::Command => None,
+        }
+    }
+
+    /// The built-in keymap of the mode, if it has one
+    pub fn default_keymap(self) -> Option<Keymap> {
+        match self {
+            MapMode::Normal => Some(Keymap::normal_default()),
+            MapMode::Visual => Some(Keymap::visual_default()),
+            MapMode::Insert => None,
+        }
+    }
+}
+
+/// Mappings added at runtime, looked up before the default keymaps
+#[derive(Debug, Default)]
+pub struct UserKeymaps {
+    pub normal: Keymap,
+    pub visual: Keymap,
+    pub insert: Keymap,
+}
+
+impl UserKeymaps {
+    pub fn set_leader(&mut self, leader: char) {
+        self.normal.set_leader(leader);
+        self.visual.set_leader(leader);
+        self.insert.set_leader(leader);
+    }
+
+    pub fn get(&self, mode: MapMode) -> &Keymap {
+        match mode {
+            MapMode::Normal => &self.normal,
+            MapMode::Visual => &self.visual,
+            MapMode::Insert => &self.insert,
+        }
+    }
+
+    pub fn get_mut(&mut self, mode: MapMode) -> &mut Keymap {
+        match mode {
+            MapMode::Normal => &mut self.normal,
+            MapMode::Visual => &mut self.visual,
+            MapMode::Insert => &mut self.insert,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KeymapEntry {
+    pub action: Action,
+    /// Shown in `:help`
+    pub description: String,
+    /// The keys as registered, which may contain the leader
+    keys: Key,
+}
 
 #[derive(Debug)]
 pub struct Keymap {
-    map: HashMap<Key, Action>,
+    /// Mappings by their keys with the leader resolved
+    map: HashMap<Key, KeymapEntry>,
+    leader: char,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self {
+            map: HashMap::default(),
+            leader: DEFAULT_LEADER,
+        }
+    }
 }
 
 impl Keymap {
     /// Register a key sequence to an action
-    pub fn reg(&mut self, key: Key, action: Action) {
-        self.map.insert(key, action);
+    pub fn reg(&mut self, key: Key, action: Action, description: &str) {
+        self.map.insert(
+            self.resolve(&key).into_owned(),
+            KeymapEntry {
+                action,
+                description: description.to_string(),
+                keys: key,
+            },
+        );
+    }
+
+    /// Replace `<leader>` in `key` with the leader key
+    fn resolve<'a>(&self, key: &'a Key) -> Cow<'a, Key> {
+        if !key.contains(&KeyCode::Leader) {
+            return Cow::Borrowed(key);
+        }
+        Cow::Owned(
+            key.iter()
+                .map(|code| match code {
+                    KeyCode::Leader => KeyCode::Char(self.leader),
+                    code => *code,
+                })
+                .collect(),
+        )
+    }
+
+    /// Change what `<leader>` stands for, in the mappings there are too
+    pub fn set_leader(&mut self, leader: char) {
+        if leader == self.leader {
+            return;
+        }
+        self.leader = leader;
+        let entries = std::mem::take(&mut self.map).into_values();
+        for entry in entries {
+            self.map
+                .insert(self.resolve(&entry.keys).into_owned(), entry);
+        }
     }
 
     pub fn get(&self, key: &Key) -> Option<&Action> {
-        self.map.get(key)
+        self.map.get(&*self.resolve(key)).map(|entry| &entry.action)
+    }
+
+    /// Remove a mapping, returning false if it did not exist
+    pub fn remove(&mut self, key: &Key) -> bool {
+        let key = self.resolve(key).into_owned();
+        self.map.remove(&key).is_some()
+    }
+
+    /// Keep only the mappings whose action matches `keep`
+    pub fn retain(&mut self, mut keep: impl FnMut(&Action) -> bool) {
+        self.map.retain(|_, entry| keep(&entry.action));
+    }
+
+    /// Whether `key` is the start of a longer mapping
+    pub fn has_prefix(&self, key: &Key) -> bool {
+        let key = self.resolve(key);
+        self.map
+            .keys()
+            .any(|mapped| mapped.len() > key.len() && mapped.starts_with(&key))
+    }
+
+    /// The mappings that start with `prefix`, as the keys that follow it
+    pub fn continuations(&self, prefix: &Key) -> Vec<(Key, &KeymapEntry)> {
+        let prefix = self.resolve(prefix);
+        self.map
+            .iter()
+            .filter(|(mapped, _)| mapped.len() > prefix.len() && mapped.starts_with(&prefix))
+            .map(|(mapped, entry)| (mapped[prefix.len()..].to_vec(), entry))
+            .collect()
+    }
+
+    /// Get all mappings as key notation and entry, sorted by key
+    pub fn entries(&self) -> Vec<(String, &KeymapEntry)> {
+        let mut entries: Vec<(String, &KeymapEntry)> = self
+            .map
+            .iter()
+            .map(|(key, entry)| (key.iter().map(|code| code.to_string()).collect(), entry))
+            .collect();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        entries
+    }
+
+    /// Arrows, Home, End and the page keys, moving like in other editors
+    fn reg_arrows(&mut self) {
+        for (key, offset, desc) in [
+            (KeyCode::Left, IVec2::left(), "move left"),
+            (KeyCode::Down, IVec2::down(), "move down"),
+            (KeyCode::Up, IVec2::up(), "move up"),
+            (KeyCode::Right, IVec2::right(), "move right"),
+        ] {
+            self.reg(
+                vec![key],
+                Action::Builtin(BuiltinAction::MoveBy(offset)),
+                desc,
+            );
+        }
+        self.reg(
+            vec![KeyCode::Home],
+            Action::Builtin(BuiltinAction::MoveToX(Position::Start)),
+            "move to the start of the line",
+        );
+        self.reg(
+            vec![KeyCode::End],
+            Action::Builtin(BuiltinAction::MoveToX(Position::End)),
+            "move to the end of the line",
+        );
+        self.reg(vec![KeyCode::PageUp], Action::Page(false), "move a page up");
+        self.reg(
+            vec![KeyCode::PageDown],
+            Action::Page(true),
+            "move a page down",
+        );
     }
 
     pub fn normal_default() -> Self {
-        let mut s = Self {
-            map: HashMap::default(),
-        };
+        let mut s = Self::default();
 
         // Cursor movement
         s.reg(
             vec![KeyCode::Char('h')],
             Action::Builtin(BuiltinAction::MoveBy(IVec2::left())),
+            "move left",
         );
         s.reg(
             vec![KeyCode::Char('j')],
             Action::Builtin(BuiltinAction::MoveBy(IVec2::down())),
+            "move down",
         );
         s.reg(
             vec![KeyCode::Char('k')],
             Action::Builtin(BuiltinAction::MoveBy(IVec2::up())),
+            "move up",
         );
         s.reg(
             vec![KeyCode::Char('l')],
             Action::Builtin(BuiltinAction::MoveBy(IVec2::right())),
+            "move right",
         );
         s.reg(
             vec![KeyCode::Char('0')],
             Action::Builtin(BuiltinAction::MoveToX(Position::Start)),
+            "move to the start of the line",
         );
         s.reg(
             vec![KeyCode::Char('$')],
             Action::Builtin(BuiltinAction::MoveToX(Position::End)),
+            "move to the end of the line",
         );
         s.reg(
             vec![KeyCode::Char('g'), KeyCode::Char('g')],
             Action::Builtin(BuiltinAction::MoveToY(Position::Start)),
+            "move to the first line",
         );
         s.reg(
             vec![KeyCode::Char('G')],
             Action::Builtin(BuiltinAction::MoveToY(Position::End)),
+            "move to the last line",
         );
+        s.reg_arrows();
         // s.reg(
         //     vec![KeyCode::Char('w')],
         //     Action::Editor(EditorAction::Window(WindowAction::Cursor(
@@ -99,45 +395,55 @@ impl Keymap {
         s.reg(
             vec![KeyCode::Char('i')],
             Action::Builtin(BuiltinAction::ChangeMode(Mode::Insert(false))),
+            "insert before the cursor",
         );
         s.reg(
             vec![KeyCode::Char('a')],
             Action::Builtin(BuiltinAction::ChangeMode(Mode::Insert(true))),
+            "append after the cursor",
         );
         s.reg(
             vec![KeyCode::Char('I')],
             Action::Builtin(BuiltinAction::InsertLineStart),
+            "insert at the start of the line",
         );
         s.reg(
             vec![KeyCode::Char('A')],
             Action::Builtin(BuiltinAction::AppendLineEnd),
+            "append at the end of the line",
         );
         s.reg(
             vec![KeyCode::Char(':')],
             Action::Builtin(BuiltinAction::ChangeMode(Mode::Command)),
+            "enter a command",
         );
         s.reg(
             vec![KeyCode::Char('o')],
             Action::Builtin(BuiltinAction::OpenLineBelow),
+            "open a line below",
         );
         s.reg(
             vec![KeyCode::Char('O')],
             Action::Builtin(BuiltinAction::OpenLineAbove),
+            "open a line above",
         );
         s.reg(
             vec![KeyCode::Char('v')],
             Action::Builtin(BuiltinAction::ChangeMode(Mode::Visual)),
+            "start visual mode",
         );
 
         // Editing
         s.reg(
             vec![KeyCode::Char('x')],
             Action::Builtin(BuiltinAction::RemoveChar),
+            "delete the character under the cursor",
         );
         // s.reg(vec![KeyCode::Char('X')], "editor.edit.delete-back-char");
         s.reg(
             vec![KeyCode::Char('d'), KeyCode::Char('d')],
             Action::Builtin(BuiltinAction::RemoveLine),
+            "delete the line",
         );
         // s.reg(vec![KeyCode::Char('D')], "editor.edit.delete-to-line-end");
         // s.reg(
@@ -145,8 +451,16 @@ impl Keymap {
         //     "editor.edit.replace-char",
         // );
         // s.reg(vec![KeyCode::Char('R')], "editor.edit.replace-mode");
-        // s.reg(vec![KeyCode::Char('p')], "editor.edit.paste-after");
-        // s.reg(vec![KeyCode::Char('P')], "editor.edit.paste-before");
+        s.reg(
+            vec![KeyCode::Char('p')],
+            Action::Paste(true),
+            "put the unnamed register after the cursor",
+        );
+        s.reg(
+            vec![KeyCode::Char('P')],
+            Action::Paste(false),
+            "put the unnamed register before the cursor",
+        );
         // s.reg(
         //     vec![KeyCode::Char('y'), KeyCode::Char('y')],
         //     "editor.edit.yank-line",
@@ -155,50 +469,81 @@ impl Keymap {
 
         // UI
         // s.reg(vec![KeyCode::Char(':')], "editor.ui.command");
-        // s.reg(vec![KeyCode::Char('/')], "editor.ui.search");
+        s.reg(
+            vec![KeyCode::Char('/')],
+            Action::StartSearch,
+            "search forward",
+        );
+        s.reg(
+            vec![KeyCode::Char('n')],
+            Action::SearchNext(true),
+            "next match",
+        );
+        s.reg(
+            vec![KeyCode::Char('N')],
+            Action::SearchNext(false),
+            "previous match",
+        );
         // s.reg(vec![KeyCode::Char('%')], "editor.ui.replace");
+        s.reg(
+            vec![KeyCode::Leader, KeyCode::Char('w')],
+            Action::Builtin(BuiltinAction::Save),
+            "save the buffer",
+        );
+        s.reg(
+            vec![KeyCode::Leader, KeyCode::Char('q')],
+            Action::Quit(false),
+            "close the window",
+        );
 
         s
     }
 
     pub fn visual_default() -> Self {
-        let mut s = Self {
-            map: HashMap::default(),
-        };
+        let mut s = Self::default();
 
         // Cursor movement
         s.reg(
             vec![KeyCode::Char('h')],
             Action::Builtin(BuiltinAction::MoveBy(IVec2::left())),
+            "move left",
         );
         s.reg(
             vec![KeyCode::Char('j')],
             Action::Builtin(BuiltinAction::MoveBy(IVec2::down())),
+            "move down",
         );
         s.reg(
             vec![KeyCode::Char('k')],
             Action::Builtin(BuiltinAction::MoveBy(IVec2::up())),
+            "move up",
         );
         s.reg(
             vec![KeyCode::Char('l')],
             Action::Builtin(BuiltinAction::MoveBy(IVec2::right())),
+            "move right",
         );
         s.reg(
             vec![KeyCode::Char('0')],
             Action::Builtin(BuiltinAction::MoveToX(Position::Start)),
+            "move to the start of the line",
         );
         s.reg(
             vec![KeyCode::Char('$')],
             Action::Builtin(BuiltinAction::MoveToX(Position::End)),
+            "move to the end of the line",
         );
         s.reg(
             vec![KeyCode::Char('g'), KeyCode::Char('g')],
             Action::Builtin(BuiltinAction::MoveToY(Position::Start)),
+            "move to the first line",
         );
         s.reg(
             vec![KeyCode::Char('G')],
             Action::Builtin(BuiltinAction::MoveToY(Position::End)),
+            "move to the last line",
         );
+        s.reg_arrows();
         // s.reg(
         //     vec![KeyCode::Char('w')],
         //     Action::Editor(EditorAction::Window(WindowAction::Cursor(
@@ -222,16 +567,24 @@ impl Keymap {
         s.reg(
             vec![KeyCode::Esc],
             Action::Builtin(BuiltinAction::ChangeMode(Mode::Normal)),
+            "back to normal mode",
+        );
+        s.reg(
+            vec![KeyCode::Char(':')],
+            Action::Builtin(BuiltinAction::ChangeMode(Mode::Command)),
+            "enter a command",
         );
 
         // Editing
         s.reg(
             vec![KeyCode::Char('x')],
             Action::Builtin(BuiltinAction::RemoveSelection),
+            "delete the selection",
         );
         s.reg(
             vec![KeyCode::Char('d')],
             Action::Builtin(BuiltinAction::RemoveSelection),
+            "delete the selection",
         );
         // s.reg(vec![KeyCode::Char('D')], "editor.edit.delete-to-line-end");
         // s.reg(
@@ -253,14 +606,51 @@ impl Keymap {
 
         s
     }
+
+    /// Keys with a meaning of their own in insert mode. Other keys are typed into the
+    /// buffer, except control characters.
+    pub fn insert_default() -> Self {
+        let mut s = Self::default();
+
+        s.reg(
+            vec![KeyCode::Ctrl('n')],
+            Action::Complete(1),
+            "complete, or select the next candidate",
+        );
+        s.reg(
+            vec![KeyCode::Ctrl('p')],
+            Action::Complete(-1),
+            "complete, or select the previous candidate",
+        );
+        s.reg(
+            vec![KeyCode::Ctrl('w')],
+            Action::DeleteWordBack,
+            "delete the word before the cursor",
+        );
+        s.reg(
+            vec![KeyCode::Ctrl('u')],
+            Action::DeleteToLineStart,
+            "delete everything before the cursor on the line",
+        );
+
+        s
+    }
 }
 
 #[derive(Debug)]
 pub struct InputManager {
     normal_keymap: Keymap,
     visual_keymap: Keymap,
+    insert_keymap: Keymap,
     key_buffers: Key,
     last_time: Option<DateTime<Local>>,
+    /// How long to wait for the next key of a sequence, see `timeoutlen`
+    timeout: Duration,
+    /// Keys typed in insert mode that may still become a mapping
+    insert_pending: Key,
+    /// The pending sequence waits for its next key however long it takes, as the keys
+    /// that can follow are shown
+    held: bool,
 }
 
 impl InputManager {
@@ -268,6 +658,12 @@ impl InputManager {
         Ok(match evt {
             Event::Key(evt) => {
                 let ch = match evt.code {
+                    // Some terminals send control characters instead of the key with
+                    // the modifier
+                    event::KeyCode::Char(ch @ '\u{1}'..='\u{1a}') if ch != '\t' && ch != '\n' => {
+                        return Ok(Some(KeyCode::Ctrl((b'a' + ch as u8 - 1) as char)));
+                    }
+                    event::KeyCode::Char(ch) if ch.is_control() => None,
                     event::KeyCode::Char(ch) => Some(ch),
                     event::KeyCode::Enter => Some('\n'),
                     event::KeyCode::Tab => Some('\t'),
@@ -286,6 +682,10 @@ impl InputManager {
                     event::KeyCode::Down => Some(KeyCode::Down),
                     event::KeyCode::Left => Some(KeyCode::Left),
                     event::KeyCode::Right => Some(KeyCode::Right),
+                    event::KeyCode::Home => Some(KeyCode::Home),
+                    event::KeyCode::End => Some(KeyCode::End),
+                    event::KeyCode::PageUp => Some(KeyCode::PageUp),
+                    event::KeyCode::PageDown => Some(KeyCode::PageDown),
                     event::KeyCode::Backspace => Some(KeyCode::Backspace),
                     event::KeyCode::Delete => Some(KeyCode::Delete),
                     event::KeyCode::Esc => Some(KeyCode::Esc),
@@ -296,76 +696,320 @@ impl InputManager {
         })
     }
 
-    pub fn read_event_normal(&mut self, evt: event::Event) -> anyhow::Result<Option<Action>> {
-        let key = self.event_to_key(evt)?;
-
-        // 500ms以上間隔が空いたらバッファをクリア
+    /// Add a key to the pending sequence and look it up in `keymap`, then in `default`.
+    /// `keymap` holds user mappings and is skipped for non-recursive replays.
+    fn read_key(&mut self, key: KeyCode, keymap: Option<&Keymap>, mode: MapMode) -> Option<Action> {
+        // Drop a sequence whose next key came too late
         let now = Local::now();
-        if let Some(last_time) = self.last_time {
+        if !std::mem::take(&mut self.held)
+            && let Some(last_time) = self.last_time
+        {
             let duration: Duration = now - last_time;
-            if duration.num_milliseconds() > 500 {
+            if duration > self.timeout {
                 self.key_buffers = Vec::default();
                 self.last_time = None;
             }
         }
 
-        // キーが押されたらバッファに追加
-        if let Some(code) = key {
-            self.key_buffers.push(code);
-            self.last_time = Some(now);
-        } else {
-            return Ok(None);
-        }
-
-        // バッファが登録されているアクションにマッチするか確認
-        if let Some(action) = self.normal_keymap.get(&self.key_buffers) {
+        // Esc cancels a pending sequence instead of extending it
+        if key == KeyCode::Esc && !self.key_buffers.is_empty() {
             self.key_buffers = Vec::default();
             self.last_time = None;
-            Ok(Some(action.clone()))
-        } else {
-            Ok(None)
+            return None;
         }
-    }
 
-    pub fn read_event_visual(&mut self, evt: event::Event) -> anyhow::Result<Option<Action>> {
-        let key = self.event_to_key(evt)?;
+        // キーが押されたらバッファに追加
+        self.key_buffers.push(key);
+        self.last_time = Some(now);
+
+        let default = match mode {
+            MapMode::Normal => &self.normal_keymap,
+            MapMode::Visual => &self.visual_keymap,
+            MapMode::Insert => return None,
+        };
 
-        // 500ms以上間隔が空いたらバッファをクリア
-        let now = Local::now();
-        if let Some(last_time) = self.last_time {
-            let duration: Duration = now - last_time;
-            if duration.num_milliseconds() > 500 {
+        loop {
+            // バッファが登録されているアクションにマッチするか確認
+            let action = keymap
+                .and_then(|keymap| keymap.get(&self.key_buffers))
+                .or_else(|| default.get(&self.key_buffers))
+                .cloned();
+            if action.is_some() {
+                self.key_buffers = Vec::default();
+                self.last_time = None;
+                return action;
+            }
+
+            let is_prefix = keymap.is_some_and(|keymap| keymap.has_prefix(&self.key_buffers))
+                || default.has_prefix(&self.key_buffers);
+            if is_prefix {
+                return None;
+            }
+
+            // The sequence can no longer match, so drop it and retry the new key alone
+            if self.key_buffers.len() > 1 {
+                self.key_buffers = vec![key];
+            } else {
                 self.key_buffers = Vec::default();
                 self.last_time = None;
+                return None;
             }
         }
+    }
 
-        // キーが押されたらバッファに追加
-        if let Some(code) = key {
-            self.key_buffers.push(code);
-            self.last_time = Some(now);
-        } else {
-            return Ok(None);
+    pub fn set_timeout(&mut self, millis: usize) {
+        self.timeout = Duration::milliseconds(millis as i64);
+    }
+
+    pub fn set_leader(&mut self, leader: char) {
+        self.normal_keymap.set_leader(leader);
+        self.visual_keymap.set_leader(leader);
+        self.insert_keymap.set_leader(leader);
+    }
+
+    /// The keys of a sequence waiting for more, with how long it has waited
+    pub fn get_pending(&self) -> Option<(&Key, Duration)> {
+        let last_time = self.last_time?;
+        Some((&self.key_buffers, Local::now() - last_time))
+    }
+
+    /// Keep the pending sequence until the next key, however long it takes
+    pub fn hold(&mut self) {
+        self.held = true;
+    }
+
+    /// The built-in keymap of a mode
+    pub fn get_keymap(&self, mode: MapMode) -> &Keymap {
+        match mode {
+            MapMode::Normal => &self.normal_keymap,
+            MapMode::Visual => &self.visual_keymap,
+            MapMode::Insert => &self.insert_keymap,
         }
+    }
 
-        // バッファが登録されているアクションにマッチするか確認
-        if let Some(action) = self.visual_keymap.get(&self.key_buffers) {
-            self.key_buffers = Vec::default();
-            self.last_time = None;
-            Ok(Some(action.clone()))
+    pub fn read_key_normal(&mut self, key: KeyCode, keymap: Option<&Keymap>) -> Option<Action> {
+        self.read_key(key, keymap, MapMode::Normal)
+    }
+
+    pub fn read_key_visual(&mut self, key: KeyCode, keymap: Option<&Keymap>) -> Option<Action> {
+        self.read_key(key, keymap, MapMode::Visual)
+    }
+
+    /// Get the action of a key in insert mode, if it is not typed into the buffer
+    pub fn get_insert_action(&self, key: KeyCode) -> Option<Action> {
+        self.insert_keymap.get(&vec![key]).cloned()
+    }
+
+    /// Look up insert mode mappings. Returns the action of a complete mapping, or
+    /// the keys to insert as typed once they can no longer start a mapping.
+    pub fn read_key_insert(&mut self, key: KeyCode, keymap: &Keymap) -> Result<Action, Key> {
+        self.insert_pending.push(key);
+        if let Some(action) = keymap.get(&self.insert_pending) {
+            self.insert_pending = Vec::default();
+            return Ok(action.clone());
+        }
+
+        if keymap.has_prefix(&self.insert_pending) {
+            Err(Vec::default())
         } else {
-            Ok(None)
+            Err(std::mem::take(&mut self.insert_pending))
         }
     }
 }
 
+impl InputManager {
+    /// Get the keymaps with the name of their mode
+    pub fn get_keymaps(&self) -> [(&str, &Keymap); 3] {
+        [
+            ("Normal", &self.normal_keymap),
+            ("Visual", &self.visual_keymap),
+            ("Insert", &self.insert_keymap),
+        ]
+    }
+}
+
 impl Default for InputManager {
     fn default() -> Self {
         Self {
             normal_keymap: Keymap::normal_default(),
             visual_keymap: Keymap::visual_default(),
+            insert_keymap: Keymap::insert_default(),
             key_buffers: Vec::default(),
             last_time: None,
+            timeout: Duration::milliseconds(500),
+            insert_pending: Vec::default(),
+            held: false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_keys() {
+        assert_eq!(
+            parse_keys("<leader>w").unwrap(),
+            vec![KeyCode::Leader, KeyCode::Char('w')]
+        );
+        assert_eq!(
+            parse_keys(":w<CR>").unwrap(),
+            vec![KeyCode::Char(':'), KeyCode::Char('w'), KeyCode::Char('\n')]
+        );
+        assert_eq!(
+            parse_keys("<C-x><esc>").unwrap(),
+            vec![KeyCode::Ctrl('x'), KeyCode::Esc]
+        );
+        assert_eq!(
+            parse_keys("<lt>a>").unwrap(),
+            vec![KeyCode::Char('<'), KeyCode::Char('a'), KeyCode::Char('>')]
+        );
+        assert_eq!(
+            parse_keys("<foo").unwrap(),
+            vec![
+                KeyCode::Char('<'),
+                KeyCode::Char('f'),
+                KeyCode::Char('o'),
+                KeyCode::Char('o')
+            ]
+        );
+        assert!(parse_keys("").is_err());
+    }
+
+    #[test]
+    fn test_user_keymap_first() {
+        let mut input = InputManager::default();
+        let mut user = Keymap::default();
+        user.reg(vec![KeyCode::Char('x')], Action::QuitAll(true), "");
+
+        assert!(matches!(
+            input.read_key_normal(KeyCode::Char('x'), Some(&user)),
+            Some(Action::QuitAll(true))
+        ));
+        assert!(matches!(
+            input.read_key_normal(KeyCode::Char('x'), None),
+            Some(Action::Builtin(BuiltinAction::RemoveChar))
+        ));
+    }
+
+    #[test]
+    fn test_esc_cancels_pending_keys() {
+        let mut input = InputManager::default();
+        assert!(input.read_key_normal(KeyCode::Char('d'), None).is_none());
+        assert!(input.read_key_normal(KeyCode::Esc, None).is_none());
+        assert!(input.key_buffers.is_empty());
+        assert!(input.last_time.is_none());
+
+        // The stale `d` must not complete a `dd`
+        assert!(input.read_key_normal(KeyCode::Char('d'), None).is_none());
+        assert_eq!(input.key_buffers, vec![KeyCode::Char('d')]);
+    }
+
+    #[test]
+    fn test_arrow_keys() {
+        assert_eq!(
+            parse_keys("<Up><PageDown><home>").unwrap(),
+            vec![KeyCode::Up, KeyCode::PageDown, KeyCode::Home]
+        );
+
+        let mut input = InputManager::default();
+        assert!(matches!(
+            input.read_key_normal(KeyCode::Left, None),
+            Some(Action::Builtin(BuiltinAction::MoveBy(offset))) if offset == IVec2::left()
+        ));
+        assert!(matches!(
+            input.read_key_visual(KeyCode::End, None),
+            Some(Action::Builtin(BuiltinAction::MoveToX(Position::End)))
+        ));
+        assert!(matches!(
+            input.read_key_normal(KeyCode::PageUp, None),
+            Some(Action::Page(false))
+        ));
+    }
+
+    #[test]
+    fn test_esc_without_pending_keys() {
+        let mut input = InputManager::default();
+        assert!(matches!(
+            input.read_key_visual(KeyCode::Esc, None),
+            Some(Action::Builtin(BuiltinAction::ChangeMode(Mode::Normal)))
+        ));
+    }
+
+    #[test]
+    fn test_sequence_timeout() {
+        let mut input = InputManager::default();
+        assert!(input.read_key_normal(KeyCode::Char('d'), None).is_none());
+        input.last_time = Some(Local::now() - Duration::milliseconds(100));
+        assert!(matches!(
+            input.read_key_normal(KeyCode::Char('d'), None),
+            Some(Action::Builtin(BuiltinAction::RemoveLine))
+        ));
+
+        input.set_timeout(50);
+        assert!(input.read_key_normal(KeyCode::Char('d'), None).is_none());
+        input.last_time = Some(Local::now() - Duration::milliseconds(100));
+        // The first `d` timed out, so this one starts a new sequence
+        assert!(input.read_key_normal(KeyCode::Char('d'), None).is_none());
+        assert_eq!(input.key_buffers, vec![KeyCode::Char('d')]);
+    }
+
+    #[test]
+    fn test_invalid_sequence_fails_fast() {
+        let mut input = InputManager::default();
+        assert!(input.read_key_normal(KeyCode::Char('d'), None).is_none());
+        // `dx` is not mapped, so `x` runs on its own
+        assert!(matches!(
+            input.read_key_normal(KeyCode::Char('x'), None),
+            Some(Action::Builtin(BuiltinAction::RemoveChar))
+        ));
+        assert!(input.key_buffers.is_empty());
+
+        assert!(input.read_key_normal(KeyCode::Char('g'), None).is_none());
+        assert!(input.read_key_normal(KeyCode::Char('q'), None).is_none());
+        assert!(input.key_buffers.is_empty());
+
+        assert!(input.read_key_normal(KeyCode::Char('d'), None).is_none());
+        assert!(matches!(
+            input.read_key_normal(KeyCode::Char('d'), None),
+            Some(Action::Builtin(BuiltinAction::RemoveLine))
+        ));
+    }
+
+    #[test]
+    fn test_insert_mapping() {
+        let mut input = InputManager::default();
+        let mut user = Keymap::default();
+        user.reg(
+            vec![KeyCode::Char('j'), KeyCode::Char('k')],
+            Action::Feed(vec![KeyCode::Esc], false),
+            "<Esc>",
+        );
+
+        assert_eq!(
+            input
+                .read_key_insert(KeyCode::Char('a'), &user)
+                .unwrap_err(),
+            vec![KeyCode::Char('a')]
+        );
+        assert_eq!(
+            input
+                .read_key_insert(KeyCode::Char('j'), &user)
+                .unwrap_err(),
+            vec![]
+        );
+        assert!(matches!(
+            input.read_key_insert(KeyCode::Char('k'), &user),
+            Ok(Action::Feed(..))
+        ));
+
+        input.read_key_insert(KeyCode::Char('j'), &user).ok();
+        assert_eq!(
+            input
+                .read_key_insert(KeyCode::Char('x'), &user)
+                .unwrap_err(),
+            vec![KeyCode::Char('j'), KeyCode::Char('x')]
+        );
+    }
+}
@@ -1,3 +1,5 @@
 pub mod commands;
 pub mod input;
 pub mod render;
+pub mod statusline;
+pub mod theme;
@@ -1,24 +1,87 @@
 use std::{
-    io::{Stdout, Write, stdout},
-    sync::Arc,
+    io::{Write, stdout},
+    ops::Range,
 };
 
-use api::Mode;
+use api::{DiagnosticSeverity, Mode, Popup, StatusAlign};
 use crossterm::{
     cursor::{self, MoveTo},
     execute, queue,
-    style::{self, Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor},
+    style::{
+        self, Attribute, Color, Print, ResetColor, SetAttribute, SetBackgroundColor,
+        SetForegroundColor,
+    },
     terminal::{self, disable_raw_mode, enable_raw_mode},
 };
-use tokio::sync::Mutex;
 use utils::vec2::UVec2;
 
-use crate::{buffer::Buffer, window::Window};
+use crate::{
+    buffer::char_to_byte,
+    highlight::{Highlight, HighlightSource},
+    layout::Border,
+    messages::{Message, MessageLevel},
+    state::EditorState,
+    ui::{
+        statusline::{StatusSegment, layout_segments},
+        theme::Theme,
+    },
+    window::Window,
+};
+
+const SPLASH_KEYS: &[&str] = &[
+    "i         insert mode",
+    ":w        save",
+    ":q        quit",
+    ":help     help",
+];
+
+/// Columns of the sign column, shown left of the text while the buffer has diagnostics
+const SIGN_WIDTH: usize = 2;
+
+/// Leave the alternate screen and raw mode from wherever the editor stopped
+pub fn restore_terminal() {
+    execute!(stdout(), terminal::LeaveAlternateScreen, cursor::Show).ok();
+    disable_raw_mode().ok();
+}
+
+/// Run `restore` before the message of any panic, then let the panic go on as it would
+/// have, ending only the thread or task it happened in
+pub fn restore_on_panic(restore: impl Fn() + Send + Sync + 'static) {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore();
+        default_hook(info);
+    }));
+}
 
 #[derive(Debug, Default)]
-pub struct Renderer {}
+pub struct Renderer {
+    theme: Theme,
+    last_frame: Option<Frame>,
+    /// A frame is built here and written at once, and the allocation kept for the next one
+    out: Vec<u8>,
+}
+
+/// Written in pieces rather than allocating padding for every frame
+const SPACES: &str = "                                                                ";
+
+fn pad(stdout: &mut impl Write, mut width: usize) -> anyhow::Result<()> {
+    while width > 0 {
+        let n = width.min(SPACES.len());
+        stdout.write_all(&SPACES.as_bytes()[..n])?;
+        width -= n;
+    }
+    Ok(())
+}
 
 impl Renderer {
+    pub fn new(theme: Theme) -> Self {
+        Self {
+            theme,
+            ..Default::default()
+        }
+    }
+
     pub fn init_screen(&self) -> anyhow::Result<()> {
         enable_raw_mode()?;
         execute!(
@@ -30,19 +93,19 @@ impl Renderer {
     }
 
     pub fn clean_screen(&self) -> anyhow::Result<()> {
-        execute!(stdout(), terminal::LeaveAlternateScreen)?;
+        execute!(stdout(), terminal::LeaveAlternateScreen, cursor::Show)?;
         disable_raw_mode()?;
         Ok(())
     }
 
-    fn render_move_cursor(&self, stdout: &mut Stdout, cursor: UVec2) -> anyhow::Result<()> {
+    fn render_move_cursor(&self, stdout: &mut impl Write, cursor: UVec2) -> anyhow::Result<()> {
         queue!(stdout, MoveTo(cursor.x as u16, cursor.y as u16))?;
         Ok(())
     }
 
     fn render_code_token(
         &self,
-        stdout: &mut Stdout,
+        stdout: &mut impl Write,
         token: &str,
         fg: Option<Color>,
         bg: Option<Color>,
@@ -62,15 +125,38 @@ impl Renderer {
         Ok(())
     }
 
+    /// Draw the sign of the most severe diagnostic starting on a line, or blank it
+    fn render_sign(
+        &self,
+        stdout: &mut impl Write,
+        sign: Option<DiagnosticSeverity>,
+        position: UVec2,
+    ) -> anyhow::Result<()> {
+        self.render_move_cursor(stdout, position)?;
+        match sign {
+            Some(severity) => {
+                let text = match severity {
+                    DiagnosticSeverity::Error => "E ",
+                    DiagnosticSeverity::Warning => "W ",
+                    DiagnosticSeverity::Info => "I ",
+                    DiagnosticSeverity::Hint => "H ",
+                };
+                self.render_code_token(stdout, text, Some(self.theme.severity_fg(severity)), None)
+            }
+            None => Ok(pad(stdout, SIGN_WIDTH)?),
+        }
+    }
+
     #[allow(clippy::too_many_arguments)]
     fn render_code_line(
         &self,
-        stdout: &mut Stdout,
+        stdout: &mut impl Write,
         line: &str,
         line_y: usize,
         y: usize,
         mode: &Mode,
         visual_cursor: (UVec2, UVec2),
+        highlights: &[Highlight],
         position: UVec2,
     ) -> anyhow::Result<()> {
         self.render_move_cursor(stdout, UVec2::new(position.x, position.y + y))?;
@@ -79,93 +165,289 @@ impl Renderer {
             let (left, right) = visual_cursor;
 
             if left.y == line_y && right.y == line_y {
-                let (line_left, line_right) = line.split_at(left.x);
-                let (line_center, line_right) = line_right.split_at(right.x - left.x);
+                let (line_left, line_right) = line.split_at(char_to_byte(line, left.x));
+                let (line_center, line_right) =
+                    line_right.split_at(char_to_byte(line_right, right.x - left.x));
 
                 self.render_code_token(stdout, line_left, None, None)?;
-                self.render_code_token(stdout, line_center, None, Some(Color::Blue))?;
+                self.render_code_token(stdout, line_center, None, Some(self.theme.selection_bg))?;
                 self.render_code_token(stdout, line_right, None, None)?;
             } else if left.y == line_y && !line.is_empty() {
-                let (line_left, line_right) = line.split_at(left.x);
+                let (line_left, line_right) = line.split_at(char_to_byte(line, left.x));
 
                 self.render_code_token(stdout, line_left, None, None)?;
-                self.render_code_token(stdout, line_right, None, Some(Color::Blue))?;
+                self.render_code_token(stdout, line_right, None, Some(self.theme.selection_bg))?;
             } else if right.y == line_y {
-                let (line_left, line_right) = line.split_at(right.x);
+                let (line_left, line_right) = line.split_at(char_to_byte(line, right.x));
 
-                self.render_code_token(stdout, line_left, None, Some(Color::Blue))?;
+                self.render_code_token(stdout, line_left, None, Some(self.theme.selection_bg))?;
                 self.render_code_token(stdout, line_right, None, None)?;
             } else if left.y < line_y && right.y > line_y {
-                self.render_code_token(stdout, line, None, Some(Color::Blue))?;
+                self.render_code_token(stdout, line, None, Some(self.theme.selection_bg))?;
             } else {
                 self.render_code_token(stdout, line, None, None)?;
             }
         } else {
-            self.render_code_token(stdout, line, None, None)?;
+            let (mut syntax, mut spans): (Vec<&Highlight>, Vec<&Highlight>) = highlights
+                .iter()
+                .filter(|highlight| highlight.y == line_y)
+                .partition(|highlight| matches!(highlight.source, HighlightSource::Syntax(_)));
+            syntax.sort_by_key(|highlight| highlight.range.start);
+            spans.sort_by_key(|highlight| highlight.range.start);
+
+            let mut x = 0;
+            for span in spans {
+                let start = span.range.start.max(x);
+                let end = span.range.end.min(line.len());
+                if start >= end || !line.is_char_boundary(start) || !line.is_char_boundary(end) {
+                    continue;
+                }
+
+                self.render_syntax(stdout, line, x..start, &syntax)?;
+                match span.source {
+                    HighlightSource::Search => self.render_code_token(
+                        stdout,
+                        &line[start..end],
+                        None,
+                        Some(self.theme.search_bg),
+                    )?,
+                    HighlightSource::Plugin => self.render_code_token(
+                        stdout,
+                        &line[start..end],
+                        None,
+                        Some(self.theme.selection_bg),
+                    )?,
+                    HighlightSource::Diagnostic(severity) => queue!(
+                        stdout,
+                        ResetColor,
+                        SetForegroundColor(self.theme.severity_fg(severity)),
+                        SetAttribute(Attribute::Underlined),
+                        Print(&line[start..end]),
+                        SetAttribute(Attribute::NoUnderline),
+                    )?,
+                    HighlightSource::Syntax(kind) => self.render_code_token(
+                        stdout,
+                        &line[start..end],
+                        Some(self.theme.syntax_fg(kind)),
+                        None,
+                    )?,
+                }
+                x = end;
+            }
+            self.render_syntax(stdout, line, x..line.len(), &syntax)?;
         }
 
         Ok(())
     }
 
-    pub async fn render(
+    /// Draw `range` of `line`, colored by the `syntax` highlights in it
+    fn render_syntax(
+        &self,
+        stdout: &mut impl Write,
+        line: &str,
+        range: Range<usize>,
+        syntax: &[&Highlight],
+    ) -> anyhow::Result<()> {
+        let mut x = range.start;
+        for span in syntax {
+            let HighlightSource::Syntax(kind) = span.source else {
+                continue;
+            };
+            let start = span.range.start.max(x);
+            let end = span.range.end.min(range.end);
+            if start >= end || !line.is_char_boundary(start) || !line.is_char_boundary(end) {
+                continue;
+            }
+
+            self.render_code_token(stdout, &line[x..start], None, None)?;
+            let fg = self.theme.syntax_fg(kind);
+            self.render_code_token(stdout, &line[start..end], Some(fg), None)?;
+            x = end;
+        }
+        self.render_code_token(stdout, &line[x..range.end], None, None)
+    }
+
+    /// Draw the startup screen centered in the window
+    fn render_splash(
         &self,
-        active_window: Arc<Mutex<Window>>,
-        active_buffer: Arc<Mutex<Buffer>>,
-        mode: Arc<Mutex<Mode>>,
-        command_buf: String,
+        stdout: &mut impl Write,
+        position: UVec2,
+        size: UVec2,
     ) -> anyhow::Result<()> {
-        let mut win = active_window.lock().await;
+        let title = format!("Cuprum Editor v{}", env!("CARGO_PKG_VERSION"));
+        let mut lines = vec![title.as_str(), ""];
+        lines.extend(SPLASH_KEYS);
 
-        let (w, h) = terminal::size()?;
-        win.set_size(UVec2::new(w.into(), (h - 1).into()));
+        let width = lines
+            .iter()
+            .map(|line| line.chars().count())
+            .max()
+            .unwrap_or(0);
+        if size.x < width || size.y < lines.len() {
+            return Ok(());
+        }
 
-        let cursor = win.get_render_cursor().await;
-        let visual_start = win.get_visual_start().await;
-        let scroll = win.get_scroll();
+        let top = position.y + (size.y - lines.len()) / 2;
+        for (y, line) in lines.iter().enumerate() {
+            let left = position.x + (size.x - line.chars().count()) / 2;
+            self.render_move_cursor(stdout, UVec2::new(left, top + y))?;
+            queue!(stdout, Print(line))?;
+        }
 
-        let visual_cursor = if cursor < visual_start {
-            (cursor + UVec2::new(1, 0), visual_start + UVec2::new(1, 0))
-        } else {
-            (visual_start, cursor)
-        };
+        Ok(())
+    }
 
-        let position = win.get_position();
-        let size = win.get_size();
+    /// Draw a popup on top of the text area, clipped to `screen`
+    fn render_popup(
+        &self,
+        stdout: &mut impl Write,
+        popup: &Popup,
+        screen: UVec2,
+    ) -> anyhow::Result<()> {
+        let UVec2 { x: left, y: top } = popup.position;
+        if left >= screen.x || top >= screen.y {
+            return Ok(());
+        }
 
-        let mut stdout = stdout();
+        let width = popup.size.x.min(screen.x - left);
+        let height = popup.size.y.min(screen.y - top);
+        let inset = if popup.border { 1 } else { 0 };
+        if width < inset * 2 || height < inset * 2 {
+            return Ok(());
+        }
+        let inner_width = width - inset * 2;
 
-        queue!(
-            stdout,
-            cursor::MoveTo(0, 0),
-            terminal::Clear(terminal::ClearType::All)
-        )?;
+        for y in 0..height {
+            let row = if popup.border && y == 0 {
+                let title = popup.title.clone().unwrap_or_default();
+                let title: String = title.chars().take(inner_width).collect();
+                let fill = "─".repeat(inner_width - title.chars().count());
+                format!("┌{}{}┐", title, fill)
+            } else if popup.border && y == height - 1 {
+                format!("└{}┘", "─".repeat(inner_width))
+            } else {
+                let line = popup.lines.get(y - inset).cloned().unwrap_or_default();
+                let line: String = line.chars().take(inner_width).collect();
+                let padding = " ".repeat(inner_width - line.chars().count());
+                if popup.border {
+                    format!("│{}{}│", line, padding)
+                } else {
+                    line + padding.as_str()
+                }
+            };
 
-        let mode = mode.lock().await.clone();
-        let buf = active_buffer.lock().await;
-        for (y, line) in buf
-            .get_all_lines()
-            .iter()
-            .skip(scroll)
-            .take(size.y)
-            .enumerate()
-        {
-            self.render_code_line(
-                &mut stdout,
-                line,
-                y + scroll,
-                y,
-                &mode,
-                visual_cursor,
-                position,
-            )?;
+            self.render_move_cursor(stdout, UVec2::new(left, top + y))?;
+            queue!(stdout, ResetColor, Print(row))?;
+        }
+
+        Ok(())
+    }
+
+    /// Draw the text of a window, in `mode` if it is active
+    fn render_window(
+        &self,
+        stdout: &mut impl Write,
+        window: &WindowFrame,
+        mode: &Mode,
+        term_width: usize,
+    ) -> anyhow::Result<()> {
+        let WindowFrame {
+            scroll,
+            visual_cursor,
+            position,
+            size,
+            ..
+        } = *window;
+        // Rows reaching the right edge are cleared after their text rather than the screen
+        // before it, which flickers. Those of windows left of another are padded instead.
+        let clear = position.x + size.x >= term_width;
+
+        for y in 0..size.y {
+            if !window.signs.is_empty() {
+                let sign = window.signs.get(y).copied().flatten();
+                let at = UVec2::new(position.x - SIGN_WIDTH, position.y + y);
+                self.render_sign(stdout, sign, at)?;
+            }
+            let width = match window.lines.get(y) {
+                Some(line) => {
+                    self.render_code_line(
+                        stdout,
+                        line,
+                        y + scroll,
+                        y,
+                        mode,
+                        visual_cursor,
+                        &window.highlights,
+                        position,
+                    )?;
+                    line.chars().count()
+                }
+                None => {
+                    self.render_move_cursor(stdout, position + UVec2::new(0, y))?;
+                    0
+                }
+            };
+            queue!(stdout, ResetColor)?;
+            if clear {
+                queue!(stdout, terminal::Clear(terminal::ClearType::UntilNewLine))?;
+            } else {
+                pad(stdout, size.x.saturating_sub(width))?;
+            }
+        }
+
+        if window.splash {
+            self.render_splash(stdout, position, size)?;
+        }
+        Ok(())
+    }
+
+    /// Draw a line between split windows
+    fn render_border(&self, stdout: &mut impl Write, border: &Border) -> anyhow::Result<()> {
+        queue!(stdout, ResetColor)?;
+        if border.vertical {
+            for y in 0..border.length {
+                self.render_move_cursor(stdout, border.position + UVec2::new(0, y))?;
+                queue!(stdout, Print("│"))?;
+            }
+        } else {
+            self.render_move_cursor(stdout, border.position)?;
+            queue!(stdout, Print("─".repeat(border.length)))?;
+        }
+        Ok(())
+    }
+
+    /// Draw a frame. Nothing is locked here, so a slow terminal does not hold up input.
+    pub fn render(&self, stdout: &mut impl Write, frame: &Frame) -> anyhow::Result<()> {
+        let Frame {
+            term_size,
+            ref window,
+            ref mode,
+            ..
+        } = *frame;
+        let (w, h) = (term_size.x as u16, term_size.y.max(1) as u16);
+
+        self.render_window(stdout, window, mode, w.into())?;
+        for other in &frame.others {
+            self.render_window(stdout, other, &Mode::Normal, w.into())?;
+        }
+        for border in &frame.borders {
+            self.render_border(stdout, border)?;
+        }
+
+        for popup in &frame.popups {
+            self.render_popup(stdout, popup, UVec2::new(w.into(), (h - 1).into()))?;
         }
 
         if let Mode::Command = mode {
+            let (line, cursor_x) =
+                format_command_line(&frame.command_prompt, &frame.command_buf, w as usize);
+
             queue!(
                 stdout,
                 cursor::MoveTo(0, h - 1),
-                Print(':'),
-                Print(&command_buf),
+                terminal::Clear(terminal::ClearType::CurrentLine),
+                Print(line),
+                cursor::MoveTo(cursor_x as u16, h - 1),
             )?;
         } else {
             let status = format!(" {} ", mode);
@@ -173,21 +455,41 @@ impl Renderer {
             queue!(
                 stdout,
                 cursor::MoveTo(0, h - 1),
-                style::SetBackgroundColor(style::Color::White),
-                style::SetForegroundColor(style::Color::Black),
+                style::SetBackgroundColor(self.theme.mode_bg(mode)),
+                style::SetForegroundColor(self.theme.mode_fg),
                 Print(status.clone()),
-                Print(" ".repeat(w as usize - status.len())),
-                style::ResetColor
+                style::SetBackgroundColor(self.theme.status_bg),
+                style::SetForegroundColor(self.theme.status_fg),
             )?;
 
-            let cursor = UVec2::new(cursor.x, cursor.y.saturating_sub(scroll));
-            queue!(
-                stdout,
-                cursor::MoveTo(
-                    (position.x + cursor.x) as u16,
-                    (position.y + cursor.y) as u16
-                )
-            )?;
+            let mut used = status.chars().count();
+            let (left, right) = layout_segments(&frame.segments, (w as usize).saturating_sub(used));
+            let right_width = right.chars().count();
+            queue!(stdout, Print(left.clone()))?;
+            used += left.chars().count() + right_width;
+
+            if let Some(message) = &frame.message {
+                let text: String = format!(" {}", message.text)
+                    .chars()
+                    .take((w as usize).saturating_sub(used))
+                    .collect();
+                used += text.chars().count();
+
+                if message.level == MessageLevel::Error {
+                    queue!(stdout, style::SetForegroundColor(self.theme.error_fg))?;
+                }
+                queue!(
+                    stdout,
+                    Print(text),
+                    style::SetForegroundColor(self.theme.status_fg)
+                )?;
+            }
+
+            pad(stdout, (w as usize).saturating_sub(used))?;
+            queue!(stdout, Print(right), style::ResetColor)?;
+
+            let cursor = screen_cursor(window.cursor, window.scroll, window.position, window.size);
+            queue!(stdout, cursor::MoveTo(cursor.x as u16, cursor.y as u16))?;
         }
 
         if let Mode::Normal | Mode::Visual = mode {
@@ -196,8 +498,665 @@ impl Renderer {
             queue!(stdout, cursor::SetCursorStyle::SteadyBar)?;
         }
 
+        Ok(())
+    }
+
+    /// Draw `frame` unless it is the one drawn last, so nothing is written while idle.
+    /// Returns whether it was drawn.
+    pub fn render_changed(
+        &mut self,
+        stdout: &mut impl Write,
+        frame: Frame,
+    ) -> anyhow::Result<bool> {
+        if self.last_frame.as_ref() == Some(&frame) {
+            return Ok(false);
+        }
+        let mut out = std::mem::take(&mut self.out);
+        out.clear();
+        let rendered = self.render(&mut out, &frame);
+        self.out = out;
+        rendered?;
+
+        stdout.write_all(&self.out)?;
         stdout.flush()?;
+        self.last_frame = Some(frame);
+        Ok(true)
+    }
+}
 
-        Ok(())
+/// What a window shows, copied out of the editor with the frame
+#[derive(Debug, PartialEq)]
+pub struct WindowFrame {
+    /// Columns of the cursor and the selection count from `scroll_x`
+    pub cursor: UVec2,
+    pub scroll: usize,
+    /// Characters scrolled past at the start of every line, to show the cursor
+    pub scroll_x: usize,
+    pub visual_cursor: (UVec2, UVec2),
+    /// Where the text starts, right of the sign column
+    pub position: UVec2,
+    /// The size of the text, without the sign column
+    pub size: UVec2,
+    /// The most severe diagnostic starting on each visible line, or nothing without a sign column
+    pub signs: Vec<Option<DiagnosticSeverity>>,
+    /// The visible part of the visible lines
+    pub lines: Vec<String>,
+    /// Whether the window shows an untouched scratch buffer
+    pub splash: bool,
+    pub highlights: Vec<Highlight>,
+}
+
+/// What a frame shows, copied out of the editor so that drawing it locks nothing
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub term_size: UVec2,
+    /// The active window, which has the cursor
+    pub window: WindowFrame,
+    /// The other windows on the screen
+    pub others: Vec<WindowFrame>,
+    pub borders: Vec<Border>,
+    pub mode: Mode,
+    pub command_prompt: String,
+    pub command_buf: String,
+    pub popups: Vec<Popup>,
+    pub message: Option<Message>,
+    pub segments: Vec<StatusSegment>,
+}
+
+impl WindowFrame {
+    /// Copy what `win` shows, with `highlights` on its buffer, scrolled sideways to keep
+    /// `sidescrolloff` columns right of the cursor
+    async fn capture(
+        win: &Window,
+        mut highlights: Vec<Highlight>,
+        sidescrolloff: usize,
+    ) -> (Self, Option<String>) {
+        let cursor = win.get_render_cursor().await;
+        let selection = win.get_selection().await;
+        let (scroll, size) = (win.get_scroll(), win.get_size());
+
+        let buf = win.get_buffer();
+        let mut buf = buf.lock().await;
+        let diagnostics = buf.get_diagnostics();
+        let sign_width = if diagnostics.is_empty() {
+            0
+        } else {
+            SIGN_WIDTH
+        };
+        let size = UVec2::new(size.x.saturating_sub(sign_width), size.y);
+        // Half the width at most, so the cursor always fits between the margins
+        let margin = sidescrolloff.min(size.x.saturating_sub(1) / 2);
+        let scroll_x = (cursor.x + 1 + margin).saturating_sub(size.x);
+        let shift = |pos: UVec2| UVec2::new(pos.x.saturating_sub(scroll_x), pos.y);
+        // Only what fits in the window is copied, however long the buffer and its lines are
+        let (lines, visible): (Vec<String>, Vec<Range<usize>>) = buf
+            .get_lines_range(scroll, size.y)
+            .iter()
+            .map(|line| {
+                let start = char_to_byte(line, scroll_x);
+                let end = start + char_to_byte(&line[start..], size.x);
+                (line[start..end].to_string(), start..end)
+            })
+            .unzip();
+        let signs = if sign_width == 0 {
+            Vec::new()
+        } else {
+            (scroll..scroll + lines.len())
+                .map(|y| {
+                    diagnostics
+                        .iter()
+                        .filter(|diagnostic| diagnostic.range.start.y == y)
+                        .map(|diagnostic| diagnostic.severity)
+                        .min()
+                })
+                .collect()
+        };
+        if let Some(syntax) = buf.highlight_syntax(scroll + lines.len()) {
+            highlights.extend((scroll..scroll + lines.len()).flat_map(|y| {
+                let spans = syntax.get_spans(y).unwrap_or_default();
+                spans.iter().map(move |span| Highlight {
+                    y,
+                    range: span.range.clone(),
+                    source: HighlightSource::Syntax(span.kind),
+                })
+            }));
+        }
+        let splash = buf.is_scratch() && !buf.is_touched() && !buf.is_readonly();
+        let filetype = buf.get_filetype().map(str::to_string);
+        drop(buf);
+
+        highlights.retain_mut(|highlight| {
+            let Some(visible) = highlight.y.checked_sub(scroll).and_then(|y| visible.get(y)) else {
+                return false;
+            };
+            let clip = |x: usize| x.clamp(visible.start, visible.end) - visible.start;
+            highlight.range = clip(highlight.range.start)..clip(highlight.range.end);
+            !highlight.range.is_empty()
+        });
+
+        let frame = Self {
+            cursor: shift(cursor),
+            scroll,
+            scroll_x,
+            visual_cursor: (shift(selection.start), shift(selection.end)),
+            position: win.get_position() + UVec2::new(sign_width, 0),
+            size,
+            signs,
+            lines,
+            splash,
+            highlights,
+        };
+        (frame, filetype)
+    }
+}
+
+impl Frame {
+    /// Copy what the windows on the screen show, or `None` if there is no active window
+    pub async fn capture(state: &EditorState) -> Option<Self> {
+        let active = state.get_active_window()?;
+        // Taken first, as it locks the window itself
+        let highlights = state.get_highlights().await;
+        let sidescrolloff = state.options.sidescrolloff;
+        let (window, filetype) =
+            WindowFrame::capture(&*active.lock().await, highlights, sidescrolloff).await;
+
+        let (placements, borders) = state.get_layout();
+        let mut others = Vec::new();
+        for placement in placements {
+            if placement.id == state.get_active_window_id() {
+                continue;
+            }
+            let Some(win) = state.window_manager.get_window(placement.id) else {
+                continue;
+            };
+            let win = win.lock().await;
+            let highlights = state.get_window_highlights(&win).await;
+            others.push(
+                WindowFrame::capture(&win, highlights, sidescrolloff)
+                    .await
+                    .0,
+            );
+        }
+
+        // Shown after the segments of plugins, and dropped first when there is no room
+        let mut segments = state.statusline.get_all();
+        if let Some(filetype) = filetype {
+            segments.push(StatusSegment {
+                id: "filetype".to_string(),
+                text: filetype,
+                align: StatusAlign::Right,
+            });
+        }
+
+        Some(Self {
+            term_size: state.term_size,
+            window,
+            others,
+            borders,
+            mode: state.mode.lock().await.clone(),
+            command_prompt: state.get_prompt_text(),
+            command_buf: state.command_buf.clone(),
+            popups: state.popup_manager.get_popups(),
+            message: state.messages.get_current(),
+            segments,
+        })
+    }
+}
+
+/// Convert a buffer cursor to a screen position clamped to the window rectangle
+fn screen_cursor(cursor: UVec2, scroll: usize, position: UVec2, size: UVec2) -> UVec2 {
+    let relative = UVec2::new(cursor.x, cursor.y.saturating_sub(scroll));
+    if cursor.y < scroll || relative.y >= size.y || relative.x >= size.x {
+        log::warn!(
+            "cursor {:?} is outside the viewport (scroll: {}, size: {:?})",
+            cursor,
+            scroll,
+            size
+        );
+    }
+
+    let x = relative.x.min(size.x.saturating_sub(1));
+    let y = relative.y.min(size.y.saturating_sub(1));
+    position + UVec2::new(x, y)
+}
+
+/// Build the command line shown at the bottom of the screen.
+///
+/// The text is padded to `width` so that stale characters from the previous
+/// frame are overwritten, and scrolled horizontally when it does not fit so the
+/// end of the command (where the cursor is) stays visible.
+/// Returns the line and the column of the cursor.
+fn format_command_line(prompt: &str, command_buf: &str, width: usize) -> (String, usize) {
+    if width == 0 {
+        return (String::new(), 0);
+    }
+
+    let text: Vec<char> = prompt.chars().chain(command_buf.chars()).collect();
+
+    // Keep one column free for the cursor after the last character
+    let skip = (text.len() + 1).saturating_sub(width);
+    let visible: String = text[skip..].iter().collect();
+    let cursor_x = text.len() - skip;
+
+    let padding = width.saturating_sub(cursor_x);
+    (
+        visible + " ".repeat(padding).as_str(),
+        cursor_x.min(width - 1),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+        },
+        time::{Duration, Instant},
+    };
+
+    use api::{Diagnostic, Position, SplitDirection, TextRange};
+
+    use super::*;
+    use crate::{
+        highlight::{diagnostic_highlights, find_matches},
+        syntax::SyntaxKind,
+        test_editor::TestEditor,
+    };
+
+    #[tokio::test]
+    async fn test_capture_frame() {
+        let lines: Vec<String> = (0..30).map(|i| i.to_string()).collect();
+        let mut editor = TestEditor::new(&[("a.txt", &lines.join("\n"))]);
+        editor.keys("Gv").await;
+
+        let frame = Frame::capture(&*editor.app.state.lock().await)
+            .await
+            .unwrap();
+        assert_eq!(frame.window.scroll, 7);
+        assert_eq!(frame.window.lines, lines[7..]);
+        assert_eq!(frame.window.cursor.y, 29);
+        assert_eq!(frame.mode, Mode::Visual);
+        assert!(!frame.window.splash);
+    }
+
+    /// Frame time on a long buffer with search matches and diagnostics, against copying and
+    /// highlighting the whole buffer as every frame once did. Run with
+    /// `cargo test --release bench_capture_frame -- --ignored --nocapture`.
+    #[tokio::test]
+    #[ignore]
+    async fn bench_capture_frame() {
+        const FRAMES: u32 = 20;
+        let lines: Vec<String> = (0..200_000)
+            .map(|i| format!("let value{} = foo({});", i, i))
+            .collect();
+        let mut editor = TestEditor::new(&[("a.rs", &lines.join("\n"))]);
+        let diagnostics = (0..lines.len())
+            .step_by(10)
+            .map(|y| Diagnostic {
+                range: TextRange {
+                    start: UVec2::new(4, y),
+                    end: UVec2::new(9, y),
+                },
+                severity: DiagnosticSeverity::Warning,
+                message: "unused".to_string(),
+            })
+            .collect();
+        editor
+            .api
+            .publish_diagnostics(None, diagnostics)
+            .await
+            .unwrap();
+        editor.keys("/foo<CR>:100000<CR>").await;
+
+        let started = Instant::now();
+        for _ in 0..FRAMES {
+            let frame = Frame::capture(&*editor.app.state.lock().await).await;
+            assert!(!frame.unwrap().window.highlights.is_empty());
+        }
+        let visible = started.elapsed() / FRAMES;
+
+        let started = Instant::now();
+        for _ in 0..FRAMES {
+            let state = editor.app.state.lock().await;
+            let buf = state.get_active_window().unwrap().lock().await.get_buffer();
+            let buf = buf.lock().await;
+            let lines = buf.get_all_lines();
+            let mut highlights = find_matches(&lines, 0, "foo");
+            highlights.extend(diagnostic_highlights(&lines, 0, buf.get_diagnostics()));
+            assert!(!highlights.is_empty());
+        }
+        let whole = started.elapsed() / FRAMES;
+
+        println!(
+            "frame time: {:?} for the visible lines, {:?} for the whole buffer",
+            visible, whole
+        );
+        assert!(visible < whole);
+    }
+
+    /// A terminal that takes `delay` for every write, like one over a slow connection
+    struct SlowTerminal {
+        delay: Duration,
+    }
+
+    impl Write for SlowTerminal {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            std::thread::sleep(self.delay);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Time to handle a key while frames are drawn to a slow terminal, against holding the
+    /// state while writing as rendering once did. Run with
+    /// `cargo test --release bench_input_latency -- --ignored --nocapture`.
+    // The writes block a worker thread, so others are left for the keys
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore]
+    async fn bench_input_latency() {
+        const KEYS: u32 = 50;
+        async fn key_time(hold_state: bool) -> Duration {
+            let lines: Vec<String> = (0..100).map(|i| format!("line {}", i)).collect();
+            let mut editor = TestEditor::new(&[("a.txt", &lines.join("\n"))]);
+            let state = editor.app.state.clone();
+            let stopped = Arc::new(AtomicBool::new(false));
+            let render = tokio::spawn({
+                let stopped = stopped.clone();
+                async move {
+                    let mut renderer = Renderer::default();
+                    let mut terminal = SlowTerminal {
+                        delay: Duration::from_millis(20),
+                    };
+                    while !stopped.load(Ordering::Relaxed) {
+                        let state = state.lock().await;
+                        let frame = Frame::capture(&state).await.unwrap();
+                        let held = hold_state.then_some(state);
+                        renderer.render_changed(&mut terminal, frame).unwrap();
+                        drop(held);
+                        tokio::task::yield_now().await;
+                    }
+                }
+            });
+
+            let started = Instant::now();
+            for i in 0..KEYS {
+                editor.keys(if i % 2 == 0 { "j" } else { "k" }).await;
+            }
+            let elapsed = started.elapsed() / KEYS;
+            stopped.store(true, Ordering::Relaxed);
+            render.await.unwrap();
+            elapsed
+        }
+
+        let copied = key_time(false).await;
+        let held = key_time(true).await;
+        println!(
+            "key time with a 20ms write: {:?} drawing a copied frame, {:?} holding the state",
+            copied, held
+        );
+        assert!(copied < held);
+    }
+
+    #[tokio::test]
+    async fn test_syntax_highlights() {
+        let mut editor = TestEditor::new(&[("a.rs", "/* x\n*/ fn f() {}\nlet y;")]);
+        let syntax = async |editor: &TestEditor| {
+            let frame = Frame::capture(&*editor.app.state.lock().await)
+                .await
+                .unwrap();
+            frame
+                .window
+                .highlights
+                .into_iter()
+                .map(|highlight| (highlight.y, highlight.range, highlight.source))
+                .collect::<Vec<_>>()
+        };
+        let comment = HighlightSource::Syntax(SyntaxKind::Comment);
+        let keyword = HighlightSource::Syntax(SyntaxKind::Keyword);
+        assert_eq!(
+            syntax(&editor).await,
+            vec![
+                (0, 0..4, comment),
+                (1, 0..2, comment),
+                (1, 3..5, keyword),
+                (2, 0..3, keyword)
+            ]
+        );
+
+        // Closing the comment early changes the line after the edit too
+        editor.keys("A*/<Esc>").await;
+        assert_eq!(
+            syntax(&editor).await,
+            vec![(0, 0..6, comment), (1, 3..5, keyword), (2, 0..3, keyword)]
+        );
+    }
+
+    /// Counts the writes a frame is made of
+    #[derive(Default)]
+    struct Terminal {
+        writes: usize,
+        bytes: Vec<u8>,
+    }
+
+    impl Write for Terminal {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.writes += 1;
+            self.bytes.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frame_is_written_at_once() {
+        let lines: Vec<String> = (0..100).map(|i| format!("line {}", i)).collect();
+        let editor = TestEditor::new(&[("a.txt", &lines.join("\n"))]);
+        editor
+            .app
+            .state
+            .lock()
+            .await
+            .resize(UVec2::new(80, 50))
+            .await;
+        let frame = Frame::capture(&*editor.app.state.lock().await)
+            .await
+            .unwrap();
+
+        let mut renderer = Renderer::default();
+        let mut terminal = Terminal::default();
+        renderer.render_changed(&mut terminal, frame).unwrap();
+        assert_eq!(terminal.writes, 1);
+        let output = String::from_utf8(terminal.bytes).unwrap();
+        assert!(output.contains("line 48"));
+        assert!(!output.contains("line 49"));
+        // Rows are cleared one by one, never the whole screen
+        assert!(!output.contains("\x1b[2J"));
+        assert_eq!(output.matches("\x1b[K").count(), 49);
+    }
+
+    #[tokio::test]
+    async fn test_split_windows() {
+        let editor = TestEditor::new(&[("a.txt", "one"), ("b.txt", "two"), ("c.txt", "three")]);
+        let draw = async |direction: SplitDirection| {
+            let mut state = editor.app.state.lock().await;
+            state.split_windows(direction).await;
+            let frame = Frame::capture(&state).await.unwrap();
+            let mut out = Vec::new();
+            Renderer::default().render(&mut out, &frame).unwrap();
+            (frame, String::from_utf8(out).unwrap())
+        };
+
+        // 23 rows above the status line, two of them borders
+        let (frame, output) = draw(SplitDirection::Horizontal).await;
+        let windows: Vec<_> = [&frame.window]
+            .into_iter()
+            .chain(&frame.others)
+            .map(|window| (window.lines.clone(), window.position, window.size))
+            .collect();
+        assert_eq!(
+            windows,
+            vec![
+                (vec!["one".to_string()], UVec2::new(0, 0), UVec2::new(80, 7)),
+                (vec!["two".to_string()], UVec2::new(0, 8), UVec2::new(80, 7)),
+                (
+                    vec!["three".to_string()],
+                    UVec2::new(0, 16),
+                    UVec2::new(80, 7)
+                ),
+            ]
+        );
+        assert_eq!(frame.borders.len(), 2);
+        assert_eq!(output.matches(&"─".repeat(80)).count(), 2);
+
+        // Side by side, the rows of the windows left of another are padded, not cleared
+        let (frame, output) = draw(SplitDirection::Vertical).await;
+        let columns: Vec<_> = [&frame.window]
+            .into_iter()
+            .chain(&frame.others)
+            .map(|window| (window.position.x, window.size.x))
+            .collect();
+        assert_eq!(columns, vec![(0, 26), (27, 26), (54, 26)]);
+        assert_eq!(output.matches('│').count(), 23 * 2);
+        assert!(output.contains(&format!("one\x1b[0m{}", " ".repeat(23))));
+        assert_eq!(output.matches("\x1b[K").count(), 23);
+    }
+
+    #[tokio::test]
+    async fn test_long_line() {
+        let line = "{\"key\":[1,2,3]},".repeat(10 * 1024 * 1024 / 16);
+        let mut editor = TestEditor::new(&[("a.json", &line)]);
+        let frame = async |editor: &TestEditor| {
+            let state = editor.app.state.lock().await;
+            let started = Instant::now();
+            let frame = Frame::capture(&state).await.unwrap();
+            assert!(started.elapsed() < Duration::from_secs(1));
+            frame
+        };
+
+        let start = frame(&editor).await;
+        assert_eq!(
+            start.window.lines,
+            [line.chars().take(80).collect::<String>()]
+        );
+        assert_eq!((start.window.scroll_x, start.window.cursor.x), (0, 0));
+
+        editor.keys("$").await;
+        let end = frame(&editor).await;
+        let len = line.chars().count();
+        assert_eq!(end.window.scroll_x, len - 80);
+        assert_eq!(end.window.lines, [&line[len - 80..]]);
+        assert_eq!(end.window.cursor, UVec2::new(79, 0));
+
+        editor.keys("hhx0").await;
+        assert_eq!(frame(&editor).await.window.lines, start.window.lines);
+        assert_eq!(editor.lines().await[0].len(), len - 1);
+
+        // Columns are kept right of the cursor, scrolling sooner
+        editor.keys(":set siso=5<CR>").await;
+        editor
+            .api
+            .move_to_x(None, Position::Number(74))
+            .await
+            .unwrap();
+        assert_eq!(frame(&editor).await.window.scroll_x, 0);
+        editor.keys("l").await;
+        let margin = frame(&editor).await;
+        assert_eq!((margin.window.scroll_x, margin.window.cursor.x), (1, 74));
+        editor.keys("$").await;
+        assert_eq!(frame(&editor).await.window.scroll_x, len - 1 - 75);
+    }
+
+    #[tokio::test]
+    async fn test_idle_frames_are_not_drawn() {
+        let mut editor = TestEditor::new(&[("a.txt", "one\ntwo")]);
+        let mut renderer = Renderer::default();
+        let mut out = Vec::new();
+        let mut draw = async |editor: &TestEditor, out: &mut Vec<u8>| {
+            let frame = Frame::capture(&*editor.app.state.lock().await).await;
+            renderer.render_changed(out, frame.unwrap()).unwrap()
+        };
+
+        assert!(draw(&editor, &mut out).await);
+        assert!(!out.is_empty());
+        out.clear();
+        for _ in 0..10 {
+            assert!(!draw(&editor, &mut out).await);
+        }
+        assert!(out.is_empty());
+
+        editor.keys("j").await;
+        assert!(draw(&editor, &mut out).await);
+        assert!(!out.is_empty());
+    }
+
+    #[test]
+    fn test_restore_on_panic() {
+        static RESTORED: AtomicUsize = AtomicUsize::new(0);
+        restore_on_panic(|| {
+            RESTORED.fetch_add(1, Ordering::Relaxed);
+        });
+
+        // The panic still unwinds, and the process goes on
+        let result = std::thread::spawn(|| panic!("in a task")).join();
+        assert!(result.is_err());
+        assert!(RESTORED.load(Ordering::Relaxed) >= 1);
+    }
+
+    #[test]
+    fn test_screen_cursor() {
+        let size = UVec2::new(80, 23);
+        let cursor = screen_cursor(UVec2::new(3, 12), 10, UVec2::default(), size);
+        assert_eq!(cursor, UVec2::new(3, 2));
+
+        let cursor = screen_cursor(UVec2::new(3, 5), 0, UVec2::new(10, 1), size);
+        assert_eq!(cursor, UVec2::new(13, 6));
+    }
+
+    #[test]
+    fn test_screen_cursor_outside_viewport() {
+        // After jumping to the end of a long buffer before the scroll catches up
+        let size = UVec2::new(80, 23);
+        let cursor = screen_cursor(UVec2::new(100, 500), 0, UVec2::default(), size);
+        assert_eq!(cursor, UVec2::new(79, 22));
+
+        let cursor = screen_cursor(UVec2::new(0, 2), 10, UVec2::default(), size);
+        assert_eq!(cursor, UVec2::new(0, 0));
+    }
+
+    #[test]
+    fn test_format_command_line() {
+        let (line, cursor_x) = format_command_line(":", "w", 10);
+        assert_eq!(line, ":w        ");
+        assert_eq!(cursor_x, 2);
+
+        let (line, cursor_x) = format_command_line(":", "", 4);
+        assert_eq!(line, ":   ");
+        assert_eq!(cursor_x, 1);
+    }
+
+    #[test]
+    fn test_format_command_line_with_prompt() {
+        let (line, cursor_x) = format_command_line("Name: ", "ab", 10);
+        assert_eq!(line, "Name: ab  ");
+        assert_eq!(cursor_x, 8);
+    }
+
+    #[test]
+    fn test_format_command_line_longer_than_width() {
+        let (line, cursor_x) = format_command_line(":", "abcdefghij", 5);
+        assert_eq!(line, "ghij ");
+        assert_eq!(line.chars().count(), 5);
+        assert_eq!(cursor_x, 4);
+
+        let (line, cursor_x) = format_command_line(":", "abc", 0);
+        assert_eq!(line, "");
+        assert_eq!(cursor_x, 0);
     }
 }
@@ -0,0 +1,89 @@
+use api::StatusAlign;
+
+/// Text a plugin placed on the status line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusSegment {
+    pub id: String,
+    pub text: String,
+    pub align: StatusAlign,
+}
+
+/// Status line segments, in priority order (the first added is kept the longest)
+#[derive(Debug, Default)]
+pub struct StatusSegments {
+    segments: Vec<StatusSegment>,
+}
+
+impl StatusSegments {
+    /// Add a segment, or replace the text of an existing one keeping its priority
+    pub fn set(&mut self, id: &str, text: &str, align: StatusAlign) {
+        let segment = StatusSegment {
+            id: id.to_string(),
+            text: text.to_string(),
+            align,
+        };
+        match self.segments.iter_mut().find(|segment| segment.id == id) {
+            Some(old) => *old = segment,
+            None => self.segments.push(segment),
+        }
+    }
+
+    /// Remove a segment, returning false if it did not exist
+    pub fn remove(&mut self, id: &str) -> bool {
+        let count = self.segments.len();
+        self.segments.retain(|segment| segment.id != id);
+        self.segments.len() != count
+    }
+
+    pub fn get_all(&self) -> Vec<StatusSegment> {
+        self.segments.clone()
+    }
+}
+
+/// Join the segments that fit in `width` columns into left and right text,
+/// dropping the lowest-priority segments first
+pub fn layout_segments(segments: &[StatusSegment], width: usize) -> (String, String) {
+    let mut shown = segments.len();
+    let columns = |segment: &StatusSegment| segment.text.chars().count() + 1;
+    while segments[..shown].iter().map(columns).sum::<usize>() > width {
+        shown -= 1;
+    }
+
+    let join = |align: StatusAlign| {
+        segments[..shown]
+            .iter()
+            .filter(|segment| segment.align == align)
+            .map(|segment| format!(" {}", segment.text))
+            .collect::<String>()
+    };
+    (join(StatusAlign::Left), join(StatusAlign::Right))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout_segments() {
+        let mut segments = StatusSegments::default();
+        segments.set("git", "main", StatusAlign::Right);
+        segments.set("lsp", "ok", StatusAlign::Left);
+        segments.set("clock", "12:00", StatusAlign::Right);
+        segments.set("git", "dev", StatusAlign::Right);
+
+        let all = segments.get_all();
+        assert_eq!(
+            layout_segments(&all, 80),
+            (" ok".to_string(), " dev 12:00".to_string())
+        );
+        assert_eq!(
+            layout_segments(&all, 8),
+            (" ok".to_string(), " dev".to_string())
+        );
+        assert_eq!(layout_segments(&all, 2), (String::new(), String::new()));
+
+        assert!(segments.remove("lsp"));
+        assert!(!segments.remove("lsp"));
+        assert_eq!(segments.get_all().len(), 2);
+    }
+}
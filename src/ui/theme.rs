@@ -0,0 +1,122 @@
+use api::{DiagnosticSeverity, Mode};
+use crossterm::style::{Color, available_color_count};
+
+use crate::syntax::SyntaxKind;
+
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub status_fg: Color,
+    pub status_bg: Color,
+    pub mode_fg: Color,
+    pub normal_mode_bg: Color,
+    pub visual_mode_bg: Color,
+    pub insert_mode_bg: Color,
+    pub command_mode_bg: Color,
+    pub selection_bg: Color,
+    pub search_bg: Color,
+    pub error_fg: Color,
+    pub warning_fg: Color,
+    pub info_fg: Color,
+    pub hint_fg: Color,
+    pub comment_fg: Color,
+    pub string_fg: Color,
+    pub keyword_fg: Color,
+}
+
+impl Theme {
+    /// A theme using only the neutral status colors, for terminals without colors
+    pub fn monochrome() -> Self {
+        Self {
+            status_fg: Color::Black,
+            status_bg: Color::White,
+            mode_fg: Color::Black,
+            normal_mode_bg: Color::White,
+            visual_mode_bg: Color::White,
+            insert_mode_bg: Color::White,
+            command_mode_bg: Color::White,
+            selection_bg: Color::White,
+            search_bg: Color::White,
+            error_fg: Color::Black,
+            warning_fg: Color::Black,
+            info_fg: Color::Black,
+            hint_fg: Color::Black,
+            comment_fg: Color::Reset,
+            string_fg: Color::Reset,
+            keyword_fg: Color::Reset,
+        }
+    }
+
+    /// Get the background color of the mode segment in the status bar
+    pub fn mode_bg(&self, mode: &Mode) -> Color {
+        match mode {
+            Mode::Normal => self.normal_mode_bg,
+            Mode::Visual => self.visual_mode_bg,
+            Mode::Insert(_) => self.insert_mode_bg,
+            Mode::Command => self.command_mode_bg,
+        }
+    }
+
+    /// Get the color of text underlined by a diagnostic
+    pub fn severity_fg(&self, severity: DiagnosticSeverity) -> Color {
+        match severity {
+            DiagnosticSeverity::Error => self.error_fg,
+            DiagnosticSeverity::Warning => self.warning_fg,
+            DiagnosticSeverity::Info => self.info_fg,
+            DiagnosticSeverity::Hint => self.hint_fg,
+        }
+    }
+
+    /// Get the color of text highlighted by the syntax of its filetype
+    pub fn syntax_fg(&self, kind: SyntaxKind) -> Color {
+        match kind {
+            SyntaxKind::Comment => self.comment_fg,
+            SyntaxKind::String => self.string_fg,
+            SyntaxKind::Keyword => self.keyword_fg,
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        if available_color_count() < 8 {
+            return Self::monochrome();
+        }
+
+        Self {
+            status_fg: Color::Black,
+            status_bg: Color::White,
+            mode_fg: Color::Black,
+            normal_mode_bg: Color::Blue,
+            visual_mode_bg: Color::Magenta,
+            insert_mode_bg: Color::Green,
+            command_mode_bg: Color::Yellow,
+            selection_bg: Color::Blue,
+            search_bg: Color::DarkYellow,
+            error_fg: Color::Red,
+            warning_fg: Color::Yellow,
+            info_fg: Color::Blue,
+            hint_fg: Color::DarkGrey,
+            comment_fg: Color::DarkGrey,
+            string_fg: Color::Green,
+            keyword_fg: Color::Magenta,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mode_bg() {
+        let theme = Theme::monochrome();
+        assert_eq!(theme.mode_bg(&Mode::Insert(true)), theme.status_bg);
+
+        let theme = Theme {
+            insert_mode_bg: Color::Green,
+            ..Theme::monochrome()
+        };
+        assert_eq!(theme.mode_bg(&Mode::Insert(false)), Color::Green);
+        assert_eq!(theme.mode_bg(&Mode::Normal), Color::White);
+    }
+}
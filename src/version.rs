@@ -0,0 +1,76 @@
+//! What build of the editor is running, for `--version`, `--build-info` and plugins
+
+use api::{EditorInfo, PROTOCOL_VERSION};
+use plugin_manager::get_plugin_dir;
+
+/// Commit the editor was built from, unless it was built outside a git checkout
+const COMMIT: Option<&str> = option_env!("CUPRUM_GIT_COMMIT");
+
+/// The version of the crate, with the commit as semver build metadata, such as
+/// `0.1.0+1a2b3c4`
+pub fn get_version() -> String {
+    match COMMIT {
+        Some(commit) => format!("{}+{}", env!("CARGO_PKG_VERSION"), commit),
+        None => env!("CARGO_PKG_VERSION").to_string(),
+    }
+}
+
+/// What `--version` shows after the name
+pub fn get_version_text() -> String {
+    format!("{} (protocol {})", get_version(), PROTOCOL_VERSION)
+}
+
+pub fn get_editor_info() -> EditorInfo {
+    EditorInfo {
+        version: get_version(),
+        commit: COMMIT.map(str::to_string),
+        protocol_version: PROTOCOL_VERSION,
+        features: env!("CUPRUM_FEATURES")
+            .split(',')
+            .filter(|feature| !feature.is_empty())
+            .map(str::to_string)
+            .collect(),
+        plugin_dir: get_plugin_dir().to_string_lossy().to_string(),
+    }
+}
+
+/// What `--build-info` shows, a line for each detail
+pub fn get_build_info() -> String {
+    let info = get_editor_info();
+    let features = match info.features.is_empty() {
+        true => "none".to_string(),
+        false => info.features.join(", "),
+    };
+    [
+        ("version", info.version),
+        ("commit", info.commit.unwrap_or("unknown".to_string())),
+        ("protocol", info.protocol_version.to_string()),
+        ("profile", env!("CUPRUM_PROFILE").to_string()),
+        ("target", env!("CUPRUM_TARGET").to_string()),
+        ("features", features),
+        ("plugin directory", info.plugin_dir),
+    ]
+    .iter()
+    .map(|(name, value)| format!("{}: {}", name, value))
+    .collect::<Vec<String>>()
+    .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_is_semver() {
+        let version = semver::Version::parse(&get_version()).unwrap();
+        assert_eq!(version.to_string(), get_version());
+        assert_eq!(
+            version.build.as_str(),
+            COMMIT.unwrap_or_default(),
+            "{}",
+            version
+        );
+        assert!(get_version_text().ends_with(&format!("(protocol {})", PROTOCOL_VERSION)));
+        assert!(get_build_info().contains("\nplugin directory: "));
+    }
+}
@@ -1,14 +1,19 @@
 use std::sync::Arc;
 
-use api::Mode;
+use api::{Mode, TextRange};
 use tokio::sync::Mutex;
 use utils::{
-    term::get_terminal_size,
+    grapheme,
     vec2::{IVec2, UVec2},
 };
 
 use crate::{BufferId, buffer::Buffer};
 
+/// The size of a window filling a terminal of `term_size`, above the command line
+pub fn get_window_size(term_size: UVec2) -> UVec2 {
+    UVec2::new(term_size.x, term_size.y.saturating_sub(1))
+}
+
 #[derive(Debug)]
 pub struct Window {
     buffer_id: BufferId,
@@ -16,24 +21,29 @@ pub struct Window {
     mode: Arc<Mutex<Mode>>,
     cursor: UVec2,
     visual_start: UVec2,
+    last_visual: Option<(UVec2, UVec2)>,
     scroll: usize,
     position: UVec2,
     size: UVec2,
 }
 
 impl Window {
-    pub fn new(buffer_id: BufferId, buffer: Arc<Mutex<Buffer>>, mode: Arc<Mutex<Mode>>) -> Self {
-        let term_size = get_terminal_size().unwrap();
-
+    pub fn new(
+        buffer_id: BufferId,
+        buffer: Arc<Mutex<Buffer>>,
+        mode: Arc<Mutex<Mode>>,
+        size: UVec2,
+    ) -> Self {
         Self {
             buffer_id,
             buffer,
             mode,
             cursor: UVec2::default(),
             visual_start: UVec2::default(),
+            last_visual: None,
             scroll: 0,
             position: UVec2::default(),
-            size: UVec2::new(term_size.x, term_size.y - 1),
+            size,
         }
     }
 
@@ -41,7 +51,6 @@ impl Window {
         self.position
     }
 
-    #[allow(dead_code)] // TODO
     pub fn set_position(&mut self, position: UVec2) {
         self.position = position;
     }
@@ -58,135 +67,207 @@ impl Window {
         self.buffer.clone()
     }
 
-    #[allow(dead_code)] // TODO
     pub fn get_buffer_id(&self) -> BufferId {
         self.buffer_id
     }
 
-    #[allow(dead_code)] // TODO
     pub fn get_cursor(&self) -> UVec2 {
         self.cursor
     }
 
+    async fn is_insert(&self) -> bool {
+        matches!(*self.mode.lock().await, Mode::Insert(_))
+    }
+
+    /// The cursor clamped to its line, at the start of the grapheme cluster it is in
     pub(crate) async fn get_render_cursor(&self) -> UVec2 {
-        if let Some(max_x) = self.get_cursor_max_x().await {
-            if self.cursor.x > max_x {
-                return UVec2::new(max_x, self.cursor.y);
-            } else {
-                return self.cursor;
-            }
+        let insert = self.is_insert().await;
+        let buffer = self.buffer.lock().await;
+        match buffer.get_line_ref(self.cursor.y) {
+            Some(line) => UVec2::new(get_render_x(line, self.cursor.x, insert), self.cursor.y),
+            None => self.cursor,
         }
-        self.cursor
     }
 
     pub async fn get_visual_start(&self) -> UVec2 {
         self.visual_start
     }
 
+    /// Anchor the selection at the cursor when entering visual mode
     pub async fn start_visual(&mut self) {
-        if let Some(max_x) = self.get_cursor_max_x().await {
-            if self.cursor.x > max_x {
-                self.visual_start = UVec2::new(max_x, self.cursor.y);
-            } else {
-                self.visual_start = self.cursor;
+        self.visual_start = self.get_render_cursor().await;
+    }
+
+    /// The visual selection, from its first character to after its last. Selecting past
+    /// the end of a line takes its line break.
+    pub async fn get_selection(&self) -> TextRange {
+        let cursor = self.get_render_cursor().await;
+        let (start, last) = if cursor < self.visual_start {
+            (cursor, self.visual_start)
+        } else {
+            (self.visual_start, cursor)
+        };
+
+        let buffer = self.buffer.lock().await;
+        let line = buffer.get_line_ref(last.y).unwrap_or_default();
+        let end = match grapheme::next_start(line, last.x) {
+            Some(x) => UVec2::new(x, last.y),
+            None => {
+                let line_len = line.chars().count();
+                if last.x < line_len {
+                    UVec2::new(line_len, last.y)
+                } else if last.y + 1 < buffer.get_line_count() {
+                    UVec2::new(0, last.y + 1)
+                } else {
+                    UVec2::new(line_len, last.y)
+                }
             }
         };
+        TextRange { start, end }
     }
 
+    /// Remember the selection when leaving visual mode
+    pub async fn end_visual(&mut self) {
+        let cursor = self.get_render_cursor().await;
+        self.last_visual = Some(if cursor < self.visual_start {
+            (cursor, self.visual_start)
+        } else {
+            (self.visual_start, cursor)
+        });
+    }
+
+    /// Get the start and end of the last visual selection
+    pub fn get_last_visual(&self) -> Option<(UVec2, UVec2)> {
+        self.last_visual
+    }
+
+    /// After the last character in insert mode, on its first char otherwise
     pub async fn get_cursor_max_x(&self) -> Option<usize> {
+        let insert = self.is_insert().await;
         let buffer = self.buffer.lock().await;
-        if let Some(line_len) = buffer.get_line_length(self.cursor.y) {
-            Some(if let Mode::Insert(_) = self.mode.lock().await.clone() {
-                line_len
-            } else {
-                line_len.checked_sub(1).unwrap_or(line_len)
-            })
+        let line = buffer.get_line_ref(self.cursor.y)?;
+        Some(if insert {
+            line.chars().count()
+        } else {
+            grapheme::last(line)
+        })
+    }
+
+    /// Type `ch` at the cursor in insert mode and move past it, returning where it went.
+    /// The buffer is locked once, as this runs for every key typed.
+    pub async fn type_char(&mut self, ch: char) -> UVec2 {
+        let mut buffer = self.buffer.lock().await;
+        let line = buffer.get_line_ref(self.cursor.y).unwrap_or_default();
+        let x = grapheme::floor(line, self.cursor.x);
+        let pos = UVec2::new(x, self.cursor.y);
+
+        if ch == '\n' {
+            buffer.split_line(pos);
+            self.cursor = UVec2::new(0, pos.y + 1);
         } else {
-            None
+            buffer.insert_char(pos, ch);
+            let line = buffer.get_line_ref(pos.y).unwrap_or_default();
+            self.cursor.x = grapheme::next(line, x);
         }
+        drop(buffer);
+        self.sync_scroll();
+        pos
     }
 
     pub fn get_scroll(&self) -> usize {
         self.scroll
     }
 
+    /// Move by lines and by grapheme clusters
     pub async fn move_by(&mut self, offset: IVec2) {
-        {
-            if let Some(max_x) = self.get_cursor_max_x().await
-                && offset.x != 0
-                && self.cursor.x > max_x
-            {
-                self.cursor.x = max_x;
-            }
-        }
-
-        if let Some(pos) = self.cursor.checked_add(offset) {
-            {
-                let buffer = self.buffer.lock().await;
-                let line_count = buffer.get_line_count();
-                if pos.y >= line_count {
-                    return;
-                }
+        let Some(y) = self.cursor.y.checked_add_signed(offset.y) else {
+            return;
+        };
+        let insert = self.is_insert().await;
+        let buffer = self.buffer.lock().await;
+        let Some(line) = buffer.get_line_ref(y) else {
+            return;
+        };
 
-                self.cursor = pos;
+        if offset.x != 0 {
+            let mut x = get_render_x(line, self.cursor.x, insert);
+            for _ in 0..offset.x.unsigned_abs() {
+                x = if offset.x < 0 {
+                    grapheme::prev(line, x)
+                } else if insert {
+                    grapheme::next(line, x)
+                } else {
+                    // Moving right stops on the last character
+                    grapheme::next_start(line, x).unwrap_or(x)
+                };
             }
-            self.sync_scroll();
+            self.cursor.x = x;
         }
+        drop(buffer);
+        self.cursor.y = y;
+        self.sync_scroll();
     }
 
     pub async fn move_to_x(&mut self, x: usize) {
-        {
-            let buffer = self.buffer.lock().await;
-            let line_count = buffer.get_line_count();
-            if self.cursor.y >= line_count {
-                return;
-            }
-        }
-
-        if let Some(max_x) = self.get_cursor_max_x().await {
-            if x > max_x {
-                self.cursor.x = max_x;
-            } else {
-                self.cursor.x = x;
-            }
-        }
+        let insert = self.is_insert().await;
+        let buffer = self.buffer.lock().await;
+        let Some(line) = buffer.get_line_ref(self.cursor.y) else {
+            return;
+        };
+        self.cursor.x = get_render_x(line, x, insert);
+        drop(buffer);
         self.sync_scroll();
     }
 
+    /// Move to line `y`, clamped to the last line
     pub async fn move_to_y(&mut self, y: usize) {
         {
             let buffer = self.buffer.lock().await;
             let line_count = buffer.get_line_count();
-            if y >= line_count {
+            if line_count == 0 {
                 return;
             }
 
-            self.cursor.y = y;
-
-            if let Some(line) = buffer.get_line(self.cursor.y)
-                && self.cursor.x > line.len()
-            {
-                self.cursor.x = line.len();
-            }
+            self.cursor.y = y.min(line_count - 1);
+        }
+        if let Some(max_x) = self.get_cursor_max_x().await
+            && self.cursor.x > max_x
+        {
+            self.cursor.x = max_x;
         }
         self.sync_scroll();
     }
 
+    /// Move a window height down, or up if `forward` is false, stopping at the first and
+    /// last lines
+    pub async fn move_page(&mut self, forward: bool) {
+        let height = self.size.y.saturating_sub(1).max(1);
+        let y = if forward {
+            self.cursor.y.saturating_add(height)
+        } else {
+            self.cursor.y.saturating_sub(height)
+        };
+        self.move_to_y(y).await;
+    }
+
     pub fn move_to_line_start(&mut self) {
         self.cursor.x = 0;
         self.sync_scroll();
     }
 
+    /// Move after the last character in insert mode. Otherwise move onto it, and stay at
+    /// the end of the lines moved to from there.
     pub async fn move_to_line_end(&mut self) {
-        {
-            let buffer = self.buffer.lock().await;
-            let line_count = buffer.get_line_count();
-            if self.cursor.y >= line_count {
-                return;
-            }
-
-            self.cursor.x = usize::MAX;
+        if self.cursor.y >= self.buffer.lock().await.get_line_count() {
+            return;
         }
+
+        let mode = self.mode.lock().await.clone();
+        self.cursor.x = if let Mode::Insert(_) = mode {
+            self.get_cursor_max_x().await.unwrap_or_default()
+        } else {
+            usize::MAX
+        };
         self.sync_scroll();
     }
 
@@ -195,15 +276,22 @@ impl Window {
         self.sync_scroll();
     }
 
+    /// Move to the end of the last line
     pub async fn move_to_buffer_end(&mut self) {
-        {
-            let buffer = self.buffer.lock().await;
-            let line_count = buffer.get_line_count();
-            if line_count > 0
-                && let Some(line) = buffer.get_line(line_count - 1)
-            {
-                self.cursor = UVec2::new(line.len(), line_count - 1);
-            }
+        let line_count = self.buffer.lock().await.get_line_count();
+        if line_count > 0 {
+            self.cursor.y = line_count - 1;
+            self.cursor.x = self.get_cursor_max_x().await.unwrap_or_default();
+        }
+        self.sync_scroll();
+    }
+
+    /// Bring the cursor back onto the buffer after it changed
+    pub async fn clamp_to_buffer(&mut self) {
+        let line_count = self.buffer.lock().await.get_line_count();
+        self.cursor.y = self.cursor.y.min(line_count.saturating_sub(1));
+        if let Some(max_x) = self.get_cursor_max_x().await {
+            self.cursor.x = self.cursor.x.min(max_x);
         }
         self.sync_scroll();
     }
@@ -220,3 +308,13 @@ impl Window {
         }
     }
 }
+
+/// Where char `x` of `line` is shown: on the line, at the start of its grapheme cluster.
+/// Only insert mode goes past the last character.
+fn get_render_x(line: &str, x: usize, insert: bool) -> usize {
+    if insert {
+        grapheme::floor(line, x)
+    } else {
+        grapheme::clamp(line, x)
+    }
+}
@@ -0,0 +1,94 @@
+//! Run the editor binary with `--headless` against fixture files
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
+use serde_json::json;
+
+/// Copy a fixture to a directory of its own, so that tests can edit it
+fn copy_fixture(test: &str, name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cuprum-headless-{}-{}", test, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    fs::copy(Path::new("fixtures/headless").join(name), &path).unwrap();
+    path
+}
+
+/// Run the editor on `file` without a config, plugins or a terminal
+fn run_headless(file: &Path, commands: &[&str]) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_cuprum"));
+    command
+        .args(["--headless", "--clean", "--log-file"])
+        .arg(file.with_extension("log"));
+    for line in commands {
+        command.args(["-c", line]);
+    }
+    command.arg(file).output().unwrap()
+}
+
+#[test]
+fn test_headless_commands() {
+    let file = copy_fixture("commands", "input.txt");
+    let output = run_headless(&file, &["%s/foo/bar/g", "$r !echo four", "wq"]);
+    assert!(output.status.success(), "{:?}", output);
+
+    let expected = fs::read_to_string("fixtures/headless/expected.txt").unwrap();
+    assert_eq!(fs::read_to_string(&file).unwrap(), expected);
+    fs::remove_dir_all(file.parent().unwrap()).ok();
+}
+
+#[test]
+fn test_headless_errors() {
+    let file = copy_fixture("errors", "input.txt");
+    let output = run_headless(&file, &["nosuchcommand", "1d", "w"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("E492: Not an editor command: nosuchcommand"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("1 of 3 commands failed"), "{}", stderr);
+
+    // The commands after the failing one still ran
+    assert_eq!(fs::read_to_string(&file).unwrap(), "two foo foo\nthree\n");
+    fs::remove_dir_all(file.parent().unwrap()).ok();
+}
+
+#[test]
+fn test_headless_replay() {
+    // What `--record` writes: the terminal size, then `dd`
+    let dd = Event::Key(KeyEvent::new(KeyCode::Char('d'), KeyModifiers::NONE));
+    let session: String = [Event::Resize(80, 24), dd.clone(), dd]
+        .into_iter()
+        .enumerate()
+        .map(|(time, event)| format!("{}\n", json!({ "time": time, "event": event })))
+        .collect();
+
+    let replay = |test: &str| {
+        let file = copy_fixture(test, "input.txt");
+        let path = file.with_extension("jsonl");
+        fs::write(&path, &session).unwrap();
+        let output = Command::new(env!("CARGO_BIN_EXE_cuprum"))
+            .args(["--headless", "--clean", "--replay-fast", "--replay"])
+            .arg(&path)
+            .args(["-c", "wq", "--log-file"])
+            .arg(file.with_extension("log"))
+            .arg(&file)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "two foo foo\nthree\n");
+        fs::remove_dir_all(file.parent().unwrap()).ok();
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    // The same session ends in the same state, whichever process replays it
+    let stdout = replay("replay");
+    assert!(stdout.starts_with("state hash: "), "{}", stdout);
+    assert_eq!(replay("replay-again"), stdout);
+}